@@ -28,6 +28,12 @@ impl Bounds3 {
     }
   }
   
+  /// The center and radius of a sphere that encloses the box
+  pub fn bounding_sphere(&self) -> (Point3, f64) {
+    let center = self.min + (self.max - self.min) / 2.;
+    (center, (self.max - center).length())
+  }
+
   pub fn maximum_dimension(&self) -> u8 {
     let range = self.max - self.min;
     if range.x > range.y {
@@ -72,6 +78,24 @@ impl Bounds3 {
 
     return t_min < ray.time_max && t_max > 0.;
   }
+
+  /// The times at which the ray enters and leaves the box, clipped to the extent of the ray, if it passes through it
+  pub fn intersect_times(&self, ray: &Ray) -> Option<(f64, f64)> { // pbrt: IntersectP()
+    let (mut t0, mut t1) = (0., ray.time_max);
+    for axis in 0..3 {
+      let inv_dir = 1. / ray.direction[axis];
+      let t_near = (self.min[axis] - ray.origin[axis]) * inv_dir;
+      let t_far = (self.max[axis] - ray.origin[axis]) * inv_dir;
+      let (t_near, t_far) = if t_near > t_far { (t_far, t_near) } else { (t_near, t_far) };
+      let t_far = t_far * (1. + 2. * gamma(3));
+
+      // Written so that NaNs, from rays lying in a slab's plane, leave the range alone
+      t0 = if t_near > t0 { t_near } else { t0 };
+      t1 = if t_far < t1 { t_far } else { t1 };
+      if t0 > t1 { return None; }
+    }
+    Some((t0, t1))
+  }
 }
 
 #[derive(Default, Copy, Clone, Debug)]
@@ -79,6 +103,32 @@ pub struct Bounds2<T> {
   pub min: Point2<T>,
   pub max: Point2<T>,
 }
+
+impl Bounds2<u32> {
+  pub fn intersect(&self, other: &Self) -> Self {
+    let min = Point2 { x: self.min.x.max(other.min.x), y: self.min.y.max(other.min.y) };
+    let max = Point2 { x: self.max.x.min(other.max.x).max(min.x), y: self.max.y.min(other.max.y).max(min.y) };
+    Bounds2 { min, max }
+  }
+
+  /// Split these bounds into square tiles of the given size, in row-major order
+  /// Tiles along the right and bottom edges are clipped to the bounds
+  pub fn tiles(&self, tile_size: u32) -> Vec<Bounds2<u32>> {
+    assert!(tile_size > 0, "Tiles must be at least one pixel across");
+    let mut tiles = vec![];
+    let mut y = self.min.y;
+    while y < self.max.y {
+      let mut x = self.min.x;
+      while x < self.max.x {
+        let tile = Bounds2 { min: Point2 { x, y }, max: Point2 { x: x + tile_size, y: y + tile_size } };
+        tiles.push(tile.intersect(self));
+        x += tile_size;
+      }
+      y += tile_size;
+    }
+    tiles
+  }
+}
   
 pub struct PixelIterator {
   pub bounds: Bounds2<u32>,
@@ -0,0 +1,81 @@
+use std::f64::consts::PI;
+
+use super::{Bounds3, Point3, Transform, Vector3};
+
+/// A set of directions within an angle of a central one, like the directions a surface faces or a light shines in
+#[derive(Copy, Clone, Debug)]
+pub struct DirectionCone {
+  pub direction: Vector3,
+  /// Cosine of the angle from `direction` to the edge of the cone
+  pub cos_theta: f64,
+}
+
+impl Default for DirectionCone {
+  /// No directions at all
+  fn default() -> Self {
+    Self { direction: Vector3::default(), cos_theta: f64::INFINITY }
+  }
+}
+
+impl DirectionCone {
+  pub fn new(direction: Vector3, cos_theta: f64) -> Self {
+    Self { direction: direction.normalized(), cos_theta }
+  }
+
+  pub fn from_direction(direction: Vector3) -> Self {
+    Self::new(direction, 1.)
+  }
+
+  pub fn entire_sphere() -> Self {
+    Self { direction: Vector3::new(0., 0., 1.), cos_theta: -1. }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.cos_theta == f64::INFINITY
+  }
+
+  /// The directions from a point towards anywhere in a box
+  pub fn subtended_by(bounds: &Bounds3, point: Point3) -> Self {
+    let (center, radius) = bounds.bounding_sphere();
+    let offset = center - point;
+    let distance_sq = offset.length_squared();
+    if distance_sq < radius * radius {
+      return Self::entire_sphere();
+    }
+    let sin_theta_sq = radius * radius / distance_sq;
+    Self::new(offset, (1. - sin_theta_sq).max(0.).sqrt())
+  }
+
+  /// The smallest cone containing both cones
+  pub fn union(&self, other: &Self) -> Self {
+    if self.is_empty() {
+      return *other;
+    }
+    if other.is_empty() {
+      return *self;
+    }
+
+    // If either cone already contains the other, use it
+    let theta_a = self.cos_theta.clamp(-1., 1.).acos();
+    let theta_b = other.cos_theta.clamp(-1., 1.).acos();
+    let theta_d = self.direction.dot(other.direction).clamp(-1., 1.).acos();
+    if (theta_d + theta_b).min(PI) <= theta_a {
+      return *self;
+    }
+    if (theta_d + theta_a).min(PI) <= theta_b {
+      return *other;
+    }
+
+    // Otherwise turn this cone's direction towards the other's until it covers both
+    let theta_o = (theta_a + theta_d + theta_b) / 2.;
+    if theta_o >= PI {
+      return Self::entire_sphere();
+    }
+    let axis = self.direction.cross(other.direction);
+    if axis.length_squared() == 0. {
+      return Self::entire_sphere();
+    }
+    let direction = Transform::rotate((theta_o - theta_a).to_degrees(), axis) * self.direction;
+    Self::new(direction, theta_o.cos())
+  }
+}
@@ -3,4 +3,6 @@ use std::f64::consts;
 use consts::PI;
 
 pub const TO_RADIANS: f64 = PI / 180.;
-pub const TO_DEGREES: f64 = 180. / PI;
\ No newline at end of file
+pub const TO_DEGREES: f64 = 180. / PI;
+/// How far short of its target a shadow ray stops, to avoid hitting the surface it was aimed at
+pub const SHADOW_EPSILON: f64 = 0.0001;
\ No newline at end of file
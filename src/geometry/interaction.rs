@@ -1,12 +1,16 @@
+use std::sync::Arc;
+
 use bumpalo::Bump;
 
-use crate::{render::{BSDF, Spectrum}, scene::{AreaLight, Material, MaterialInstance, TransportMode}};
+use crate::{render::{BSDF, Spectrum}, scene::{AreaLight, Material, MaterialInstance, MediumInstance, MediumInterface, TransportMode}};
 
-use super::{Normal3, Point3, Ray, Vector3};
+use super::{Normal3, Point2, Point3, Ray, SHADOW_EPSILON, Vector3};
 
 #[derive(Clone, Copy, Default)]
 pub struct Intersection {
   pub point: Point3,
+  /// The surface's own 2D parameterization of the point
+  pub uv: Point2,
   pub point_derivative: (Vector3, Vector3),
   pub outgoing: Vector3,
   pub normal: Normal3,
@@ -21,22 +25,40 @@ pub struct Interaction {
   pub intersection: Intersection,
   pub emission: Option<AreaLight>,
   pub material: Option<MaterialInstance>,
+  /// Set if the surface is the boundary between two media
+  pub medium_interface: Option<MediumInterface>,
 }
 
 impl Intersection {
+  /// A ray leaving this point in the given direction, nudged off the surface so it doesn't hit it again
+  pub fn spawn_ray(&self, direction: Vector3) -> Ray {
+    let origin = self.point.offset_for_error(self.error, self.normal, direction);
+    Ray { origin, direction, time_max: f64::INFINITY }
+  }
+
   pub fn ray_between(&self, other: &Intersection) -> Ray {
     let origin = self.point.offset_for_error(self.error, self.normal, Vector3::from(other.point - self.point));
     let target = other.point.offset_for_error(other.error, other.normal, Vector3::from(origin - other.point));
-    let direction = Vector3::from(target - origin).normalized();
-    Ray { origin, direction, time_max: f64::INFINITY }
+    let offset = target - origin;
+    let distance = offset.length();
+    // Stop just short of the other point, so only things in between count as occluders
+    Ray { origin, direction: offset / distance, time_max: distance * (1. - SHADOW_EPSILON) }
   }
 }
 
 impl Interaction {
+  /// The medium a ray leaving the surface in the given direction travels through,
+  /// which is the one it arrived in unless the surface is a boundary between media
+  pub fn medium_towards(&self, direction: Vector3, current: &Option<Arc<MediumInstance>>) -> Option<Arc<MediumInstance>> {
+    match &self.medium_interface {
+      Some(interface) => interface.towards(&self.intersection, direction),
+      None => current.clone(),
+    }
+  }
+
   pub fn emitted_radiance(&self) -> Spectrum {
     if let Some(emission) = &self.emission {
-      // TOOD(pi): Should this be shading normal?
-      emission.emitted_radiance(&self.intersection, self.intersection.normal.into())
+      emission.emitted_radiance(&self.intersection, self.intersection.outgoing)
     } else {
       Spectrum::default()
     }
@@ -101,16 +101,17 @@ impl Matrix4x4 {
           }
         }
       }
+    }
 
-      for j in (0..4).rev() {
-        if row_indexes[j] != col_indexes[j] {
-          for k in 0..4 {
-            let row_index = row_indexes[j];
-            let col_index = col_indexes[j];
-            let tmp = result[k][row_index];
-            result[k][row_index] = result[k][col_index];
-            result[k][col_index] = tmp;
-          }
+    // Undo the column swaps from pivoting, now that elimination is finished
+    for j in (0..4).rev() {
+      if row_indexes[j] != col_indexes[j] {
+        for k in 0..4 {
+          let row_index = row_indexes[j];
+          let col_index = col_indexes[j];
+          let tmp = result[k][row_index];
+          result[k][row_index] = result[k][col_index];
+          result[k][col_index] = tmp;
         }
       }
     }
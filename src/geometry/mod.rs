@@ -2,6 +2,7 @@ mod constants;
 mod error;
 mod ray;
 mod bounds;
+mod cone;
 mod point;
 mod vector;
 mod normal;
@@ -12,6 +13,7 @@ pub use constants::*;
 pub use error::*;
 pub use ray::*;
 pub use bounds::*;
+pub use cone::*;
 pub use point::*;
 pub use vector::*;
 pub use normal::*;
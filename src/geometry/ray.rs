@@ -1,6 +1,6 @@
 use super::{Point3, Vector3};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default)]
 pub struct Ray {
   pub origin: Point3,
   pub direction: Vector3,
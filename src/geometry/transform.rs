@@ -216,6 +216,7 @@ impl Mul<Intersection> for Transform {
     let normal = (self * i.normal).normalized();
     Self::Output {
       point, error,
+      uv: i.uv,
       point_derivative: (self * i.point_derivative.0, self * i.point_derivative.1),
       outgoing: (self * i.outgoing).normalized(),
       normal,
@@ -47,6 +47,15 @@ impl Vector3 {
   pub fn abs(&self) -> Self {
     Self { x: self.x.abs(), y: self.y.abs(), z: self.z.abs() }
   }
+  /// Two more vectors, which together with this (normalized) one form an orthonormal basis
+  pub fn coordinate_system(&self) -> (Vector3, Vector3) {
+    let v2 = if self.x.abs() > self.y.abs() {
+      Vector3::new(-self.z, 0., self.x) / (self.x * self.x + self.z * self.z).sqrt()
+    } else {
+      Vector3::new(0., self.z, -self.y) / (self.y * self.y + self.z * self.z).sqrt()
+    };
+    (v2, self.cross(v2))
+  }
   pub fn reflect(&self, normal: Normal3) -> Self {
     // NOTE: assumes the normal is normalized
     let dot = self.dot(normal.into());
@@ -4,20 +4,40 @@ mod geometry;
 mod options;
 mod render;
 mod scene;
+mod obj;
 mod ply;
 mod utils;
-use std::{path::PathBuf, sync::Arc, time::Instant, unimplemented};
+use std::{fs, io, path::{Path, PathBuf}, sync::Arc, time::{Duration, Instant}, unimplemented};
 
 use clap::Clap;
 use geometry::{Bounds2, Normal3, Point2, Point3, Transform, Vector3};
+use obj::read_obj;
 use options::*;
 use ply::read_ply;
 use render::*;
-use scene::{AreaLight, BVHAggregate, BVHNode, DiskShape, GeometricPrimitive, Glass, LightInstance, Matte, Mirror, Plastic, PointLight, PrimitiveInstance, PrimitiveList, Scene, ShapeInstance, SphereShape, SplitMethod, TriangleMesh};
+use scene::{AreaLight, BVHAggregate, BVHNode, DiskShape, DistantLight, GeometricPrimitive, Glass, GoniometricLight, GridDensityMedium, HomogeneousMedium, InfiniteAreaLight, LightInstance, Matte, MediumInstance, Mirror, PbrtParameters, Plastic, PointLight, PowerLightSampler, PrimitiveInstance, ProjectionLight, PrimitiveList, Scene, ShapeInstance, SkyModel, SphereShape, SplitMethod, SpotLight, TriangleMesh, UniformLightSampler, pbrt_statements};
 
 fn main() {
     let options: Options = Options::parse();
 
+    match &options.command {
+        Some(Command::Merge(merge)) => {
+            if let Err(e) = merge_films(merge) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        },
+        Some(Command::Denoise(denoise)) => return denoise_film(denoise),
+        None => {},
+    }
+
+    let unsupported = unsupported_options(&options);
+    if !unsupported.is_empty() {
+        eprintln!("The {} integrator doesn't support {}", options.integrator, unsupported.join(", "));
+        std::process::exit(1);
+    }
+
     let (_scene, _state) = if true || options.input_files.len() == 1 {
         let mut scene_info = pbrt_rs::Scene::default();
         let mut state = pbrt_rs::State::default();
@@ -46,38 +66,43 @@ fn main() {
         Transform::translate(Vector3::new(0., -1., -5.)) *
         Transform::rotate(90., Vector3::new(1., 0., 0.));
 
-    let mesh = Arc::new(read_ply(s0, "scenes/bunny/bun_3.ply".into()));
+    let mesh = Arc::new(read_ply(s0, "scenes/bunny/bun_3.ply".into()).expect("Unable to read the bunny"));
     let tris = mesh.to_triangles();
     let mut prims: Vec<PrimitiveInstance> = vec![];
     // tris.into_iter().map(|t| GeometricPrimitive {
     //     shape: t,
     //     emission: None,
+    //     medium_interface: None,
     //     material: Some(Matte { color: Spectrum { r: 0.5, g: 0.7, b: 0.7 }, roughness: 0. }.into()),
     // }.into()).collect();
 
     prims.append(&mut vec![
         GeometricPrimitive {
-            shape: DiskShape { object_to_world: s5, height: 0., radius: 20., inner_radius: 0.}.into(),
+            shape: Arc::new(DiskShape { object_to_world: s5, height: 0., radius: 20., inner_radius: 0.}.into()),
             material: Some(Matte { color: Spectrum { r: 0.8, g: 0.8, b: 0.8 }, roughness: 1. }.into()),
             emission: None,
+            medium_interface: None,
         }.into(),
         GeometricPrimitive {
-            shape: SphereShape { object_to_world: s1, radius: 1. }.into(),
+            shape: Arc::new(SphereShape { object_to_world: s1, radius: 1. }.into()),
             material: Some(Matte { color: Spectrum { r: 0.576, g: 0.859, b: 0.475 }, roughness: 0. }.into()),
             emission: None,
+            medium_interface: None,
         }.into(),
         GeometricPrimitive {
-            shape: SphereShape { object_to_world: s6, radius: 1. }.into(),
+            shape: Arc::new(SphereShape { object_to_world: s6, radius: 1. }.into()),
             material: Some(Matte { color: Spectrum { r: 0.576, g: 0.859, b: 0.475 }, roughness: 0. }.into()),
             emission: None,
+            medium_interface: None,
         }.into(),
         GeometricPrimitive {
-            shape: SphereShape { object_to_world: s2, radius: 5. }.into(),
+            shape: Arc::new(SphereShape { object_to_world: s2, radius: 5. }.into()),
             material: Some(Mirror { color: Spectrum { r: 0.75, g: 0.75, b: 0.75 } }.into()),
-            emission: None
+            emission: None,
+            medium_interface: None,
         }.into(),
         GeometricPrimitive {
-        shape: SphereShape { object_to_world: s7, radius: 1. }.into(),
+        shape: Arc::new(SphereShape { object_to_world: s7, radius: 1. }.into()),
             material: Some(Glass {
                 color_reflected: Spectrum::white(),
                 color_transmitted: Spectrum::white(),
@@ -85,52 +110,136 @@ fn main() {
                 roughness: (0., 0.),
                 remap_roughness: true,
             }.into()),
-            emission: None
+            emission: None,
+            medium_interface: None,
         }.into(),
         GeometricPrimitive {
-            shape: SphereShape { object_to_world: s3, radius: 1. }.into(),
+            shape: Arc::new(SphereShape { object_to_world: s3, radius: 1. }.into()),
             material: Some(Mirror { color: Spectrum { r: 0.623, g: 0.204, b: 0.788 } }.into()),
-            emission: None
+            emission: None,
+            medium_interface: None,
         }.into(),
         GeometricPrimitive {
-            shape: SphereShape { object_to_world: s4, radius: 1. }.into(),
+            shape: Arc::new(SphereShape { object_to_world: s4, radius: 1. }.into()),
             material: Some(Plastic {
                 diffuse_reflection: Spectrum { r: 0.623, g: 0.204, b: 0.788 },
                 glossy_reflection: Spectrum { r: 0.725, g: 0.416, b: 0.851 },
                 roughness: 0.15,
                 remap_roughness: true,
             }.into()),
-            emission: None
+            emission: None,
+            medium_interface: None,
         }.into(),
     ]);
 
-    let agg = BVHAggregate::new(prims, 100, SplitMethod::Middle);
-    // let agg = PrimitiveList { primitives: prims };
-
-    let scene = Scene::new(
-        agg.into(),
-        vec![
+    let lights = match &options.lights {
+        Some(file) => {
+            let (lights, mut shapes) = load_lights(file);
+            prims.append(&mut shapes);
+            lights
+        },
+        None => vec![
             LightInstance::from(PointLight {
                 position: Point3 { x: 1., y: 7., z: 2. },
                 color: Spectrum { r: 300., g: 300., b: 300. }
             }),
         ],
-    );
+    };
+
+    let agg = BVHAggregate::new(prims, 100, SplitMethod::Middle);
+    // let agg = PrimitiveList { primitives: prims };
+    let mut scene = Scene::new(agg.into(), lights);
+    match options.light_sampler.as_str() {
+        // The scene already samples its lights with a light BVH
+        "bvh" => {},
+        "uniform" => scene.light_sampler = UniformLightSampler::new(&scene.lights).into(),
+        "power" => scene.light_sampler = PowerLightSampler::new(&scene.lights).into(),
+        other => unreachable!("clap only accepts known light samplers, not {:?}", other),
+    }
 
     let cam_trans = Transform::look_at(
         Point3 { x: 10., y: 3.0, z: 3. },
         Point3 { x: 0., y: 0., z: -7. },
         Vector3 { x: 0., y: 1., z: 0. }
     ).inverse();
-    let mut i = WhittedIntegrator::new(
-        20,
-        CameraInstance::from(PerspectiveCamera::new(
-            cam_trans, Bounds2 { min: Point2 { x: -1.0, y: -0.3 }, max: Point2 { x: 1.0, y: 0.3 } },
-            0., 0., 0., 0., 75.,
-            Arc::new(Film::new(Point2 { x: 1000, y: 300 }))
-        )),
-        SamplerInstance::from(NullSampler {}),
+    let out_file = options.out_file.clone().unwrap_or(PathBuf::from("./out.png"));
+    let settings = RenderSettings {
+      checkpoint: options.checkpoint.clone(),
+      checkpoint_interval: Duration::from_secs(options.checkpoint_interval),
+      tile_size: options.tile_size,
+      tiles: options.tiles.as_ref().map(|r| r.start as usize..r.end as usize),
+      samples: options.samples.as_ref().map(|r| r.start as i64..r.end as i64),
+      progressive: options.progressive,
+      snapshot: Some(out_file.clone()),
+      snapshot_interval: options.snapshot_interval.map(Duration::from_secs),
+      time_limit: options.time_limit.map(Duration::from_secs),
+      // Any saved film state might be denoised later, so make sure it has the features to do so
+      collect_features: options.denoise || options.partial.is_some() || options.checkpoint.is_some(),
+      max_direct: options.clamp_direct,
+      max_indirect: options.clamp_indirect,
+      outlier_rejection: options.reject_outliers,
+      seed: options.seed,
+    };
+    let sampler = match options.spp {
+      Some(spp) => SamplerInstance::from(RandomSampler::new(spp, options.seed)),
+      None => SamplerInstance::from(NullSampler {}),
+    };
+    let film = Arc::new(Film::new(Point2 { x: 1000, y: 300 }));
+    let mut camera = PerspectiveCamera::new(
+        cam_trans, Bounds2 { min: Point2 { x: -1.0, y: -0.3 }, max: Point2 { x: 1.0, y: 0.3 } },
+        0., 0., 75.,
+        film.clone()
     );
+    camera.medium = options.medium.as_ref().map(|file| Arc::new(load_medium(file)));
+    let camera = CameraInstance::from(camera);
+    let mut i: IntegratorInstance = match options.integrator.as_str() {
+        "whitted" => SamplerIntegratorInstance::from(
+            WhittedIntegrator::new(options.max_depth.unwrap_or(20), camera, sampler, settings)
+        ).into(),
+        "directlighting" => {
+            let strategy = match options.light_strategy.as_str() {
+                "all" => LightStrategy::UniformAll,
+                "one" => LightStrategy::SampleOne,
+                other => unreachable!("clap only accepts known light strategies, not {:?}", other),
+            };
+            SamplerIntegratorInstance::from(
+                DirectLightingIntegrator::new(strategy, options.max_depth.unwrap_or(5), options.light_samples, camera, sampler, settings)
+            ).into()
+        },
+        "volpath" => SamplerIntegratorInstance::from(
+            VolPathIntegrator::new(options.max_depth.unwrap_or(5), camera, sampler, settings)
+        ).into(),
+        "guidedpath" => GuidedPathIntegrator::new(options.max_depth.unwrap_or(5), camera, sampler, settings).into(),
+        "vpl" => SamplerIntegratorInstance::from(
+            VPLIntegrator::new(options.max_depth.unwrap_or(5), options.vpl_paths, options.vpl_clamp, camera, sampler, settings)
+        ).into(),
+        "bdpt" => SamplerIntegratorInstance::from(
+            BDPTIntegrator::new(options.max_depth.unwrap_or(5), camera, sampler, settings)
+        ).into(),
+        "lighttracing" => SamplerIntegratorInstance::from(
+            LightTracingIntegrator::new(options.max_depth.unwrap_or(5), camera, sampler, settings)
+        ).into(),
+        "mlt" => MLTIntegrator::new(
+            options.max_depth.unwrap_or(5), camera, options.spp.unwrap_or(100),
+            options.mlt_bootstrap_samples as u64, options.mlt_chains as u64, options.seed,
+        ).into(),
+        "sppm" => SPPMIntegrator::new(options.max_depth.unwrap_or(5), camera, sampler, settings).into(),
+        "ambientocclusion" => SamplerIntegratorInstance::from(
+            AOIntegrator::new(options.ao_samples, options.ao_distance.unwrap_or(f64::INFINITY), camera, sampler, settings)
+        ).into(),
+        view @ ("normals" | "geometricnormals" | "uv" | "depth" | "materialid" | "bvhnodes") => {
+            let view = match view {
+                "normals" => DebugView::Surface(SurfaceProperty::ShadingNormals),
+                "geometricnormals" => DebugView::Surface(SurfaceProperty::GeometricNormals),
+                "uv" => DebugView::Surface(SurfaceProperty::UV),
+                "depth" => DebugView::Surface(SurfaceProperty::Depth),
+                "materialid" => DebugView::Surface(SurfaceProperty::MaterialID),
+                _ => DebugView::BVHNodes { max_nodes: options.bvh_max_nodes },
+            };
+            SamplerIntegratorInstance::from(DebugIntegrator::new(view, camera, sampler, settings)).into()
+        },
+        other => unreachable!("clap only accepts known integrators, not {:?}", other),
+    };
         
     println!("Starting...");
     let start = Instant::now();
@@ -139,5 +248,228 @@ fn main() {
 
     println!("Finished.  Took: {:.2}s", start.elapsed().as_secs_f64());
 
-    i.camera.film().write_to(options.out_file.unwrap_or(PathBuf::from("./out.png")));
+    if let Some(partial) = options.partial {
+        film.write_state(partial).expect("Unable to write partial film");
+    }
+    if options.denoise {
+        Denoiser::default().denoise(&film).write_to(out_file);
+    } else {
+        film.write_to(out_file);
+    }
+}
+
+/// Load the medium that fills the scene, from the first MakeNamedMedium statement in a pbrt file,
+/// or from a binary density volume filling the unit cube
+fn load_medium(file: &PathBuf) -> MediumInstance {
+    let directory = file.parent().unwrap_or_else(|| Path::new("."));
+    let medium: io::Result<MediumInstance> = if file.extension().map_or(false, |e| e == "pbrt") {
+        fs::read_to_string(file).and_then(|text| {
+            let statement = pbrt_statements(&text).into_iter()
+                .find(|statement| statement.starts_with("MakeNamedMedium"))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No MakeNamedMedium statement"))?;
+            match PbrtParameters::parse(statement)?.string("type") {
+                Some("homogeneous") => HomogeneousMedium::from_pbrt(statement).map(MediumInstance::from),
+                _ => GridDensityMedium::from_pbrt(statement, Transform::default(), directory).map(MediumInstance::from),
+            }
+        })
+    } else {
+        GridDensityMedium::from_volume(file, Transform::default()).map(MediumInstance::from)
+    };
+    medium.unwrap_or_else(|e| panic!("Unable to read medium {:?}: {}", file, e))
+}
+
+/// Load the lights from a pbrt file, following any transforms that place them, along with any triangle meshes,
+/// which give off light if they follow an AreaLightSource
+fn load_lights(file: &PathBuf) -> (Vec<LightInstance>, Vec<PrimitiveInstance>) {
+    let text = fs::read_to_string(file).unwrap_or_else(|e| panic!("Unable to read lights {:?}: {}", file, e));
+    let directory = file.parent().unwrap_or_else(|| Path::new("."));
+
+    let mut lights = vec![];
+    let mut shapes = vec![];
+    // The transform, and the AreaLightSource statement for any shapes, within each AttributeBegin
+    let mut attributes: Vec<(Transform, Option<&str>)> = vec![(Transform::default(), None)];
+    for statement in pbrt_statements(&text) {
+        let (keyword, rest) = statement.split_once(char::is_whitespace).unwrap_or((statement, ""));
+        let numbers: Vec<f64> = rest.split(|c: char| c.is_whitespace() || c == '[' || c == ']')
+            .filter_map(|word| word.parse().ok())
+            .collect();
+        let (current, area_light) = *attributes.last().unwrap();
+        let transform = match (keyword, numbers.as_slice()) {
+            ("AttributeBegin" | "TransformBegin", _) => {
+                attributes.push((current, area_light));
+                continue;
+            },
+            ("AttributeEnd" | "TransformEnd", _) => {
+                if attributes.len() > 1 {
+                    attributes.pop();
+                }
+                continue;
+            },
+            ("Translate", &[x, y, z]) => Transform::translate(Vector3::new(x, y, z)),
+            ("Scale", &[x, y, z]) => Transform::scale(Vector3::new(x, y, z)),
+            ("Rotate", &[angle, x, y, z]) => Transform::rotate(angle, Vector3::new(x, y, z)),
+            ("LightSource", _) => {
+                let loaded = load_light(statement, current, directory)
+                    .unwrap_or_else(|e| panic!("Unable to read lights {:?}: {}", file, e));
+                lights.extend(loaded);
+                continue;
+            },
+            ("AreaLightSource", _) => {
+                attributes.last_mut().unwrap().1 = Some(statement);
+                continue;
+            },
+            ("Shape", _) => {
+                let loaded = load_shape(statement, current, area_light, directory)
+                    .unwrap_or_else(|e| panic!("Unable to read shapes {:?}: {}", file, e));
+                shapes.extend(loaded);
+                continue;
+            },
+            (keyword, _) => {
+                println!("Ignoring {} in {:?}", keyword, file);
+                continue;
+            },
+        };
+        attributes.last_mut().unwrap().0 = current * transform;
+    }
+    (lights, shapes)
+}
+
+/// Most statements make a single light, but a sky can come with a sun
+fn load_light(statement: &str, light_to_world: Transform, directory: &Path) -> io::Result<Vec<LightInstance>> {
+    let parameters = PbrtParameters::parse(statement)?;
+    let light = match parameters.kind() {
+        Some("point") => PointLight {
+            position: light_to_world * parameters.point("from", Point3 { x: 0., y: 0., z: 0. })?,
+            color: parameters.spectrum("I", Spectrum::white())? * parameters.float("scale", 1.)?,
+        }.into(),
+        Some("spot") => SpotLight::from_pbrt(statement, light_to_world)?.into(),
+        Some("goniometric") => GoniometricLight::from_pbrt(statement, light_to_world, directory)?.into(),
+        Some("projection") => ProjectionLight::from_pbrt(statement, light_to_world, directory)?.into(),
+        Some("distant") => DistantLight::from_pbrt(statement, light_to_world)?.into(),
+        Some("infinite") => InfiniteAreaLight::from_pbrt(statement, light_to_world, directory)?.into(),
+        Some("sky") => return SkyModel::from_pbrt(statement, light_to_world),
+        other => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported light {:?}", other))),
+    };
+    Ok(vec![light])
+}
+
+/// A primitive for each triangle of a mesh, from a `trianglemesh`, `plymesh` or `objmesh` Shape statement.
+/// pbrt has no OBJ shape, so `objmesh` reads one from "string filename" the same way `plymesh` does.
+/// Each triangle gets its own area light if `area_light` is a diffuse AreaLightSource.
+fn load_shape(statement: &str, object_to_world: Transform, area_light: Option<&str>, directory: &Path) -> io::Result<Vec<PrimitiveInstance>> {
+    let parameters = PbrtParameters::parse(statement)?;
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let filename = || parameters.string("filename").map(|file| directory.join(file)).ok_or_else(|| invalid("Mesh has no filename".to_string()));
+    let mesh = match parameters.kind() {
+        Some("trianglemesh") => {
+            let vertices: Vec<Point3> = parameters.values("P").unwrap_or(&[]).chunks_exact(3).map(|p| Point3::new(p[0], p[1], p[2])).collect();
+            let mut indices: Vec<usize> = parameters.values("indices").unwrap_or(&[]).iter().map(|&i| i as usize).collect();
+            // A single triangle can leave out its indices
+            if indices.is_empty() && vertices.len() == 3 {
+                indices = vec![0, 1, 2];
+            }
+            if indices.len() % 3 != 0 || indices.iter().any(|&i| i >= vertices.len()) {
+                return Err(invalid(format!("Mesh has {} indices for {} vertices", indices.len(), vertices.len())));
+            }
+            let uvs: Vec<Point2> = parameters.values("uv").or_else(|| parameters.values("st")).unwrap_or(&[])
+                .chunks_exact(2).map(|t| Point2::new(t[0], t[1])).collect();
+            let normals: Vec<Normal3> = match parameters.values("N") {
+                Some(n) => n.chunks_exact(3).map(|n| Normal3::new(n[0], n[1], n[2])).collect(),
+                None => vec![Normal3::default(); vertices.len()],
+            };
+            if (!uvs.is_empty() && uvs.len() != vertices.len()) || normals.len() != vertices.len() {
+                return Err(invalid(format!("Mesh needs texture coordinates and normals for all {} vertices", vertices.len())));
+            }
+            TriangleMesh::new(object_to_world, &indices, &vertices, &normals, &vec![Vector3::default(); vertices.len()], &uvs)
+        },
+        Some("plymesh") => read_ply(object_to_world, filename()?)?,
+        Some("objmesh") => read_obj(object_to_world, filename()?)?,
+        other => return Err(invalid(format!("Unsupported shape {:?}, expected a triangle mesh", other))),
+    };
+
+    let emission = match area_light {
+        Some(statement) => Some(load_area_light(statement, directory)?),
+        None => None,
+    };
+    let material = Matte { color: Spectrum::greyscale(0.5), roughness: 0. }.into();
+    Ok(GeometricPrimitive::from_mesh(
+        Arc::new(mesh),
+        Some(material),
+        emission.as_ref().map(|emission| emission as &dyn Fn(Arc<ShapeInstance>) -> AreaLight),
+    ))
 }
+
+/// How an AreaLightSource statement makes the light given off by each shape after it.  Only "diffuse" lights
+/// are supported, with "rgb L", "float scale", "bool twosided", and an image in "string filename" whose
+/// colors multiply L across the shapes' texture coordinates.
+fn load_area_light(statement: &str, directory: &Path) -> io::Result<impl Fn(Arc<ShapeInstance>) -> AreaLight> {
+    let parameters = PbrtParameters::parse(statement)?;
+    if !matches!(parameters.kind(), Some("diffuse" | "area")) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported area light {:?}", parameters.kind())));
+    }
+    let color = parameters.spectrum("L", Spectrum::white())?;
+    let scale = parameters.float("scale", 1.)?;
+    let two_sided = parameters.bool("twosided", false)?;
+    let image = match parameters.string("filename") {
+        Some(file) => Some(Arc::new(HdrImage::read(&directory.join(file))?)),
+        None => None,
+    };
+    let average_color = image.as_ref().map_or(color, |image| color * image.average());
+    Ok(move |shape: Arc<ShapeInstance>| AreaLight { scale, two_sided, image: image.clone(), average_color, ..AreaLight::new(shape, color) })
+}
+
+/// The options given that the integrator can't honour, since it renders the whole image its own way
+/// rather than sample by sample through the usual render loop
+fn unsupported_options(options: &Options) -> Vec<&'static str> {
+    let (clamps, watches) = match options.integrator.as_str() {
+        "mlt" => (false, false),
+        "sppm" => (false, true),
+        "guidedpath" => (true, true),
+        _ => return vec![],
+    };
+    let given = [
+        ("--checkpoint", options.checkpoint.is_some()),
+        ("--tiles", options.tiles.is_some()),
+        ("--samples", options.samples.is_some()),
+        ("--progressive", options.progressive),
+        ("--reject-outliers", options.reject_outliers.is_some()),
+        ("--denoise", options.denoise),
+        ("--clamp-direct", !clamps && options.clamp_direct.is_some()),
+        ("--clamp-indirect", !clamps && options.clamp_indirect.is_some()),
+        ("--snapshot-interval", !watches && options.snapshot_interval.is_some()),
+        ("--time-limit", !watches && options.time_limit.is_some()),
+    ];
+    given.iter().filter(|(_, given)| *given).map(|(name, _)| *name).collect()
+}
+
+fn merge_films(merge: &MergeOptions) -> Result<(), String> {
+    let read = |input: &PathBuf| Film::read_state(input.clone())
+        .map_err(|e| format!("Unable to read partial film {:?}: {}", input, e));
+    let mut inputs = merge.input_files.iter();
+    let first = inputs.next().ok_or("No partial films to merge")?;
+    let film = read(first)?;
+    for input in inputs {
+        film.merge(&read(input)?).map_err(|e| format!("Unable to merge partial film {:?}: {}", input, e))?;
+    }
+
+    if let Some(partial) = &merge.partial {
+        film.write_state(partial.clone()).map_err(|e| format!("Unable to write merged film {:?}: {}", partial, e))?;
+    }
+    film.write_to(merge.out_file.clone().unwrap_or(PathBuf::from("./out.png")));
+    Ok(())
+}
+
+
+fn denoise_film(denoise: &DenoiseOptions) {
+    let film = Film::read_state(denoise.input_file.clone())
+        .unwrap_or_else(|e| panic!("Unable to read film {:?}: {}", denoise.input_file, e));
+    let denoiser = Denoiser {
+        radius: denoise.radius,
+        sigma_spatial: denoise.sigma_spatial,
+        sigma_albedo: denoise.sigma_albedo,
+        sigma_normal: denoise.sigma_normal,
+        sigma_depth: denoise.sigma_depth,
+        sigma_color: denoise.sigma_color,
+    };
+    denoiser.denoise(&film).write_to(denoise.out_file.clone().unwrap_or(PathBuf::from("./out.png")));
+}
\ No newline at end of file
@@ -0,0 +1,142 @@
+use std::{collections::HashMap, fs, io, path::PathBuf};
+
+use crate::{geometry::{Normal3, Point2, Point3, Transform, Vector3}, scene::TriangleMesh};
+
+/// Read the faces of a Wavefront OBJ file as a single mesh, with the texture coordinates and normals
+/// its faces refer to.  Faces with more than three corners are split into triangles, and groups,
+/// objects and materials are ignored.
+pub fn read_obj(obj_to_world: Transform, file: PathBuf) -> io::Result<TriangleMesh> {
+  parse_obj(obj_to_world, &fs::read_to_string(&file)?)
+}
+
+fn parse_obj(obj_to_world: Transform, text: &str) -> io::Result<TriangleMesh> {
+  let mut positions: Vec<Point3> = vec![];
+  let mut texture_coordinates: Vec<Point2> = vec![];
+  let mut file_normals: Vec<Normal3> = vec![];
+  // OBJ faces pick a position, texture coordinate and normal separately for each corner, so each combination
+  // that's used becomes a vertex of its own
+  let mut corners: HashMap<(usize, Option<usize>, Option<usize>), usize> = HashMap::new();
+  let mut vertices = vec![];
+  let mut normals = vec![];
+  let mut uvs = vec![];
+  let mut indices = vec![];
+  for line in text.lines() {
+    let mut words = line.split_whitespace();
+    let keyword = words.next();
+    let numbers = || line.split_whitespace().skip(1)
+      .map(|word| word.parse::<f64>().map_err(|_| invalid(&format!("Invalid OBJ line {:?}", line))))
+      .collect::<io::Result<Vec<f64>>>();
+    match keyword {
+      Some("v") => match numbers()?.as_slice() {
+        [x, y, z, ..] => positions.push(Point3::new(*x, *y, *z)),
+        _ => return Err(invalid(&format!("Invalid OBJ vertex {:?}", line))),
+      },
+      Some("vt") => match numbers()?.as_slice() {
+        [u, v, ..] => texture_coordinates.push(Point2::new(*u, *v)),
+        [u] => texture_coordinates.push(Point2::new(*u, 0.)),
+        _ => return Err(invalid(&format!("Invalid OBJ texture coordinate {:?}", line))),
+      },
+      Some("vn") => match numbers()?.as_slice() {
+        [x, y, z] => file_normals.push(Normal3::new(*x, *y, *z)),
+        _ => return Err(invalid(&format!("Invalid OBJ normal {:?}", line))),
+      },
+      Some("f") => {
+        let mut face = vec![];
+        for corner in words {
+          // Each corner is `v`, `v/vt`, `v//vn` or `v/vt/vn`, counting from 1, or back from the end if negative
+          let mut parts = corner.split('/');
+          let mut index = |count: usize| -> io::Result<Option<usize>> {
+            match parts.next() {
+              None | Some("") => Ok(None),
+              Some(part) => {
+                let index: i64 = part.parse().map_err(|_| invalid(&format!("Invalid OBJ face {:?}", line)))?;
+                let resolved = if index < 0 { count as i64 + index } else { index - 1 };
+                if resolved < 0 || resolved >= count as i64 {
+                  return Err(invalid(&format!("OBJ face {:?} refers to something missing", line)));
+                }
+                Ok(Some(resolved as usize))
+              },
+            }
+          };
+          let position = index(positions.len())?.ok_or_else(|| invalid(&format!("OBJ face {:?} is missing a vertex", line)))?;
+          let key = (position, index(texture_coordinates.len())?, index(file_normals.len())?);
+          let vertex = *corners.entry(key).or_insert_with(|| {
+            vertices.push(positions[position]);
+            normals.push(key.2.map_or(Normal3::default(), |n| file_normals[n]));
+            uvs.push(key.1.map_or(Point2::default(), |t| texture_coordinates[t]));
+            vertices.len() - 1
+          });
+          face.push(vertex);
+        }
+        for i in 2..face.len() {
+          indices.extend([face[0], face[i - 1], face[i]]);
+        }
+      },
+      _ => {},
+    }
+  }
+
+  // Only keep texture coordinates if the faces actually used some
+  if corners.keys().all(|(_, uv, _)| uv.is_none()) {
+    uvs.clear();
+  }
+  let tangents = vec![Vector3::default(); vertices.len()];
+  Ok(TriangleMesh::new(obj_to_world, &indices[..], &vertices[..], &normals[..], &tangents[..], &uvs[..]))
+}
+
+fn invalid(message: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const QUAD: &str = "# A unit square
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+vn 0 0 1
+f 1/1/1 2/2/1 3/3/1 -1/-1/-1
+";
+
+  #[test]
+  fn reads_faces() {
+    let mesh = parse_obj(Transform::default(), QUAD).unwrap();
+    assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3]);
+    assert_eq!(mesh.vertices.len(), 4);
+    assert_eq!(mesh.uvs.len(), 4);
+    assert_eq!((mesh.uvs[2].x, mesh.uvs[2].y), (1., 1.));
+  }
+
+  #[test]
+  fn shares_repeated_corners() {
+    let mesh = parse_obj(Transform::default(), "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 1 1 0\nf 1 2 3\nf 3 2 4\n").unwrap();
+    assert_eq!(mesh.indices, vec![0, 1, 2, 2, 1, 3]);
+    assert_eq!(mesh.vertices.len(), 4);
+    assert!(mesh.uvs.is_empty());
+  }
+
+  #[test]
+  fn rejects_malformed_obj() {
+    let invalid = [
+      "v 0 0\n",
+      "v 0 zero 0\n",
+      "vn 0 1\n",
+      "v 0 0 0\nf 1 2 1\n",
+      "v 0 0 0\nf 0 1 1\n",
+      "v 0 0 0\nf -2 1 1\n",
+      "v 0 0 0\nf 1/2 1 1\n",
+      "v 0 0 0\nf 1//x 1 1\n",
+      "v 0 0 0\nvt 0 0\nf /1 1 1\n",
+    ];
+    for text in invalid {
+      assert!(parse_obj(Transform::default(), text).is_err(), "accepted {:?}", text);
+    }
+  }
+}
@@ -27,6 +27,39 @@ impl FromStr for Rect {
     }
 }
 
+/// A half-open range of indices, like tiles or samples
+#[derive(Debug)]
+pub struct IndexRange {
+  pub start: u64,
+  pub end: u64,
+}
+
+impl FromStr for IndexRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(",");
+        let result: Result<IndexRange, ()> = try {
+          IndexRange {
+            start: parts.next().ok_or(())?.parse::<u64>().or(Err(()))?,
+            end: parts.next().ok_or(())?.parse::<u64>().or(Err(()))?,
+          }
+        };
+        match result {
+          Ok(range) if range.start <= range.end => Ok(range),
+          _ => Err("Invalid range.  Expected format: start,end".to_string()),
+        }
+    }
+}
+
+/// Rejects zero for options that divide the work up by size, which would never finish
+fn positive(s: &str) -> Result<u32, String> {
+  match s.parse::<u32>() {
+    Ok(0) | Err(_) => Err("Expected a whole number greater than 0".to_string()),
+    Ok(n) => Ok(n),
+  }
+}
+
 pub enum LogLevel {
   Info,
   Warning,
@@ -47,6 +80,12 @@ impl FromStr for LogLevel {
   }
 }
 
+/// The integrators `--integrator` accepts, including the debug views
+const INTEGRATORS: &[&str] = &[
+  "whitted", "directlighting", "volpath", "guidedpath", "vpl", "bdpt", "lighttracing", "mlt", "sppm", "ambientocclusion",
+  "normals", "geometricnormals", "uv", "depth", "materialid", "bvhnodes",
+];
+
 /// Command Line Physically-Based Renderer, derived from http://www.pbr-book.org/
 #[derive(Clap)]
 #[clap(version="0.1", author = "Pi Lanningham <pi.lanningham@gmail.com>")]
@@ -60,6 +99,111 @@ pub struct Options {
   /// Write the final image to the given filename.
   #[clap(long = "outfile")]
   pub out_file: Option<PathBuf>,
+  /// Which integrator to render with: whitted, directlighting, volpath, guidedpath, vpl, bdpt, lighttracing, mlt, sppm or
+  /// ambientocclusion, or one of the debug views: normals, geometricnormals, uv, depth, materialid or bvhnodes.
+  #[clap(long, default_value = "whitted", possible_values = INTEGRATORS)]
+  pub integrator: String,
+  /// How the directlighting integrator samples the lights: "all" samples every light, "one" picks one with the light sampler.
+  #[clap(long, default_value = "all", possible_values = &["all", "one"])]
+  pub light_strategy: String,
+  /// How integrators that take one light sample at a time choose the light: "uniform" picks any light equally,
+  /// "power" favours bright lights, and "bvh" favours lights that are bright and close to the point being lit.
+  #[clap(long, default_value = "bvh", possible_values = &["uniform", "power", "bvh"])]
+  pub light_sampler: String,
+  /// How many samples the directlighting integrator takes from each light, with the "all" strategy.
+  #[clap(long, default_value = "1")]
+  pub light_samples: usize,
+  /// How many BVH nodes a camera ray has to visit to show as the hottest color in the bvhnodes debug view.
+  #[clap(long, default_value = "100", validator = positive)]
+  pub bvh_max_nodes: u32,
+  /// How many occlusion rays the ambientocclusion integrator casts from each point.
+  #[clap(long, default_value = "64")]
+  pub ao_samples: usize,
+  /// How far away something can be and still occlude a point, for the ambientocclusion integrator.
+  #[clap(long)]
+  pub ao_distance: Option<f64>,
+  /// How many paths the vpl integrator traces from the lights to place virtual lights along.
+  #[clap(long, default_value = "64")]
+  pub vpl_paths: usize,
+  /// How many paths of each length the mlt integrator traces up front, to estimate how bright the image is overall
+  /// and to pick where its Markov chains start.
+  #[clap(long, default_value = "100000", validator = positive)]
+  pub mlt_bootstrap_samples: u32,
+  /// How many independent Markov chains the mlt integrator runs.  More chains explore more of the image
+  /// independently, fewer let each chain linger longer on the paths it finds.
+  #[clap(long, default_value = "1000", validator = positive)]
+  pub mlt_chains: u32,
+  /// The largest the geometry term between a point and a virtual light can be, for the vpl integrator.
+  /// Lower values hide the bright spots virtual lights leave on nearby surfaces, but lose more light.
+  #[clap(long, default_value = "10")]
+  pub vpl_clamp: f64,
+  /// Fill the scene with a medium from a pbrt file holding a homogeneous or heterogeneous MakeNamedMedium statement,
+  /// which can read its densities from the binary volume in "string filename" and is placed by "point p0" and
+  /// "point p1".  A binary volume file given on its own fills the unit cube.  Only the volpath integrator renders it.
+  #[clap(long)]
+  pub medium: Option<PathBuf>,
+  /// Light the scene with the LightSource statements in a pbrt file, instead of the default point light.
+  ///
+  ///  - "point", "spot" and "infinite" lights work as they do in pbrt.
+  ///  - "distant" lights take an extra "float angulardiameter" to make them a sun.
+  ///  - "sky" is a clear sky with a sun, lit from "vector sundir" with +z up.
+  ///  - "goniometric" follows a luminaire's IES profile from "string filename", pointing down -z.
+  ///  - "projection" casts the image in "string mapname" down +z over "float fov" degrees.
+  ///  - Translate, Rotate and Scale place the lights.
+  ///  - "trianglemesh", "plymesh" and "objmesh" Shape statements add meshes to the scene too. They glow if they
+  ///    follow a "diffuse" AreaLightSource, tinted by the image in its "string filename" if it has one.
+  ///  - Any light's color can be a temperature in kelvin, like "blackbody L" [2700], or "blackbody L" [2700 5]
+  ///    to scale it too.
+  #[clap(long)]
+  pub lights: Option<PathBuf>,
+  /// The maximum number of bounces along each path, defaulting to a sensible value for the integrator.
+  #[clap(long)]
+  pub max_depth: Option<u32>,
+  /// Override the number of samples taken for each pixel (or mutations per pixel for mlt, or iterations for sppm).
+  #[clap(long)]
+  pub spp: Option<i64>,
+  /// Seed for the random sampler.  Renders of the same scene with different seeds can be merged.
+  #[clap(long, default_value = "0")]
+  pub seed: u64,
+  /// Periodically save the film state to this file, and resume from it if it already exists.
+  #[clap(long)]
+  pub checkpoint: Option<PathBuf>,
+  /// How many seconds to wait between saving checkpoints.
+  #[clap(long, default_value = "60")]
+  pub checkpoint_interval: u64,
+  /// The width and height, in pixels, of the tiles the image is divided into.
+  #[clap(long, default_value = "16", validator = positive)]
+  pub tile_size: u32,
+  /// Only render this range of tiles, numbered in row-major order starting from 0.
+  #[clap(long, value_name="start,end")]
+  pub tiles: Option<IndexRange>,
+  /// Only take this range of samples in each pixel.
+  #[clap(long, value_name="start,end")]
+  pub samples: Option<IndexRange>,
+  /// Write the raw film state to this file, so it can be combined with other partial renders using `merge`.
+  #[clap(long)]
+  pub partial: Option<PathBuf>,
+  /// Render the whole image in passes of increasing sample count, instead of finishing one pixel at a time.
+  #[clap(long)]
+  pub progressive: bool,
+  /// Rewrite the output image every given number of seconds while rendering.
+  #[clap(long)]
+  pub snapshot_interval: Option<u64>,
+  /// Stop rendering cleanly after the given number of seconds.
+  #[clap(long)]
+  pub time_limit: Option<u64>,
+  /// Denoise the final image, guided by the albedo, normal and depth of the surfaces in each pixel.
+  #[clap(long)]
+  pub denoise: bool,
+  /// Clamp the luminance of light arriving directly from the first surface each camera ray hits.
+  #[clap(long)]
+  pub clamp_direct: Option<f64>,
+  /// Clamp the luminance of light arriving at the camera via further bounces, to suppress fireflies.
+  #[clap(long)]
+  pub clamp_indirect: Option<f64>,
+  /// Leave samples out of a pixel if they're this many standard deviations brighter than its average.
+  #[clap(long, value_name = "deviations")]
+  pub reject_outliers: Option<f64>,
   /// Automatically reduce a number of quality settings to render more quickly.
   #[clap(long)]
   pub quick: bool,
@@ -85,4 +229,54 @@ pub struct Options {
   pub toply: bool,
   /// Input pbrt files to render
   pub input_files: Vec<PathBuf>,
+  #[clap(subcommand)]
+  pub command: Option<Command>,
+}
+
+#[derive(Clap)]
+pub enum Command {
+  /// Combine partial film files, rendered separately, into a single image.
+  Merge(MergeOptions),
+  /// Denoise a saved film file into an image.
+  Denoise(DenoiseOptions),
+}
+
+#[derive(Clap)]
+pub struct MergeOptions {
+  /// Write the final image to the given filename.
+  #[clap(long = "outfile")]
+  pub out_file: Option<PathBuf>,
+  /// Also write the combined film state to this file.
+  #[clap(long)]
+  pub partial: Option<PathBuf>,
+  /// Partial film files to combine
+  #[clap(required = true)]
+  pub input_files: Vec<PathBuf>,
+}
+
+#[derive(Clap)]
+pub struct DenoiseOptions {
+  /// Write the final image to the given filename.
+  #[clap(long = "outfile")]
+  pub out_file: Option<PathBuf>,
+  /// How many pixels in each direction to search for similar neighbours.
+  #[clap(long, default_value = "5")]
+  pub radius: u32,
+  /// Standard deviation of the falloff with distance, in pixels.
+  #[clap(long, default_value = "3")]
+  pub sigma_spatial: f64,
+  /// Standard deviation of the falloff with difference in albedo.
+  #[clap(long, default_value = "0.1")]
+  pub sigma_albedo: f64,
+  /// Standard deviation of the falloff with difference in surface normal.
+  #[clap(long, default_value = "0.2")]
+  pub sigma_normal: f64,
+  /// Standard deviation of the falloff with difference in depth, relative to the depth.
+  #[clap(long, default_value = "0.05")]
+  pub sigma_depth: f64,
+  /// How many standard deviations of noise two colors may differ by and still be blended.
+  #[clap(long, default_value = "2")]
+  pub sigma_color: f64,
+  /// Film file to denoise, saved with --partial or --checkpoint
+  pub input_file: PathBuf,
 }
\ No newline at end of file
@@ -1,39 +1,164 @@
-use std::{fs, path::PathBuf};
+use std::{fs, io, path::PathBuf};
 
-use crate::{geometry::{Normal3, Point3, Transform, Vector3}, scene::TriangleMesh};
+use crate::{geometry::{Normal3, Point2, Point3, Transform, Vector3}, scene::TriangleMesh};
 
-pub fn read_ply(obj_to_world: Transform, file: PathBuf) -> TriangleMesh {
-  let file = fs::read_to_string(file).unwrap();
-  let v_line = file.lines().nth(3).unwrap();
-  let vertex_count: usize = v_line.split(" ").nth(2).unwrap().parse().unwrap();
-  let f_line = file.lines().nth(9).unwrap();
-  let face_count: usize = f_line.split(" ").nth(2).unwrap().parse().unwrap();
+/// Read an ASCII PLY file, picking up normals and texture coordinates if its vertices have them.
+/// Faces with more than three corners are split into triangles.
+pub fn read_ply(obj_to_world: Transform, file: PathBuf) -> io::Result<TriangleMesh> {
+  parse_ply(obj_to_world, &fs::read_to_string(&file)?)
+}
+
+fn parse_ply(obj_to_world: Transform, text: &str) -> io::Result<TriangleMesh> {
+  let mut lines = text.lines();
+
+  // The header says how many vertices and faces there are, and which values each vertex has
+  let (mut vertex_count, mut face_count) = (0, 0);
+  let mut properties: Vec<&str> = vec![];
+  let mut element = "";
+  for line in lines.by_ref() {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+      ["format", format, ..] if *format != "ascii" => return Err(invalid(&format!("Only ASCII PLY files are supported, not {}", format))),
+      ["element", name, count] => {
+        element = *name;
+        let count = count.parse().map_err(|_| invalid(&format!("Invalid {} count {:?}", name, count)))?;
+        match *name {
+          "vertex" => vertex_count = count,
+          "face" => face_count = count,
+          _ => {},
+        }
+      },
+      ["property", .., name] if element == "vertex" => properties.push(*name),
+      ["end_header"] => break,
+      _ => {},
+    }
+  }
+  let column = |names: &[&str]| properties.iter().position(|p| names.contains(p));
+  let position = [column(&["x"]), column(&["y"]), column(&["z"])];
+  let normal = [column(&["nx"]), column(&["ny"]), column(&["nz"])];
+  let uv = [column(&["u", "s", "texture_u", "texture_s"]), column(&["v", "t", "texture_v", "texture_t"])];
+  let position = match position {
+    [Some(x), Some(y), Some(z)] => [x, y, z],
+    _ => return Err(invalid("PLY vertices have no x, y and z")),
+  };
 
   let mut vertices = vec![];
   let mut normals = vec![];
   let mut tangents = vec![];
-  for line in file.lines().skip(12).take(vertex_count) {
-    let vertex: Vec<f64> = line.split(" ").map(|p| p.parse::<f64>().unwrap()).take(3).collect();
-    let vertex = Point3 { x: vertex[0], y: vertex[1], z: vertex[2] };
-    vertices.push(vertex);
-    normals.push(Normal3::default());
+  let mut uvs = vec![];
+  for line in lines.by_ref().take(vertex_count) {
+    let values = line.split_whitespace()
+      .map(|p| p.parse::<f64>().map_err(|_| invalid(&format!("Invalid PLY vertex {:?}", line))))
+      .collect::<io::Result<Vec<f64>>>()?;
+    let value = |column: usize| values.get(column).copied().ok_or_else(|| invalid(&format!("PLY vertex {:?} is too short", line)));
+    vertices.push(Point3 { x: value(position[0])?, y: value(position[1])?, z: value(position[2])? });
+    normals.push(match normal {
+      [Some(x), Some(y), Some(z)] => Normal3::new(value(x)?, value(y)?, value(z)?),
+      _ => Normal3::default(),
+    });
     tangents.push(Vector3::default());
+    if let [Some(u), Some(v)] = uv {
+      uvs.push(Point2::new(value(u)?, value(v)?));
+    }
+  }
+
+  if vertices.len() != vertex_count {
+    return Err(invalid(&format!("Expected {} PLY vertices, found {}", vertex_count, vertices.len())));
+  }
+
+  let faces: Vec<&str> = lines.take(face_count).collect();
+  if faces.len() != face_count {
+    return Err(invalid(&format!("Expected {} PLY faces, found {}", face_count, faces.len())));
   }
 
   let mut indices = vec![];
-  for line in file.lines().skip(12 + vertex_count) {
-    let parts: Vec<_> = line.split(" ").skip(1).take(3).collect();
-    let face: Vec<usize> = parts.into_iter().map(|p| p.parse::<usize>().unwrap()).collect();
-    indices.push(face[0]);
-    indices.push(face[1]);
-    indices.push(face[2]);
+  for line in faces {
+    let face = line.split_whitespace()
+      .map(|p| p.parse::<usize>().map_err(|_| invalid(&format!("Invalid PLY face {:?}", line))))
+      .collect::<io::Result<Vec<usize>>>()?;
+    // Each face starts with how many corners it has
+    let corners = match face.split_first() {
+      Some((&count, corners)) if corners.len() == count => corners,
+      _ => return Err(invalid(&format!("PLY face {:?} doesn't have the number of corners it says", line))),
+    };
+    if corners.iter().any(|&corner| corner >= vertices.len()) {
+      return Err(invalid(&format!("PLY face {:?} refers to a missing vertex", line)));
+    }
+    for i in 2..corners.len() {
+      indices.extend([corners[0], corners[i - 1], corners[i]]);
+    }
   }
 
-  return TriangleMesh::new(
+  Ok(TriangleMesh::new(
     obj_to_world,
     &indices[..],
     &vertices[..],
     &normals[..],
     &tangents[..],
-  );
-}
\ No newline at end of file
+    &uvs[..],
+  ))
+}
+
+fn invalid(message: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const QUAD: &str = "ply
+format ascii 1.0
+element vertex 4
+property float x
+property float y
+property float z
+property float u
+property float v
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0 0 0
+1 0 0 1 0
+1 1 0 1 1
+0 1 0 0 1
+4 0 1 2 3
+";
+
+  #[test]
+  fn reads_faces() {
+    let mesh = parse_ply(Transform::default(), QUAD).unwrap();
+    assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3]);
+    assert_eq!(mesh.vertices.len(), 4);
+    assert_eq!((mesh.uvs[2].x, mesh.uvs[2].y), (1., 1.));
+  }
+
+  #[test]
+  fn rejects_truncated_ply() {
+    let end = QUAD.rfind("4 0 1 2 3").unwrap();
+    let mut lines = vec![];
+    for line in QUAD[..end].lines() {
+      lines.push(line);
+      let text = lines.join("\n");
+      assert!(parse_ply(Transform::default(), &text).is_err(), "accepted {:?}", text);
+    }
+  }
+
+  #[test]
+  fn rejects_malformed_ply() {
+    let invalid = [
+      QUAD.replace("format ascii", "format binary_little_endian"),
+      QUAD.replace("element vertex 4", "element vertex four"),
+      QUAD.replace("property float z", "property float w"),
+      QUAD.replace("1 1 0 1 1", "1 1 0 1"),
+      QUAD.replace("1 1 0 1 1", "1 one 0 1 1"),
+      QUAD.replace("4 0 1 2 3", "4 0 1 2"),
+      QUAD.replace("4 0 1 2 3", "3 0 1 2 3"),
+      QUAD.replace("4 0 1 2 3", "4 0 1 2 4"),
+      QUAD.replace("4 0 1 2 3", "4 0 1 -2 3"),
+    ];
+    for text in &invalid {
+      assert!(parse_ply(Transform::default(), text).is_err(), "accepted {:?}", text);
+    }
+  }
+}
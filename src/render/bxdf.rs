@@ -6,6 +6,7 @@ use enum_dispatch::enum_dispatch;
 
 use super::{
   Spectrum,
+  cosine_sample_hemisphere,
   bxdfs::{
     SpecularReflection,
     SpecularTransmission,
@@ -127,6 +128,35 @@ impl<'a> BSDF<'a> {
   }
 }
 
+/// A BSDF that owns its components rather than borrowing them from an arena, so it can be kept as long as needed
+pub struct OwnedBSDF {
+  index_of_refraction: f64,
+  geometric_normal: Normal3,
+  shading_normal: Normal3,
+  tangent_s: Vector3,
+  tangent_t: Vector3,
+  components: Vec<BxDFInstance>,
+}
+
+impl OwnedBSDF {
+  /// The BSDF itself, to evaluate or sample
+  pub fn bsdf(&self) -> BSDF<'_> {
+    let mut components: [Option<&BxDFInstance>; MAX_BXDF] = Default::default();
+    for (slot, component) in components.iter_mut().zip(&self.components) {
+      *slot = Some(component);
+    }
+    BSDF {
+      index_of_refraction: self.index_of_refraction,
+      geometric_normal: self.geometric_normal,
+      shading_normal: self.shading_normal,
+      tangent_s: self.tangent_s,
+      tangent_t: self.tangent_t,
+      num_components: self.components.len(),
+      components,
+    }
+  }
+}
+
 struct BxDFIterator<'a> {
   components: &'a [Option<&'a BxDFInstance>],
   curr: usize,
@@ -295,6 +325,23 @@ impl<'a> BSDF<'a> {
     return pdf;
   }
 
+  /// How many components match the given category
+  pub fn num_components(&self, category: BxDFCategory) -> usize {
+    self.matching_components(category).count()
+  }
+
+  /// Copy the components out of the arena, so the BSDF can outlive it
+  pub fn to_owned_bsdf(&self) -> OwnedBSDF {
+    OwnedBSDF {
+      index_of_refraction: self.index_of_refraction,
+      geometric_normal: self.geometric_normal,
+      shading_normal: self.shading_normal,
+      tangent_s: self.tangent_s,
+      tangent_t: self.tangent_t,
+      components: self.matching_components(BxDFCategory::ALL).cloned().collect(),
+    }
+  }
+
   pub fn add_component(&mut self, bxdf: &'a mut BxDFInstance) {
     self.components[self.num_components] = Some(bxdf);
     self.num_components += 1;
@@ -352,9 +399,11 @@ pub trait BxDF {
   fn evaluate(&self, outgoing: Vector3, incoming: Vector3) -> Spectrum;
 
   fn sample_function(&self, outgoing: Vector3, sample: &Point2) -> BxDFSample {
-    // TODO: hemisphere sampling
-    let incoming =
-        Vector3::new(sample.x, sample.y, if outgoing.z < 0. { 1. } else { -1. }).normalized();
+    // Cosine-weighted, in the same hemisphere as the outgoing direction
+    let mut incoming = cosine_sample_hemisphere(*sample);
+    if outgoing.z < 0. {
+        incoming.z = -incoming.z;
+    }
 
     let probability_distribution = self.probability_distribution(outgoing, incoming);
     let value = self.evaluate(outgoing, incoming);
@@ -363,7 +412,7 @@ pub trait BxDF {
         value,
         incoming,
         probability_distribution,
-        category: BxDFCategory::NONE,
+        category: self.category(),
     }
   }
   fn hemispherical_directional_reflectance(
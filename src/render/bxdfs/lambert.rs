@@ -1,6 +1,6 @@
 use std::f64::consts::FRAC_1_PI;
 
-use crate::{geometry::{Point2, Vector3}, render::{BxDF, BxDFCategory, BxDFSample, Spectrum, shading_coordinates}};
+use crate::{geometry::{Point2, Vector3}, render::{BxDF, BxDFCategory, BxDFSample, Spectrum, cosine_sample_hemisphere, shading_coordinates}};
 
 #[derive(Clone)]
 /// Represents light reflection that is perfectly uniformly scattered
@@ -40,9 +40,11 @@ impl BxDF for LambertianTransmission {
     }
 
     fn sample_function(&self, outgoing: Vector3, sample: &Point2) -> BxDFSample {
-        // TODO: proper sampling
-        let z = if outgoing.z > 0. { -1. } else { 1. };
-        let incoming = Vector3::new(sample.x, sample.y, z).normalized();
+        // Cosine-weighted, on the opposite side of the surface from the outgoing direction
+        let mut incoming = cosine_sample_hemisphere(*sample);
+        if outgoing.z > 0. {
+            incoming.z = -incoming.z;
+        }
         let pdf = self.probability_distribution(outgoing, incoming);
         return BxDFSample {
             value: self.evaluate(outgoing, incoming),
@@ -111,8 +111,9 @@ impl BxDF for SpecularTransmission {
     
     let probability_distribution = 1.;
 
-    let fresnel_value = self.fresnel_properties.evaluate(shading_coordinates::cos_theta(incoming));
-    let value = self.color * (Spectrum::white() - fresnel_value);
+    let cos_incident = shading_coordinates::cos_theta(incoming);
+    let fresnel_value = self.fresnel_properties.evaluate(cos_incident);
+    let value = self.color * (Spectrum::white() - fresnel_value) / cos_incident.abs();
     let value = if matches!(self.transport_mode, TransportMode::Radiance) {
       value * refraction_ratio * refraction_ratio
     } else {
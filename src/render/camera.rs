@@ -1,16 +1,36 @@
-use std::sync::Arc;
+use std::{f64::consts::PI, sync::Arc};
 
 use enum_dispatch::enum_dispatch;
 
-use crate::geometry::{Bounds2, Point3, Ray, RayDifferential, Transform, Vector3};
+use crate::{geometry::{Bounds2, Intersection, Normal3, Point2, Point3, Ray, RayDifferential, Transform, Vector3}, scene::MediumInstance};
 
-use super::{CameraSample, Film};
+use super::{CameraSample, Film, Spectrum, concentric_sample_disk};
+
+/// A point on the camera lens chosen to see a point in the scene, from `Camera::sample_importance`
+#[derive(Default)]
+pub struct ImportanceSample {
+  pub importance: Spectrum,
+  /// Direction from the reference point towards the lens
+  pub incident_direction: Vector3,
+  pub probability_distribution: f64,
+  /// Where on the film the reference point lands
+  pub raster_point: Point2,
+  pub lens_intersection: Intersection,
+}
 
 #[enum_dispatch]
 pub trait Camera {
     fn bounds(&self) -> Bounds2<u32>;
     fn film(&self) -> Arc<Film>;
+    fn medium(&self) -> Option<Arc<MediumInstance>>;
     fn generate_ray(&self, sample: &CameraSample) -> (f64, Ray);
+    /// How sensitive the camera is to light arriving back along a ray it could have generated,
+    /// and the point on the film that ray came from
+    fn importance(&self, ray: &Ray) -> (Spectrum, Point2); // pbrt: We()
+    /// The position and direction densities with which the camera would have generated the given ray
+    fn importance_probability(&self, ray: &Ray) -> (f64, f64); // pbrt: Pdf_We()
+    /// Choose a point on the lens that sees the reference point, for connecting light paths to the camera
+    fn sample_importance(&self, reference: &Intersection, sample: Point2) -> ImportanceSample; // pbrt: Sample_Wi()
 
     fn generate_ray_differential(&self, sample: &CameraSample) -> (f64, RayDifferential) {
       // Generate 3 rays:
@@ -39,6 +59,22 @@ pub trait Camera {
     }
 }
 
+/// Map a window of the screen onto a raster of the given size, flipping it over so that rows run from the top
+pub fn screen_to_raster(bounds: Bounds2<f64>, width: f64, height: f64) -> Transform {
+  let resolution_scale = Transform::scale(Vector3::new(width, height, 1.));
+  let screen_scale = Transform::scale(Vector3::new(
+    1. / (bounds.max.x - bounds.min.x),
+    1. / (bounds.min.y - bounds.max.y),  // NOTE!
+    1.
+  ));
+  let translate = Transform::translate(Vector3::new(
+    -bounds.min.x,
+    -bounds.max.y, // NOTE!
+    0.
+  ));
+  resolution_scale * screen_scale * translate
+}
+
 #[enum_dispatch(Camera)]
 pub enum CameraInstance {
   PerspectiveCamera,
@@ -47,43 +83,31 @@ pub enum CameraInstance {
 pub struct PerspectiveCamera {
   pub film: Arc<Film>,
   pub camera_to_world: Transform,
-  pub camera_to_screen: Transform,
   pub raster_to_camera: Transform,
-  pub screen_to_raster: Transform,
-  pub raster_to_screen: Transform,
-  pub shutter_open: f64,
-  pub shutter_close: f64,
+  /// The inverse of `camera_to_world` then `raster_to_camera`, for finding where light paths land on the film
+  pub world_to_raster: Transform,
   pub lens_radius: f64,
   pub focal_distance: f64,
   pub pixel_ray_dx: Vector3,
   pub pixel_ray_dy: Vector3,
   pub view_area: f64,
+  /// The medium the camera sits in, if any
+  pub medium: Option<Arc<MediumInstance>>,
 }
 
 impl PerspectiveCamera {
   pub fn new(
     camera_to_world: Transform, bounds: Bounds2<f64>,
-    shutter_open: f64, shutter_close: f64, lens_radius: f64, focal_distance: f64,
+    lens_radius: f64, focal_distance: f64,
     field_of_view: f64,
     film: Arc<Film>
   ) -> Self {
     let camera_to_screen = Transform::perspective(field_of_view, 0.01, 1000.);
 
     let resolution = film.bounds().max;
-    let resolution_scale = Transform::scale(Vector3::new(resolution.x as f64, resolution.y as f64, 1.));
-    let screen_scale = Transform::scale(Vector3::new(
-      1. / (bounds.max.x - bounds.min.x),
-      1. / (bounds.min.y - bounds.max.y),  // NOTE!
-      1.
-    ));
-    let translate = Transform::translate(Vector3::new(
-      -bounds.min.x,
-      -bounds.max.y, // NOTE!
-      0.
-    ));
-    let screen_to_raster = resolution_scale * screen_scale * translate;
-    let raster_to_screen = screen_to_raster.inverse();
-    let raster_to_camera = camera_to_screen.inverse() * raster_to_screen;
+    let screen_to_raster = screen_to_raster(bounds, resolution.x as f64, resolution.y as f64);
+    let raster_to_camera = camera_to_screen.inverse() * screen_to_raster.inverse();
+    let world_to_raster = raster_to_camera.inverse() * camera_to_world.inverse();
 
     let zero = Point3::default();
     let dx = Point3::new(1., 0., 0.);
@@ -99,17 +123,57 @@ impl PerspectiveCamera {
 
     PerspectiveCamera {
       film,
-      shutter_open, shutter_close,
       lens_radius, focal_distance,
       camera_to_world,
-      camera_to_screen,
-      screen_to_raster,
-      raster_to_screen,
       raster_to_camera,
+      world_to_raster,
       pixel_ray_dx,
       pixel_ray_dy,
       view_area,
+      medium: None,
+    }
+  }
+
+  /// The direction the camera faces, in world space
+  fn forward(&self) -> Vector3 {
+    (self.camera_to_world * Vector3::new(0., 0., 1.)).normalized()
+  }
+
+  /// Area of the lens, or 1 for a pinhole, so densities on the lens come out the same either way
+  fn lens_area(&self) -> f64 {
+    if self.lens_radius != 0. { PI * self.lens_radius * self.lens_radius } else { 1. }
+  }
+
+  /// The ray in camera space through a point on the near plane.  With a lens, it leaves from the point on the lens
+  /// `lens_sample` picks, towards where the pinhole ray meets the plane in focus.
+  fn camera_ray(&self, point_camera: Point3, lens_sample: Point2) -> Ray {
+    let direction = Vector3::from(point_camera).normalized();
+    if self.lens_radius <= 0. {
+      return Ray { origin: Point3::default(), direction, time_max: f64::INFINITY };
+    }
+    let lens = concentric_sample_disk(lens_sample);
+    let origin = Point3::new(lens.x * self.lens_radius, lens.y * self.lens_radius, 0.);
+    let focus = Point3::default() + direction * (self.focal_distance / direction.z);
+    Ray { origin, direction: (focus - origin).normalized(), time_max: f64::INFINITY }
+  }
+
+  /// Find where a ray leaving the camera came from on the film, if it's in view at all
+  fn raster_point(&self, ray: &Ray) -> Option<(f64, Point2)> {
+    let cos_theta = ray.direction.dot(self.forward());
+    if cos_theta <= 0. {
+      return None;
+    }
+
+    let focus_distance = if self.lens_radius > 0. { self.focal_distance } else { 1. };
+    let focus_point = ray.origin + ray.direction * (focus_distance / cos_theta);
+    let raster = self.world_to_raster * focus_point;
+    let raster = Point2::new(raster.x, raster.y);
+
+    let resolution = self.film.resolution;
+    if raster.x < 0. || raster.y < 0. || raster.x >= resolution.x as f64 || raster.y >= resolution.y as f64 {
+      return None;
     }
+    Some((cos_theta, raster))
   }
 }
 
@@ -120,34 +184,56 @@ impl Camera for PerspectiveCamera {
   fn film(&self) -> Arc<Film> {
     self.film.clone()
   }
+  fn medium(&self) -> Option<Arc<MediumInstance>> {
+    self.medium.clone()
+  }
   fn generate_ray(&self, sample: &CameraSample) -> (f64, Ray) {
     let point_raster = Point3::new(sample.film_point.x, sample.film_point.y, 0.);
     let point_camera = self.raster_to_camera * point_raster;
-    let direction = Vector3::from(point_camera).normalized();
-    let ray = Ray { origin: Point3::default(), direction, time_max: f64::INFINITY };
-    if self.lens_radius > 0. {
-      unimplemented!("Depth of field is not implemented yet");
+    (1., self.camera_to_world * self.camera_ray(point_camera, sample.lens_point))
+  }
+  fn importance(&self, ray: &Ray) -> (Spectrum, Point2) {
+    match self.raster_point(ray) {
+      Some((cos_theta, raster)) => {
+        let cos_sq_theta = cos_theta * cos_theta;
+        (Spectrum::greyscale(1. / (self.view_area * self.lens_area() * cos_sq_theta * cos_sq_theta)), raster)
+      },
+      None => (Spectrum::default(), Point2::default()),
+    }
+  }
+  fn importance_probability(&self, ray: &Ray) -> (f64, f64) {
+    match self.raster_point(ray) {
+      Some((cos_theta, _)) => (1. / self.lens_area(), 1. / (self.view_area * cos_theta * cos_theta * cos_theta)),
+      None => (0., 0.),
+    }
+  }
+  fn sample_importance(&self, reference: &Intersection, sample: Point2) -> ImportanceSample {
+    let lens = concentric_sample_disk(sample);
+    let lens_point = self.camera_to_world * Point3::new(lens.x * self.lens_radius, lens.y * self.lens_radius, 0.);
+    let lens_normal = Normal3::from(self.forward());
+    let offset = lens_point - reference.point;
+    let distance = offset.length();
+    let incident_direction = offset / distance;
+
+    let ray = Ray { origin: lens_point, direction: -incident_direction, time_max: f64::INFINITY };
+    let (importance, raster_point) = self.importance(&ray);
+    let cos_lens = lens_normal.dot(incident_direction.into()).abs();
+    ImportanceSample {
+      importance,
+      incident_direction,
+      probability_distribution: distance * distance / (cos_lens * self.lens_area()),
+      raster_point,
+      lens_intersection: Intersection { point: lens_point, normal: lens_normal, ..Default::default() },
     }
-    let ray = self.camera_to_world * ray;
-    (1., ray)
   }
   fn generate_ray_differential(&self, sample: &CameraSample) -> (f64, RayDifferential) {
-      if self.lens_radius > 0. {
-        unimplemented!("Depth of field is not implemented yet");
-      } else {
-        // NOTE: reimplements generate_ray above, because we need to reuse point_camera
-        let point_raster = Point3::new(sample.film_point.x, sample.film_point.y, 0.);
-        let point_camera = self.raster_to_camera * point_raster;
-        let direction = Vector3::from(point_camera).normalized();
-        let ray = self.camera_to_world * Ray { origin: Point3::default(), direction, time_max: f64::INFINITY, };
-        let ray_x = self.camera_to_world * Ray { origin: ray.origin, direction: Vector3::from(point_camera + self.pixel_ray_dx).normalized(), time_max: f64::INFINITY };
-        let ray_y = self.camera_to_world * Ray { origin: ray.origin, direction: Vector3::from(point_camera + self.pixel_ray_dy).normalized(), time_max: f64::INFINITY };
-
-        return (1., RayDifferential {
-          ray,
-          ray_x,
-          ray_y,
-        });
-      }
+    // Reimplements generate_ray above, so the differentials can reuse the point on the near plane and the lens
+    let point_raster = Point3::new(sample.film_point.x, sample.film_point.y, 0.);
+    let point_camera = self.raster_to_camera * point_raster;
+    (1., RayDifferential {
+      ray: self.camera_to_world * self.camera_ray(point_camera, sample.lens_point),
+      ray_x: self.camera_to_world * self.camera_ray(point_camera + self.pixel_ray_dx, sample.lens_point),
+      ray_y: self.camera_to_world * self.camera_ray(point_camera + self.pixel_ray_dy, sample.lens_point),
+    })
   }
 }
\ No newline at end of file
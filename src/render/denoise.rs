@@ -0,0 +1,115 @@
+use std::sync::RwLock;
+
+use super::{Film, FilmPixel, PixelFeatures, Spectrum};
+
+/// A joint bilateral filter for noisy renders
+///
+/// Each pixel is replaced by a weighted average of its neighbours, where neighbours only count if
+/// they saw a similar surface (by albedo, normal and depth) and have a color that's within the
+/// noise we'd expect given how much each pixel's samples varied.  This smooths out noise on flat
+/// surfaces, without blurring across the edges of objects, shadows or textures.
+#[derive(Clone)]
+pub struct Denoiser {
+  /// How many pixels in each direction to search for similar neighbours
+  pub radius: u32,
+  /// Standard deviation of the falloff with distance, in pixels
+  pub sigma_spatial: f64,
+  /// Standard deviation of the falloff with difference in albedo
+  pub sigma_albedo: f64,
+  /// Standard deviation of the falloff with difference in (unit) normals
+  pub sigma_normal: f64,
+  /// Standard deviation of the falloff with difference in depth, relative to the depth itself
+  pub sigma_depth: f64,
+  /// How many standard deviations of noise two colors may differ by and still be blended
+  pub sigma_color: f64,
+}
+
+impl Default for Denoiser {
+  fn default() -> Self {
+    Self {
+      radius: 5,
+      sigma_spatial: 3.,
+      sigma_albedo: 0.1,
+      sigma_normal: 0.2,
+      sigma_depth: 0.05,
+      sigma_color: 2.,
+    }
+  }
+}
+
+/// The parts of a pixel that the filter compares
+struct Guide {
+  color: Spectrum,
+  features: PixelFeatures,
+  /// Variance of the pixel's estimate, which shrinks the more samples we take
+  variance: f64,
+}
+
+impl Denoiser {
+  /// Filter a film, producing a new one with the same sample statistics, but denoised colors
+  pub fn denoise(&self, film: &Film) -> Film {
+    let (width, height) = (film.resolution.x as i64, film.resolution.y as i64);
+    let splat_scale = film.splat_scale();
+    let pixels = film.pixels.read().unwrap();
+    let guides: Vec<Guide> = pixels.iter().map(|pixel: &FilmPixel| Guide {
+      color: pixel.value(splat_scale),
+      features: pixel.features(),
+      variance: if pixel.sample_count > 0 { pixel.variance() / pixel.sample_count as f64 } else { 0. },
+    }).collect();
+
+    let radius = self.radius as i64;
+    let mut result = pixels.clone();
+    for y in 0..height {
+      for x in 0..width {
+        let idx = (y * width + x) as usize;
+        let center = &guides[idx];
+
+        let mut sum = Spectrum::default();
+        let mut total_weight = 0.;
+        for ny in (y - radius).max(0)..(y + radius + 1).min(height) {
+          for nx in (x - radius).max(0)..(x + radius + 1).min(width) {
+            let neighbour = &guides[(ny * width + nx) as usize];
+            let distance_sq = ((nx - x) * (nx - x) + (ny - y) * (ny - y)) as f64;
+            let weight = self.weight(distance_sq, center, neighbour);
+            sum += neighbour.color * weight;
+            total_weight += weight;
+          }
+        }
+
+        // Keep the weight sum, so the pixel's value comes out as the filtered color
+        // The filtered color already includes any splats, so they're folded in rather than added again
+        let filtered = if total_weight > 0. { sum / total_weight } else { center.color };
+        let pixel = &mut result[idx];
+        pixel.splat = Spectrum::default();
+        if pixel.weight_sum == 0. {
+          pixel.weight_sum = 1.;
+        }
+        pixel.weighted_sum = filtered * pixel.weight_sum;
+      }
+    }
+
+    Film { resolution: film.resolution, pixels: RwLock::new(result) }
+  }
+
+  fn weight(&self, distance_sq: f64, a: &Guide, b: &Guide) -> f64 {
+    let spatial = distance_sq / (2. * self.sigma_spatial * self.sigma_spatial);
+
+    let albedo = a.features.albedo - b.features.albedo;
+    let albedo = (albedo.r * albedo.r + albedo.g * albedo.g + albedo.b * albedo.b)
+      / (2. * self.sigma_albedo * self.sigma_albedo);
+
+    // Rays that escaped the scene have a zero normal and depth, so they only blend with each other
+    let normal = a.features.normal + -b.features.normal;
+    let normal = normal.length_squared() / (2. * self.sigma_normal * self.sigma_normal);
+
+    let depth_scale = a.features.depth.max(b.features.depth).max(1e-4) * self.sigma_depth;
+    let depth = (a.features.depth - b.features.depth) / depth_scale;
+    let depth = depth * depth / 2.;
+
+    // Allow colors to differ in proportion to how noisy the two pixels are
+    let color = a.color.luminance() - b.color.luminance();
+    let color = color * color / (self.sigma_color * self.sigma_color * (a.variance + b.variance) + 1e-4);
+
+    (-(spatial + albedo + normal + depth + color)).exp()
+  }
+}
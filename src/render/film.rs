@@ -1,45 +1,488 @@
-use std::{path::PathBuf, sync::RwLock};
+use std::{fs::{self, File}, io::{self, BufReader, BufWriter, Read, Write}, path::PathBuf, sync::RwLock};
 
 use image::{ImageBuffer, ImageFormat, Rgb, RgbImage};
 
-use crate::geometry::{Bounds2, Point2};
+use crate::geometry::{Bounds2, Normal3, Point2};
 
 use super::Spectrum;
 
+/// Identifies a file as saved film state
+const STATE_MAGIC: &[u8; 8] = b"OPTQFILM";
+/// Bumped whenever the on-disk layout of the film state changes
+const STATE_VERSION: u32 = 1;
+/// The bytes before the pixels in a film state file: the magic, version and resolution
+const STATE_HEADER_SIZE: u64 = 20;
+/// The bytes each pixel takes in a film state file
+const STATE_PIXEL_SIZE: u64 = 152;
+
+/// Properties of the first surface a camera ray hits, used to guide denoising
+#[derive(Copy, Clone, Default)]
+pub struct PixelFeatures {
+  /// How much light the surface reflects overall, independent of lighting
+  pub albedo: Spectrum,
+  pub normal: Normal3,
+  /// Distance from the camera, or 0 if the ray escaped the scene
+  pub depth: f64,
+}
+
+/// Samples no brighter than display white are never treated as outliers
+const MIN_OUTLIER_LUMINANCE: f64 = 1.;
+/// How many samples a pixel needs before we trust its statistics enough to reject outliers
+const MIN_OUTLIER_SAMPLES: u64 = 4;
+
+/// Everything we've accumulated for a single pixel
+/// Kept as raw sums, rather than a final color, so that partial renders can be resumed or combined
+#[derive(Copy, Clone, Default)]
+pub struct FilmPixel {
+  /// Sum of every sample's radiance, scaled by its weight
+  pub weighted_sum: Spectrum,
+  /// Sum of the weights of every sample
+  pub weight_sum: f64,
+  /// How many samples have landed in this pixel
+  pub sample_count: u64,
+  /// Running mean of the luminance of each sample
+  pub luminance_mean: f64,
+  /// Running sum of squared differences from the mean luminance (Welford's M2)
+  pub luminance_m2: f64,
+  /// Sums of the features of each sample, and how many samples recorded them
+  pub albedo_sum: Spectrum,
+  pub normal_sum: Normal3,
+  pub depth_sum: f64,
+  pub feature_count: u64,
+  /// Sum of the light splatted onto the pixel from paths that didn't start here.  Those paths could have landed
+  /// anywhere on the film, so the sum is divided by how many samples were taken per pixel across the whole film.
+  pub splat: Spectrum,
+  /// Camera samples taken in this pixel that only splatted light elsewhere, leaving this pixel's own estimate alone
+  pub splat_sample_count: u64,
+}
+
+impl FilmPixel {
+  pub fn add_sample(&mut self, value: Spectrum, weight: f64) {
+    self.weighted_sum += value * weight;
+    self.weight_sum += weight;
+    self.record_sample(value);
+  }
+
+  /// Add a sample, unless it's so much brighter than the samples before it that it's likely a firefly
+  /// Rejected samples still count towards the pixel's statistics, they just don't affect its color
+  pub fn add_sample_rejecting_outliers(&mut self, value: Spectrum, weight: f64, max_deviations: f64) -> bool {
+    let luminance = value.luminance();
+    let threshold = self.luminance_mean + max_deviations * self.variance().sqrt();
+    let is_outlier = self.sample_count >= MIN_OUTLIER_SAMPLES
+      && luminance > MIN_OUTLIER_LUMINANCE
+      && luminance > threshold;
+    if is_outlier {
+      self.record_sample(value);
+    } else {
+      self.add_sample(value, weight);
+    }
+    !is_outlier
+  }
+
+  fn record_sample(&mut self, value: Spectrum) {
+    self.sample_count += 1;
+
+    // Welford's online algorithm, so we can track variance without storing each sample
+    let luminance = value.luminance();
+    let delta = luminance - self.luminance_mean;
+    self.luminance_mean += delta / self.sample_count as f64;
+    self.luminance_m2 += delta * (luminance - self.luminance_mean);
+  }
+
+  pub fn add_features(&mut self, features: &PixelFeatures) {
+    self.albedo_sum += features.albedo;
+    self.normal_sum = self.normal_sum + features.normal;
+    self.depth_sum += features.depth;
+    self.feature_count += 1;
+  }
+
+  pub fn add_splat(&mut self, value: Spectrum) {
+    self.splat += value;
+  }
+
+  /// Count samples taken in this pixel that only splatted light elsewhere
+  pub fn add_splat_samples(&mut self, count: u64) {
+    self.splat_sample_count += count;
+  }
+
+  /// How many camera samples have been taken in this pixel, whether they added to its estimate or only splatted
+  pub fn samples_taken(&self) -> u64 {
+    self.sample_count + self.splat_sample_count
+  }
+
+  /// Combine the samples from another estimate of the same pixel into this one
+  pub fn merge(&mut self, other: &FilmPixel) {
+    self.splat += other.splat;
+    self.splat_sample_count += other.splat_sample_count;
+    self.albedo_sum += other.albedo_sum;
+    self.normal_sum = self.normal_sum + other.normal_sum;
+    self.depth_sum += other.depth_sum;
+    self.feature_count += other.feature_count;
+
+    if other.sample_count == 0 {
+      return;
+    }
+    let count = self.sample_count + other.sample_count;
+    // Chan et al.'s parallel variant of Welford's algorithm
+    let delta = other.luminance_mean - self.luminance_mean;
+    let (n_a, n_b, n) = (self.sample_count as f64, other.sample_count as f64, count as f64);
+    self.luminance_mean += delta * n_b / n;
+    self.luminance_m2 += other.luminance_m2 + delta * delta * n_a * n_b / n;
+
+    self.weighted_sum += other.weighted_sum;
+    self.weight_sum += other.weight_sum;
+    self.sample_count = count;
+  }
+
+  /// The current estimate of this pixel's color, given the film's `splat_scale`
+  pub fn value(&self, splat_scale: f64) -> Spectrum {
+    if self.weight_sum == 0. {
+      self.splat * splat_scale
+    } else {
+      self.weighted_sum / self.weight_sum + self.splat * splat_scale
+    }
+  }
+
+  /// The sample variance of the luminance landing in this pixel
+  pub fn variance(&self) -> f64 {
+    if self.sample_count < 2 {
+      0.
+    } else {
+      self.luminance_m2 / (self.sample_count - 1) as f64
+    }
+  }
+
+  /// The average features of every sample in this pixel
+  pub fn features(&self) -> PixelFeatures {
+    if self.feature_count == 0 {
+      return PixelFeatures::default();
+    }
+    let count = self.feature_count as f64;
+    let normal = self.normal_sum;
+    PixelFeatures {
+      albedo: self.albedo_sum / count,
+      normal: if normal.length_squared() > 0. { normal.normalized() } else { normal },
+      depth: self.depth_sum / count,
+    }
+  }
+}
+
 pub struct Film {
   pub resolution: Point2<u32>,
-  pub pixels: RwLock<Vec<Spectrum>>,
+  pub pixels: RwLock<Vec<FilmPixel>>,
 }
 
 impl Film {
   pub fn new(resolution: Point2<u32>) -> Self {
     assert!(resolution.x > 0 && resolution.y > 0, "Must have positive resolution");
-    let pixels = RwLock::new(vec![Spectrum::default(); (resolution.x * resolution.y) as usize]);
+    let pixels = RwLock::new(vec![FilmPixel::default(); (resolution.x * resolution.y) as usize]);
     Self { resolution, pixels }
   }
   pub fn bounds(&self) -> Bounds2<u32> {
     Bounds2 { min: Default::default(), max: self.resolution }
   }
-  pub fn add_sample(&self, pixel: Point2<u32>, value: Spectrum, _weight: f64) {
+  pub fn add_sample(&self, pixel: Point2<u32>, value: Spectrum, weight: f64) {
+    let idx = pixel.y * self.resolution.x + pixel.x;
+    let mut pixels = self.pixels.write().unwrap();
+    pixels[idx as usize].add_sample(value, weight);
+  }
+  pub fn add_sample_rejecting_outliers(&self, pixel: Point2<u32>, value: Spectrum, weight: f64, max_deviations: f64) -> bool {
+    let idx = pixel.y * self.resolution.x + pixel.x;
+    let mut pixels = self.pixels.write().unwrap();
+    pixels[idx as usize].add_sample_rejecting_outliers(value, weight, max_deviations)
+  }
+  pub fn add_features(&self, pixel: Point2<u32>, features: &PixelFeatures) {
+    let idx = pixel.y * self.resolution.x + pixel.x;
+    let mut pixels = self.pixels.write().unwrap();
+    pixels[idx as usize].add_features(features);
+  }
+  /// Add light to whichever pixel a point on the film falls in, regardless of which pixel is being sampled
+  pub fn add_splat(&self, point: Point2, value: Spectrum) {
+    let x = (point.x.floor().max(0.) as u32).min(self.resolution.x - 1);
+    let y = (point.y.floor().max(0.) as u32).min(self.resolution.y - 1);
+    let idx = y * self.resolution.x + x;
+    let mut pixels = self.pixels.write().unwrap();
+    pixels[idx as usize].add_splat(value);
+  }
+  /// Count samples taken in a pixel that only splatted light elsewhere, such as the mutations of Metropolis chains
+  pub fn add_splat_samples(&self, pixel: Point2<u32>, count: u64) {
+    let idx = pixel.y * self.resolution.x + pixel.x;
+    let mut pixels = self.pixels.write().unwrap();
+    pixels[idx as usize].add_splat_samples(count);
+  }
+  /// What to multiply each pixel's splat sum by: one over the average number of samples taken per pixel
+  pub fn splat_scale(&self) -> f64 {
+    splat_scale(&self.pixels.read().unwrap())
+  }
+  pub fn pixel(&self, pixel: Point2<u32>) -> FilmPixel {
     let idx = pixel.y * self.resolution.x + pixel.x;
+    self.pixels.read().unwrap()[idx as usize]
+  }
+
+  /// Fold the samples from another film of the same resolution into this one
+  pub fn merge(&self, other: &Film) -> io::Result<()> {
+    if self.resolution.x != other.resolution.x || self.resolution.y != other.resolution.y {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+        "Can't merge a {}x{} film into a {}x{} one",
+        other.resolution.x, other.resolution.y, self.resolution.x, self.resolution.y,
+      )));
+    }
     let mut pixels = self.pixels.write().unwrap();
-    pixels[idx as usize] = value;
+    let others = other.pixels.read().unwrap();
+    for (pixel, other) in pixels.iter_mut().zip(others.iter()) {
+      pixel.merge(other);
+    }
+    Ok(())
   }
+
+  /// Replace everything we've accumulated with a previously saved state
+  pub fn load_state(&self, file: PathBuf) -> io::Result<()> {
+    let saved = Film::read_state(file)?;
+    if self.resolution.x != saved.resolution.x || self.resolution.y != saved.resolution.y {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+        "Film state is {}x{}, but the film is {}x{}",
+        saved.resolution.x, saved.resolution.y, self.resolution.x, self.resolution.y,
+      )));
+    }
+    *self.pixels.write().unwrap() = saved.pixels.into_inner().unwrap();
+    Ok(())
+  }
+
+  /// Save the raw accumulated state of the film, so a render can be resumed or merged later
+  ///
+  /// The format is little-endian throughout:
+  ///  - the 8 byte magic `OPTQFILM`, then a u32 version, then the u32 width and height
+  ///  - then for each pixel in row-major order: the weighted r, g, b sums and weight sum as f64,
+  ///    the sample count as u64, and the luminance mean and M2 as f64
+  ///  - then the albedo r, g, b sums, normal x, y, z sums, and depth sum as f64, and the feature count as u64
+  ///  - then the splatted r, g, b sums as f64, and the splat-only sample count as u64
+  pub fn write_state(&self, file: PathBuf) -> io::Result<()> {
+    // Write to a temporary file and move it into place, so a crash mid-write never clobbers the last good state
+    let mut temp_file = file.clone().into_os_string();
+    temp_file.push(".tmp");
+    let temp_file = PathBuf::from(temp_file);
+    {
+      let mut out = BufWriter::new(File::create(&temp_file)?);
+      self.write_state_to(&mut out)?;
+      out.flush()?;
+    }
+    fs::rename(temp_file, file)
+  }
+
+  fn write_state_to(&self, out: &mut impl Write) -> io::Result<()> {
+    out.write_all(STATE_MAGIC)?;
+    out.write_all(&STATE_VERSION.to_le_bytes())?;
+    out.write_all(&self.resolution.x.to_le_bytes())?;
+    out.write_all(&self.resolution.y.to_le_bytes())?;
+    let pixels = self.pixels.read().unwrap();
+    for pixel in pixels.iter() {
+      for v in &[pixel.weighted_sum.r, pixel.weighted_sum.g, pixel.weighted_sum.b, pixel.weight_sum] {
+        out.write_all(&v.to_le_bytes())?;
+      }
+      out.write_all(&pixel.sample_count.to_le_bytes())?;
+      out.write_all(&pixel.luminance_mean.to_le_bytes())?;
+      out.write_all(&pixel.luminance_m2.to_le_bytes())?;
+      let (albedo, normal) = (pixel.albedo_sum, pixel.normal_sum);
+      for v in &[albedo.r, albedo.g, albedo.b, normal.x, normal.y, normal.z, pixel.depth_sum] {
+        out.write_all(&v.to_le_bytes())?;
+      }
+      out.write_all(&pixel.feature_count.to_le_bytes())?;
+      for v in &[pixel.splat.r, pixel.splat.g, pixel.splat.b] {
+        out.write_all(&v.to_le_bytes())?;
+      }
+      out.write_all(&pixel.splat_sample_count.to_le_bytes())?;
+    }
+    Ok(())
+  }
+
+  /// Load film state previously saved with `write_state`
+  pub fn read_state(file: PathBuf) -> io::Result<Film> {
+    let file = File::open(file)?;
+    let length = file.metadata()?.len();
+    Film::read_state_from(&mut BufReader::new(file), length)
+  }
+
+  /// Read film state from something holding `length` bytes of it, header and all
+  fn read_state_from(input: &mut impl Read, length: u64) -> io::Result<Film> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != STATE_MAGIC {
+      return Err(invalid("Not a film state file"));
+    }
+    let version = read_u32(input)?;
+    if version != STATE_VERSION {
+      return Err(invalid(&format!("Unsupported film state version {}", version)));
+    }
+    let resolution = Point2 { x: read_u32(input)?, y: read_u32(input)? };
+    if resolution.x == 0 || resolution.y == 0 {
+      return Err(invalid("Film state has an empty resolution"));
+    }
+
+    // Make sure the file really holds that many pixels before setting aside room for them.  Pixels are indexed
+    // with u32s, so there can't be more of them than that allows either
+    let count = (resolution.x as u64).checked_mul(resolution.y as u64).filter(|&count| count <= u32::MAX as u64);
+    let remaining = length.saturating_sub(STATE_HEADER_SIZE);
+    let count = match count {
+      Some(count) if count.checked_mul(STATE_PIXEL_SIZE) == Some(remaining) => count as usize,
+      _ => return Err(invalid(&format!("Film state doesn't hold the {}x{} pixels it says", resolution.x, resolution.y))),
+    };
+
+    let mut pixels = Vec::with_capacity(count);
+    for _ in 0..count {
+      pixels.push(FilmPixel {
+        weighted_sum: Spectrum { r: read_f64(input)?, g: read_f64(input)?, b: read_f64(input)? },
+        weight_sum: read_f64(input)?,
+        sample_count: read_u64(input)?,
+        luminance_mean: read_f64(input)?,
+        luminance_m2: read_f64(input)?,
+        albedo_sum: Spectrum { r: read_f64(input)?, g: read_f64(input)?, b: read_f64(input)? },
+        normal_sum: Normal3::new(read_f64(input)?, read_f64(input)?, read_f64(input)?),
+        depth_sum: read_f64(input)?,
+        feature_count: read_u64(input)?,
+        splat: Spectrum { r: read_f64(input)?, g: read_f64(input)?, b: read_f64(input)? },
+        splat_sample_count: read_u64(input)?,
+      });
+    }
+    Ok(Film { resolution, pixels: RwLock::new(pixels) })
+  }
+
   pub fn write_to(&self, file: PathBuf) {
     let (width, height) = (self.resolution.x as u32, self.resolution.y as u32);
     let mut img: RgbImage = ImageBuffer::new(width, height);
     let pixels = self.pixels.read().unwrap();
+    let splat_scale = splat_scale(&pixels);
     for y in 0..height {
       for x in 0..width {
         let idx = (y * width + x) as usize;
+        let value = pixels[idx].value(splat_scale);
         let (r,g,b) = (
-          (pixels[idx].r * 255.) as u8,
-          (pixels[idx].g * 255.) as u8,
-          (pixels[idx].b * 255.) as u8,
+          (value.r * 255.) as u8,
+          (value.g * 255.) as u8,
+          (value.b * 255.) as u8,
         );
         img.put_pixel(x, y, Rgb::from([r, g, b]));
       }
     }
     img.save_with_format(file, ImageFormat::Png).unwrap();
   }
-}
\ No newline at end of file
+}
+
+/// Splatted light could have come from a sample taken in any pixel, so it's averaged over the samples taken
+/// across the whole film, however many were actually taken.  That way a render stopped early, or merged from
+/// renders of different parts of the image, is as bright as a finished one.
+fn splat_scale(pixels: &[FilmPixel]) -> f64 {
+  let taken: u64 = pixels.iter().map(|pixel| pixel.samples_taken()).sum();
+  if taken == 0 { 0. } else { pixels.len() as f64 / taken as f64 }
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+  let mut bytes = [0u8; 4];
+  input.read_exact(&mut bytes)?;
+  Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+  let mut bytes = [0u8; 8];
+  input.read_exact(&mut bytes)?;
+  Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f64(input: &mut impl Read) -> io::Result<f64> {
+  Ok(f64::from_bits(read_u64(input)?))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Every field of a pixel, as bits so that NaNs and signed zeros compare exactly
+  fn bits(pixel: &FilmPixel) -> Vec<u64> {
+    let (w, a, n, s) = (pixel.weighted_sum, pixel.albedo_sum, pixel.normal_sum, pixel.splat);
+    let floats = [w.r, w.g, w.b, pixel.weight_sum, pixel.luminance_mean, pixel.luminance_m2,
+      a.r, a.g, a.b, n.x, n.y, n.z, pixel.depth_sum, s.r, s.g, s.b];
+    let counts = [pixel.sample_count, pixel.feature_count, pixel.splat_sample_count];
+    floats.iter().map(|v| v.to_bits()).chain(counts.iter().copied()).collect()
+  }
+
+  /// The `i`th of a made up run of samples, using values whose sums are exact
+  fn sample(film: &Film, i: u32) {
+    let pixel = Point2 { x: i % 3, y: i / 3 % 2 };
+    let value = Spectrum { r: (i % 5) as f64, g: 0.5, b: (i % 7) as f64 * 0.25 };
+    film.add_sample(pixel, value, 1. + (i % 2) as f64);
+    film.add_features(pixel, &PixelFeatures { albedo: value, normal: Normal3::new(0., 0., 1.), depth: i as f64 });
+    film.add_splat(Point2::new((i % 4) as f64 * 0.75, 0.5), value * 0.125);
+    if i % 3 == 0 {
+      film.add_splat_samples(pixel, 2);
+    }
+  }
+
+  fn state(film: &Film) -> Vec<u8> {
+    let mut data = vec![];
+    film.write_state_to(&mut data).unwrap();
+    data
+  }
+
+  #[test]
+  fn state_round_trips() {
+    let film = Film::new(Point2 { x: 3, y: 2 });
+    for i in 0..50 {
+      sample(&film, i);
+    }
+    film.pixels.write().unwrap()[5].luminance_m2 = f64::NAN;
+    let data = state(&film);
+    let read = Film::read_state_from(&mut &data[..], data.len() as u64).unwrap();
+    assert_eq!((read.resolution.x, read.resolution.y), (3, 2));
+    let (original, read) = (film.pixels.read().unwrap(), read.pixels.read().unwrap());
+    for (a, b) in original.iter().zip(read.iter()) {
+      assert_eq!(bits(a), bits(b));
+    }
+  }
+
+  #[test]
+  fn merged_halves_match_whole_render() {
+    let (whole, first, second) = (Film::new(Point2 { x: 3, y: 2 }), Film::new(Point2 { x: 3, y: 2 }), Film::new(Point2 { x: 3, y: 2 }));
+    for i in 0..60 {
+      sample(&whole, i);
+      sample(if i < 30 { &first } else { &second }, i);
+    }
+    first.merge(&second).unwrap();
+    assert_eq!(first.splat_scale(), whole.splat_scale());
+    let (merged, whole) = (first.pixels.read().unwrap(), whole.pixels.read().unwrap());
+    for (a, b) in merged.iter().zip(whole.iter()) {
+      // Everything but the luminance statistics is a sum of exact values, so should match to the bit
+      assert_eq!(bits(a)[..4], bits(b)[..4]);
+      assert_eq!(bits(a)[6..], bits(b)[6..]);
+      assert!((a.luminance_mean - b.luminance_mean).abs() < 1e-9);
+      assert!((a.luminance_m2 - b.luminance_m2).abs() < 1e-9);
+    }
+  }
+
+  #[test]
+  fn merge_rejects_other_resolutions() {
+    assert!(Film::new(Point2 { x: 3, y: 2 }).merge(&Film::new(Point2 { x: 2, y: 3 })).is_err());
+  }
+
+  #[test]
+  fn rejects_bad_state() {
+    let film = Film::new(Point2 { x: 2, y: 2 });
+    sample(&film, 1);
+    let data = state(&film);
+    for length in 0..data.len() {
+      // Whether the file is shorter than it says, or just short
+      assert!(Film::read_state_from(&mut &data[..length], length as u64).is_err(), "accepted the first {} bytes", length);
+      assert!(Film::read_state_from(&mut &data[..length], data.len() as u64).is_err(), "accepted the first {} bytes", length);
+    }
+
+    let mut wrong_magic = data.clone();
+    wrong_magic[0] = b'X';
+    assert!(Film::read_state_from(&mut &wrong_magic[..], data.len() as u64).is_err());
+    let mut wrong_version = data.clone();
+    wrong_version[8] = 2;
+    assert!(Film::read_state_from(&mut &wrong_version[..], data.len() as u64).is_err());
+    let mut wrong_resolution = data;
+    wrong_resolution[12] = 3;
+    assert!(Film::read_state_from(&mut &wrong_resolution[..], wrong_resolution.len() as u64).is_err());
+  }
+}
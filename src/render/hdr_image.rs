@@ -0,0 +1,442 @@
+use std::{fs::{self, File}, io::{self, BufReader}, path::Path};
+
+use image::codecs::hdr::HdrDecoder;
+
+use crate::{geometry::Point2, render::Spectrum};
+
+/// What lies beyond the left and right edges of an image when looking it up
+#[derive(Clone, Copy, PartialEq)]
+pub enum WrapMode {
+  /// The image tiles, as environment maps wrap all the way around
+  Repeat,
+  /// The edge pixels carry on forever
+  Clamp,
+}
+
+/// A high dynamic range image of linear radiance, such as an environment map
+pub struct HdrImage {
+  pub width: usize,
+  pub height: usize,
+  /// One row after another, from the top
+  pub pixels: Vec<Spectrum>,
+}
+
+impl HdrImage {
+  /// A single pixel image of one color
+  pub fn constant(color: Spectrum) -> Self {
+    HdrImage { width: 1, height: 1, pixels: vec![color] }
+  }
+
+  /// Read a Radiance `.hdr` or OpenEXR `.exr` file, or any ordinary image the `image` crate can read,
+  /// depending on its extension.  Ordinary images are taken to be sRGB encoded, and converted to linear values.
+  ///
+  /// Only scanline OpenEXR files are supported, stored uncompressed or with RLE, ZIPS or ZIP compression
+  pub fn read(file: &Path) -> io::Result<Self> {
+    let extension = file.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+    match extension.as_deref() {
+      Some("hdr") => read_hdr(file),
+      Some("exr") => read_exr(file),
+      _ => read_ldr(file),
+    }
+  }
+
+  /// The average of every pixel
+  pub fn average(&self) -> Spectrum {
+    let total = self.pixels.iter().fold(Spectrum::default(), |total, &pixel| total + pixel);
+    total / self.pixels.len() as f64
+  }
+
+  pub fn texel(&self, x: usize, y: usize) -> Spectrum {
+    self.pixels[y.min(self.height - 1) * self.width + x.min(self.width - 1)]
+  }
+
+  /// Bilinearly interpolate the image, where (0, 0) is the top left corner and (1, 1) the bottom right,
+  /// handling the left and right edges as `wrap` says.  The top and bottom edges are always clamped.
+  pub fn lookup(&self, st: Point2, wrap: WrapMode) -> Spectrum {
+    let x = st.x * self.width as f64 - 0.5;
+    let y = (st.y * self.height as f64 - 0.5).clamp(0., (self.height - 1) as f64);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (dx, dy) = (x - x0, y - y0);
+    let width = self.width as i64;
+    let column = |x: f64| match wrap {
+      WrapMode::Repeat => (x as i64).rem_euclid(width) as usize,
+      WrapMode::Clamp => (x as i64).clamp(0, width - 1) as usize,
+    };
+    let (left, right) = (column(x0), column(x0 + 1.));
+    let (top, bottom) = (y0 as usize, y0 as usize + 1);
+    (self.texel(left, top) * (1. - dx) + self.texel(right, top) * dx) * (1. - dy) +
+      (self.texel(left, bottom) * (1. - dx) + self.texel(right, bottom) * dx) * dy
+  }
+}
+
+fn read_hdr(file: &Path) -> io::Result<HdrImage> {
+  let decoder = HdrDecoder::new(BufReader::new(File::open(file)?)).map_err(|e| invalid(&e.to_string()))?;
+  let metadata = decoder.metadata();
+  let pixels = decoder.read_image_hdr().map_err(|e| invalid(&e.to_string()))?;
+  Ok(HdrImage {
+    width: metadata.width as usize,
+    height: metadata.height as usize,
+    pixels: pixels.iter().map(|p| Spectrum { r: p[0] as f64, g: p[1] as f64, b: p[2] as f64 }).collect(),
+  })
+}
+
+fn read_ldr(file: &Path) -> io::Result<HdrImage> {
+  let image = image::open(file).map_err(|e| invalid(&format!("Unable to read {:?}: {}", file, e)))?.to_rgb8();
+  let linear = |value: u8| {
+    let v = value as f64 / 255.;
+    if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+  };
+  Ok(HdrImage {
+    width: image.width() as usize,
+    height: image.height() as usize,
+    pixels: image.pixels().map(|p| Spectrum { r: linear(p[0]), g: linear(p[1]), b: linear(p[2]) }).collect(),
+  })
+}
+
+/// The most pixels an OpenEXR file may claim to have, so a corrupt header can't make us allocate without bound
+const MAX_EXR_PIXELS: usize = 1 << 28;
+
+/// How the values of a channel are stored in an OpenEXR file
+#[derive(Clone, Copy, PartialEq)]
+enum ExrPixelType {
+  Uint,
+  Half,
+  Float,
+}
+
+impl ExrPixelType {
+  fn size(&self) -> usize {
+    match self {
+      ExrPixelType::Half => 2,
+      ExrPixelType::Uint | ExrPixelType::Float => 4,
+    }
+  }
+}
+
+fn read_exr(file: &Path) -> io::Result<HdrImage> {
+  decode_exr(&fs::read(file)?)
+}
+
+fn decode_exr(data: &[u8]) -> io::Result<HdrImage> {
+  let mut input = ExrReader { data, position: 0 };
+  if input.take(4)? != [0x76, 0x2f, 0x31, 0x01] {
+    return Err(invalid("Not an OpenEXR file"));
+  }
+  let version = input.u32()?;
+  if version & 0x200 != 0 || version & 0x1000 != 0 {
+    return Err(invalid("Only single part, scanline OpenEXR files are supported"));
+  }
+
+  let mut channels: Vec<(String, ExrPixelType)> = vec![];
+  let mut compression = None;
+  let mut data_window = None;
+  loop {
+    let name = input.string()?;
+    if name.is_empty() {
+      break;
+    }
+    let _kind = input.string()?;
+    let size = input.u32()? as usize;
+    let mut value = ExrReader { data: input.take(size)?, position: 0 };
+    match name.as_str() {
+      "channels" => loop {
+        let channel = value.string()?;
+        if channel.is_empty() {
+          break;
+        }
+        let pixel_type = match value.u32()? {
+          0 => ExrPixelType::Uint,
+          1 => ExrPixelType::Half,
+          2 => ExrPixelType::Float,
+          other => return Err(invalid(&format!("Unknown OpenEXR pixel type {}", other))),
+        };
+        // Linear flag, padding, and sampling rates
+        value.take(12)?;
+        channels.push((channel, pixel_type));
+      },
+      "compression" => compression = Some(value.take(1)?[0]),
+      "dataWindow" => data_window = Some([value.u32()? as i32, value.u32()? as i32, value.u32()? as i32, value.u32()? as i32]),
+      _ => {},
+    }
+  }
+
+  let [x_min, y_min, x_max, y_max] = data_window.ok_or_else(|| invalid("OpenEXR file has no data window"))?;
+  if x_min > x_max || y_min > y_max {
+    return Err(invalid("OpenEXR data window is empty"));
+  }
+  let (width, height) = ((x_max as i64 - x_min as i64 + 1) as usize, (y_max as i64 - y_min as i64 + 1) as usize);
+  if width.checked_mul(height).map_or(true, |pixels| pixels > MAX_EXR_PIXELS) {
+    return Err(invalid(&format!("OpenEXR image is too large, at {}x{}", width, height)));
+  }
+  let compression = compression.unwrap_or(0);
+  let lines_per_block = match compression {
+    0..=2 => 1,
+    3 => 16,
+    other => return Err(invalid(&format!("Unsupported OpenEXR compression {}, expected none, RLE, ZIPS or ZIP", other))),
+  };
+  let find = |wanted: &str| channels.iter().position(|(name, _)| name == wanted);
+  let rgb = match (find("R"), find("G"), find("B"), find("Y")) {
+    (Some(r), Some(g), Some(b), _) => [r, g, b],
+    (_, _, _, Some(y)) => [y, y, y],
+    _ => return Err(invalid("OpenEXR file has no R, G and B channels, or Y channel")),
+  };
+  // Where each channel starts within a scanline
+  let mut channel_offsets = vec![];
+  let mut line_size = 0;
+  for (_, pixel_type) in &channels {
+    channel_offsets.push(line_size);
+    line_size += pixel_type.size() * width;
+  }
+
+  let mut pixels = vec![Spectrum::default(); width * height];
+  let blocks = height.checked_add(lines_per_block - 1)
+    .ok_or_else(|| invalid("OpenEXR image is too tall"))? / lines_per_block;
+  for _ in 0..blocks {
+    let offset = input.u64()? as usize;
+    let mut chunk = ExrReader { data, position: offset };
+    let first_line = chunk.u32()? as i32 as i64 - y_min as i64;
+    let size = chunk.u32()? as usize;
+    let packed = chunk.take(size)?;
+    if first_line < 0 || first_line >= height as i64 {
+      return Err(invalid("OpenEXR block lies outside the image"));
+    }
+    let first_line = first_line as usize;
+    let lines = lines_per_block.min(height - first_line);
+
+    let expected = line_size * lines;
+    let unpacked = if size == expected {
+      // Blocks that wouldn't get any smaller are stored as they are
+      packed.to_vec()
+    } else {
+      match compression {
+        0 => packed.to_vec(),
+        1 => unpredict(run_length_decode(packed)?),
+        _ => unpredict(miniz_oxide::inflate::decompress_to_vec_zlib(packed)
+          .map_err(|e| invalid(&format!("Corrupt OpenEXR block: {:?}", e)))?),
+      }
+    };
+    if unpacked.len() < expected {
+      return Err(invalid("OpenEXR block is too short"));
+    }
+
+    for line in 0..lines {
+      let row = &unpacked[line * line_size..(line + 1) * line_size];
+      let y = first_line + line;
+      for x in 0..width {
+        let value = |channel: usize| {
+          let pixel_type = channels[channel].1;
+          let start = channel_offsets[channel] + x * pixel_type.size();
+          let bytes = &row[start..start + pixel_type.size()];
+          match pixel_type {
+            ExrPixelType::Half => half_to_f32(u16::from_le_bytes([bytes[0], bytes[1]])) as f64,
+            ExrPixelType::Float => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            ExrPixelType::Uint => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+          }
+        };
+        pixels[y * width + x] = Spectrum { r: value(rgb[0]), g: value(rgb[1]), b: value(rgb[2]) };
+      }
+    }
+  }
+  Ok(HdrImage { width, height, pixels })
+}
+
+struct ExrReader<'a> {
+  data: &'a [u8],
+  position: usize,
+}
+
+impl<'a> ExrReader<'a> {
+  fn take(&mut self, count: usize) -> io::Result<&'a [u8]> {
+    let end = match self.position.checked_add(count) {
+      Some(end) if end <= self.data.len() => end,
+      _ => return Err(invalid("OpenEXR file ends unexpectedly")),
+    };
+    let bytes = &self.data[self.position..end];
+    self.position = end;
+    Ok(bytes)
+  }
+
+  fn u32(&mut self) -> io::Result<u32> {
+    let b = self.take(4)?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+  }
+
+  fn u64(&mut self) -> io::Result<u64> {
+    Ok(self.u32()? as u64 | (self.u32()? as u64) << 32)
+  }
+
+  fn string(&mut self) -> io::Result<String> {
+    let length = self.data.get(self.position..).unwrap_or(&[]).iter().position(|&b| b == 0).ok_or_else(|| invalid("Unterminated OpenEXR string"))?;
+    let text = String::from_utf8_lossy(self.take(length)?).into_owned();
+    self.take(1)?;
+    Ok(text)
+  }
+}
+
+fn run_length_decode(packed: &[u8]) -> io::Result<Vec<u8>> {
+  let mut result = vec![];
+  let mut bytes = packed.iter();
+  while let Some(&count) = bytes.next() {
+    let count = count as i8;
+    if count < 0 {
+      for _ in 0..-(count as i32) {
+        result.push(*bytes.next().ok_or_else(|| invalid("Corrupt OpenEXR block"))?);
+      }
+    } else {
+      let value = *bytes.next().ok_or_else(|| invalid("Corrupt OpenEXR block"))?;
+      result.extend(std::iter::repeat(value).take(count as usize + 1));
+    }
+  }
+  Ok(result)
+}
+
+/// Undo the delta encoding and byte splitting OpenEXR applies before compressing, to make the data compress better
+fn unpredict(mut data: Vec<u8>) -> Vec<u8> {
+  for i in 1..data.len() {
+    data[i] = data[i - 1].wrapping_add(data[i]).wrapping_sub(128);
+  }
+  let half = data.len() / 2 + data.len() % 2;
+  let (first, second) = data.split_at(half);
+  let mut result = Vec::with_capacity(data.len());
+  for i in 0..half {
+    result.push(first[i]);
+    if let Some(&b) = second.get(i) {
+      result.push(b);
+    }
+  }
+  result
+}
+
+fn half_to_f32(half: u16) -> f32 {
+  let sign = (half as u32 >> 15) << 31;
+  let exponent = (half >> 10) & 0x1f;
+  let mantissa = (half & 0x3ff) as u32;
+  let magnitude = match exponent {
+    0 => mantissa as f32 / (1 << 24) as f32,
+    0x1f if mantissa == 0 => f32::INFINITY,
+    0x1f => f32::NAN,
+    _ => f32::from_bits(((exponent as u32 + 112) << 23) | (mantissa << 13)),
+  };
+  f32::from_bits(magnitude.to_bits() | sign)
+}
+
+fn invalid(message: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// An uncompressed OpenEXR file with float B, G and R channels, one block per scanline
+  fn float_exr(width: usize, pixels: &[[f32; 3]]) -> Vec<u8> {
+    let height = pixels.len() / width;
+    let attribute = |data: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]| {
+      data.extend(name.as_bytes());
+      data.push(0);
+      data.extend(kind.as_bytes());
+      data.push(0);
+      data.extend(&(value.len() as u32).to_le_bytes());
+      data.extend(value);
+    };
+
+    let mut data = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+    let mut channels = vec![];
+    for name in ["B", "G", "R"] {
+      channels.extend(name.as_bytes());
+      channels.push(0);
+      channels.extend(&2u32.to_le_bytes());
+      channels.extend(&[0; 4]);
+      channels.extend(&1u32.to_le_bytes());
+      channels.extend(&1u32.to_le_bytes());
+    }
+    channels.push(0);
+    attribute(&mut data, "channels", "chlist", &channels);
+    attribute(&mut data, "compression", "compression", &[0]);
+    let window: Vec<u8> = [0, 0, width as u32 - 1, height as u32 - 1].iter().flat_map(|v| v.to_le_bytes()).collect();
+    attribute(&mut data, "dataWindow", "box2i", &window);
+    data.push(0);
+
+    let line_size = 3 * 4 * width;
+    let first_block = data.len() + 8 * height;
+    for y in 0..height {
+      data.extend(&((first_block + y * (8 + line_size)) as u64).to_le_bytes());
+    }
+    for (y, row) in pixels.chunks(width).enumerate() {
+      data.extend(&(y as u32).to_le_bytes());
+      data.extend(&(line_size as u32).to_le_bytes());
+      for channel in [2, 1, 0] {
+        for pixel in row {
+          data.extend(&pixel[channel].to_le_bytes());
+        }
+      }
+    }
+    data
+  }
+
+  #[test]
+  fn reads_uncompressed_exr() {
+    let image = decode_exr(&float_exr(2, &[[1., 2., 3.], [4., 5., 6.], [0.5, 0.25, 0.], [7., 8., 9.]])).unwrap();
+    assert_eq!((image.width, image.height), (2, 2));
+    let p = image.texel(1, 0);
+    assert_eq!((p.r, p.g, p.b), (4., 5., 6.));
+    let p = image.texel(0, 1);
+    assert_eq!((p.r, p.g, p.b), (0.5, 0.25, 0.));
+  }
+
+  #[test]
+  fn rejects_truncated_exr() {
+    let data = float_exr(2, &[[1., 2., 3.], [4., 5., 6.]]);
+    for length in 0..data.len() {
+      assert!(decode_exr(&data[..length]).is_err(), "accepted the first {} bytes", length);
+    }
+  }
+
+  #[test]
+  fn rejects_bad_magic_number() {
+    let mut data = float_exr(1, &[[1., 1., 1.]]);
+    data[0] = 0;
+    assert!(decode_exr(&data).is_err());
+  }
+
+  #[test]
+  fn rejects_blocks_outside_image() {
+    let mut data = float_exr(1, &[[1., 1., 1.]]);
+    let block = data.len() - 20;
+    data[block..block + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert!(decode_exr(&data).is_err());
+
+    // Far from a data window at the bottom of the range
+    let mut data = float_exr(1, &[[1., 1., 1.]]);
+    let window = data.windows(10).position(|w| w == b"dataWindow").unwrap() + 21;
+    data[window + 4..window + 8].copy_from_slice(&i32::MIN.to_le_bytes());
+    data[window + 12..window + 16].copy_from_slice(&i32::MIN.to_le_bytes());
+    data[block..block + 4].copy_from_slice(&i32::MAX.to_le_bytes());
+    assert!(decode_exr(&data).is_err());
+  }
+
+  #[test]
+  fn rejects_huge_data_window() {
+    let mut data = float_exr(1, &[[1., 1., 1.]]);
+    let window = data.windows(10).position(|w| w == b"dataWindow").unwrap() + 21;
+    data[window + 8..window + 12].copy_from_slice(&i32::MAX.to_le_bytes());
+    data[window + 12..window + 16].copy_from_slice(&i32::MAX.to_le_bytes());
+    assert!(decode_exr(&data).is_err());
+  }
+
+  #[test]
+  fn converts_halves() {
+    assert_eq!(half_to_f32(0x3c00), 1.);
+    assert_eq!(half_to_f32(0xc000), -2.);
+    assert_eq!(half_to_f32(0x0001), 1. / (1 << 24) as f32);
+    assert_eq!(half_to_f32(0x7c00), f32::INFINITY);
+    assert!(half_to_f32(0x7e00).is_nan());
+  }
+
+  #[test]
+  fn rejects_corrupt_run_lengths() {
+    assert_eq!(run_length_decode(&[2, 7, 0xfe, 1, 2]).unwrap(), vec![7, 7, 7, 1, 2]);
+    assert!(run_length_decode(&[3]).is_err());
+    assert!(run_length_decode(&[0xfd, 1]).is_err());
+  }
+}
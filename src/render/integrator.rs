@@ -1,10 +1,10 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc, time::Instant};
 
 use bumpalo::Bump;
 use enum_dispatch::enum_dispatch;
-use crate::{geometry::{Intersection, Point2, Ray, RayDifferential}, scene::{Light, Scene}};
+use crate::{geometry::{Interaction, Intersection, Point2, Ray, RayDifferential, Vector3}, scene::{Light, LightInstance, LightSampler, Scene, TransportMode}};
 
-use super::{BSDF, BxDFCategory, Camera, CameraInstance, RadianceProblems, Sampler, SamplerInstance, Spectrum};
+use super::{AOIntegrator, BDPTIntegrator, BSDF, DebugIntegrator, DirectLightingIntegrator, GuidedPathIntegrator, LightTracingIntegrator, MLTIntegrator, BxDFCategory, Camera, CameraInstance, Film, PixelFeatures, RadianceProblems, RenderSettings, SPPMIntegrator, Sampler, SamplerInstance, Spectrum, VolPathIntegrator, VPLIntegrator, power_heuristic};
 
 #[enum_dispatch]
 pub trait Integrator {
@@ -14,7 +14,10 @@ pub trait Integrator {
 #[enum_dispatch(Integrator)]
 pub enum IntegratorInstance {
   NullIntegrator,
-  SamplerIntegratorInstance
+  SamplerIntegratorInstance,
+  MLTIntegrator,
+  SPPMIntegrator,
+  GuidedPathIntegrator,
 }
 
 pub struct NullIntegrator {}
@@ -28,14 +31,14 @@ impl Integrator for NullIntegrator {
 #[enum_dispatch]
 pub trait SamplerIntegrator {
   fn preprocess(&mut self, scene: &Scene);
-  fn light_along_ray(&self, rd: RayDifferential, scene: &Scene, sampler: &SamplerInstance, arena: &Bump, depth: u32) -> Spectrum;
+  fn light_along_ray(&self, rd: RayDifferential, scene: &Scene, sampler: &mut SamplerInstance, arena: &Bump, depth: u32) -> Spectrum;
   fn specular_reflect(
     &self,
     rd: RayDifferential,
     intersection: Intersection,
     bsdf: &BSDF,
     scene: &Scene,
-    sampler: &SamplerInstance,
+    sampler: &mut SamplerInstance,
     arena: &Bump,
     depth: u32
   ) -> Spectrum {
@@ -64,7 +67,7 @@ pub trait SamplerIntegrator {
     intersection: Intersection,
     bsdf: &BSDF,
     scene: &Scene,
-    sampler: &SamplerInstance,
+    sampler: &mut SamplerInstance,
     arena: &Bump,
     depth: u32
   ) -> Spectrum {
@@ -88,8 +91,30 @@ pub trait SamplerIntegrator {
     return Spectrum::black();
   }
 
+  /// Find the properties of the first surface along a camera ray, to guide denoising
+  fn features_along_ray(&self, ray: &Ray, scene: &Scene, arena: &Bump) -> PixelFeatures {
+    let interaction = match scene.intersect(ray) {
+      Some(interaction) => interaction,
+      None => return PixelFeatures::default(),
+    };
+    let intersection = interaction.intersection;
+    let albedo = match interaction.compute_scattering_functions(arena, TransportMode::Radiance, true) {
+      Some(bsdf) => bsdf.hemispherical_directional_reflectance(intersection.outgoing, &[Point2::new(0.5, 0.5)], BxDFCategory::ALL),
+      None => Spectrum::default(),
+    };
+    PixelFeatures {
+      albedo,
+      normal: intersection.shading_normal,
+      depth: (intersection.point - ray.origin).length(),
+    }
+  }
+
+  /// Whether every sample only splats light elsewhere on the film, so its own radiance says nothing about the pixel
+  fn splat_only(&self) -> bool { false }
+
   fn get_camera(&mut self) -> Arc<CameraInstance>;
   fn get_sampler(&self, seed: u64) -> SamplerInstance;
+  fn get_settings(&self) -> &RenderSettings;
 }
 
 impl<T: SamplerIntegrator> Integrator for T {
@@ -101,68 +126,162 @@ impl<T: SamplerIntegrator> Integrator for T {
     // We use a bump arena to efficiently drop temporary allocations on the floor
     let mut arena = Bump::new();
 
+    let start = Instant::now();
+    let settings = self.get_settings().clone();
     let mut sampler = self.get_sampler(0);
     let camera = self.get_camera();
     let film = camera.film();
     let bounds = camera.bounds();
-    for pixel in bounds {
-      sampler.start_pixel(&pixel);
-      loop {
-
-        // Choose a random ray to project along
-        let camera_sample = sampler.get_camera_sample(pixel);
-        let (weight, mut ray) = camera.generate_ray_differential(&camera_sample);
-        
-        // Scale the ray differential offsets down the more samples we're taking per pixel
-        let factor = 1. / (sampler.samples_per_pixel() as f64).sqrt();
-        ray.scale(factor);
-
-        // Sample light along the ray
-        let l = if weight > 0. { self.light_along_ray(ray, scene, &sampler, &arena, 0) } else { Spectrum::default() };
-        use RadianceProblems::*;
-        let l = match l.is_valid() {
-          Some(HasNaNs) => {
-            println!("Not-A-Number radiance for pixel {:?}, setting pixel to black.", pixel);
-            Spectrum::default()
-          },
-          Some(NegativeLuminance) => {
-            println!("Negative luminance for pixel {:?}, setting pixel to black.", pixel);
-            Spectrum::default()
-          },
-          Some(InfiniteLuminance) => {
-            println!("Infinite luminance for pixel {:?}, setting pixel to black.", pixel);
-            Spectrum::default()
-          },
-          None => l,
-        };
-
-        // And mix that sample onto our film
-        film.add_sample(pixel, l, weight);
-
-        // Reset the arena for the next round
-        arena.reset();
-        // Break if the sampler is done
-        if !sampler.start_next() {
-          break;
+
+    // Pick up where a previous run left off, if it saved any progress
+    if let Some(checkpoint) = &settings.checkpoint {
+      if checkpoint.exists() {
+        match film.load_state(checkpoint.clone()) {
+          Ok(()) => println!("Resuming from checkpoint {:?}", checkpoint),
+          Err(e) => println!("Unable to resume from checkpoint {:?}: {}", checkpoint, e),
+        }
+      }
+    }
+    let mut last_checkpoint = Instant::now();
+    let mut last_snapshot = Instant::now();
+
+    // Split the image into tiles, so a single frame can be divided up between processes
+    let tiles = bounds.tiles(settings.tile_size);
+    let tiles = match &settings.tiles {
+      Some(range) => &tiles[range.start.min(tiles.len())..range.end.min(tiles.len())],
+      None => &tiles[..],
+    };
+    // Likewise, each process can take a different subset of the samples in each pixel
+    let samples = settings.samples.clone().unwrap_or(0..sampler.samples_per_pixel().max(1));
+
+    // When rendering progressively, sweep the whole image with passes that double the sample count each time,
+    // so a complete (if noisy) image is available as early as possible
+    let passes = if settings.progressive {
+      let mut passes = vec![];
+      let (mut pass_start, mut pass_size) = (samples.start, 1);
+      while pass_start < samples.end {
+        let pass_end = (pass_start + pass_size).min(samples.end);
+        passes.push(pass_start..pass_end);
+        pass_start = pass_end;
+        pass_size *= 2;
+      }
+      passes
+    } else {
+      vec![samples.clone()]
+    };
+
+    'render: for pass in passes {
+      for pixel in tiles.iter().flat_map(|tile| *tile) {
+        // We only ever checkpoint between pixels, so a pixel's sample count tells us exactly which samples
+        // were already taken, either by an earlier pass or by a previous run
+        let taken = samples.start + film.pixel(pixel).samples_taken() as i64;
+        let pass_samples = pass.start.max(taken)..pass.end;
+        if pass_samples.is_empty() {
+          continue;
+        }
+
+        sampler.start_pixel(&pixel);
+        for sample in pass_samples {
+          if !sampler.set_sample_number(sample) {
+            break;
+          }
+
+          // Choose a random ray to project along
+          let camera_sample = sampler.get_camera_sample(pixel);
+          let (weight, mut ray) = camera.generate_ray_differential(&camera_sample);
+
+          // Scale the ray differential offsets down the more samples we're taking per pixel
+          let factor = 1. / (sampler.samples_per_pixel() as f64).sqrt();
+          ray.scale(factor);
+
+          // Sample light along the ray
+          let l = if weight > 0. { self.light_along_ray(ray, scene, &mut sampler, &arena, 0) } else { Spectrum::default() };
+          use RadianceProblems::*;
+          let l = match l.is_valid() {
+            Some(HasNaNs) => {
+              println!("Not-A-Number radiance for pixel {:?}, setting pixel to black.", pixel);
+              Spectrum::default()
+            },
+            Some(NegativeLuminance) => {
+              println!("Negative luminance for pixel {:?}, setting pixel to black.", pixel);
+              Spectrum::default()
+            },
+            Some(InfiniteLuminance) => {
+              println!("Infinite luminance for pixel {:?}, setting pixel to black.", pixel);
+              Spectrum::default()
+            },
+            None => l,
+          };
+
+          // And mix that sample onto our film, unless all its light was splatted, in which case just count it
+          match settings.outlier_rejection {
+            _ if self.splat_only() => film.add_splat_samples(pixel, 1),
+            Some(max_deviations) => { film.add_sample_rejecting_outliers(pixel, l, weight, max_deviations); },
+            None => film.add_sample(pixel, l, weight),
+          }
+          if settings.collect_features {
+            let features = if weight > 0. { self.features_along_ray(&ray.ray, scene, &arena) } else { PixelFeatures::default() };
+            film.add_features(pixel, &features);
+          }
+
+          // Reset the arena for the next round
+          arena.reset();
+        }
+
+        if let Some(checkpoint) = &settings.checkpoint {
+          if last_checkpoint.elapsed() >= settings.checkpoint_interval {
+            save_checkpoint(&film, checkpoint);
+            last_checkpoint = Instant::now();
+          }
+        }
+        if let (Some(snapshot), Some(interval)) = (&settings.snapshot, settings.snapshot_interval) {
+          if last_snapshot.elapsed() >= interval {
+            film.write_to(snapshot.clone());
+            last_snapshot = Instant::now();
+          }
+        }
+        if let Some(time_limit) = settings.time_limit {
+          if start.elapsed() >= time_limit {
+            println!("Reached the time limit of {:.2}s, stopping early.", time_limit.as_secs_f64());
+            break 'render;
+          }
         }
       }
     }
+
+    if let Some(checkpoint) = &settings.checkpoint {
+      save_checkpoint(&film, checkpoint);
+    }
+  }
+}
+
+fn save_checkpoint(film: &Film, checkpoint: &PathBuf) {
+  if let Err(e) = film.write_state(checkpoint.clone()) {
+    println!("Unable to write checkpoint {:?}: {}", checkpoint, e);
   }
 }
 
 #[enum_dispatch(SamplerIntegrator)]
 pub enum SamplerIntegratorInstance {
-  WhittedIntegrator
+  WhittedIntegrator,
+  BDPTIntegrator,
+  DirectLightingIntegrator,
+  AOIntegrator,
+  DebugIntegrator,
+  LightTracingIntegrator,
+  VolPathIntegrator,
+  VPLIntegrator,
 }
 
 pub struct WhittedIntegrator {
   pub max_depth: u32,
   pub camera: Arc<CameraInstance>,
   pub sampler: SamplerInstance,
+  pub settings: RenderSettings,
 }
 impl WhittedIntegrator {
-  pub fn new(max_depth: u32, camera: CameraInstance, sampler: SamplerInstance) -> Self {
-    Self { max_depth, camera: Arc::new(camera), sampler }
+  pub fn new(max_depth: u32, camera: CameraInstance, sampler: SamplerInstance, settings: RenderSettings) -> Self {
+    Self { max_depth, camera: Arc::new(camera), sampler, settings }
   }
 }
 
@@ -170,9 +289,10 @@ impl SamplerIntegrator for WhittedIntegrator {
   fn preprocess(&mut self, _scene: &Scene) {
   }
 
-  fn light_along_ray(&self, rd: RayDifferential, scene: &Scene, sampler: &SamplerInstance, arena: &Bump, depth: u32) -> Spectrum {
+  fn light_along_ray(&self, rd: RayDifferential, scene: &Scene, sampler: &mut SamplerInstance, arena: &Bump, depth: u32) -> Spectrum {
 
     let mut result = Spectrum::default();
+    let settings = &self.settings;
 
     let interaction = scene.intersect(&rd.ray);
     if interaction.is_none() {
@@ -181,7 +301,7 @@ impl SamplerIntegrator for WhittedIntegrator {
       for light in &scene.lights {
         result += light.background_radiance(&rd.ray);
       }
-      return result;
+      return if depth == 0 { settings.clamp_direct(result) } else { result };
     }
 
     let interaction = interaction.unwrap();
@@ -194,7 +314,7 @@ impl SamplerIntegrator for WhittedIntegrator {
     let bsdf = if let Some(bsdf) = bsdf {
       bsdf
     } else {
-      return result;
+      return if depth == 0 { settings.clamp_direct(result) } else { result };
     };
 
     // Now add in the contribution from each light source
@@ -219,14 +339,164 @@ impl SamplerIntegrator for WhittedIntegrator {
     }
 
     // And trace more bounces
+    let mut indirect = Spectrum::default();
     if depth + 1 < self.max_depth {
-      result += self.specular_reflect(rd, interaction.intersection, bsdf, scene, sampler, arena, depth);
-      result += self.specular_transmit(rd, interaction.intersection, bsdf, scene, sampler, arena, depth);
+      indirect += self.specular_reflect(rd, interaction.intersection, bsdf, scene, sampler, arena, depth);
+      indirect += self.specular_transmit(rd, interaction.intersection, bsdf, scene, sampler, arena, depth);
     }
 
-    return result;
+    // Everything past the first surface is indirect as far as the camera is concerned,
+    // so only clamp once we're back at the camera ray
+    if depth == 0 {
+      return settings.clamp_direct(result) + settings.clamp_indirect(indirect);
+    }
+    return result + indirect;
   }
 
   fn get_camera(&mut self) -> Arc<CameraInstance> { self.camera.clone() }
   fn get_sampler(&self, _: u64) -> SamplerInstance { self.sampler.clone() }
-}
\ No newline at end of file
+  fn get_settings(&self) -> &RenderSettings { &self.settings }
+}
+
+/// Estimate the light arriving directly from a single light, chosen by the scene's light sampler,
+/// scaled up to account for all of them
+pub fn sample_one_light(scene: &Scene, intersection: &Intersection, bsdf: &BSDF, sampler: &mut impl Sampler) -> Spectrum {
+  let (index, probability) = match scene.light_sampler.sample(intersection, sampler.get_1d()) {
+    Some(choice) => choice,
+    None => return Spectrum::default(),
+  };
+  let (light_sample, scattering_sample) = (sampler.get_2d(), sampler.get_2d());
+  let scatterer = SurfaceScatterer::new(intersection, bsdf);
+  estimate_direct(intersection, &scatterer, &scene.lights[index], light_sample, scattering_sample, &mut SurfaceVisibility(scene)) / probability
+}
+
+/// Estimate the light arriving directly from every light, averaging `samples_per_light` samples of each
+pub fn uniform_sample_all_lights(scene: &Scene, intersection: &Intersection, bsdf: &BSDF, sampler: &mut impl Sampler, samples_per_light: usize) -> Spectrum {
+  let scatterer = SurfaceScatterer::new(intersection, bsdf);
+  let mut result = Spectrum::default();
+  for light in &scene.lights {
+    let mut direct = Spectrum::default();
+    for _ in 0..samples_per_light {
+      let (light_sample, scattering_sample) = (sampler.get_2d(), sampler.get_2d());
+      direct += estimate_direct(intersection, &scatterer, light, light_sample, scattering_sample, &mut SurfaceVisibility(scene));
+    }
+    result += direct / samples_per_light.max(1) as f64;
+  }
+  result
+}
+
+/// Whatever scatters light arriving directly from a light, such as a surface's BSDF
+pub trait Scatterer {
+  /// The light scattered from `incoming` to `outgoing`, including the cosine factor on surfaces,
+  /// and the density with which `sample` would choose `incoming`
+  fn evaluate(&self, outgoing: Vector3, incoming: Vector3) -> (Spectrum, f64);
+  /// Choose an incoming direction, returning the light scattered along it, the direction, its density,
+  /// and whether it was chosen from a specular lobe
+  fn sample(&self, outgoing: Vector3, u: Point2) -> (Spectrum, Vector3, f64, bool);
+}
+
+/// How much of the light headed towards the point being lit gets there
+pub trait Visibility {
+  /// The fraction of light from a point sampled on a light that reaches the point being lit, arriving along `incoming`
+  fn transmittance(&mut self, from: &Intersection, to: &Intersection, incoming: Vector3) -> Spectrum;
+  /// The first surface a ray leaving the point being lit hits, and the fraction of light from there that makes it back
+  fn trace(&mut self, ray: &Ray) -> (Option<Interaction>, Spectrum);
+}
+
+/// Light scattering off a surface, leaving out specular lobes since they can't be hit by sampling a light
+pub struct SurfaceScatterer<'a> {
+  pub bsdf: &'a BSDF<'a>,
+  pub shading_normal: Vector3,
+}
+
+impl<'a> SurfaceScatterer<'a> {
+  pub fn new(intersection: &Intersection, bsdf: &'a BSDF<'a>) -> Self {
+    Self { bsdf, shading_normal: intersection.shading_normal.into() }
+  }
+}
+
+impl<'a> Scatterer for SurfaceScatterer<'a> {
+  fn evaluate(&self, outgoing: Vector3, incoming: Vector3) -> (Spectrum, f64) {
+    let category = BxDFCategory::ALL - BxDFCategory::SPECULAR;
+    let cos_theta = incoming.dot(self.shading_normal).abs();
+    (self.bsdf.evaluate(outgoing, incoming, category) * cos_theta, self.bsdf.probability_distribution(outgoing, incoming, category))
+  }
+
+  fn sample(&self, outgoing: Vector3, u: Point2) -> (Spectrum, Vector3, f64, bool) {
+    let sample = self.bsdf.sample_function(outgoing, &u, BxDFCategory::ALL - BxDFCategory::SPECULAR);
+    let cos_theta = sample.incoming.dot(self.shading_normal).abs();
+    (sample.value * cos_theta, sample.incoming, sample.probability_distribution, sample.category.contains(BxDFCategory::SPECULAR))
+  }
+}
+
+/// Light travelling through empty space, where it either gets through unblocked or not at all
+pub struct SurfaceVisibility<'a>(pub &'a Scene);
+
+impl<'a> Visibility for SurfaceVisibility<'a> {
+  fn transmittance(&mut self, from: &Intersection, to: &Intersection, _: Vector3) -> Spectrum {
+    if self.0.any_intersect(&from.ray_between(to)) { Spectrum::default() } else { Spectrum::white() }
+  }
+
+  fn trace(&mut self, ray: &Ray) -> (Option<Interaction>, Spectrum) {
+    (self.0.intersect(ray), Spectrum::white())
+  }
+}
+
+/// Estimate the light arriving directly from one light, combining a direction chosen by the light
+/// with one chosen by the scatterer using multiple importance sampling
+pub fn estimate_direct(
+  intersection: &Intersection,
+  scatterer: &impl Scatterer,
+  light: &LightInstance,
+  light_sample: Point2,
+  scattering_sample: Point2,
+  visibility: &mut impl Visibility,
+) -> Spectrum {
+  let outgoing = intersection.outgoing;
+  let delta = light.flags().is_delta();
+  let mut result = Spectrum::default();
+
+  // Sample the light
+  let sample = light.sample_radiance(intersection, light_sample);
+  if sample.probability_distribution > 0. && !sample.color.is_black() {
+    let incoming = sample.incident_direction;
+    let (value, scattering_pdf) = scatterer.evaluate(outgoing, incoming);
+    if !value.is_black() {
+      let radiance = sample.color * visibility.transmittance(&sample.intersections.0, &sample.intersections.1, incoming);
+      if !radiance.is_black() {
+        let weight = if delta { 1. } else { power_heuristic(1, sample.probability_distribution, 1, scattering_pdf) };
+        result += value * radiance * weight / sample.probability_distribution;
+      }
+    }
+  }
+
+  // Sample the scattering, which a light that can only be reached one way could never be hit by
+  if !delta {
+    let (value, incoming, pdf, specular) = scatterer.sample(outgoing, scattering_sample);
+    if !value.is_black() && pdf > 0. {
+      let mut weight = 1.;
+      if !specular {
+        let light_pdf = light.radiance_probability(intersection, incoming);
+        if light_pdf == 0. {
+          return result;
+        }
+        weight = power_heuristic(1, pdf, 1, light_pdf);
+      }
+
+      let ray = intersection.spawn_ray(incoming);
+      let (found, tr) = visibility.trace(&ray);
+      let radiance = match found {
+        // Only light from the surface of the light being sampled counts, other lights get their own turn
+        Some(interaction) => match &interaction.emission {
+          Some(emission) if emission.is_light(light) => emission.emitted_radiance(&interaction.intersection, -incoming),
+          _ => Spectrum::default(),
+        },
+        None => light.background_radiance(&ray),
+      };
+      if !radiance.is_black() {
+        result += value * radiance * tr * weight / pdf;
+      }
+    }
+  }
+  result
+}
@@ -0,0 +1,61 @@
+use std::sync::Arc;
+
+use bumpalo::Bump;
+
+use crate::{geometry::{RayDifferential, Vector3}, scene::Scene};
+use crate::render::{CameraInstance, RenderSettings, Sampler, SamplerInstance, SamplerIntegrator, Spectrum, cosine_sample_hemisphere};
+
+/// Shades each point by how much of the hemisphere above it is open, ignoring lights and materials entirely
+pub struct AOIntegrator {
+  /// Rays cast from each point the camera sees
+  pub samples: usize,
+  /// Occluders further away than this don't count
+  pub max_distance: f64,
+  pub camera: Arc<CameraInstance>,
+  pub sampler: SamplerInstance,
+  pub settings: RenderSettings,
+}
+
+impl AOIntegrator {
+  pub fn new(samples: usize, max_distance: f64, camera: CameraInstance, sampler: SamplerInstance, settings: RenderSettings) -> Self {
+    Self { samples, max_distance, camera: Arc::new(camera), sampler, settings }
+  }
+}
+
+impl SamplerIntegrator for AOIntegrator {
+  fn preprocess(&mut self, _scene: &Scene) {
+  }
+
+  fn light_along_ray(&self, rd: RayDifferential, scene: &Scene, sampler: &mut SamplerInstance, _arena: &Bump, _depth: u32) -> Spectrum {
+    let interaction = match scene.intersect(&rd.ray) {
+      Some(interaction) => interaction,
+      None => return Spectrum::default(),
+    };
+    let intersection = &interaction.intersection;
+
+    // Look out from whichever side of the surface the ray arrived on
+    let mut normal: Vector3 = intersection.normal.into();
+    if normal.dot(intersection.outgoing) < 0. {
+      normal = -normal;
+    }
+    let (s, t) = normal.coordinate_system();
+
+    // Cosine-weighted directions already count each one by how much it faces the surface,
+    // so the fraction that escape is the answer
+    let mut unoccluded = 0;
+    for _ in 0..self.samples {
+      let local = cosine_sample_hemisphere(sampler.get_2d());
+      let direction = s * local.x + t * local.y + normal * local.z;
+      let mut ray = intersection.spawn_ray(direction);
+      ray.time_max = self.max_distance;
+      if !scene.any_intersect(&ray) {
+        unoccluded += 1;
+      }
+    }
+    Spectrum::greyscale(unoccluded as f64 / self.samples.max(1) as f64)
+  }
+
+  fn get_camera(&mut self) -> Arc<CameraInstance> { self.camera.clone() }
+  fn get_sampler(&self, _: u64) -> SamplerInstance { self.sampler.clone() }
+  fn get_settings(&self) -> &RenderSettings { &self.settings }
+}
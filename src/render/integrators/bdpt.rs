@@ -0,0 +1,730 @@
+use std::{f64::consts::PI, sync::Arc};
+
+use bumpalo::Bump;
+
+use crate::{
+  geometry::{Interaction, Intersection, Normal3, Point2, Point3, Ray, RayDifferential, Vector3},
+  render::{BSDF, BxDFCategory, Camera, CameraInstance, RenderSettings, Sampler, SamplerInstance, SamplerIntegrator, Spectrum},
+  scene::{AreaLight, Light, LightFlags, LightInstance, LightSampler, Scene, TransportMode},
+};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum VertexKind {
+  Camera,
+  Light,
+  Surface,
+  /// A camera path that left the scene, which sees the background
+  Escaped,
+}
+
+/// One vertex of a path traced from either the camera or a light
+#[derive(Clone, Copy)]
+pub struct Vertex<'a> {
+  pub kind: VertexKind,
+  pub intersection: Intersection,
+  /// The path throughput from the start of the subpath up to this vertex
+  pub beta: Spectrum,
+  pub bsdf: Option<&'a BSDF<'a>>,
+  pub light: Option<&'a LightInstance>,
+  pub emission: Option<&'a AreaLight>,
+  /// Whether the path scattered off this vertex specularly, so no other strategy could have found it
+  pub delta: bool,
+  /// Density of sampling this vertex from the one before it, per unit area
+  pub pdf_forward: f64,
+  /// Density of sampling this vertex from the one after it, if the path were traced the other way
+  pub pdf_reverse: f64,
+}
+
+impl<'a> Vertex<'a> {
+  fn new(kind: VertexKind, intersection: Intersection, beta: Spectrum) -> Self {
+    Vertex {
+      kind, intersection, beta,
+      bsdf: None, light: None, emission: None,
+      delta: false,
+      pdf_forward: 0., pdf_reverse: 0.,
+    }
+  }
+
+  pub fn camera(intersection: Intersection, beta: Spectrum) -> Self {
+    Vertex::new(VertexKind::Camera, intersection, beta)
+  }
+
+  pub fn light(light: &'a LightInstance, intersection: Intersection, beta: Spectrum, pdf_forward: f64) -> Self {
+    Vertex { light: Some(light), pdf_forward, ..Vertex::new(VertexKind::Light, intersection, beta) }
+  }
+
+  pub fn surface(interaction: &'a Interaction, beta: Spectrum, pdf: f64, previous: &Vertex) -> Self {
+    let mut vertex = Vertex::new(VertexKind::Surface, interaction.intersection, beta);
+    vertex.emission = interaction.emission.as_ref();
+    vertex.pdf_forward = previous.convert_density(pdf, &vertex);
+    vertex
+  }
+
+  pub fn escaped(ray: &Ray, beta: Spectrum, pdf_forward: f64) -> Self {
+    let intersection = Intersection {
+      point: ray.origin + ray.direction,
+      outgoing: -ray.direction,
+      ..Default::default()
+    };
+    Vertex { pdf_forward, ..Vertex::new(VertexKind::Escaped, intersection, beta) }
+  }
+
+  pub fn point(&self) -> Point3 {
+    self.intersection.point
+  }
+
+  /// Whether the vertex lies on a surface with a normal, as opposed to a point light or pinhole
+  pub fn on_surface(&self) -> bool {
+    self.intersection.normal.length_squared() > 0.
+  }
+
+  pub fn is_light(&self) -> bool {
+    match self.kind {
+      VertexKind::Light | VertexKind::Escaped => true,
+      VertexKind::Surface => self.emission.is_some(),
+      VertexKind::Camera => false,
+    }
+  }
+
+  /// Whether the vertex is on a light outside the scene, which is better thought of as a direction than a point
+  pub fn is_infinite_light(&self) -> bool {
+    match self.kind {
+      VertexKind::Escaped => true,
+      VertexKind::Light => self.light.map_or(false, |light| light.flags().intersects(LightFlags::INFINITE | LightFlags::DELTA_DIRECTION)),
+      _ => false,
+    }
+  }
+
+  pub fn is_delta_light(&self) -> bool {
+    self.kind == VertexKind::Light && self.light.map_or(false, |light| light.flags().is_delta())
+  }
+
+  /// Whether we can join this vertex to a vertex on another path, rather than only scattering off it
+  pub fn is_connectible(&self) -> bool {
+    match self.kind {
+      VertexKind::Camera | VertexKind::Escaped => true,
+      VertexKind::Light => self.light.map_or(false, |light| !light.flags().contains(LightFlags::DELTA_DIRECTION)),
+      VertexKind::Surface => self.bsdf.map_or(false, |bsdf| bsdf.num_components(BxDFCategory::ALL - BxDFCategory::SPECULAR) > 0),
+    }
+  }
+
+  /// How much light arriving from `next` scatters back along the path
+  pub fn scattering(&self, next: &Vertex, mode: TransportMode) -> Spectrum {
+    let incoming = direction_between(self.point(), next.point());
+    match (self.kind, self.bsdf) {
+      (VertexKind::Surface, Some(bsdf)) => {
+        let outgoing = self.intersection.outgoing;
+        bsdf.evaluate(outgoing, incoming, BxDFCategory::ALL) * correct_shading_normal(&self.intersection, outgoing, incoming, mode)
+      },
+      _ => Spectrum::default(),
+    }
+  }
+
+  /// Turn a density per unit solid angle, leaving this vertex, into a density per unit area at `next`
+  pub fn convert_density(&self, pdf: f64, next: &Vertex) -> f64 {
+    if next.is_infinite_light() {
+      return pdf;
+    }
+    let offset = next.point() - self.point();
+    let distance_sq = offset.length_squared();
+    if distance_sq == 0. {
+      return 0.;
+    }
+    let mut pdf = pdf / distance_sq;
+    if next.on_surface() {
+      pdf *= next.intersection.normal.dot((offset / distance_sq.sqrt()).into()).abs();
+    }
+    pdf
+  }
+
+  /// The density, per unit area, with which this vertex would sample `next` after arriving from `previous`
+  pub fn probability(&self, scene: &Scene, camera: &CameraInstance, previous: Option<&Vertex>, next: &Vertex) -> f64 {
+    if self.kind == VertexKind::Light || self.kind == VertexKind::Escaped {
+      return self.light_probability(scene, next);
+    }
+
+    let offset = next.point() - self.point();
+    if offset.length_squared() == 0. {
+      return 0.;
+    }
+    let direction = offset.normalized();
+    let pdf = match (self.kind, self.bsdf, previous) {
+      (VertexKind::Camera, _, _) => {
+        let ray = Ray { origin: self.point(), direction, time_max: f64::INFINITY };
+        camera.importance_probability(&ray).1
+      },
+      (VertexKind::Surface, Some(bsdf), Some(previous)) => {
+        bsdf.probability_distribution(direction_between(self.point(), previous.point()), direction, BxDFCategory::ALL)
+      },
+      _ => 0.,
+    };
+    self.convert_density(pdf, next)
+  }
+
+  /// The density, per unit area, with which this vertex would emit light towards `next`
+  pub fn light_probability(&self, scene: &Scene, next: &Vertex) -> f64 {
+    let offset = next.point() - self.point();
+    let distance_sq = offset.length_squared();
+    if distance_sq == 0. {
+      return 0.;
+    }
+    let direction = offset / distance_sq.sqrt();
+    let mut pdf = if self.is_infinite_light() {
+      // Paths from lights outside the scene start on a disc facing the light, as wide as the scene
+      let (_, radius) = scene.world_bounds.bounding_sphere();
+      1. / (PI * radius * radius)
+    } else {
+      let ray = Ray { origin: self.point(), direction, time_max: f64::INFINITY };
+      self.emission_probability(&ray).1 / distance_sq
+    };
+    if next.on_surface() {
+      pdf *= next.intersection.normal.dot(direction.into()).abs();
+    }
+    pdf
+  }
+
+  /// The density, per unit area, with which we'd have started a light path at this vertex, heading towards `next`
+  pub fn light_origin_probability(&self, scene: &Scene, next: &Vertex) -> f64 {
+    let direction = direction_between(self.point(), next.point());
+    if self.is_infinite_light() {
+      return infinite_light_density(scene, -direction);
+    }
+    let ray = Ray { origin: self.point(), direction, time_max: f64::INFINITY };
+    self.light_choice_probability(scene) * self.emission_probability(&ray).0
+  }
+
+  /// The probability of choosing this vertex's light to start a light path from
+  fn light_choice_probability(&self, scene: &Scene) -> f64 {
+    let index = match (self.light, self.emission) {
+      (Some(light), _) => scene.light_index(light),
+      (None, Some(emission)) => scene.area_light_index(emission),
+      _ => None,
+    };
+    index.map_or(0., |index| scene.light_sampler.probability_any(index))
+  }
+
+  /// Light leaving this vertex towards `next`, if the vertex is on a light
+  pub fn emitted_radiance(&self, scene: &Scene, next: &Vertex) -> Spectrum {
+    if !self.is_light() {
+      return Spectrum::default();
+    }
+    let direction = direction_between(self.point(), next.point());
+    match (self.kind, self.emission) {
+      (VertexKind::Escaped, _) => {
+        let ray = Ray { origin: next.point(), direction: -direction, time_max: f64::INFINITY };
+        let mut result = Spectrum::default();
+        for light in &scene.lights {
+          result += light.background_radiance(&ray);
+        }
+        result
+      },
+      (VertexKind::Surface, Some(emission)) => emission.emitted_radiance(&self.intersection, direction),
+      _ => Spectrum::default(),
+    }
+  }
+
+  fn emission_probability(&self, ray: &Ray) -> (f64, f64) {
+    let normal = self.intersection.normal;
+    match (self.light, self.emission) {
+      (Some(light), _) => light.emission_probability(ray, normal),
+      (None, Some(emission)) => emission.emission_probability(ray, normal),
+      _ => (0., 0.),
+    }
+  }
+}
+
+fn direction_between(from: Point3, to: Point3) -> Vector3 {
+  (to - from).normalized()
+}
+
+/// An intersection for a path endpoint, like a light or the camera lens, which has no surface properties
+fn endpoint(point: Point3, normal: Normal3) -> Intersection {
+  Intersection { point, normal, shading_normal: normal, ..Default::default() }
+}
+
+/// Shading normals make light transport asymmetric, so paths carrying importance need this correction
+/// See Veach's thesis, section 5.3
+pub fn correct_shading_normal(intersection: &Intersection, outgoing: Vector3, incoming: Vector3, mode: TransportMode) -> f64 {
+  if mode == TransportMode::Radiance {
+    return 1.;
+  }
+  let (normal, shading_normal): (Vector3, Vector3) = (intersection.normal.into(), intersection.shading_normal.into());
+  let numerator = outgoing.dot(shading_normal).abs() * incoming.dot(normal).abs();
+  let denominator = outgoing.dot(normal).abs() * incoming.dot(shading_normal).abs();
+  if denominator == 0. { 0. } else { numerator / denominator }
+}
+
+/// The density with which we'd choose to start a light path from outside the scene in the given direction,
+/// counting every light that lies outside it
+fn infinite_light_density(scene: &Scene, towards_light: Vector3) -> f64 {
+  let intersection = Intersection::default();
+  scene.lights.iter().enumerate()
+    .filter(|(_, light)| light.flags().contains(LightFlags::INFINITE))
+    .map(|(index, light)| light.radiance_probability(&intersection, towards_light) * scene.light_sampler.probability_any(index))
+    .sum()
+}
+
+fn unoccluded(scene: &Scene, a: &Intersection, b: &Intersection) -> bool {
+  !scene.any_intersect(&a.ray_between(b))
+}
+
+/// The geometric coupling between two vertices, including whether they can see each other
+fn geometry_term(scene: &Scene, a: &Vertex, b: &Vertex) -> f64 {
+  let offset = a.point() - b.point();
+  let mut g = 1. / offset.length_squared();
+  let direction = offset * g.sqrt();
+  if a.on_surface() {
+    g *= a.intersection.shading_normal.dot(direction.into()).abs();
+  }
+  if b.on_surface() {
+    g *= b.intersection.shading_normal.dot(direction.into()).abs();
+  }
+  if unoccluded(scene, &a.intersection, &b.intersection) { g } else { 0. }
+}
+
+/// Extend a path by repeatedly sampling the BSDF at each surface, until it leaves the scene,
+/// is absorbed, or has `max_depth` more vertices
+pub fn random_walk<'a>(
+  scene: &'a Scene,
+  ray: Ray,
+  sampler: &mut impl Sampler,
+  arena: &'a Bump,
+  beta: Spectrum,
+  pdf: f64,
+  max_depth: u32,
+  mode: TransportMode,
+  path: &mut Vec<Vertex<'a>>,
+) {
+  if max_depth == 0 {
+    return;
+  }
+  let (mut ray, mut beta, mut pdf_forward) = (ray, beta, pdf);
+  let mut bounces = 0;
+  loop {
+    let previous = path.len() - 1;
+    let interaction = match scene.intersect(&ray) {
+      Some(interaction) => interaction,
+      None => {
+        // Only paths from the camera can see the background
+        if mode == TransportMode::Radiance {
+          path.push(Vertex::escaped(&ray, beta, pdf_forward));
+        }
+        break;
+      }
+    };
+    // The BSDF borrows from the interaction, so keep it alive as long as the path
+    let interaction: &'a Interaction = arena.alloc(interaction);
+    let mut vertex = Vertex::surface(interaction, beta, pdf_forward, &path[previous]);
+    bounces += 1;
+
+    // The last vertex still needs its BSDF, for connecting to the other path
+    let bsdf = match interaction.compute_scattering_functions(arena, mode, true) {
+      Some(bsdf) => &*bsdf,
+      None => {
+        path.push(vertex);
+        break;
+      }
+    };
+    vertex.bsdf = Some(bsdf);
+    if bounces >= max_depth {
+      path.push(vertex);
+      break;
+    }
+
+    let outgoing = interaction.intersection.outgoing;
+    let sample = bsdf.sample_function(outgoing, &sampler.get_2d(), BxDFCategory::ALL);
+    if sample.value.is_black() || sample.probability_distribution == 0. {
+      path.push(vertex);
+      break;
+    }
+    let incoming = sample.incoming;
+    let shading_normal: Vector3 = interaction.intersection.shading_normal.into();
+    beta = beta * sample.value * incoming.dot(shading_normal).abs() / sample.probability_distribution;
+    beta = beta * correct_shading_normal(&interaction.intersection, outgoing, incoming, mode);
+    pdf_forward = sample.probability_distribution;
+    let mut pdf_reverse = bsdf.probability_distribution(incoming, outgoing, BxDFCategory::ALL);
+    if sample.category.contains(BxDFCategory::SPECULAR) {
+      vertex.delta = true;
+      pdf_forward = 0.;
+      pdf_reverse = 0.;
+    }
+
+    path[previous].pdf_reverse = vertex.convert_density(pdf_reverse, &path[previous]);
+    path.push(vertex);
+    ray = interaction.intersection.spawn_ray(incoming);
+  }
+}
+
+/// Trace a path of up to `max_depth` vertices starting at the camera, along the given camera ray
+pub fn camera_subpath<'a>(
+  scene: &'a Scene,
+  camera: &CameraInstance,
+  ray: &Ray,
+  sampler: &mut impl Sampler,
+  arena: &'a Bump,
+  max_depth: u32,
+  path: &mut Vec<Vertex<'a>>,
+) {
+  if max_depth == 0 {
+    return;
+  }
+  let beta = Spectrum::white();
+  path.push(Vertex::camera(endpoint(ray.origin, Normal3::default()), beta));
+  let (_, pdf_direction) = camera.importance_probability(ray);
+  random_walk(scene, *ray, sampler, arena, beta, pdf_direction, max_depth - 1, TransportMode::Radiance, path);
+}
+
+/// Trace a path of up to `max_depth` vertices starting at a randomly chosen light
+pub fn light_subpath<'a>(
+  scene: &'a Scene,
+  sampler: &mut impl Sampler,
+  arena: &'a Bump,
+  max_depth: u32,
+  path: &mut Vec<Vertex<'a>>,
+) {
+  if max_depth == 0 {
+    return;
+  }
+  let (light, choice_pdf) = match scene.light_sampler.sample_any(sampler.get_1d()) {
+    Some((index, probability)) => (&scene.lights[index], probability),
+    None => return,
+  };
+  let emission = light.sample_emission(sampler.get_2d(), sampler.get_2d());
+  if emission.position_probability == 0. || emission.direction_probability == 0. || emission.color.is_black() {
+    return;
+  }
+
+  let ray = emission.ray;
+  path.push(Vertex::light(light, endpoint(ray.origin, emission.normal), emission.color, emission.position_probability * choice_pdf));
+  let cos_theta = emission.normal.dot(ray.direction.into()).abs();
+  let beta = emission.color * cos_theta / (choice_pdf * emission.position_probability * emission.direction_probability);
+  random_walk(scene, ray, sampler, arena, beta, emission.direction_probability, max_depth - 1, TransportMode::Importance, path);
+
+  // Lights outside the scene choose the direction first and the origin second, so their densities are the other way round
+  if path[0].is_infinite_light() {
+    if let Some(first) = path.get_mut(1) {
+      first.pdf_forward = emission.position_probability;
+      if first.on_surface() {
+        first.pdf_forward *= first.intersection.normal.dot(ray.direction.into()).abs();
+      }
+    }
+    path[0].pdf_forward = infinite_light_density(scene, -ray.direction);
+  }
+}
+
+/// Join a vertex of a light path to a freshly sampled point on the camera lens, returning the light it carries
+/// to the camera, where it lands on the film, and the camera vertex
+pub fn connect_to_camera<'a>(
+  scene: &Scene,
+  camera: &CameraInstance,
+  vertex: &Vertex<'a>,
+  sampler: &mut impl Sampler,
+) -> Option<(Spectrum, Point2, Vertex<'a>)> {
+  if !vertex.is_connectible() {
+    return None;
+  }
+  let sample = camera.sample_importance(&vertex.intersection, sampler.get_2d());
+  if sample.probability_distribution == 0. || sample.importance.is_black() {
+    return None;
+  }
+  let camera_vertex = Vertex::camera(sample.lens_intersection, sample.importance / sample.probability_distribution);
+  let mut result = vertex.beta * vertex.scattering(&camera_vertex, TransportMode::Importance) * camera_vertex.beta;
+  if vertex.on_surface() {
+    result = result * sample.incident_direction.dot(vertex.intersection.shading_normal.into()).abs();
+  }
+  if !result.is_black() && !unoccluded(scene, &vertex.intersection, &camera_vertex.intersection) {
+    result = Spectrum::default();
+  }
+  Some((result, sample.raster_point, camera_vertex))
+}
+
+/// Join the first `s` vertices of a light path to the first `t` vertices of a camera path,
+/// returning the MIS-weighted contribution of the resulting path
+///
+/// When `t` is 1 the light path is connected straight to the camera, which may land on any pixel,
+/// so the film position it lands on is written to `raster`
+pub fn connect_subpaths<'a>(
+  scene: &'a Scene,
+  camera: &CameraInstance,
+  light_path: &[Vertex<'a>],
+  camera_path: &[Vertex<'a>],
+  s: usize,
+  t: usize,
+  sampler: &mut impl Sampler,
+  raster: &mut Point2,
+) -> Spectrum {
+  // Paths that escaped the scene can only be lit by the background itself
+  if t > 1 && s != 0 && camera_path[t - 1].kind == VertexKind::Escaped {
+    return Spectrum::default();
+  }
+
+  let mut sampled = None;
+  let mut result = Spectrum::default();
+  if s == 0 {
+    // Use the camera path as is, if it happened to hit a light
+    let pt = &camera_path[t - 1];
+    if pt.is_light() {
+      result = pt.emitted_radiance(scene, &camera_path[t - 2]) * pt.beta;
+    }
+  } else if t == 1 {
+    // Connect the light path straight to the camera
+    if let Some((l, point, vertex)) = connect_to_camera(scene, camera, &light_path[s - 1], sampler) {
+      result = l;
+      *raster = point;
+      sampled = Some(vertex);
+    }
+  } else if s == 1 {
+    // Pick a fresh point on a light, rather than the start of the light path, since we can choose one that's better for this vertex
+    let pt = &camera_path[t - 1];
+    // Light paths start from lights chosen without regard to where they are, so choose this one the same way
+    // to keep the densities the MIS weights compare consistent
+    let choice = if pt.is_connectible() { scene.light_sampler.sample_any(sampler.get_1d()) } else { None };
+    if let Some((index, choice_pdf)) = choice {
+      let light = &scene.lights[index];
+      let sample = light.sample_radiance(&pt.intersection, sampler.get_2d());
+      if sample.probability_distribution > 0. && !sample.color.is_black() {
+        let beta = sample.color / (sample.probability_distribution * choice_pdf);
+        let mut vertex = Vertex::light(light, sample.intersections.1, beta, 0.);
+        vertex.pdf_forward = vertex.light_origin_probability(scene, pt);
+        result = pt.beta * pt.scattering(&vertex, TransportMode::Radiance) * vertex.beta;
+        if pt.on_surface() {
+          result = result * sample.incident_direction.dot(pt.intersection.shading_normal.into()).abs();
+        }
+        if !result.is_black() && !unoccluded(scene, &pt.intersection, &vertex.intersection) {
+          result = Spectrum::default();
+        }
+        sampled = Some(vertex);
+      }
+    }
+  } else {
+    // Join the ends of both paths
+    let (qs, pt) = (&light_path[s - 1], &camera_path[t - 1]);
+    if qs.is_connectible() && pt.is_connectible() {
+      result = qs.beta * qs.scattering(pt, TransportMode::Importance) * pt.scattering(qs, TransportMode::Radiance) * pt.beta;
+      if !result.is_black() {
+        result = result * geometry_term(scene, qs, pt);
+      }
+    }
+  }
+
+  if result.is_black() {
+    return result;
+  }
+  result * mis_weight(scene, camera, light_path, camera_path, sampled, s, t)
+}
+
+/// Weight a path by how likely this strategy was to find it, relative to every other way of splitting it
+/// into a light and camera path (the balance heuristic)
+fn mis_weight<'a>(
+  scene: &'a Scene,
+  camera: &CameraInstance,
+  light_path: &[Vertex<'a>],
+  camera_path: &[Vertex<'a>],
+  sampled: Option<Vertex<'a>>,
+  s: usize,
+  t: usize,
+) -> f64 {
+  if s + t == 2 {
+    return 1.;
+  }
+  // Deltas contribute no density, but shouldn't zero out the ratios
+  let remap = |f: f64| if f != 0. { f } else { 1. };
+
+  // Work on copies of the paths, with the endpoint swapped for whichever vertex we sampled for this strategy
+  let mut lights = light_path[..s].to_vec();
+  let mut cameras = camera_path[..t].to_vec();
+  if let Some(sampled) = sampled {
+    if s == 1 {
+      lights[0] = sampled;
+    } else if t == 1 {
+      cameras[0] = sampled;
+    }
+  }
+
+  // The vertices we connected were connectible, however they were sampled
+  if t > 0 {
+    cameras[t - 1].delta = false;
+  }
+  if s > 0 {
+    lights[s - 1].delta = false;
+  }
+
+  // The reverse densities at the connection depend on the strategy, so recompute them
+  let qs = if s > 0 { Some(lights[s - 1]) } else { None };
+  let pt = if t > 0 { Some(cameras[t - 1]) } else { None };
+  let qs_minus = if s > 1 { Some(lights[s - 2]) } else { None };
+  let pt_minus = if t > 1 { Some(cameras[t - 2]) } else { None };
+  if let Some(pt) = &pt {
+    cameras[t - 1].pdf_reverse = match (&qs, &pt_minus) {
+      (Some(qs), _) => qs.probability(scene, camera, qs_minus.as_ref(), pt),
+      (None, Some(pt_minus)) => pt.light_origin_probability(scene, pt_minus),
+      (None, None) => 0.,
+    };
+  }
+  if let (Some(pt), Some(pt_minus)) = (&pt, &pt_minus) {
+    cameras[t - 2].pdf_reverse = match &qs {
+      Some(qs) => pt.probability(scene, camera, Some(qs), pt_minus),
+      None => pt.light_probability(scene, pt_minus),
+    };
+  }
+  if let (Some(qs), Some(pt)) = (&qs, &pt) {
+    lights[s - 1].pdf_reverse = pt.probability(scene, camera, pt_minus.as_ref(), qs);
+  }
+  if let (Some(qs), Some(qs_minus)) = (&qs, &qs_minus) {
+    lights[s - 2].pdf_reverse = qs.probability(scene, camera, pt.as_ref(), qs_minus);
+  }
+
+  // Sum the ratios of every other strategy's density to ours, walking outwards along each path
+  let mut sum = 0.;
+  let mut ratio = 1.;
+  for i in (1..t).rev() {
+    ratio *= remap(cameras[i].pdf_reverse) / remap(cameras[i].pdf_forward);
+    if !cameras[i].delta && !cameras[i - 1].delta {
+      sum += ratio;
+    }
+  }
+  let mut ratio = 1.;
+  for i in (0..s).rev() {
+    ratio *= remap(lights[i].pdf_reverse) / remap(lights[i].pdf_forward);
+    let delta_light = if i > 0 { lights[i - 1].delta } else { lights[0].is_delta_light() };
+    if !lights[i].delta && !delta_light {
+      sum += ratio;
+    }
+  }
+  1. / (1. + sum)
+}
+
+/// Bidirectional path tracing: traces one path from the camera and one from a light for each sample,
+/// and connects every prefix of one to every prefix of the other
+pub struct BDPTIntegrator {
+  pub max_depth: u32,
+  pub camera: Arc<CameraInstance>,
+  pub sampler: SamplerInstance,
+  pub settings: RenderSettings,
+}
+
+impl BDPTIntegrator {
+  pub fn new(max_depth: u32, camera: CameraInstance, sampler: SamplerInstance, settings: RenderSettings) -> Self {
+    Self { max_depth, camera: Arc::new(camera), sampler, settings }
+  }
+}
+
+impl SamplerIntegrator for BDPTIntegrator {
+  fn preprocess(&mut self, _scene: &Scene) {
+  }
+
+  fn light_along_ray(&self, rd: RayDifferential, scene: &Scene, sampler: &mut SamplerInstance, arena: &Bump, _depth: u32) -> Spectrum {
+    let camera = &*self.camera;
+    let settings = &self.settings;
+
+    let mut camera_path = Vec::with_capacity(self.max_depth as usize + 2);
+    let mut light_path = Vec::with_capacity(self.max_depth as usize + 1);
+    camera_subpath(scene, camera, &rd.ray, sampler, arena, self.max_depth + 2, &mut camera_path);
+    light_subpath(scene, sampler, arena, self.max_depth + 1, &mut light_path);
+
+    // Light connected straight to the camera lands on some other pixel, so it's splatted instead,
+    // and the film averages it out over every sample taken
+    let film = camera.film();
+
+    let mut result = Spectrum::default();
+    for t in 1..=camera_path.len() {
+      for s in 0..=light_path.len() {
+        let depth = (s + t) as i64 - 2;
+        if (s == 1 && t == 1) || depth < 0 || depth > self.max_depth as i64 {
+          continue;
+        }
+
+        let mut raster = Point2::default();
+        let l = connect_subpaths(scene, camera, &light_path, &camera_path, s, t, sampler, &mut raster);
+        let l = if depth <= 1 { settings.clamp_direct(l) } else { settings.clamp_indirect(l) };
+        if t == 1 {
+          if !l.is_black() && l.is_valid().is_none() {
+            film.add_splat(raster, l);
+          }
+        } else {
+          result += l;
+        }
+      }
+    }
+    result
+  }
+
+  fn get_camera(&mut self) -> Arc<CameraInstance> { self.camera.clone() }
+  fn get_sampler(&self, _: u64) -> SamplerInstance { self.sampler.clone() }
+  fn get_settings(&self) -> &RenderSettings { &self.settings }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::geometry::{Bounds2, Transform};
+  use crate::render::{Film, LambertianReflection, PerspectiveCamera};
+  use crate::scene::{NullPrimitive, PointLight};
+
+  /// A diffuse surface vertex at `point`, seen from `from`
+  fn surface<'a>(arena: &'a Bump, point: Point3, normal: Normal3, tangent: Normal3, from: Point3) -> Vertex<'a> {
+    let intersection = Intersection {
+      point, normal, shading_normal: normal,
+      shading_normal_derivative: (tangent, Normal3::default()),
+      outgoing: direction_between(point, from),
+      ..Default::default()
+    };
+    let bsdf = BSDF::new(arena, &intersection, 1.);
+    bsdf.add_component(arena.alloc(LambertianReflection { color: Spectrum::greyscale(0.5) }.into()));
+    Vertex { bsdf: Some(&*bsdf), ..Vertex::new(VertexKind::Surface, intersection, Spectrum::white()) }
+  }
+
+  #[test]
+  fn mis_weights_sum_to_one() {
+    // The camera at the origin sees a wall ahead, lit by a point light via a second wall off to the side
+    let scene = Scene::new(NullPrimitive {}.into(), vec![PointLight { position: Point3::new(-1., 0., 1.), color: Spectrum::white() }.into()]);
+    let film = Arc::new(Film::new(Point2::new(16, 16)));
+    let camera: CameraInstance = PerspectiveCamera::new(
+      Transform::default(), Bounds2 { min: Point2::new(-1., -1.), max: Point2::new(1., 1.) }, 0., 0., 75., film,
+    ).into();
+    let arena = Bump::new();
+    let light = &scene.lights[0];
+    let (eye, first, second, light_point) = (Point3::default(), Point3::new(0., 0., 4.), Point3::new(1., 0., 2.), Point3::new(-1., 0., 1.));
+    let (first_normal, first_tangent) = (Normal3::new(0., 0., -1.), Normal3::new(1., 0., 0.));
+    let (second_normal, second_tangent) = (Normal3::new(-1., 0., 0.), Normal3::new(0., 1., 0.));
+
+    // The camera path, with the densities random_walk would have left on it
+    let mut camera_path = vec![
+      Vertex::camera(endpoint(eye, Normal3::default()), Spectrum::white()),
+      surface(&arena, first, first_normal, first_tangent, eye),
+      surface(&arena, second, second_normal, second_tangent, first),
+    ];
+    camera_path[1].pdf_forward = camera_path[0].probability(&scene, &camera, None, &camera_path[1]);
+    camera_path[2].pdf_forward = camera_path[1].probability(&scene, &camera, Some(&camera_path[0]), &camera_path[2]);
+    camera_path[0].pdf_reverse = camera_path[1].probability(&scene, &camera, Some(&camera_path[2]), &camera_path[0]);
+
+    // The same vertices traced the other way, from the light
+    let choice_pdf = scene.light_sampler.probability_any(0);
+    let mut light_path = vec![
+      Vertex::light(light, endpoint(light_point, direction_between(light_point, second).into()), Spectrum::white(), choice_pdf),
+      surface(&arena, second, second_normal, second_tangent, light_point),
+      surface(&arena, first, first_normal, first_tangent, second),
+    ];
+    light_path[1].pdf_forward = light_path[0].light_probability(&scene, &light_path[1]);
+    light_path[2].pdf_forward = light_path[1].probability(&scene, &camera, Some(&light_path[0]), &light_path[2]);
+    light_path[0].pdf_reverse = light_path[1].probability(&scene, &camera, Some(&light_path[2]), &light_path[0]);
+
+    // The vertices connect_subpaths samples afresh when one of the paths is a single vertex
+    let mut sampled_light = Vertex::light(light, endpoint(light_point, Normal3::default()), Spectrum::white(), 0.);
+    sampled_light.pdf_forward = sampled_light.light_origin_probability(&scene, &camera_path[2]);
+    let lens = camera.sample_importance(&light_path[2].intersection, Point2::new(0.5, 0.5)).lens_intersection;
+    let sampled_camera = Vertex::camera(lens, Spectrum::white());
+
+    // A point light can't be hit by chance, so (s, t) = (0, 4) never finds the path, and the rest share it
+    let weights = [
+      mis_weight(&scene, &camera, &light_path, &camera_path, Some(sampled_light), 1, 3),
+      mis_weight(&scene, &camera, &light_path, &camera_path, None, 2, 2),
+      mis_weight(&scene, &camera, &light_path, &camera_path, Some(sampled_camera), 3, 1),
+    ];
+    for weight in &weights {
+      assert!(*weight > 0. && *weight < 1., "weight {} should be strictly between 0 and 1", weight);
+    }
+    let total: f64 = weights.iter().sum();
+    assert!((total - 1.).abs() < 1e-9, "weights {:?} sum to {}", weights, total);
+  }
+}
@@ -0,0 +1,102 @@
+use std::sync::Arc;
+
+use bumpalo::Bump;
+
+use crate::{geometry::{Interaction, Normal3, Ray, RayDifferential}, scene::Scene};
+use crate::render::{CameraInstance, RenderSettings, SamplerInstance, SamplerIntegrator, Spectrum};
+
+/// Which property of the scene the debug integrator shows
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DebugView {
+  /// Something about the first surface each camera ray hits
+  Surface(SurfaceProperty),
+  /// How many BVH nodes each camera ray visits, from blue for none to red for `max_nodes` or more
+  BVHNodes { max_nodes: u32 },
+}
+
+/// The properties of a surface the debug integrator can show
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SurfaceProperty {
+  ShadingNormals,
+  GeometricNormals,
+  UV,
+  /// Distance from the camera, with white nearest
+  Depth,
+  /// A different color for each material, and magenta for surfaces without one
+  MaterialID,
+}
+
+/// Visualizes the geometry of a scene directly, rather than the light in it, for tracking down bugs in
+/// shapes, normals and the acceleration structure
+pub struct DebugIntegrator {
+  pub view: DebugView,
+  pub camera: Arc<CameraInstance>,
+  pub sampler: SamplerInstance,
+  pub settings: RenderSettings,
+}
+
+impl DebugIntegrator {
+  pub fn new(view: DebugView, camera: CameraInstance, sampler: SamplerInstance, settings: RenderSettings) -> Self {
+    Self { view, camera: Arc::new(camera), sampler, settings }
+  }
+}
+
+impl SamplerIntegrator for DebugIntegrator {
+  fn preprocess(&mut self, _scene: &Scene) {
+  }
+
+  fn light_along_ray(&self, rd: RayDifferential, scene: &Scene, _sampler: &mut SamplerInstance, _arena: &Bump, _depth: u32) -> Spectrum {
+    match self.view {
+      DebugView::Surface(property) => match scene.intersect(&rd.ray) {
+        Some(interaction) => surface_color(property, &interaction, scene, &rd.ray),
+        None => Spectrum::default(),
+      },
+      DebugView::BVHNodes { max_nodes } => heatmap(scene.nodes_visited(&rd.ray) as f64 / max_nodes.max(1) as f64),
+    }
+  }
+
+  fn get_camera(&mut self) -> Arc<CameraInstance> { self.camera.clone() }
+  fn get_sampler(&self, _: u64) -> SamplerInstance { self.sampler.clone() }
+  fn get_settings(&self) -> &RenderSettings { &self.settings }
+}
+
+/// The color showing a property of the surface a ray hit
+fn surface_color(property: SurfaceProperty, interaction: &Interaction, scene: &Scene, ray: &Ray) -> Spectrum {
+  let intersection = &interaction.intersection;
+  match property {
+    SurfaceProperty::ShadingNormals => normal_color(intersection.shading_normal),
+    SurfaceProperty::GeometricNormals => normal_color(intersection.normal),
+    SurfaceProperty::UV => Spectrum { r: intersection.uv.x, g: intersection.uv.y, b: 0. },
+    SurfaceProperty::Depth => {
+      let extent = (scene.world_bounds.max - scene.world_bounds.min).length();
+      let depth = (intersection.point - ray.origin).length();
+      Spectrum::greyscale((1. - depth / extent).max(0.))
+    },
+    SurfaceProperty::MaterialID => match &interaction.material {
+      Some(material) => id_color(material.id()),
+      None => Spectrum { r: 1., g: 0., b: 1. },
+    },
+  }
+}
+
+/// A color picked from the bits of an id, kept away from black so every material shows up
+fn id_color(id: u64) -> Spectrum {
+  let channel = |shift: u32| 0.2 + 0.7 * ((id >> shift) & 0xff) as f64 / 255.;
+  Spectrum { r: channel(0), g: channel(8), b: channel(16) }
+}
+
+/// Map each component of a unit normal from [-1, 1] to [0, 1]
+fn normal_color(normal: Normal3) -> Spectrum {
+  Spectrum { r: (normal.x + 1.) / 2., g: (normal.y + 1.) / 2., b: (normal.z + 1.) / 2. }
+}
+
+/// Blue at 0, through cyan, green and yellow, to red at 1 and above
+fn heatmap(t: f64) -> Spectrum {
+  let t = t.clamp(0., 1.) * 4.;
+  match t as u32 {
+    0 => Spectrum { r: 0., g: t, b: 1. },
+    1 => Spectrum { r: 0., g: 1., b: 2. - t },
+    2 => Spectrum { r: t - 2., g: 1., b: 0. },
+    _ => Spectrum { r: 1., g: (4. - t).max(0.), b: 0. },
+  }
+}
@@ -0,0 +1,80 @@
+use std::sync::Arc;
+
+use bumpalo::Bump;
+
+use crate::{geometry::RayDifferential, scene::{Light, Scene, TransportMode}};
+use crate::render::{CameraInstance, RenderSettings, SamplerInstance, SamplerIntegrator, Spectrum, uniform_sample_all_lights, sample_one_light};
+
+/// How the direct lighting integrator divides its samples between the lights
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LightStrategy {
+  /// Take the same number of samples from every light at each point
+  UniformAll,
+  /// Take a single sample from one light at each point, chosen by the scene's light sampler
+  SampleOne,
+}
+
+/// Only accounts for light arriving straight from the lights, plus perfect reflection and refraction,
+/// so it's quick enough to preview a scene with before rendering it properly
+pub struct DirectLightingIntegrator {
+  pub strategy: LightStrategy,
+  pub max_depth: u32,
+  /// Samples taken from each light at each point, with `LightStrategy::UniformAll`
+  pub light_samples: usize,
+  pub camera: Arc<CameraInstance>,
+  pub sampler: SamplerInstance,
+  pub settings: RenderSettings,
+}
+
+impl DirectLightingIntegrator {
+  pub fn new(strategy: LightStrategy, max_depth: u32, light_samples: usize, camera: CameraInstance, sampler: SamplerInstance, settings: RenderSettings) -> Self {
+    Self { strategy, max_depth, light_samples, camera: Arc::new(camera), sampler, settings }
+  }
+}
+
+impl SamplerIntegrator for DirectLightingIntegrator {
+  fn preprocess(&mut self, _scene: &Scene) {
+  }
+
+  fn light_along_ray(&self, rd: RayDifferential, scene: &Scene, sampler: &mut SamplerInstance, arena: &Bump, depth: u32) -> Spectrum {
+    let mut result = Spectrum::default();
+    let settings = &self.settings;
+
+    let interaction = match scene.intersect(&rd.ray) {
+      Some(interaction) => interaction,
+      None => {
+        for light in &scene.lights {
+          result += light.background_radiance(&rd.ray);
+        }
+        return if depth == 0 { settings.clamp_direct(result) } else { result };
+      },
+    };
+    result += interaction.emitted_radiance();
+
+    let bsdf = match interaction.compute_scattering_functions(arena, TransportMode::Radiance, false) {
+      Some(bsdf) => bsdf,
+      None => return if depth == 0 { settings.clamp_direct(result) } else { result },
+    };
+
+    let intersection = &interaction.intersection;
+    result += match self.strategy {
+      LightStrategy::UniformAll => uniform_sample_all_lights(scene, intersection, bsdf, sampler, self.light_samples),
+      LightStrategy::SampleOne => sample_one_light(scene, intersection, bsdf, sampler),
+    };
+
+    let mut indirect = Spectrum::default();
+    if depth + 1 < self.max_depth {
+      indirect += self.specular_reflect(rd, interaction.intersection, bsdf, scene, sampler, arena, depth);
+      indirect += self.specular_transmit(rd, interaction.intersection, bsdf, scene, sampler, arena, depth);
+    }
+
+    if depth == 0 {
+      return settings.clamp_direct(result) + settings.clamp_indirect(indirect);
+    }
+    result + indirect
+  }
+
+  fn get_camera(&mut self) -> Arc<CameraInstance> { self.camera.clone() }
+  fn get_sampler(&self, _: u64) -> SamplerInstance { self.sampler.clone() }
+  fn get_settings(&self) -> &RenderSettings { &self.settings }
+}
@@ -0,0 +1,449 @@
+use std::{f64::consts::PI, sync::Arc, time::Instant};
+
+use bumpalo::Bump;
+
+use crate::{geometry::{Bounds3, Point2, Point3, Ray, Vector3}, scene::{Light, Scene, TransportMode}};
+use crate::render::{BxDFCategory, Camera, CameraInstance, Integrator, RenderSettings, Sampler, SamplerInstance, Spectrum, sample_one_light};
+
+/// How deep a directional quadtree can get, which bounds how narrow a beam of light it can single out
+const MAX_DIRECTIONAL_DEPTH: u32 = 20;
+
+/// Map a direction onto the unit square, preserving area, so a uniform density on the square is uniform over the sphere
+fn direction_to_square(direction: Vector3) -> Point2 {
+  let cos_theta = direction.z.clamp(-1., 1.);
+  let mut phi = direction.y.atan2(direction.x);
+  if phi < 0. {
+    phi += 2. * PI;
+  }
+  Point2::new((cos_theta + 1.) / 2., phi / (2. * PI))
+}
+
+fn square_to_direction(point: Point2) -> Vector3 {
+  let cos_theta = 2. * point.x - 1.;
+  let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+  let phi = 2. * PI * point.y;
+  Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+/// Which quadrant of the unit square a point is in, and where it is within that quadrant, scaled back up to the unit square
+fn quadrant(point: Point2) -> (usize, Point2) {
+  let (x, y) = ((point.x >= 0.5) as usize, (point.y >= 0.5) as usize);
+  (x + 2 * y, Point2::new(point.x * 2. - x as f64, point.y * 2. - y as f64))
+}
+
+#[derive(Clone, Copy, Default)]
+struct QuadNode {
+  /// The light recorded through each quadrant, ordered with x varying fastest
+  energy: [f64; 4],
+  /// The node subdividing each quadrant, or 0 if it's a leaf
+  children: [usize; 4],
+}
+
+/// A distribution over directions, as a quadtree over the square they map onto that's finest where the most light arrives from
+#[derive(Clone)]
+struct DirectionalTree {
+  nodes: Vec<QuadNode>,
+}
+
+impl DirectionalTree {
+  fn new() -> Self {
+    Self { nodes: vec![QuadNode::default()] }
+  }
+
+  fn total(&self) -> f64 {
+    self.nodes[0].energy.iter().sum()
+  }
+
+  fn record(&mut self, direction: Vector3, value: f64) {
+    let (mut node, mut point) = (0, direction_to_square(direction));
+    loop {
+      let (q, within) = quadrant(point);
+      self.nodes[node].energy[q] += value;
+      match self.nodes[node].children[q] {
+        0 => return,
+        child => { node = child; point = within; },
+      }
+    }
+  }
+
+  /// The density, over the sphere, with which `sample` chooses the direction
+  fn probability(&self, direction: Vector3) -> f64 {
+    if self.total() <= 0. {
+      return 1. / (4. * PI);
+    }
+    let (mut node, mut point, mut pdf) = (0, direction_to_square(direction), 1.);
+    loop {
+      let n = &self.nodes[node];
+      let (q, within) = quadrant(point);
+      let sum: f64 = n.energy.iter().sum();
+      if sum <= 0. {
+        return 0.;
+      }
+      pdf *= 4. * n.energy[q] / sum;
+      match n.children[q] {
+        0 => return pdf / (4. * PI),
+        child => { node = child; point = within; },
+      }
+    }
+  }
+
+  /// Choose a direction in proportion to the light recorded from it
+  fn sample(&self, u: Point2) -> Vector3 {
+    if self.total() <= 0. {
+      return square_to_direction(u);
+    }
+    let (mut node, mut u) = (0, u);
+    let (mut origin, mut size) = (Point2::new(0., 0.), 1.);
+    loop {
+      let energy = self.nodes[node].energy;
+      // Pick the left or right half, and then the top or bottom quadrant within it, reusing what's left of each sample
+      let left = (energy[0] + energy[2]) / energy.iter().sum::<f64>();
+      let x = if u.x < left { u.x /= left; 0 } else { u.x = (u.x - left) / (1. - left); 1 };
+      let bottom = energy[x] / (energy[x] + energy[x + 2]);
+      let y = if u.y < bottom { u.y /= bottom; 0 } else { u.y = (u.y - bottom) / (1. - bottom); 1 };
+
+      size /= 2.;
+      origin = Point2::new(origin.x + x as f64 * size, origin.y + y as f64 * size);
+      match self.nodes[node].children[x + 2 * y] {
+        0 => return square_to_direction(Point2::new(origin.x + u.x.min(1.) * size, origin.y + u.y.min(1.) * size)),
+        child => node = child,
+      }
+    }
+  }
+
+  /// An empty tree to record into next, subdivided wherever this one saw more than `threshold` of its light
+  fn refined(&self, threshold: f64) -> Self {
+    let total = self.total();
+    let mut tree = DirectionalTree::new();
+    if total > 0. {
+      self.refine_node(Some(0), self.nodes[0].energy, 1, total * threshold, &mut tree, 0);
+    }
+    tree
+  }
+
+  fn refine_node(&self, old: Option<usize>, energy: [f64; 4], depth: u32, threshold: f64, tree: &mut Self, new: usize) {
+    for q in 0..4 {
+      if depth >= MAX_DIRECTIONAL_DEPTH || energy[q] <= threshold {
+        continue;
+      }
+      // Quadrants that weren't subdivided before split their energy evenly between their children
+      let old_child = old.map(|node| self.nodes[node].children[q]).filter(|&child| child != 0);
+      let child_energy = match old_child {
+        Some(child) => self.nodes[child].energy,
+        None => [energy[q] / 4.; 4],
+      };
+      let index = tree.nodes.len();
+      tree.nodes.push(QuadNode::default());
+      tree.nodes[new].children[q] = index;
+      self.refine_node(old_child, child_energy, depth + 1, threshold, tree, index);
+    }
+  }
+}
+
+struct SpatialNode {
+  /// The axis this node is split along, if it has children
+  axis: usize,
+  /// The two halves of the node, or None if it's a leaf
+  children: Option<[usize; 2]>,
+  /// Where light arrived from in this region last iteration, which guides sampling this iteration
+  sampling: DirectionalTree,
+  /// Where light arrives from in this region this iteration
+  building: DirectionalTree,
+  /// How many samples were recorded in the region this iteration
+  samples: u64,
+}
+
+/// A binary tree over the scene, splitting each region in half along alternating axes,
+/// with a directional tree in each leaf
+struct SpatialTree {
+  bounds: Bounds3,
+  nodes: Vec<SpatialNode>,
+}
+
+impl SpatialTree {
+  fn new(bounds: Bounds3) -> Self {
+    let root = SpatialNode { axis: 0, children: None, sampling: DirectionalTree::new(), building: DirectionalTree::new(), samples: 0 };
+    Self { bounds, nodes: vec![root] }
+  }
+
+  /// The leaf whose region holds the point
+  fn leaf(&self, point: Point3) -> usize {
+    let (min, max) = (self.bounds.min, self.bounds.max);
+    let mut p = [
+      (point.x - min.x) / (max.x - min.x).max(f64::EPSILON),
+      (point.y - min.y) / (max.y - min.y).max(f64::EPSILON),
+      (point.z - min.z) / (max.z - min.z).max(f64::EPSILON),
+    ];
+    let mut node = 0;
+    while let Some(children) = self.nodes[node].children {
+      let axis = self.nodes[node].axis;
+      if p[axis] < 0.5 {
+        p[axis] *= 2.;
+        node = children[0];
+      } else {
+        p[axis] = p[axis] * 2. - 1.;
+        node = children[1];
+      }
+    }
+    node
+  }
+
+  fn record(&mut self, point: Point3, direction: Vector3, value: f64) {
+    let leaf = self.leaf(point);
+    let node = &mut self.nodes[leaf];
+    node.building.record(direction, value);
+    node.samples += 1;
+  }
+
+  /// Split every leaf that saw more than `spatial_threshold` samples, then switch every leaf over to sampling from
+  /// what it just recorded and start recording afresh
+  fn refine(&mut self, spatial_threshold: f64, directional_threshold: f64) {
+    let mut stack = vec![0];
+    while let Some(node) = stack.pop() {
+      if let Some(children) = self.nodes[node].children {
+        stack.extend_from_slice(&children);
+        continue;
+      }
+      if (self.nodes[node].samples as f64) <= spatial_threshold {
+        continue;
+      }
+
+      // Each half starts out with a copy of everything its parent learned
+      let parent = &mut self.nodes[node];
+      let axis = (parent.axis + 1) % 3;
+      let building = std::mem::replace(&mut parent.building, DirectionalTree::new());
+      let samples = parent.samples / 2;
+      let first = self.nodes.len();
+      for _ in 0..2 {
+        self.nodes.push(SpatialNode { axis, children: None, sampling: DirectionalTree::new(), building: building.clone(), samples });
+      }
+      self.nodes[node].children = Some([first, first + 1]);
+      self.nodes[node].sampling = DirectionalTree::new();
+      stack.extend_from_slice(&[first, first + 1]);
+    }
+
+    for node in self.nodes.iter_mut().filter(|node| node.children.is_none()) {
+      node.sampling = std::mem::replace(&mut node.building, DirectionalTree::new());
+      node.building = node.sampling.refined(directional_threshold);
+      node.samples = 0;
+    }
+  }
+}
+
+/// A path vertex whose next direction was chosen with guiding, waiting to find out how much light came back along it
+struct GuidedVertex {
+  point: Point3,
+  direction: Vector3,
+  probability: f64,
+  /// The luminance of the path throughput after scattering at the vertex
+  throughput: f64,
+  /// The luminance of the light found further along the path
+  radiance: f64,
+}
+
+/// A path tracer that learns where light comes from as it goes, following "Practical Path Guiding for Efficient
+/// Light-Transport Simulation" by Müller et al
+///
+/// The samples are taken in passes that double in size, and each trains a tree of directional distributions over
+/// the scene that the next pass samples from, combined with the BSDF using one-sample MIS.  Only the last pass,
+/// which takes whatever's left of the samples, goes on the film.  It's worth the training time in scenes lit
+/// through small openings, which are found only rarely by sampling the BSDF alone.  Media are ignored.
+pub struct GuidedPathIntegrator {
+  pub max_depth: u32,
+  /// How often to sample the BSDF rather than the learned distribution
+  pub bsdf_fraction: f64,
+  /// Regions are split in two once they see this many samples in an iteration, scaled by the square root
+  /// of the iteration's samples per pixel
+  pub spatial_threshold: f64,
+  /// Directional quadrants are subdivided once they receive more than this fraction of the light
+  pub directional_threshold: f64,
+  pub camera: Arc<CameraInstance>,
+  pub sampler: SamplerInstance,
+  pub settings: RenderSettings,
+}
+
+impl GuidedPathIntegrator {
+  pub fn new(max_depth: u32, camera: CameraInstance, sampler: SamplerInstance, settings: RenderSettings) -> Self {
+    Self {
+      max_depth,
+      bsdf_fraction: 0.5,
+      spatial_threshold: 12000.,
+      directional_threshold: 0.01,
+      camera: Arc::new(camera),
+      sampler,
+      settings,
+    }
+  }
+
+  /// Trace a path from the camera, guided by `tree`, adding a record to `records` for each guided bounce
+  fn light_along_ray(
+    &self,
+    ray: Ray,
+    scene: &Scene,
+    tree: &SpatialTree,
+    sampler: &mut SamplerInstance,
+    arena: &Bump,
+    records: &mut Vec<GuidedVertex>,
+  ) -> Spectrum {
+    let settings = &self.settings;
+    let (mut direct, mut indirect) = (Spectrum::default(), Spectrum::default());
+    let mut ray = ray;
+    let mut beta = Spectrum::white();
+    let mut specular_bounce = false;
+    let mut bounces = 0;
+    let first_record = records.len();
+
+    // Credit light found along the path to every guided vertex before it
+    let deposit = |l: &mut Spectrum, records: &mut Vec<GuidedVertex>, value: Spectrum| {
+      *l += value;
+      let luminance = value.luminance();
+      for vertex in &mut records[first_record..] {
+        if vertex.throughput > 0. {
+          vertex.radiance += luminance / vertex.throughput;
+        }
+      }
+    };
+
+    loop {
+      let l = if bounces == 0 { &mut direct } else { &mut indirect };
+      let interaction = match scene.intersect(&ray) {
+        Some(interaction) => interaction,
+        None => {
+          if bounces == 0 || specular_bounce {
+            for light in &scene.lights {
+              deposit(l, records, beta * light.background_radiance(&ray));
+            }
+          }
+          break;
+        },
+      };
+      // Emission further along the path is already counted by sampling the lights
+      if bounces == 0 || specular_bounce {
+        deposit(l, records, beta * interaction.emitted_radiance());
+      }
+      if bounces >= self.max_depth {
+        break;
+      }
+
+      let intersection = &interaction.intersection;
+      let bsdf = match interaction.compute_scattering_functions(arena, TransportMode::Radiance, true) {
+        Some(bsdf) => bsdf,
+        None => break,
+      };
+      deposit(l, records, beta * sample_one_light(scene, intersection, bsdf, sampler));
+
+      // Only guide off surfaces without any specular lobes, which a learned distribution could never hit
+      let outgoing = intersection.outgoing;
+      let components = bsdf.num_components(BxDFCategory::ALL);
+      let distribution = &tree.nodes[tree.leaf(intersection.point)].sampling;
+      let guided = components > 0 && bsdf.num_components(BxDFCategory::ALL - BxDFCategory::SPECULAR) == components && distribution.total() > 0.;
+
+      let (incoming, value, pdf) = if guided {
+        let (choice, u) = (sampler.get_1d(), sampler.get_2d());
+        let incoming = if choice < self.bsdf_fraction {
+          bsdf.sample_function(outgoing, &u, BxDFCategory::ALL).incoming
+        } else {
+          distribution.sample(u)
+        };
+        let pdf = self.bsdf_fraction * bsdf.probability_distribution(outgoing, incoming, BxDFCategory::ALL) +
+          (1. - self.bsdf_fraction) * distribution.probability(incoming);
+        (incoming, bsdf.evaluate(outgoing, incoming, BxDFCategory::ALL), pdf)
+      } else {
+        let sample = bsdf.sample_function(outgoing, &sampler.get_2d(), BxDFCategory::ALL);
+        specular_bounce = sample.category.contains(BxDFCategory::SPECULAR);
+        (sample.incoming, sample.value, sample.probability_distribution)
+      };
+      if value.is_black() || pdf == 0. {
+        break;
+      }
+      beta = beta * value * incoming.dot(intersection.shading_normal.into()).abs() / pdf;
+      if guided {
+        specular_bounce = false;
+        records.push(GuidedVertex { point: intersection.point, direction: incoming, probability: pdf, throughput: beta.luminance(), radiance: 0. });
+      }
+      ray = intersection.spawn_ray(incoming);
+
+      // Russian roulette, once the path has had a chance to pick up the bulk of its light
+      if bounces > 3 {
+        let max = beta.max_component();
+        if max < 1. {
+          let termination_probability = (1. - max).max(0.05);
+          if sampler.get_1d() < termination_probability {
+            break;
+          }
+          beta = beta / (1. - termination_probability);
+        }
+      }
+      bounces += 1;
+    }
+
+    settings.clamp_direct(direct) + settings.clamp_indirect(indirect)
+  }
+}
+
+impl Integrator for GuidedPathIntegrator {
+  fn render(&mut self, scene: &Scene) {
+    let start = Instant::now();
+    let settings = &self.settings;
+    let camera = self.camera.clone();
+    let film = camera.film();
+    let mut sampler = self.sampler.clone();
+    let total = sampler.samples_per_pixel().max(1);
+
+    let mut tree = SpatialTree::new(scene.world_bounds);
+    let mut records = vec![];
+    let mut arena = Bump::new();
+    let mut last_snapshot = Instant::now();
+
+    let (mut pass_start, mut pass_size) = (0, 1);
+    loop {
+      // Once there wouldn't be enough samples left for a pass twice the size, spend all of them on the final image
+      let last = pass_start + 3 * pass_size > total;
+      let pass = pass_start..if last { total } else { pass_start + pass_size };
+
+      for pixel in camera.bounds() {
+        sampler.start_pixel(&pixel);
+        for sample in pass.clone() {
+          if !sampler.set_sample_number(sample) {
+            break;
+          }
+          let (weight, ray) = camera.generate_ray(&sampler.get_camera_sample(pixel));
+          let l = if weight > 0. { self.light_along_ray(ray, scene, &tree, &mut sampler, &arena, &mut records) } else { Spectrum::default() };
+          arena.reset();
+
+          if last {
+            film.add_sample(pixel, if l.is_valid().is_none() { l } else { Spectrum::default() }, weight);
+          } else {
+            for vertex in records.drain(..) {
+              tree.record(vertex.point, vertex.direction, vertex.radiance / vertex.probability);
+            }
+          }
+          records.clear();
+        }
+
+        if last {
+          if let (Some(snapshot), Some(interval)) = (&settings.snapshot, settings.snapshot_interval) {
+            if last_snapshot.elapsed() >= interval {
+              film.write_to(snapshot.clone());
+              last_snapshot = Instant::now();
+            }
+          }
+          if let Some(time_limit) = settings.time_limit {
+            if start.elapsed() >= time_limit {
+              println!("Reached the time limit of {:.2}s, stopping early.", time_limit.as_secs_f64());
+              return;
+            }
+          }
+        }
+      }
+
+      if last {
+        return;
+      }
+      tree.refine(self.spatial_threshold * (pass_size as f64).sqrt(), self.directional_threshold);
+      println!("Trained on {} samples per pixel in {:.2}s", pass.end, start.elapsed().as_secs_f64());
+      pass_start = pass.end;
+      pass_size *= 2;
+    }
+  }
+}
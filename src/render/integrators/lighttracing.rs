@@ -0,0 +1,78 @@
+use std::sync::Arc;
+
+use bumpalo::Bump;
+
+use crate::{geometry::{Point2, RayDifferential}, scene::{LightInstance, Scene}};
+use crate::render::{Camera, CameraInstance, RenderSettings, SamplerInstance, SamplerIntegrator, Spectrum};
+
+use super::{Vertex, connect_to_camera, light_subpath};
+
+/// Traces paths only from the lights, and connects every vertex along them to the camera
+///
+/// Each connection can land on any pixel, so everything is splatted onto the film and the camera rays
+/// themselves go unused.  It's the one integrator that finds caustics seen straight from the camera,
+/// and since it shares nothing with the camera-side integrators it makes a good cross-check of them
+pub struct LightTracingIntegrator {
+  pub max_depth: u32,
+  pub camera: Arc<CameraInstance>,
+  pub sampler: SamplerInstance,
+  pub settings: RenderSettings,
+}
+
+impl LightTracingIntegrator {
+  pub fn new(max_depth: u32, camera: CameraInstance, sampler: SamplerInstance, settings: RenderSettings) -> Self {
+    Self { max_depth, camera: Arc::new(camera), sampler, settings }
+  }
+}
+
+impl SamplerIntegrator for LightTracingIntegrator {
+  fn preprocess(&mut self, _scene: &Scene) {
+  }
+
+  fn light_along_ray(&self, _rd: RayDifferential, scene: &Scene, sampler: &mut SamplerInstance, arena: &Bump, _depth: u32) -> Spectrum {
+    let camera = &*self.camera;
+    let settings = &self.settings;
+
+    let mut light_path = Vec::with_capacity(self.max_depth as usize + 1);
+    light_subpath(scene, sampler, arena, self.max_depth + 1, &mut light_path);
+
+    // Every path is spread over the whole image, and the film averages the splats out over every sample taken
+    let film = camera.film();
+    for (depth, vertex) in light_path.iter().enumerate() {
+      let connection = if depth == 0 {
+        see_light(scene, camera, vertex, sampler)
+      } else {
+        connect_to_camera(scene, camera, vertex, sampler).map(|(l, raster, _)| (l, raster))
+      };
+      if let Some((l, raster)) = connection {
+        let l = if depth <= 1 { settings.clamp_direct(l) } else { settings.clamp_indirect(l) };
+        if !l.is_black() && l.is_valid().is_none() {
+          film.add_splat(raster, l);
+        }
+      }
+    }
+    Spectrum::default()
+  }
+
+  fn splat_only(&self) -> bool { true }
+
+  fn get_camera(&mut self) -> Arc<CameraInstance> { self.camera.clone() }
+  fn get_sampler(&self, _: u64) -> SamplerInstance { self.sampler.clone() }
+  fn get_settings(&self) -> &RenderSettings { &self.settings }
+}
+
+/// The light leaving the start of a light path straight towards the camera, for lights the camera can see directly
+fn see_light(scene: &Scene, camera: &CameraInstance, vertex: &Vertex, sampler: &mut SamplerInstance) -> Option<(Spectrum, Point2)> {
+  let area = match vertex.light {
+    Some(LightInstance::AreaLight(area)) => area,
+    _ => return None,
+  };
+  let (_, raster, camera_vertex) = connect_to_camera(scene, camera, vertex, sampler)?;
+  let direction = (camera_vertex.point() - vertex.point()).normalized();
+  let emitted = area.emitted_radiance(&vertex.intersection, direction);
+  if emitted.is_black() || vertex.pdf_forward == 0. || scene.any_intersect(&vertex.intersection.ray_between(&camera_vertex.intersection)) {
+    return None;
+  }
+  let cos_theta = vertex.intersection.normal.dot(direction.into()).abs();
+  Some((emitted * camera_vertex.beta * cos_theta / vertex.pdf_forward, raster))
+}
@@ -0,0 +1,165 @@
+use std::sync::Arc;
+
+use bumpalo::Bump;
+
+use crate::{geometry::Point2, scene::Scene};
+use crate::render::{Camera, CameraInstance, CameraSample, Distribution1D, Integrator, MLTSampler, Rng, Sampler, Spectrum, mix_bits};
+
+use super::{camera_subpath, connect_subpaths, light_subpath};
+
+/// The sampler streams each part of a path reads from, so mutating one part doesn't shuffle the others
+const CAMERA_STREAM: usize = 0;
+const LIGHT_STREAM: usize = 1;
+const CONNECTION_STREAM: usize = 2;
+const STREAM_COUNT: usize = 3;
+
+/// Metropolis light transport in primary sample space (Kelemen et al.), using bidirectional path connections
+///
+/// Markov chains wander through the random numbers that drive a bidirectional path tracer, lingering on
+/// ones that produce bright paths, so hard-to-find light paths get explored once they're found.
+pub struct MLTIntegrator {
+  pub max_depth: u32,
+  /// How many independent paths of each length to trace up front, to estimate the overall image brightness
+  pub bootstrap_samples: u64,
+  /// How many independent Markov chains to run
+  pub chains: u64,
+  pub mutations_per_pixel: i64,
+  /// Standard deviation of small-step mutations
+  pub sigma: f64,
+  /// How often to jump to an entirely new path instead of perturbing the current one
+  pub large_step_probability: f64,
+  /// Mixed into every chain and path's random numbers, so renders with different seeds can be merged
+  pub seed: u64,
+  pub camera: Arc<CameraInstance>,
+}
+
+impl MLTIntegrator {
+  pub fn new(max_depth: u32, camera: CameraInstance, mutations_per_pixel: i64, bootstrap_samples: u64, chains: u64, seed: u64) -> Self {
+    Self {
+      max_depth,
+      bootstrap_samples,
+      chains,
+      mutations_per_pixel,
+      sigma: 0.01,
+      large_step_probability: 0.3,
+      seed,
+      camera: Arc::new(camera),
+    }
+  }
+
+  fn sampler(&self, sequence: u64) -> MLTSampler {
+    MLTSampler::new(self.mutations_per_pixel, mix_bits(self.seed) ^ sequence, self.sigma, self.large_step_probability, STREAM_COUNT)
+  }
+
+  /// Trace a single path with exactly `depth` bounces, with the strategy and vertices all chosen by the sampler
+  /// Returns the light it carries, scaled up by the number of strategies, and where it lands on the film
+  fn light_along_path(&self, scene: &Scene, arena: &Bump, sampler: &mut MLTSampler, depth: u32) -> (Spectrum, Point2) {
+    let camera = &*self.camera;
+
+    sampler.start_stream(CAMERA_STREAM);
+    let (strategies, s, t) = if depth == 0 {
+      (1, 0, 2)
+    } else {
+      let strategies = depth as usize + 2;
+      let s = ((sampler.get_1d() * strategies as f64) as usize).min(strategies - 1);
+      (strategies, s, strategies - s)
+    };
+
+    let resolution = camera.film().resolution;
+    let sample = sampler.get_2d();
+    let mut raster = Point2::new(sample.x * resolution.x as f64, sample.y * resolution.y as f64);
+    let (weight, ray) = camera.generate_ray(&CameraSample { film_point: raster, lens_point: sampler.get_2d() });
+    if weight == 0. {
+      return (Spectrum::default(), raster);
+    }
+    let mut camera_path = Vec::with_capacity(t);
+    camera_subpath(scene, camera, &ray, sampler, arena, t as u32, &mut camera_path);
+    if camera_path.len() != t {
+      return (Spectrum::default(), raster);
+    }
+
+    sampler.start_stream(LIGHT_STREAM);
+    let mut light_path = Vec::with_capacity(s);
+    light_subpath(scene, sampler, arena, s as u32, &mut light_path);
+    if light_path.len() != s {
+      return (Spectrum::default(), raster);
+    }
+
+    sampler.start_stream(CONNECTION_STREAM);
+    let l = connect_subpaths(scene, camera, &light_path, &camera_path, s, t, sampler, &mut raster) * weight;
+    if l.is_valid().is_some() {
+      return (Spectrum::default(), raster);
+    }
+    (l * strategies as f64, raster)
+  }
+}
+
+impl Integrator for MLTIntegrator {
+  fn render(&mut self, scene: &Scene) {
+    let mut arena = Bump::new();
+    let film = self.camera.film();
+    let depths = self.max_depth as u64 + 1;
+
+    // Trace a batch of independent paths, both to estimate the average brightness of the image,
+    // and to pick starting points for each chain in proportion to how bright they are
+    let bootstrap_weights: Vec<f64> = (0..self.bootstrap_samples * depths).map(|i| {
+      let mut sampler = self.sampler(i);
+      let (l, _) = self.light_along_path(scene, &arena, &mut sampler, (i % depths) as u32);
+      arena.reset();
+      l.luminance()
+    }).collect();
+    let bootstrap = Distribution1D::new(bootstrap_weights);
+    let brightness = bootstrap.function_integral * depths as f64;
+    if brightness == 0. {
+      println!("No light found while bootstrapping, so the image is black.");
+      return;
+    }
+
+    // Each chain's samples are distributed in proportion to luminance, so scale them back so
+    // the average over the whole image comes out to the brightness we estimated.  The film divides
+    // the splats by the mutations made for each pixel.
+    let pixel_count = (film.resolution.x * film.resolution.y) as u64;
+    let total_mutations = self.mutations_per_pixel as u64 * pixel_count;
+    let splat_scale = brightness;
+    for pixel in film.bounds() {
+      film.add_splat_samples(pixel, self.mutations_per_pixel as u64);
+    }
+
+    for chain in 0..self.chains {
+      let chain_mutations = ((chain + 1) * total_mutations / self.chains).min(total_mutations) - chain * total_mutations / self.chains;
+      let mut rng = Rng::new(mix_bits(self.seed) ^ mix_bits(chain));
+      let (bootstrap_index, _) = bootstrap.sample_discrete(rng.uniform());
+      let depth = (bootstrap_index as u64 % depths) as u32;
+
+      // Replay the chosen bootstrap path, to start the chain from it
+      let mut sampler = self.sampler(bootstrap_index as u64);
+      let (mut l_current, mut raster_current) = self.light_along_path(scene, &arena, &mut sampler, depth);
+      arena.reset();
+
+      for _ in 0..chain_mutations {
+        sampler.start_iteration();
+        let (l_proposed, raster_proposed) = self.light_along_path(scene, &arena, &mut sampler, depth);
+        arena.reset();
+
+        let (y_current, y_proposed) = (l_current.luminance(), l_proposed.luminance());
+        let accept = if y_current > 0. { (y_proposed / y_current).min(1.) } else { 1. };
+
+        // Splat both, weighted by the chance of moving, which cuts down the variance of rejecting
+        if accept > 0. && y_proposed > 0. {
+          film.add_splat(raster_proposed, l_proposed * (accept / y_proposed * splat_scale));
+        }
+        if accept < 1. && y_current > 0. {
+          film.add_splat(raster_current, l_current * ((1. - accept) / y_current * splat_scale));
+        }
+
+        if rng.uniform() < accept {
+          l_current = l_proposed;
+          raster_current = raster_proposed;
+          sampler.accept();
+        } else {
+          sampler.reject();
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,20 @@
+mod ao;
+mod bdpt;
+mod debug;
+mod direct;
+mod guided;
+mod lighttracing;
+mod mlt;
+mod sppm;
+mod volpath;
+mod vpl;
+pub use ao::*;
+pub use bdpt::*;
+pub use debug::*;
+pub use direct::*;
+pub use guided::*;
+pub use lighttracing::*;
+pub use mlt::*;
+pub use sppm::*;
+pub use volpath::*;
+pub use vpl::*;
\ No newline at end of file
@@ -0,0 +1,335 @@
+use std::{collections::HashMap, f64::consts::PI, sync::Arc, time::Instant};
+
+use bumpalo::Bump;
+
+use crate::{geometry::{Interaction, Point2, Point3, Ray, Vector3}, scene::{Light, Scene, TransportMode}};
+use crate::render::{BSDF, BxDFCategory, Camera, CameraInstance, Distribution1D, Film, Integrator, RenderSettings, Rng, Sampler, SamplerInstance, Spectrum, mix_bits, sample_one_light};
+
+/// The point a camera path came to rest on this iteration, where photons landing nearby are gathered
+struct VisiblePoint<'a> {
+  point: Point3,
+  outgoing: Vector3,
+  bsdf: &'a BSDF<'a>,
+  /// The throughput of the camera path up to this point
+  beta: Spectrum,
+}
+
+/// The running photon density estimate for a single pixel, carried from one iteration to the next
+#[derive(Clone, Copy, Default)]
+struct SPPMPixel {
+  /// How far from the visible point photons are gathered, which shrinks as more photons arrive
+  radius: f64,
+  /// Light arriving at the camera directly or via specular paths, summed over iterations
+  direct: Spectrum,
+  /// Flux carried by the photons gathered this iteration
+  flux: Spectrum,
+  /// Number of photons gathered this iteration
+  photons: u64,
+  /// Number of photons the estimate is based on, discounted as the radius shrinks
+  estimated_photons: f64,
+  /// Accumulated flux, scaled to the current radius
+  tau: Spectrum,
+}
+
+/// Stochastic progressive photon mapping
+///
+/// Alternates between tracing a path from each pixel until it comes to rest on a diffuse surface,
+/// and shooting photons from the lights, which deposit their light at any of those points nearby.
+/// The gathering radius shrinks every iteration so the result converges, and unlike tracing from
+/// the camera alone it picks up light that reaches diffuse surfaces through specular ones, like caustics.
+pub struct SPPMIntegrator {
+  pub max_depth: u32,
+  /// The gathering radius to start from, in world units
+  pub initial_radius: f64,
+  /// How many photons to shoot each iteration, defaulting to one per pixel
+  pub photons_per_iteration: u64,
+  pub camera: Arc<CameraInstance>,
+  /// The number of iterations comes from the sampler's samples per pixel
+  pub sampler: SamplerInstance,
+  pub settings: RenderSettings,
+}
+
+impl SPPMIntegrator {
+  pub fn new(max_depth: u32, camera: CameraInstance, sampler: SamplerInstance, settings: RenderSettings) -> Self {
+    let resolution = camera.film().resolution;
+    Self {
+      max_depth,
+      initial_radius: 0.25,
+      photons_per_iteration: resolution.x as u64 * resolution.y as u64,
+      camera: Arc::new(camera),
+      sampler,
+      settings,
+    }
+  }
+
+  /// Follow a camera ray through specular bounces until it lands on a surface photons can be gathered on,
+  /// adding any light it picks up along the way to `direct`
+  fn trace_visible_point<'a>(
+    &self,
+    scene: &'a Scene,
+    ray: Ray,
+    sampler: &mut SamplerInstance,
+    arena: &'a Bump,
+    direct: &mut Spectrum,
+  ) -> Option<VisiblePoint<'a>> {
+    let (mut ray, mut beta, mut specular_bounce) = (ray, Spectrum::white(), false);
+    for depth in 0..self.max_depth {
+      let interaction = match scene.intersect(&ray) {
+        Some(interaction) => interaction,
+        None => {
+          for light in &scene.lights {
+            *direct += beta * light.background_radiance(&ray);
+          }
+          return None;
+        },
+      };
+      let interaction: &'a Interaction = arena.alloc(interaction);
+      let intersection = &interaction.intersection;
+
+      if depth == 0 || specular_bounce {
+        *direct += beta * interaction.emitted_radiance();
+      }
+      let bsdf = &*interaction.compute_scattering_functions(arena, TransportMode::Radiance, true)?;
+      *direct += beta * sample_one_light(scene, intersection, bsdf, sampler);
+
+      // Gather photons on the first rough surface, or a glossy one if it's the last chance
+      let diffuse = bsdf.num_components(BxDFCategory::DIFFUSE | BxDFCategory::REFLECTION | BxDFCategory::TRANSMISSION) > 0;
+      let glossy = bsdf.num_components(BxDFCategory::GLOSSY | BxDFCategory::REFLECTION | BxDFCategory::TRANSMISSION) > 0;
+      if diffuse || (glossy && depth + 1 == self.max_depth) {
+        return Some(VisiblePoint { point: intersection.point, outgoing: intersection.outgoing, bsdf, beta });
+      }
+      if depth + 1 == self.max_depth {
+        break;
+      }
+
+      let sample = bsdf.sample_function(intersection.outgoing, &sampler.get_2d(), BxDFCategory::ALL);
+      if sample.value.is_black() || sample.probability_distribution == 0. {
+        break;
+      }
+      specular_bounce = sample.category.contains(BxDFCategory::SPECULAR);
+      beta = beta * sample.value * sample.incoming.dot(intersection.shading_normal.into()).abs() / sample.probability_distribution;
+
+      // Russian roulette, once the path carries little enough light that losing it doesn't matter much
+      let luminance = beta.luminance();
+      if luminance < 0.25 {
+        let continue_probability = luminance.min(1.);
+        if sampler.get_1d() > continue_probability {
+          break;
+        }
+        beta = beta / continue_probability;
+      }
+      ray = intersection.spawn_ray(sample.incoming);
+    }
+    None
+  }
+
+  /// Shoot one photon from a light, depositing its flux at every visible point it passes near
+  fn trace_photon(
+    &self,
+    scene: &Scene,
+    lights: &Distribution1D,
+    rng: &mut Rng,
+    arena: &Bump,
+    grid: &PhotonGrid,
+    visible: &[Option<VisiblePoint>],
+    pixels: &mut [SPPMPixel],
+  ) {
+    let (light_index, light_probability) = lights.sample_discrete(rng.uniform());
+    let position_sample = Point2::new(rng.uniform(), rng.uniform());
+    let direction_sample = Point2::new(rng.uniform(), rng.uniform());
+    let emission = scene.lights[light_index].sample_emission(position_sample, direction_sample);
+    if emission.position_probability == 0. || emission.direction_probability == 0. || emission.color.is_black() {
+      return;
+    }
+    let cos_theta = emission.normal.dot(emission.ray.direction.into()).abs();
+    let mut beta = emission.color * cos_theta / (light_probability * emission.position_probability * emission.direction_probability);
+    let mut ray = emission.ray;
+
+    for depth in 0..self.max_depth {
+      let interaction = match scene.intersect(&ray) {
+        Some(interaction) => interaction,
+        None => break,
+      };
+      let intersection = &interaction.intersection;
+
+      // Light arriving straight from the light is already accounted for by the camera paths
+      if depth > 0 {
+        let incoming = -ray.direction;
+        for &index in grid.nearby(intersection.point) {
+          let (pixel, vp) = match &visible[index] {
+            Some(vp) => (&mut pixels[index], vp),
+            None => continue,
+          };
+          if (vp.point - intersection.point).length_squared() > pixel.radius * pixel.radius {
+            continue;
+          }
+          pixel.flux += beta * vp.bsdf.evaluate(vp.outgoing, incoming, BxDFCategory::ALL);
+          pixel.photons += 1;
+        }
+      }
+
+      let bsdf = match interaction.compute_scattering_functions(arena, TransportMode::Importance, true) {
+        Some(bsdf) => bsdf,
+        None => break,
+      };
+      let sample = bsdf.sample_function(intersection.outgoing, &Point2::new(rng.uniform(), rng.uniform()), BxDFCategory::ALL);
+      if sample.value.is_black() || sample.probability_distribution == 0. {
+        break;
+      }
+      let scattered = beta * sample.value * sample.incoming.dot(intersection.shading_normal.into()).abs() / sample.probability_distribution;
+
+      // Keep photons at roughly constant power, by ending them in proportion to how much they lost
+      let termination_probability = (1. - scattered.luminance() / beta.luminance()).max(0.);
+      if rng.uniform() < termination_probability {
+        break;
+      }
+      beta = scattered / (1. - termination_probability);
+      ray = intersection.spawn_ray(sample.incoming);
+    }
+  }
+
+  /// The current estimate of the light reaching each pixel, after the given number of iterations
+  fn write_estimate(&self, film: &Film, pixels: &[SPPMPixel], iterations: i64) {
+    let photons = (iterations as u64 * self.photons_per_iteration) as f64;
+    for pixel in film.bounds() {
+      let p = &pixels[(pixel.y * film.resolution.x + pixel.x) as usize];
+      let mut l = p.direct / iterations as f64;
+      if p.radius > 0. {
+        l += p.tau / (photons * PI * p.radius * p.radius);
+      }
+      film.add_sample(pixel, l, 1.);
+    }
+  }
+}
+
+impl Integrator for SPPMIntegrator {
+  fn render(&mut self, scene: &Scene) {
+    let start = Instant::now();
+    let settings = &self.settings;
+    let camera = self.camera.clone();
+    let film = camera.film();
+    let resolution = film.resolution;
+    let mut sampler = self.sampler.clone();
+    let iterations = sampler.samples_per_pixel().max(1);
+
+    if scene.lights.is_empty() {
+      println!("The scene has no lights, so the image is black.");
+      return;
+    }
+    // Shoot more photons from brighter lights
+    let lights = Distribution1D::new(scene.lights.iter().map(|light| light.power().luminance()).collect());
+
+    let mut pixels = vec![SPPMPixel { radius: self.initial_radius, ..Default::default() }; (resolution.x * resolution.y) as usize];
+    let mut arena = Bump::new();
+    let mut photon_arena = Bump::new();
+    let mut last_snapshot = Instant::now();
+
+    let mut completed = 0;
+    for iteration in 0..iterations {
+      {
+        // Find where each pixel's camera path comes to rest this time
+        let mut visible: Vec<Option<VisiblePoint>> = (0..pixels.len()).map(|_| None).collect();
+        for pixel in camera.bounds() {
+          let index = (pixel.y * resolution.x + pixel.x) as usize;
+          sampler.start_pixel(&pixel);
+          sampler.set_sample_number(iteration);
+          let (weight, ray) = camera.generate_ray(&sampler.get_camera_sample(pixel));
+          if weight == 0. {
+            continue;
+          }
+          let mut direct = Spectrum::default();
+          visible[index] = self.trace_visible_point(scene, ray, &mut sampler, &arena, &mut direct)
+            .map(|vp| VisiblePoint { beta: vp.beta * weight, ..vp });
+          pixels[index].direct += direct * weight;
+        }
+
+        let grid = PhotonGrid::new(&visible, &pixels);
+        for photon in 0..self.photons_per_iteration {
+          let mut rng = Rng::new(mix_bits(settings.seed) ^ (iteration as u64 * self.photons_per_iteration + photon));
+          self.trace_photon(scene, &lights, &mut rng, &photon_arena, &grid, &visible, &mut pixels);
+          photon_arena.reset();
+        }
+
+        // Fold this iteration's photons into each pixel's estimate, shrinking the radius so
+        // only a fraction of the newly gathered photons count towards the density
+        for (pixel, vp) in pixels.iter_mut().zip(visible.iter()) {
+          if let (Some(vp), true) = (vp, pixel.photons > 0) {
+            const ALPHA: f64 = 2. / 3.;
+            let estimated_photons = pixel.estimated_photons + ALPHA * pixel.photons as f64;
+            let radius = pixel.radius * (estimated_photons / (pixel.estimated_photons + pixel.photons as f64)).sqrt();
+            pixel.tau = (pixel.tau + vp.beta * pixel.flux) * (radius * radius) / (pixel.radius * pixel.radius);
+            pixel.estimated_photons = estimated_photons;
+            pixel.radius = radius;
+          }
+          pixel.photons = 0;
+          pixel.flux = Spectrum::default();
+        }
+      }
+      arena.reset();
+      completed = iteration + 1;
+
+      if let (Some(snapshot), Some(interval)) = (&settings.snapshot, settings.snapshot_interval) {
+        if last_snapshot.elapsed() >= interval {
+          let estimate = Film::new(resolution);
+          self.write_estimate(&estimate, &pixels, completed);
+          estimate.write_to(snapshot.clone());
+          last_snapshot = Instant::now();
+        }
+      }
+      if let Some(time_limit) = settings.time_limit {
+        if start.elapsed() >= time_limit {
+          println!("Reached the time limit of {:.2}s, stopping early.", time_limit.as_secs_f64());
+          break;
+        }
+      }
+    }
+
+    self.write_estimate(&film, &pixels, completed);
+  }
+}
+
+/// A uniform grid over the visible points, hashed so only the occupied cells take up space
+struct PhotonGrid {
+  cell_size: f64,
+  cells: HashMap<(i64, i64, i64), Vec<usize>>,
+}
+
+impl PhotonGrid {
+  /// Add each visible point to every cell its gathering radius overlaps
+  fn new(visible: &[Option<VisiblePoint>], pixels: &[SPPMPixel]) -> Self {
+    let max_radius = visible.iter().zip(pixels)
+      .filter(|(vp, _)| vp.is_some())
+      .fold(0., |max: f64, (_, pixel)| max.max(pixel.radius));
+    let mut grid = PhotonGrid { cell_size: max_radius.max(f64::EPSILON), cells: HashMap::new() };
+
+    for (index, (vp, pixel)) in visible.iter().zip(pixels).enumerate() {
+      let vp = match vp {
+        Some(vp) if !vp.beta.is_black() => vp,
+        _ => continue,
+      };
+      let radius = Vector3::new(pixel.radius, pixel.radius, pixel.radius);
+      let (min, max) = (grid.cell(vp.point - radius), grid.cell(vp.point + radius));
+      for x in min.0..=max.0 {
+        for y in min.1..=max.1 {
+          for z in min.2..=max.2 {
+            grid.cells.entry((x, y, z)).or_default().push(index);
+          }
+        }
+      }
+    }
+    grid
+  }
+
+  fn cell(&self, point: Point3) -> (i64, i64, i64) {
+    (
+      (point.x / self.cell_size).floor() as i64,
+      (point.y / self.cell_size).floor() as i64,
+      (point.z / self.cell_size).floor() as i64,
+    )
+  }
+
+  /// The visible points that might be close enough to gather a photon landing at this point
+  fn nearby(&self, point: Point3) -> &[usize] {
+    self.cells.get(&self.cell(point)).map(|cell| &cell[..]).unwrap_or(&[])
+  }
+}
@@ -0,0 +1,260 @@
+use std::sync::Arc;
+
+use bumpalo::Bump;
+
+use crate::{geometry::{Intersection, Interaction, Point2, Ray, RayDifferential, Vector3}, scene::{Light, LightSampler, Medium, MediumInstance, MediumInteraction, Scene, TransportMode}};
+use crate::render::{BxDFCategory, Camera, CameraInstance, RenderSettings, Sampler, SamplerInstance, SamplerIntegrator, Scatterer, Spectrum, SurfaceScatterer, Visibility, estimate_direct};
+
+/// Path tracing through participating media, like fog, smoke or murky water, as well as between surfaces
+///
+/// Each path samples how far it travels through the medium it's in before scattering, and light arriving
+/// at each vertex is attenuated by the media it passes through on the way.
+pub struct VolPathIntegrator {
+  pub max_depth: u32,
+  pub camera: Arc<CameraInstance>,
+  pub sampler: SamplerInstance,
+  pub settings: RenderSettings,
+}
+
+impl VolPathIntegrator {
+  pub fn new(max_depth: u32, camera: CameraInstance, sampler: SamplerInstance, settings: RenderSettings) -> Self {
+    Self { max_depth, camera: Arc::new(camera), sampler, settings }
+  }
+}
+
+impl SamplerIntegrator for VolPathIntegrator {
+  fn preprocess(&mut self, _scene: &Scene) {
+  }
+
+  fn light_along_ray(&self, rd: RayDifferential, scene: &Scene, sampler: &mut SamplerInstance, arena: &Bump, _depth: u32) -> Spectrum {
+    let settings = &self.settings;
+    let (mut direct, mut indirect) = (Spectrum::default(), Spectrum::default());
+    let (mut ray, mut medium) = (rd.ray, self.camera.medium());
+    let mut beta = Spectrum::white();
+    let mut specular_bounce = false;
+    let mut bounces = 0;
+
+    loop {
+      let found = scene.intersect(&ray);
+      if let Some(interaction) = &found {
+        ray.time_max = interaction.intersection.distance;
+      }
+
+      // See whether the ray scatters in the medium before it reaches the surface
+      let mut scattered = None;
+      if let Some(m) = &medium {
+        let sample = m.sample(&ray, sampler);
+        beta = beta * sample.weight;
+        scattered = sample.interaction;
+      }
+      if beta.is_black() {
+        break;
+      }
+      let l = if bounces == 0 { &mut direct } else { &mut indirect };
+
+      if let Some(mi) = scattered {
+        if bounces >= self.max_depth {
+          break;
+        }
+        let intersection = mi.intersection();
+        *l += beta * sample_one_light(scene, &intersection, &VolumeScatterer::Medium(&mi), &medium, sampler);
+
+        let (_, incoming) = mi.phase.sample(mi.outgoing, sampler.get_2d());
+        ray = intersection.spawn_ray(incoming);
+        specular_bounce = false;
+      } else {
+        let interaction = match found {
+          Some(interaction) => interaction,
+          None => {
+            if bounces == 0 || specular_bounce {
+              for light in &scene.lights {
+                *l += beta * light.background_radiance(&ray);
+              }
+            }
+            break;
+          },
+        };
+        // Emission further along the path is already counted by sampling the lights
+        if bounces == 0 || specular_bounce {
+          *l += beta * interaction.emitted_radiance();
+        }
+        if bounces >= self.max_depth {
+          break;
+        }
+
+        let interaction: &Interaction = arena.alloc(interaction);
+        let intersection = &interaction.intersection;
+        let bsdf = match interaction.compute_scattering_functions(arena, TransportMode::Radiance, true) {
+          Some(bsdf) => &*bsdf,
+          // Surfaces without a material that separate two media only mark the boundary,
+          // so carry on through them without counting a bounce
+          None if interaction.medium_interface.is_some() => {
+            medium = interaction.medium_towards(ray.direction, &medium);
+            ray = intersection.spawn_ray(ray.direction);
+            continue;
+          },
+          None => break,
+        };
+        *l += beta * sample_one_light(scene, intersection, &VolumeScatterer::Surface(interaction, SurfaceScatterer::new(intersection, bsdf)), &medium, sampler);
+
+        let sample = bsdf.sample_function(intersection.outgoing, &sampler.get_2d(), BxDFCategory::ALL);
+        if sample.value.is_black() || sample.probability_distribution == 0. {
+          break;
+        }
+        beta = beta * sample.value * sample.incoming.dot(intersection.shading_normal.into()).abs() / sample.probability_distribution;
+        specular_bounce = sample.category.contains(BxDFCategory::SPECULAR);
+        medium = interaction.medium_towards(sample.incoming, &medium);
+        ray = intersection.spawn_ray(sample.incoming);
+      }
+
+      // Russian roulette, once the path has had a chance to pick up the bulk of its light
+      if bounces > 3 {
+        let max = beta.max_component();
+        if max < 1. {
+          let termination_probability = (1. - max).max(0.05);
+          if sampler.get_1d() < termination_probability {
+            break;
+          }
+          beta = beta / (1. - termination_probability);
+        }
+      }
+      bounces += 1;
+    }
+
+    settings.clamp_direct(direct) + settings.clamp_indirect(indirect)
+  }
+
+  fn get_camera(&mut self) -> Arc<CameraInstance> { self.camera.clone() }
+  fn get_sampler(&self, _: u64) -> SamplerInstance { self.sampler.clone() }
+  fn get_settings(&self) -> &RenderSettings { &self.settings }
+}
+
+/// Whatever light scatters off at a path vertex
+enum VolumeScatterer<'a> {
+  Surface(&'a Interaction, SurfaceScatterer<'a>),
+  Medium(&'a MediumInteraction),
+}
+
+impl<'a> VolumeScatterer<'a> {
+  fn medium_towards(&self, direction: Vector3, current: &Option<Arc<MediumInstance>>) -> Option<Arc<MediumInstance>> {
+    match self {
+      VolumeScatterer::Surface(interaction, _) => interaction.medium_towards(direction, current),
+      VolumeScatterer::Medium(_) => current.clone(),
+    }
+  }
+}
+
+impl<'a> Scatterer for VolumeScatterer<'a> {
+  fn evaluate(&self, outgoing: Vector3, incoming: Vector3) -> (Spectrum, f64) {
+    match self {
+      VolumeScatterer::Surface(_, surface) => surface.evaluate(outgoing, incoming),
+      VolumeScatterer::Medium(mi) => {
+        let p = mi.phase.evaluate(outgoing, incoming);
+        (Spectrum::greyscale(p), p)
+      },
+    }
+  }
+
+  fn sample(&self, outgoing: Vector3, u: Point2) -> (Spectrum, Vector3, f64, bool) {
+    match self {
+      VolumeScatterer::Surface(_, surface) => surface.sample(outgoing, u),
+      VolumeScatterer::Medium(mi) => {
+        let (p, incoming) = mi.phase.sample(outgoing, u);
+        (Spectrum::greyscale(p), incoming, p, false)
+      },
+    }
+  }
+}
+
+/// Light travelling through the media around a path vertex, and on through any boundaries between media
+struct MediumVisibility<'a, 'b> {
+  scene: &'a Scene,
+  scatterer: &'a VolumeScatterer<'b>,
+  medium: &'a Option<Arc<MediumInstance>>,
+  sampler: &'a mut SamplerInstance,
+}
+
+impl<'a, 'b> Visibility for MediumVisibility<'a, 'b> {
+  fn transmittance(&mut self, from: &Intersection, to: &Intersection, incoming: Vector3) -> Spectrum {
+    transmittance(self.scene, from, to, self.scatterer.medium_towards(incoming, self.medium), self.sampler)
+  }
+
+  fn trace(&mut self, ray: &Ray) -> (Option<Interaction>, Spectrum) {
+    intersect_through_media(self.scene, *ray, self.scatterer.medium_towards(ray.direction, self.medium), self.sampler)
+  }
+}
+
+/// Estimate the light arriving directly from a single light, chosen by the scene's light sampler, attenuated by any media in the way.
+/// Both samples are drawn up front, so they come from the same dimensions however much tracking through media takes.
+fn sample_one_light(
+  scene: &Scene,
+  intersection: &Intersection,
+  scatterer: &VolumeScatterer,
+  medium: &Option<Arc<MediumInstance>>,
+  sampler: &mut SamplerInstance,
+) -> Spectrum {
+  let (index, probability) = match scene.light_sampler.sample(intersection, sampler.get_1d()) {
+    Some(choice) => choice,
+    None => return Spectrum::default(),
+  };
+  let (light_sample, scattering_sample) = (sampler.get_2d(), sampler.get_2d());
+  let mut visibility = MediumVisibility { scene, scatterer, medium, sampler };
+  estimate_direct(intersection, scatterer, &scene.lights[index], light_sample, scattering_sample, &mut visibility) / probability
+}
+
+/// Surfaces without a material that separate two media don't block light, they just change the medium it's in
+fn is_medium_boundary(interaction: &Interaction) -> bool {
+  interaction.material.is_none() && interaction.medium_interface.is_some()
+}
+
+/// The fraction of light that makes it from one point to another, through any media in between
+fn transmittance(scene: &Scene, from: &Intersection, to: &Intersection, medium: Option<Arc<MediumInstance>>, sampler: &mut SamplerInstance) -> Spectrum {
+  let (mut ray, mut medium) = (from.ray_between(to), medium);
+  let mut result = Spectrum::white();
+  loop {
+    let found = scene.intersect(&ray);
+    if let Some(interaction) = &found {
+      if !is_medium_boundary(interaction) {
+        return Spectrum::default();
+      }
+    }
+    if let Some(m) = &medium {
+      let segment = Ray { time_max: found.as_ref().map_or(ray.time_max, |i| i.intersection.distance), ..ray };
+      result = result * m.transmittance(&segment, sampler);
+    }
+    match found {
+      Some(interaction) => {
+        medium = interaction.medium_towards(ray.direction, &medium);
+        ray = interaction.intersection.ray_between(to);
+      },
+      None => return result,
+    }
+  }
+}
+
+/// Find the first surface along the ray that isn't just a medium boundary,
+/// and the fraction of light that makes it through the media on the way there
+fn intersect_through_media(
+  scene: &Scene,
+  ray: Ray,
+  medium: Option<Arc<MediumInstance>>,
+  sampler: &mut SamplerInstance,
+) -> (Option<Interaction>, Spectrum) {
+  let (mut ray, mut medium) = (ray, medium);
+  let mut result = Spectrum::white();
+  loop {
+    let found = scene.intersect(&ray);
+    if let Some(m) = &medium {
+      let segment = Ray { time_max: found.as_ref().map_or(ray.time_max, |i| i.intersection.distance), ..ray };
+      result = result * m.transmittance(&segment, sampler);
+    }
+    match found {
+      Some(interaction) if is_medium_boundary(&interaction) => {
+        medium = interaction.medium_towards(ray.direction, &medium);
+        ray = interaction.intersection.spawn_ray(ray.direction);
+      },
+      Some(interaction) => return (Some(interaction), result),
+      None => return (None, result),
+    }
+  }
+}
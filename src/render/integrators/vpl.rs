@@ -0,0 +1,163 @@
+use std::sync::Arc;
+
+use bumpalo::Bump;
+
+use crate::{geometry::{Interaction, Point2, RayDifferential}, scene::{Light, LightSampler, Scene, TransportMode}};
+use crate::render::{BSDF, BxDFCategory, CameraInstance, OwnedBSDF, RenderSettings, Rng, SamplerInstance, SamplerIntegrator, Spectrum, mix_bits, uniform_sample_all_lights};
+
+/// A point where a light path bounced, which goes on to light the rest of the scene as though it were a light itself
+struct VirtualLight {
+  /// The surface the path bounced off, with `outgoing` pointing back along the path towards the light
+  interaction: Interaction,
+  /// How the surface scatters the light arriving along the path
+  bsdf: OwnedBSDF,
+  /// The path throughput arriving at the surface
+  beta: Spectrum,
+}
+
+/// Instant radiosity: traces a fixed set of light paths up front, leaving a virtual point light
+/// wherever they bounce, and then lights every point the camera sees from all of them
+///
+/// Every pixel shares the same virtual lights, so the indirect lighting comes out smooth rather than noisy,
+/// at the cost of some blotchiness and of the energy lost to clamping the geometry term
+pub struct VPLIntegrator {
+  pub max_depth: u32,
+  /// How many paths to trace from the lights
+  pub light_paths: usize,
+  /// The greatest the geometry term between a point and a virtual light can be, to keep virtual lights
+  /// right next to a surface from showing up as bright spots
+  pub max_geometry: f64,
+  pub camera: Arc<CameraInstance>,
+  pub sampler: SamplerInstance,
+  pub settings: RenderSettings,
+  virtual_lights: Vec<VirtualLight>,
+}
+
+impl VPLIntegrator {
+  pub fn new(max_depth: u32, light_paths: usize, max_geometry: f64, camera: CameraInstance, sampler: SamplerInstance, settings: RenderSettings) -> Self {
+    Self { max_depth, light_paths, max_geometry, camera: Arc::new(camera), sampler, settings, virtual_lights: vec![] }
+  }
+
+  /// The light arriving at a point from every virtual light that can see it
+  fn gather(&self, scene: &Scene, interaction: &Interaction, bsdf: &BSDF) -> Spectrum {
+    let intersection = &interaction.intersection;
+    let mut result = Spectrum::default();
+    for light in &self.virtual_lights {
+      let target = &light.interaction.intersection;
+      let offset = target.point - intersection.point;
+      let distance_sq = offset.length_squared();
+      if distance_sq == 0. {
+        continue;
+      }
+      let incoming = offset / distance_sq.sqrt();
+
+      let f = bsdf.evaluate(intersection.outgoing, incoming, BxDFCategory::ALL);
+      let cos_here = incoming.dot(intersection.shading_normal.into()).abs();
+      let cos_there = incoming.dot(target.shading_normal.into()).abs();
+      let g = (cos_here * cos_there / distance_sq).min(self.max_geometry);
+      if f.is_black() || g == 0. || scene.any_intersect(&intersection.ray_between(target)) {
+        continue;
+      }
+
+      let light_f = light.bsdf.bsdf().evaluate(target.outgoing, -incoming, BxDFCategory::ALL);
+      result += f * light_f * light.beta * g;
+    }
+    result / self.light_paths.max(1) as f64
+  }
+}
+
+impl SamplerIntegrator for VPLIntegrator {
+  fn preprocess(&mut self, scene: &Scene) {
+    self.virtual_lights.clear();
+
+    // Trace paths from the lights the scene's light sampler favours, just as the other integrators start theirs
+    let mut arena = Bump::new();
+    for path in 0..self.light_paths {
+      let mut rng = Rng::new(mix_bits(self.settings.seed) ^ path as u64);
+      let (light_index, light_probability) = match scene.light_sampler.sample_any(rng.uniform()) {
+        Some(choice) => choice,
+        None => return,
+      };
+      let position_sample = Point2::new(rng.uniform(), rng.uniform());
+      let direction_sample = Point2::new(rng.uniform(), rng.uniform());
+      let emission = scene.lights[light_index].sample_emission(position_sample, direction_sample);
+      if emission.position_probability == 0. || emission.direction_probability == 0. || emission.color.is_black() {
+        continue;
+      }
+      let cos_theta = emission.normal.dot(emission.ray.direction.into()).abs();
+      let mut beta = emission.color * cos_theta / (light_probability * emission.position_probability * emission.direction_probability);
+      let mut ray = emission.ray;
+
+      // The lights themselves are sampled directly when shading, so virtual lights only go where their paths land
+      for _ in 0..self.max_depth {
+        let interaction = match scene.intersect(&ray) {
+          Some(interaction) => interaction,
+          None => break,
+        };
+        let intersection = interaction.intersection;
+        let (sample, light_bsdf) = match interaction.compute_scattering_functions(&arena, TransportMode::Importance, true) {
+          Some(bsdf) => (
+            bsdf.sample_function(intersection.outgoing, &Point2::new(rng.uniform(), rng.uniform()), BxDFCategory::ALL),
+            // Perfectly specular surfaces never reflect a virtual light's light towards anything
+            if bsdf.num_components(BxDFCategory::ALL - BxDFCategory::SPECULAR) > 0 { Some(bsdf.to_owned_bsdf()) } else { None },
+          ),
+          None => break,
+        };
+        arena.reset();
+        if let Some(bsdf) = light_bsdf {
+          self.virtual_lights.push(VirtualLight { interaction, bsdf, beta });
+        }
+
+        if sample.value.is_black() || sample.probability_distribution == 0. {
+          break;
+        }
+        let scattered = beta * sample.value * sample.incoming.dot(intersection.shading_normal.into()).abs() / sample.probability_distribution;
+
+        // End paths in proportion to how much they lost, so the virtual lights keep roughly constant power
+        let termination_probability = (1. - scattered.luminance() / beta.luminance()).max(0.);
+        if rng.uniform() < termination_probability {
+          break;
+        }
+        beta = scattered / (1. - termination_probability);
+        ray = intersection.spawn_ray(sample.incoming);
+      }
+    }
+  }
+
+  fn light_along_ray(&self, rd: RayDifferential, scene: &Scene, sampler: &mut SamplerInstance, arena: &Bump, depth: u32) -> Spectrum {
+    let mut result = Spectrum::default();
+    let settings = &self.settings;
+
+    let interaction = match scene.intersect(&rd.ray) {
+      Some(interaction) => interaction,
+      None => {
+        for light in &scene.lights {
+          result += light.background_radiance(&rd.ray);
+        }
+        return if depth == 0 { settings.clamp_direct(result) } else { result };
+      },
+    };
+    result += interaction.emitted_radiance();
+
+    let bsdf = match interaction.compute_scattering_functions(arena, TransportMode::Radiance, false) {
+      Some(bsdf) => bsdf,
+      None => return if depth == 0 { settings.clamp_direct(result) } else { result },
+    };
+
+    result += uniform_sample_all_lights(scene, &interaction.intersection, bsdf, sampler, 1);
+    let mut indirect = self.gather(scene, &interaction, bsdf);
+    if depth + 1 < self.max_depth {
+      indirect += self.specular_reflect(rd, interaction.intersection, bsdf, scene, sampler, arena, depth);
+      indirect += self.specular_transmit(rd, interaction.intersection, bsdf, scene, sampler, arena, depth);
+    }
+
+    if depth == 0 {
+      return settings.clamp_direct(result) + settings.clamp_indirect(indirect);
+    }
+    result + indirect
+  }
+
+  fn get_camera(&mut self) -> Arc<CameraInstance> { self.camera.clone() }
+  fn get_sampler(&self, _: u64) -> SamplerInstance { self.sampler.clone() }
+  fn get_settings(&self) -> &RenderSettings { &self.settings }
+}
@@ -1,14 +1,26 @@
 mod bxdf;
 mod bxdfs;
 mod integrator;
+mod integrators;
 mod camera;
+mod denoise;
 mod film;
+mod hdr_image;
+mod rng;
 mod sampler;
+mod sampling;
+mod settings;
 mod spectrum;
 pub use bxdf::*;
 pub use bxdfs::*;
 pub use integrator::*;
+pub use integrators::*;
 pub use camera::*;
+pub use denoise::*;
 pub use film::*;
+pub use hdr_image::*;
+pub use rng::*;
 pub use sampler::*;
+pub use sampling::*;
+pub use settings::*;
 pub use spectrum::*;
\ No newline at end of file
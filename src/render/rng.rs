@@ -0,0 +1,60 @@
+/// The largest f64 strictly less than one, so samples stay in [0, 1)
+pub const ONE_MINUS_EPSILON: f64 = 1. - f64::EPSILON / 2.;
+
+const PCG32_DEFAULT_STATE: u64 = 0x853c_49e6_748f_ea9b;
+const PCG32_DEFAULT_STREAM: u64 = 0xda3e_39cb_94b9_5bdb;
+const PCG32_MULTIPLIER: u64 = 0x5851_f42d_4c95_7f2d;
+
+/// A small, fast, deterministic random number generator (PCG32, the same one pbrt uses)
+#[derive(Clone)]
+pub struct Rng {
+  state: u64,
+  increment: u64,
+}
+
+impl Default for Rng {
+  fn default() -> Self {
+    Rng { state: PCG32_DEFAULT_STATE, increment: PCG32_DEFAULT_STREAM }
+  }
+}
+
+impl Rng {
+  pub fn new(sequence: u64) -> Self {
+    let mut rng = Rng::default();
+    rng.set_sequence(sequence);
+    rng
+  }
+
+  /// Restart the generator on the given stream, so the same sequence always yields the same numbers
+  pub fn set_sequence(&mut self, sequence: u64) {
+    self.state = 0;
+    self.increment = (sequence << 1) | 1;
+    self.next_u32();
+    self.state = self.state.wrapping_add(PCG32_DEFAULT_STATE);
+    self.next_u32();
+  }
+
+  pub fn next_u32(&mut self) -> u32 {
+    let old_state = self.state;
+    self.state = old_state.wrapping_mul(PCG32_MULTIPLIER).wrapping_add(self.increment);
+    let xor_shifted = (((old_state >> 18) ^ old_state) >> 27) as u32;
+    let rotation = (old_state >> 59) as u32;
+    xor_shifted.rotate_right(rotation)
+  }
+
+  /// A uniformly distributed number in [0, 1)
+  pub fn uniform(&mut self) -> f64 {
+    (self.next_u32() as f64 * (1. / 4_294_967_296.)).min(ONE_MINUS_EPSILON)
+  }
+}
+
+/// Scramble the bits of a value, so that nearby inputs (like neighbouring pixels) produce unrelated seeds
+pub fn mix_bits(v: u64) -> u64 {
+  let mut v = v;
+  v ^= v >> 31;
+  v = v.wrapping_mul(0x7fb5_d329_728e_a185);
+  v ^= v >> 27;
+  v = v.wrapping_mul(0x81da_def4_bc2d_d44d);
+  v ^= v >> 33;
+  v
+}
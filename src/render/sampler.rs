@@ -2,18 +2,31 @@ use enum_dispatch::enum_dispatch;
 
 use crate::geometry::Point2;
 
+use super::{Rng, erf_inv, mix_bits};
+
 #[enum_dispatch]
 pub trait Sampler {
   fn start_pixel(&mut self, p: &Point2<u32>);
   fn start_next(&mut self) -> bool;
+  /// Jump straight to a particular sample of the current pixel
+  /// Returns false if the pixel doesn't have that many samples
+  fn set_sample_number(&mut self, sample: i64) -> bool;
   
   fn samples_per_pixel(&self) -> i64;
 
-  fn get_camera_sample(&self, raster_point: Point2<u32>) -> CameraSample {
-    CameraSample {
-      film_point: Point2::<f64>::from(raster_point) + Point2::default(),
-      lens_point: Point2::default(),
-    }
+  /// The next dimension of the current sample, in [0, 1)
+  fn get_1d(&mut self) -> f64;
+  /// The next two dimensions of the current sample, in [0, 1)^2
+  fn get_2d(&mut self) -> Point2 {
+    let x = self.get_1d();
+    let y = self.get_1d();
+    Point2::new(x, y)
+  }
+
+  fn get_camera_sample(&mut self, raster_point: Point2<u32>) -> CameraSample {
+    let film_point = Point2::<f64>::from(raster_point) + self.get_2d();
+    let lens_point = self.get_2d();
+    CameraSample { film_point, lens_point }
   }
 }
 
@@ -36,7 +49,10 @@ pub struct NullSampler {}
 impl Sampler for NullSampler {
   fn start_pixel(&mut self, _: &Point2<u32>) {}
   fn start_next(&mut self) -> bool { false }
+  fn set_sample_number(&mut self, sample: i64) -> bool { sample == 0 }
   fn samples_per_pixel(&self) -> i64 { 0 }
+  // With no randomness, always sample the middle of the domain
+  fn get_1d(&mut self) -> f64 { 0.5 }
 }
 
 #[derive(Clone)]
@@ -44,15 +60,169 @@ pub struct RandomSampler {
   // TODO: pre-sample for performance
   pub samples_per_pixel: i64,
   pub current_sample: i64,
+  /// Distinguishes otherwise identical renders, so their results can be merged
+  pub seed: u64,
+  current_pixel: Point2<u32>,
+  rng: Rng,
+}
+
+impl RandomSampler {
+  pub fn new(samples_per_pixel: i64, seed: u64) -> Self {
+    Self { samples_per_pixel, current_sample: 0, seed, current_pixel: Point2::default(), rng: Rng::new(seed) }
+  }
+
+  /// Seed each sample of each pixel independently, so the result never depends on
+  /// which process renders it, or what order the pixels and samples are rendered in
+  fn reseed(&mut self) {
+    let pixel = ((self.current_pixel.y as u64) << 32) | self.current_pixel.x as u64;
+    let sample = mix_bits(self.seed) ^ mix_bits(self.current_sample as u64);
+    self.rng.set_sequence(mix_bits(pixel ^ sample));
+  }
 }
 
 impl Sampler for RandomSampler {
-  fn start_pixel(&mut self, _: &Point2<u32>) {
+  fn start_pixel(&mut self, p: &Point2<u32>) {
+    self.current_pixel = *p;
     self.current_sample = 0;
+    self.reseed();
   }
   fn start_next(&mut self) -> bool {
-    self.current_sample += 1;
+    self.set_sample_number(self.current_sample + 1)
+  }
+  fn set_sample_number(&mut self, sample: i64) -> bool {
+    self.current_sample = sample;
+    self.reseed();
     self.current_sample < self.samples_per_pixel
   }
   fn samples_per_pixel(&self) -> i64 { self.samples_per_pixel }
+  fn get_1d(&mut self) -> f64 { self.rng.uniform() }
+}
+
+
+/// One coordinate of a point in primary sample space, with enough history to undo a rejected mutation
+#[derive(Clone, Copy, Default)]
+struct PrimarySample {
+  value: f64,
+  /// The iteration this coordinate was last changed on
+  last_modification: i64,
+  value_backup: f64,
+  modification_backup: i64,
+}
+
+impl PrimarySample {
+  fn backup(&mut self) {
+    self.value_backup = self.value;
+    self.modification_backup = self.last_modification;
+  }
+  fn restore(&mut self) {
+    self.value = self.value_backup;
+    self.last_modification = self.modification_backup;
+  }
+}
+
+/// A sampler for primary sample space Metropolis light transport
+///
+/// Rather than independent random numbers, each iteration hands out a mutation of the numbers from the
+/// last accepted iteration: either a small perturbation of each, or (a "large step") entirely fresh ones.
+/// Coordinates are only mutated when they're asked for, so paths of different lengths can share a sampler.
+#[derive(Clone)]
+pub struct MLTSampler {
+  pub mutations_per_pixel: i64,
+  /// Standard deviation of the perturbation applied by small steps
+  pub sigma: f64,
+  pub large_step_probability: f64,
+  /// Number of independent sequences of coordinates, so each part of a path always reads the same ones
+  pub stream_count: usize,
+  rng: Rng,
+  samples: Vec<PrimarySample>,
+  current_iteration: i64,
+  large_step: bool,
+  last_large_step: i64,
+  stream_index: usize,
+  sample_index: usize,
+}
+
+impl MLTSampler {
+  pub fn new(mutations_per_pixel: i64, sequence: u64, sigma: f64, large_step_probability: f64, stream_count: usize) -> Self {
+    Self {
+      mutations_per_pixel, sigma, large_step_probability, stream_count,
+      rng: Rng::new(sequence),
+      samples: vec![],
+      current_iteration: 0,
+      // The first iteration must choose every coordinate from scratch
+      large_step: true,
+      last_large_step: 0,
+      stream_index: 0,
+      sample_index: 0,
+    }
+  }
+
+  /// Begin a new mutation of the current sample
+  pub fn start_iteration(&mut self) {
+    self.current_iteration += 1;
+    self.large_step = self.rng.uniform() < self.large_step_probability;
+  }
+
+  /// Start reading from one of the independent sequences of coordinates
+  pub fn start_stream(&mut self, index: usize) {
+    assert!(index < self.stream_count, "Stream index out of range");
+    self.stream_index = index;
+    self.sample_index = 0;
+  }
+
+  /// Keep this iteration's mutation
+  pub fn accept(&mut self) {
+    if self.large_step {
+      self.last_large_step = self.current_iteration;
+    }
+  }
+
+  /// Undo this iteration's mutation
+  pub fn reject(&mut self) {
+    for sample in self.samples.iter_mut() {
+      if sample.last_modification == self.current_iteration {
+        sample.restore();
+      }
+    }
+    self.current_iteration -= 1;
+  }
+
+  /// Bring a coordinate up to date with the current iteration, mutating it if needed
+  fn ensure_ready(&mut self, index: usize) {
+    if index >= self.samples.len() {
+      self.samples.resize(index + 1, PrimarySample::default());
+    }
+    let sample = &mut self.samples[index];
+
+    // Coordinates that weren't read since the last large step need the fresh value it would have given them
+    if sample.last_modification < self.last_large_step {
+      sample.value = self.rng.uniform();
+      sample.last_modification = self.last_large_step;
+    }
+
+    sample.backup();
+    if self.large_step {
+      sample.value = self.rng.uniform();
+    } else {
+      // Apply every small step this coordinate missed at once, as a single wider perturbation
+      let small_steps = (self.current_iteration - sample.last_modification) as f64;
+      let normal_sample = std::f64::consts::SQRT_2 * erf_inv(2. * self.rng.uniform() - 1.);
+      sample.value += normal_sample * self.sigma * small_steps.sqrt();
+      sample.value -= sample.value.floor();
+    }
+    sample.last_modification = self.current_iteration;
+  }
+}
+
+impl Sampler for MLTSampler {
+  fn start_pixel(&mut self, _: &Point2<u32>) {}
+  fn start_next(&mut self) -> bool { false }
+  fn set_sample_number(&mut self, _: i64) -> bool { true }
+  fn samples_per_pixel(&self) -> i64 { self.mutations_per_pixel }
+  fn get_1d(&mut self) -> f64 {
+    let index = self.stream_index + self.stream_count * self.sample_index;
+    self.sample_index += 1;
+    self.ensure_ready(index);
+    self.samples[index].value
+  }
 }
\ No newline at end of file
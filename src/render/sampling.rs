@@ -0,0 +1,261 @@
+use std::f64::consts::{FRAC_PI_2, FRAC_PI_4, PI, TAU};
+
+use crate::geometry::{Point2, Vector3};
+
+/// Map a uniform sample on the unit square to a uniform sample on the unit disk,
+/// in a way that keeps nearby samples close together
+pub fn concentric_sample_disk(u: Point2) -> Point2 {
+  // Map to [-1, 1]^2
+  let offset = Point2::new(2. * u.x - 1., 2. * u.y - 1.);
+  if offset.x == 0. && offset.y == 0. {
+    return Point2::default();
+  }
+
+  let (radius, theta) = if offset.x.abs() > offset.y.abs() {
+    (offset.x, FRAC_PI_4 * (offset.y / offset.x))
+  } else {
+    (offset.y, FRAC_PI_2 - FRAC_PI_4 * (offset.x / offset.y))
+  };
+  Point2::new(radius * theta.cos(), radius * theta.sin())
+}
+
+/// Choose a direction in the +z hemisphere, with density proportional to the cosine of the angle to +z
+pub fn cosine_sample_hemisphere(u: Point2) -> Vector3 {
+  // Malley's method: project uniform samples on the disk up onto the hemisphere
+  let d = concentric_sample_disk(u);
+  let z = (1. - d.x * d.x - d.y * d.y).max(0.).sqrt();
+  Vector3::new(d.x, d.y, z)
+}
+
+pub fn uniform_sample_sphere(u: Point2) -> Vector3 {
+  let z = 1. - 2. * u.x;
+  let r = (1. - z * z).max(0.).sqrt();
+  let phi = TAU * u.y;
+  Vector3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+pub fn uniform_sphere_pdf() -> f64 {
+  1. / (4. * PI)
+}
+
+/// Choose a direction uniformly from the cone around +z whose edge makes an angle with cosine `cos_max` to it
+pub fn uniform_sample_cone(u: Point2, cos_max: f64) -> Vector3 {
+  let cos_theta = (1. - u.x) + u.x * cos_max;
+  let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+  let phi = TAU * u.y;
+  Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+pub fn uniform_cone_pdf(cos_max: f64) -> f64 {
+  1. / (TAU * (1. - cos_max))
+}
+
+/// The solid angle covered by the triangle on the unit sphere with corners in the directions `a`, `b` and `c`
+pub fn spherical_triangle_area(a: Vector3, b: Vector3, c: Vector3) -> f64 {
+  (2. * a.dot(b.cross(c)).atan2(1. + a.dot(b) + a.dot(c) + b.dot(c))).abs()
+}
+
+/// Choose a direction uniformly from the triangle on the unit sphere with corners in the directions `a`, `b` and `c`,
+/// returning it with the density of choosing it, or nothing if the triangle is too thin to choose from (Arvo's method)
+pub fn uniform_sample_spherical_triangle(a: Vector3, b: Vector3, c: Vector3, u: Point2) -> Option<(Vector3, f64)> {
+  // The planes through the center of the sphere and each edge, and the angles between them at each corner
+  let (n_ab, n_bc, n_ca) = (a.cross(b), b.cross(c), c.cross(a));
+  if n_ab.length_squared() == 0. || n_bc.length_squared() == 0. || n_ca.length_squared() == 0. {
+    return None;
+  }
+  let (n_ab, n_bc, n_ca) = (n_ab.normalized(), n_bc.normalized(), n_ca.normalized());
+  let angle_between = |v: Vector3, w: Vector3| v.dot(w).clamp(-1., 1.).acos();
+  let alpha = angle_between(n_ab, -n_ca);
+  let beta = angle_between(n_bc, -n_ab);
+  let gamma = angle_between(n_ca, -n_bc);
+  let area = alpha + beta + gamma - PI;
+  if area <= 0. {
+    return None;
+  }
+
+  // Choose the sub-triangle with corners a, b and some c' along the edge from a to c, with the sampled area
+  let area_pi = PI + u.x * area;
+  let (sin_alpha, cos_alpha) = alpha.sin_cos();
+  let sin_phi = area_pi.sin() * cos_alpha - area_pi.cos() * sin_alpha;
+  let cos_phi = area_pi.cos() * cos_alpha + area_pi.sin() * sin_alpha;
+  let k1 = cos_phi + cos_alpha;
+  let k2 = sin_phi - sin_alpha * a.dot(b);
+  let cos_b = ((k2 + (k2 * cos_phi - k1 * sin_phi) * cos_alpha) / ((k2 * sin_phi + k1 * cos_phi) * sin_alpha)).clamp(-1., 1.);
+  let sin_b = (1. - cos_b * cos_b).max(0.).sqrt();
+  let c_prime = a * cos_b + (c - a * c.dot(a)).normalized() * sin_b;
+
+  // Then choose a point along the arc from b to c'
+  let cos_theta = 1. - u.y * (1. - c_prime.dot(b));
+  let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+  let direction = b * cos_theta + (c_prime - b * c_prime.dot(b)).normalized() * sin_theta;
+  Some((direction, 1. / area))
+}
+
+/// Weight a sample from one of two sampling strategies, favouring whichever was more likely to choose it
+/// `nf` and `ng` are how many samples were taken with each strategy
+pub fn power_heuristic(nf: usize, f_pdf: f64, ng: usize, g_pdf: f64) -> f64 {
+  let f = nf as f64 * f_pdf;
+  let g = ng as f64 * g_pdf;
+  if f.is_infinite() {
+    return 1.;
+  }
+  (f * f) / (f * f + g * g)
+}
+
+/// The inverse of the error function, accurate enough for turning uniform samples into normally distributed ones
+/// (Giles' single-precision approximation)
+pub fn erf_inv(x: f64) -> f64 {
+  let x = x.clamp(-0.99999, 0.99999);
+  let w = -((1. - x) * (1. + x)).ln();
+  let p = if w < 5. {
+    let w = w - 2.5;
+    [
+      3.43273939e-07, -3.5233877e-06, -4.39150654e-06, 0.00021858087, -0.00125372503,
+      -0.00417768164, 0.246640727, 1.50140941,
+    ].iter().fold(2.81022636e-08, |p, c| c + p * w)
+  } else {
+    let w = w.sqrt() - 3.;
+    [
+      0.000100950558, 0.00134934322, -0.00367342844, 0.00573950773, -0.0076224613,
+      0.00943887047, 1.00167406, 2.83297682,
+    ].iter().fold(-0.000200214257, |p, c| c + p * w)
+  };
+  p * x
+}
+
+/// A piecewise-constant distribution over [0, 1), for choosing among items in proportion to some weight
+#[derive(Clone)]
+pub struct Distribution1D {
+  pub function: Vec<f64>,
+  /// The cumulative distribution, with one more entry than `function`
+  pub cdf: Vec<f64>,
+  /// The integral of the function over [0, 1)
+  pub function_integral: f64,
+}
+
+impl Distribution1D {
+  pub fn new(function: Vec<f64>) -> Self {
+    let n = function.len();
+    let mut cdf = vec![0.; n + 1];
+    for i in 1..=n {
+      cdf[i] = cdf[i - 1] + function[i - 1] / n as f64;
+    }
+    let function_integral = cdf[n];
+    for (i, c) in cdf.iter_mut().enumerate() {
+      // If everything is zero, fall back to a uniform distribution
+      *c = if function_integral == 0. { i as f64 / n as f64 } else { *c / function_integral };
+    }
+    Distribution1D { function, cdf, function_integral }
+  }
+
+  pub fn count(&self) -> usize {
+    self.function.len()
+  }
+
+  /// Choose an item, returning its index and the probability of choosing it
+  pub fn sample_discrete(&self, u: f64) -> (usize, f64) {
+    let offset = self.find_interval(u);
+    (offset, self.discrete_probability(offset))
+  }
+
+  /// Choose a point in [0, 1), returning it, the density it was chosen with, and which piece it fell in
+  pub fn sample_continuous(&self, u: f64) -> (f64, f64, usize) {
+    let offset = self.find_interval(u);
+    let mut du = u - self.cdf[offset];
+    if self.cdf[offset + 1] - self.cdf[offset] > 0. {
+      du /= self.cdf[offset + 1] - self.cdf[offset];
+    }
+    let pdf = if self.function_integral > 0. { self.function[offset] / self.function_integral } else { 1. };
+    ((offset as f64 + du) / self.count() as f64, pdf, offset)
+  }
+
+  pub fn discrete_probability(&self, index: usize) -> f64 {
+    if self.function_integral == 0. {
+      return 1. / self.count() as f64;
+    }
+    self.function[index] / (self.function_integral * self.count() as f64)
+  }
+
+  /// The index of the last cdf entry no greater than `u`
+  fn find_interval(&self, u: f64) -> usize {
+    let first_above = self.cdf.partition_point(|&c| c <= u);
+    first_above.saturating_sub(1).min(self.count() - 1)
+  }
+}
+
+/// A piecewise-constant distribution over [0, 1)^2, for choosing points in proportion to the values of an image
+pub struct Distribution2D {
+  /// The distribution along u within each row
+  conditional: Vec<Distribution1D>,
+  /// The distribution of the rows themselves
+  marginal: Distribution1D,
+}
+
+impl Distribution2D {
+  /// `function` holds `width` values for each row, one row after another
+  pub fn new(function: &[f64], width: usize, height: usize) -> Self {
+    let conditional: Vec<_> = function.chunks(width).take(height).map(|row| Distribution1D::new(row.to_vec())).collect();
+    let marginal = Distribution1D::new(conditional.iter().map(|row| row.function_integral).collect());
+    Distribution2D { conditional, marginal }
+  }
+
+  /// Choose a point, returning it and the density it was chosen with
+  pub fn sample_continuous(&self, u: Point2) -> (Point2, f64) {
+    let (v, v_pdf, row) = self.marginal.sample_continuous(u.y);
+    let (u, u_pdf, _) = self.conditional[row].sample_continuous(u.x);
+    (Point2::new(u, v), u_pdf * v_pdf)
+  }
+
+  pub fn probability(&self, p: Point2) -> f64 {
+    let width = self.conditional[0].count();
+    let height = self.marginal.count();
+    let column = ((p.x * width as f64) as usize).min(width - 1);
+    let row = ((p.y * height as f64) as usize).min(height - 1);
+    if self.marginal.function_integral == 0. {
+      return 1.;
+    }
+    self.conditional[row].function[column] / self.marginal.function_integral
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn inverts_erf() {
+    // erf at a few points, both where the approximation uses its central and tail polynomials
+    for &(x, erf) in &[(0.5, 0.5204998778130465), (1.5, 0.9661051464753108), (2.5, 0.999593047982555)] {
+      assert!((erf_inv(erf) - x).abs() < 1e-5, "erf_inv({}) = {}, expected {}", erf, erf_inv(erf), x);
+      assert!((erf_inv(-erf) + x).abs() < 1e-5);
+    }
+    assert_eq!(erf_inv(0.), 0.);
+    // Clamped rather than infinite at the ends
+    assert!(erf_inv(1.).is_finite() && erf_inv(-1.).is_finite());
+  }
+
+  #[test]
+  fn samples_in_proportion() {
+    let distribution = Distribution1D::new(vec![1., 3., 0., 4.]);
+    assert_eq!(distribution.function_integral, 2.);
+    assert_eq!(distribution.cdf, vec![0., 0.125, 0.5, 0.5, 1.]);
+    assert_eq!(distribution.sample_discrete(0.1), (0, 0.125));
+    assert_eq!(distribution.sample_discrete(0.2), (1, 0.375));
+    // Items with no weight are never chosen
+    assert_eq!(distribution.sample_discrete(0.5), (3, 0.5));
+    assert_eq!(distribution.sample_discrete(0.999), (3, 0.5));
+    let total: f64 = (0..distribution.count()).map(|i| distribution.discrete_probability(i)).sum();
+    assert_eq!(total, 1.);
+
+    assert_eq!(distribution.sample_continuous(0.75), (0.875, 2., 3));
+    assert_eq!(distribution.sample_continuous(0.0625), (0.125, 0.5, 0));
+  }
+
+  #[test]
+  fn falls_back_to_uniform() {
+    let distribution = Distribution1D::new(vec![0., 0.]);
+    assert_eq!(distribution.function_integral, 0.);
+    assert_eq!(distribution.sample_discrete(0.6), (1, 0.5));
+    assert_eq!(distribution.sample_continuous(0.25), (0.25, 1., 0));
+  }
+}
@@ -0,0 +1,66 @@
+use std::{ops::Range, path::PathBuf, time::Duration};
+
+use super::Spectrum;
+
+/// Options that control how a render runs, independent of which integrator is doing the work
+#[derive(Clone)]
+pub struct RenderSettings {
+  /// Where to periodically save the film state, and resume from if it already exists
+  pub checkpoint: Option<PathBuf>,
+  /// How often to save the film state while rendering
+  pub checkpoint_interval: Duration,
+  /// The width and height of the square tiles the image is split into
+  pub tile_size: u32,
+  /// Only render this range of tiles, numbered in row-major order
+  pub tiles: Option<Range<usize>>,
+  /// Only take this range of samples in each pixel
+  pub samples: Option<Range<i64>>,
+  /// Render the whole image in passes of increasing sample count, rather than finishing each pixel in turn
+  pub progressive: bool,
+  /// Where to periodically write the image while rendering, so progress can be watched
+  pub snapshot: Option<PathBuf>,
+  /// How often to write the snapshot image
+  pub snapshot_interval: Option<Duration>,
+  /// Stop rendering once this much time has passed
+  pub time_limit: Option<Duration>,
+  /// Record the albedo, normal and depth seen by each camera ray, so the image can be denoised
+  pub collect_features: bool,
+  /// The brightest (by luminance) that light arriving directly from the first surface a camera ray hits can be
+  pub max_direct: Option<f64>,
+  /// The brightest (by luminance) that light arriving via further bounces can be
+  pub max_indirect: Option<f64>,
+  /// Leave samples out of a pixel's color if they're this many standard deviations brighter than the pixel's average
+  pub outlier_rejection: Option<f64>,
+  /// Mixed into random numbers drawn outside the sampler, so renders with different seeds can be merged
+  pub seed: u64,
+}
+
+impl RenderSettings {
+  pub fn clamp_direct(&self, l: Spectrum) -> Spectrum {
+    self.max_direct.map_or(l, |max| l.clamp_luminance(max))
+  }
+  pub fn clamp_indirect(&self, l: Spectrum) -> Spectrum {
+    self.max_indirect.map_or(l, |max| l.clamp_luminance(max))
+  }
+}
+
+impl Default for RenderSettings {
+  fn default() -> Self {
+    Self {
+      checkpoint: None,
+      checkpoint_interval: Duration::from_secs(60),
+      tile_size: 16,
+      tiles: None,
+      samples: None,
+      progressive: false,
+      snapshot: None,
+      snapshot_interval: None,
+      time_limit: None,
+      collect_features: false,
+      max_direct: None,
+      max_indirect: None,
+      outlier_rejection: None,
+      seed: 0,
+    }
+  }
+}
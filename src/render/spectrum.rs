@@ -25,10 +25,48 @@ impl Spectrum {
     Spectrum { r: f, g: f, b: f }
   }
 
+  /// The color of a blackbody radiator at a temperature in kelvin, like 2700 for a tungsten bulb or 6500 for daylight,
+  /// with its spectrum scaled so that it peaks at 1.  Cooler radiators peak in the infrared, so they come out dimmer.
+  // pbrt: BlackbodyNormalized()
+  pub fn blackbody(temperature: f64) -> Spectrum {
+    let peak = blackbody(WIEN_DISPLACEMENT / temperature, temperature);
+    if peak <= 0. {
+      return Spectrum::default();
+    }
+    // Weigh the spectrum by how much each wavelength excites the eye's cones, for its XYZ color
+    let (mut x, mut y, mut z, mut y_integral) = (0., 0., 0., 0.);
+    for nm in VISIBLE_NANOMETERS {
+      let wavelength = nm as f64;
+      let radiance = blackbody(wavelength * 1e-9, temperature) / peak;
+      let (x_bar, y_bar, z_bar) = color_matching(wavelength);
+      x += radiance * x_bar;
+      y += radiance * y_bar;
+      z += radiance * z_bar;
+      y_integral += y_bar;
+    }
+    let (x, y, z) = (x / y_integral, y / y_integral, z / y_integral);
+    // Linear sRGB, with a D65 white point, dropping the little that lies outside its gamut at very low temperatures
+    Spectrum {
+      r: (3.240_454_2 * x - 1.537_138_5 * y - 0.498_531_4 * z).max(0.),
+      g: (-0.969_266_0 * x + 1.876_010_8 * y + 0.041_556_0 * z).max(0.),
+      b: (0.055_643_4 * x - 0.204_025_9 * y + 1.057_225_2 * z).max(0.),
+    }
+  }
+
   pub fn luminance(&self) -> f64 {
     self.r * LUMINANCE_WEIGHT[0] + self.g * LUMINANCE_WEIGHT[1] + self.b * LUMINANCE_WEIGHT[2]
   }
 
+  /// Scale the color down, preserving its hue, so its luminance is at most `max`
+  pub fn clamp_luminance(&self, max: f64) -> Spectrum {
+    let lum = self.luminance();
+    if lum > max {
+      *self * (max / lum)
+    } else {
+      *self
+    }
+  }
+
   pub fn is_valid(&self) -> Option<RadianceProblems> {
     use RadianceProblems::*;
     if self.r.is_nan() || self.g.is_nan() || self.b.is_nan() {
@@ -52,6 +90,59 @@ impl Spectrum {
   pub fn sqrt(&self) -> Spectrum {
     Spectrum { r: self.r.sqrt(), g: self.g.sqrt(), b: self.b.sqrt() }
   }
+
+  pub fn exp(&self) -> Spectrum {
+    Spectrum { r: self.r.exp(), g: self.g.exp(), b: self.b.exp() }
+  }
+
+  pub fn max_component(&self) -> f64 {
+    self.r.max(self.g).max(self.b)
+  }
+
+  pub fn average(&self) -> f64 {
+    (self.r + self.g + self.b) / 3.
+  }
+
+  /// One of the red, green or blue components, by index
+  pub fn channel(&self, index: usize) -> f64 {
+    match index {
+      0 => self.r,
+      1 => self.g,
+      _ => self.b,
+    }
+  }
+}
+
+/// Wien's displacement constant, in metre kelvins: the wavelength at which a blackbody radiator is brightest,
+/// multiplied by its temperature
+const WIEN_DISPLACEMENT: f64 = 2.897_771_955e-3;
+/// The wavelengths, in nanometres, over which colors are found from spectra
+const VISIBLE_NANOMETERS: std::ops::RangeInclusive<u32> = 360..=830;
+
+/// Planck's law: the radiance given off at a wavelength in metres by a blackbody radiator at a temperature in kelvin
+pub fn blackbody(wavelength: f64, temperature: f64) -> f64 {
+  if temperature <= 0. {
+    return 0.;
+  }
+  const SPEED_OF_LIGHT: f64 = 299_792_458.;
+  const PLANCK: f64 = 6.626_070_15e-34;
+  const BOLTZMANN: f64 = 1.380_649e-23;
+  2. * PLANCK * SPEED_OF_LIGHT * SPEED_OF_LIGHT /
+    (wavelength.powi(5) * ((PLANCK * SPEED_OF_LIGHT / (wavelength * BOLTZMANN * temperature)).exp() - 1.))
+}
+
+/// The CIE 1931 color matching functions at a wavelength in nanometres, from the multi-lobe Gaussian fit in
+/// Wyman, Sloan and Shirley's "Simple Analytic Approximations to the CIE XYZ Color Matching Functions"
+fn color_matching(wavelength: f64) -> (f64, f64, f64) {
+  let lobe = |mean: f64, below: f64, above: f64| {
+    let t = (wavelength - mean) / if wavelength < mean { below } else { above };
+    (-0.5 * t * t).exp()
+  };
+  (
+    1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7) - 0.065 * lobe(501.1, 20.4, 26.2),
+    0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+    1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8),
+  )
 }
 
 impl Add<f64> for Spectrum {
@@ -1,8 +1,29 @@
-use crate::{geometry::{Intersection, Point2, Point3, Ray, Vector3}, render::Spectrum};
+use crate::{geometry::{Intersection, Normal3, Point2, Ray, Vector3}, render::Spectrum};
 
-use super::Scene;
+use super::{AreaLight, LightBounds, DistantLight, GoniometricLight, InfiniteAreaLight, PointLight, ProjectionLight, Scene, SpotLight};
+use bitflags::bitflags;
 use enum_dispatch::enum_dispatch;
 
+bitflags! {
+  pub struct LightFlags: u8 {
+    const NONE              = 0b00000000;
+    /// Emits from a single point, so can't be hit by a ray
+    const DELTA_POSITION    = 0b00000001;
+    /// Emits in a single direction, so can't be hit by a ray
+    const DELTA_DIRECTION   = 0b00000010;
+    /// Emits from the surface of a shape
+    const AREA              = 0b00000100;
+    /// Surrounds the whole scene
+    const INFINITE          = 0b00001000;
+  }
+}
+
+impl LightFlags {
+  pub fn is_delta(&self) -> bool {
+    self.intersects(LightFlags::DELTA_POSITION | LightFlags::DELTA_DIRECTION)
+  }
+}
+
 #[derive(Default)]
 pub struct RadianceSample {
   pub color: Spectrum,
@@ -11,18 +32,46 @@ pub struct RadianceSample {
   pub intersections: (Intersection, Intersection)
 }
 
+/// A ray of light leaving a light source, chosen by `Light::sample_emission`
+#[derive(Default)]
+pub struct EmissionSample {
+  pub color: Spectrum,
+  pub ray: Ray,
+  /// The surface normal at the point the ray leaves from, or the ray direction for lights without a surface
+  pub normal: Normal3,
+  /// Probability density of choosing the ray's origin, with respect to area
+  pub position_probability: f64,
+  /// Probability density of choosing the ray's direction, with respect to solid angle
+  pub direction_probability: f64,
+}
+
 #[enum_dispatch]
 pub trait Light {
   fn preprocess(&mut self, scene: &Scene);
   fn power(&self) -> Spectrum;
   fn background_radiance(&self, ray: &Ray) -> Spectrum; // pbrt: Le()
   fn sample_radiance(&self, interaction: &Intersection, point: Point2) -> RadianceSample; // pbrt: Sample_Li()
+  /// The density with which `sample_radiance` would have chosen the given direction, with respect to solid angle
+  fn radiance_probability(&self, intersection: &Intersection, incident_direction: Vector3) -> f64; // pbrt: Pdf_Li()
+  fn flags(&self) -> LightFlags;
+  /// Choose a ray of light leaving the light, for tracing paths from the lights into the scene
+  fn sample_emission(&self, position_sample: Point2, direction_sample: Point2) -> EmissionSample; // pbrt: Sample_Le()
+  /// The position and direction densities with which `sample_emission` would have chosen the given ray
+  fn emission_probability(&self, ray: &Ray, normal: Normal3) -> (f64, f64); // pbrt: Pdf_Le()
+  /// Where the light is and which way it shines, for light samplers that favour the lights near a point.
+  /// Lights outside the scene have no bounds.
+  fn bounds(&self) -> Option<LightBounds> { None }
 }
 
 #[enum_dispatch(Light)]
 pub enum LightInstance {
   NullLight,
   PointLight,
+  SpotLight,
+  GoniometricLight,
+  ProjectionLight,
+  DistantLight,
+  InfiniteAreaLight,
   AreaLight,
 }
 
@@ -40,57 +89,8 @@ impl Light for NullLight {
   fn sample_radiance(&self, _: &Intersection, _: Point2) -> RadianceSample {
     RadianceSample::default()
   }
+  fn radiance_probability(&self, _: &Intersection, _: Vector3) -> f64 { 0. }
+  fn flags(&self) -> LightFlags { LightFlags::NONE }
+  fn sample_emission(&self, _: Point2, _: Point2) -> EmissionSample { EmissionSample::default() }
+  fn emission_probability(&self, _: &Ray, _: Normal3) -> (f64, f64) { (0., 0.) }
 }
-
-pub struct PointLight {
-  pub position: Point3,
-  pub color: Spectrum,
-}
-
-impl Light for PointLight {
-  fn preprocess(&mut self, _: &Scene) {}
-  fn power(&self) -> Spectrum { self.color * 4. * 3.141592 }
-  fn background_radiance(&self, _: &Ray) -> Spectrum { Spectrum { r: 0.1, g: 0.1, b: 0.1 } }
-  fn sample_radiance(&self, intersection: &Intersection, _: Point2) -> RadianceSample {
-    let offset = Vector3::from(self.position - intersection.point);
-    let incident_direction = offset.normalized();
-    let color = self.color / offset.length_squared();
-    let light_interaction = Intersection {
-      point: self.position,
-      distance: offset.length(),
-      ..Default::default()
-    };
-    return RadianceSample {
-      color,
-      incident_direction,
-      probability_distribution: 1.,
-      intersections: (intersection.clone(), light_interaction),
-    }
-  }
-}
-
-#[derive(Clone)]
-pub struct AreaLight {
-  pub emitted_color: Spectrum,
-}
-
-impl Light for AreaLight {
-    fn preprocess(&mut self, _scene: &Scene) {}
-
-    fn power(&self) -> Spectrum { Spectrum::default() }
-    fn background_radiance(&self, _ray: &Ray) -> Spectrum { Spectrum { r: 1., g: 1., b: 1. } }
-    fn sample_radiance(&self, _: &Intersection, _point: Point2) -> RadianceSample { RadianceSample::default() }
-}
-
-impl AreaLight {
-  // pbrt: L()
-  pub fn emitted_radiance(&self, intersection: &Intersection, direction: Vector3) -> Spectrum {
-    // TODO: This is actually DiffuseAreaLight
-    // TODO: should this be shading normal?
-    if direction.dot(intersection.normal.into()) > 0. {
-      self.emitted_color.clone()
-    } else {
-      Spectrum::default()
-    }
-  }
-}
\ No newline at end of file
@@ -0,0 +1,380 @@
+use std::f64::consts::PI;
+
+use enum_dispatch::enum_dispatch;
+
+use crate::{geometry::{Bounds3, DirectionCone, Intersection, Normal3, Point3, Vector3}, render::Distribution1D};
+
+use super::{Light, LightInstance};
+
+/// Where a light is and which way it shines, for guessing how much it could light a point without looking at the light itself
+///
+/// The light gives off light in directions within `cos_theta_o` of `direction`, fading out over a further `cos_theta_e`.
+#[derive(Copy, Clone, Debug)]
+pub struct LightBounds {
+  pub bounds: Bounds3,
+  /// Roughly how bright the light is in the direction it shines most, as an intensity
+  pub intensity: f64,
+  pub direction: Vector3,
+  /// Cosine of the angle from `direction` within which the light shines at full strength
+  pub cos_theta_o: f64,
+  /// Cosine of the angle beyond `cos_theta_o` over which the light fades out
+  pub cos_theta_e: f64,
+  /// Whether the light also shines the opposite way to `direction`, like a two sided area light
+  pub two_sided: bool,
+}
+
+impl LightBounds {
+  /// A light at a single point, shining in directions within `cone`
+  pub fn point(position: Point3, intensity: f64, cone: DirectionCone, cos_theta_e: f64) -> Self {
+    Self {
+      bounds: Bounds3::new(position, position),
+      intensity,
+      direction: cone.direction,
+      cos_theta_o: cone.cos_theta,
+      cos_theta_e,
+      two_sided: false,
+    }
+  }
+
+  pub fn centroid(&self) -> Point3 {
+    self.bounds.min + (self.bounds.max - self.bounds.min) / 2.
+  }
+
+  /// Bounds covering both lights
+  pub fn union(&self, other: &Self) -> Self {
+    if self.intensity == 0. {
+      return *other;
+    }
+    if other.intensity == 0. {
+      return *self;
+    }
+    let cone = DirectionCone::new(self.direction, self.cos_theta_o).union(&DirectionCone::new(other.direction, other.cos_theta_o));
+    Self {
+      bounds: self.bounds.union(&other.bounds),
+      intensity: self.intensity + other.intensity,
+      direction: cone.direction,
+      cos_theta_o: cone.cos_theta,
+      cos_theta_e: self.cos_theta_e.min(other.cos_theta_e),
+      two_sided: self.two_sided || other.two_sided,
+    }
+  }
+
+  /// A conservative guess at how much light could reach a point with the given surface normal, which is zero for points in media
+  // pbrt: LightBounds::Importance()
+  pub fn importance(&self, point: Point3, normal: Normal3) -> f64 {
+    let center = self.centroid();
+    let offset = point - center;
+    let distance_sq = offset.length_squared().max((self.bounds.max - self.bounds.min).length() / 2.);
+
+    // The smallest angle between the light's cone of directions and the direction from the light to the point
+    let towards_point = if offset.length_squared() > 0. { offset.normalized() } else { self.direction };
+    let mut cos_theta_w = self.direction.dot(towards_point);
+    if self.two_sided {
+      cos_theta_w = cos_theta_w.abs();
+    }
+    let sin_theta_w = safe_sqrt(1. - cos_theta_w * cos_theta_w);
+    let cos_theta_b = DirectionCone::subtended_by(&self.bounds, point).cos_theta;
+    let sin_theta_b = safe_sqrt(1. - cos_theta_b * cos_theta_b);
+    let sin_theta_o = safe_sqrt(1. - self.cos_theta_o * self.cos_theta_o);
+    let (sin_theta_x, cos_theta_x) = subtract_angles(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+    let (_, cos_theta) = subtract_angles(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+    if cos_theta <= self.cos_theta_e {
+      return 0.;
+    }
+
+    let mut importance = self.intensity * cos_theta / distance_sq;
+    // Light arriving at a grazing angle to a surface counts for less
+    if normal.length_squared() > 0. {
+      let cos_theta_i = towards_point.dot(normal.normalized().into()).abs();
+      let sin_theta_i = safe_sqrt(1. - cos_theta_i * cos_theta_i);
+      importance *= subtract_angles(sin_theta_i, cos_theta_i, sin_theta_b, cos_theta_b).1;
+    }
+    importance.max(0.)
+  }
+
+  /// How costly it'd be to have these lights together in a node, for choosing where to split them
+  // pbrt: LightBVH::EvaluateCost()
+  fn cost(&self, node_bounds: &Bounds3, axis: u8) -> f64 {
+    let theta_o = self.cos_theta_o.clamp(-1., 1.).acos();
+    let theta_e = self.cos_theta_e.clamp(-1., 1.).acos();
+    let theta_w = (theta_o + theta_e).min(PI);
+    let sin_theta_o = safe_sqrt(1. - self.cos_theta_o * self.cos_theta_o);
+    let solid_angle = 2. * PI * (1. - self.cos_theta_o) +
+      PI / 2. * (2. * theta_w * sin_theta_o - (theta_o - 2. * theta_w).cos() - 2. * theta_o * sin_theta_o + self.cos_theta_o);
+    let extent = node_bounds.max - node_bounds.min;
+    let longest = extent.x.max(extent.y).max(extent.z);
+    let regularization = if extent[axis] > 0. { longest / extent[axis] } else { 1. };
+    self.intensity * solid_angle * regularization * surface_area(&self.bounds)
+  }
+}
+
+fn safe_sqrt(x: f64) -> f64 {
+  x.max(0.).sqrt()
+}
+
+/// The sine and cosine of the difference of two angles, or zero if the second is bigger
+fn subtract_angles(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> (f64, f64) {
+  if cos_a > cos_b {
+    (0., 1.)
+  } else {
+    (sin_a * cos_b - cos_a * sin_b, cos_a * cos_b + sin_a * sin_b)
+  }
+}
+
+fn surface_area(bounds: &Bounds3) -> f64 {
+  let d = bounds.max - bounds.min;
+  2. * (d.x * d.y + d.x * d.z + d.y * d.z)
+}
+
+/// Chooses which of the scene's lights to take a sample from, so integrators can take one sample from one light
+/// rather than one from each
+#[enum_dispatch]
+pub trait LightSampler {
+  /// Choose a light to light the reference point with, returning its index and the probability of choosing it
+  fn sample(&self, reference: &Intersection, u: f64) -> Option<(usize, f64)>;
+  /// Choose a light without regard to any point it'll light, such as for starting light paths
+  fn sample_any(&self, u: f64) -> Option<(usize, f64)>;
+  /// The probability that `sample_any` would choose the light with the given index
+  fn probability_any(&self, light: usize) -> f64;
+}
+
+#[enum_dispatch(LightSampler)]
+pub enum LightSamplerInstance {
+  UniformLightSampler,
+  PowerLightSampler,
+  BVHLightSampler,
+}
+
+/// Chooses every light with the same probability
+pub struct UniformLightSampler {
+  pub count: usize,
+}
+
+impl UniformLightSampler {
+  pub fn new(lights: &[LightInstance]) -> Self {
+    Self { count: lights.len() }
+  }
+}
+
+impl LightSampler for UniformLightSampler {
+  fn sample(&self, _: &Intersection, u: f64) -> Option<(usize, f64)> { self.sample_any(u) }
+  fn sample_any(&self, u: f64) -> Option<(usize, f64)> {
+    if self.count == 0 {
+      return None;
+    }
+    Some((((u * self.count as f64) as usize).min(self.count - 1), 1. / self.count as f64))
+  }
+  fn probability_any(&self, _: usize) -> f64 {
+    if self.count == 0 { 0. } else { 1. / self.count as f64 }
+  }
+}
+
+/// Chooses lights in proportion to their power, so bright lights get more samples than dim ones
+/// wherever they are
+pub struct PowerLightSampler {
+  /// Empty if there are no lights
+  pub distribution: Option<Distribution1D>,
+}
+
+impl PowerLightSampler {
+  pub fn new(lights: &[LightInstance]) -> Self {
+    let distribution = if lights.is_empty() {
+      None
+    } else {
+      Some(Distribution1D::new(lights.iter().map(|light| light.power().luminance()).collect()))
+    };
+    Self { distribution }
+  }
+}
+
+impl LightSampler for PowerLightSampler {
+  fn sample(&self, _: &Intersection, u: f64) -> Option<(usize, f64)> { self.sample_any(u) }
+  fn sample_any(&self, u: f64) -> Option<(usize, f64)> {
+    self.distribution.as_ref().map(|distribution| distribution.sample_discrete(u))
+  }
+  fn probability_any(&self, light: usize) -> f64 {
+    self.distribution.as_ref().map_or(0., |distribution| distribution.discrete_probability(light))
+  }
+}
+
+pub enum LightNode {
+  Leaf { bounds: LightBounds, light: usize },
+  Interior { bounds: LightBounds, second_child: usize },
+}
+
+impl LightNode {
+  pub fn bounds(&self) -> &LightBounds {
+    match self {
+      LightNode::Leaf { bounds, .. } | LightNode::Interior { bounds, .. } => bounds,
+    }
+  }
+}
+
+/// Chooses lights according to how much they could light each point, from a tree of the lights' bounds,
+/// so that with many small lights each point mostly samples the ones near it.
+///
+/// Lights without bounds, like those outside the scene, are chosen separately and uniformly.
+/// Light paths don't have a point to light, so start from lights chosen by power.
+// pbrt: BVHLightSampler
+pub struct BVHLightSampler {
+  /// Depth first, with each interior node's first child straight after it
+  pub nodes: Vec<LightNode>,
+  /// Indices of the lights that have no bounds
+  pub infinite_lights: Vec<usize>,
+  pub power: PowerLightSampler,
+}
+
+impl BVHLightSampler {
+  pub fn new(lights: &[LightInstance]) -> Self {
+    let mut sampler = Self {
+      nodes: vec![],
+      infinite_lights: vec![],
+      power: PowerLightSampler::new(lights),
+    };
+    let mut bounded = vec![];
+    for (index, light) in lights.iter().enumerate() {
+      match light.bounds() {
+        Some(bounds) if bounds.intensity > 0. => bounded.push((index, bounds)),
+        // Lights that can't give off any light are never chosen
+        Some(_) => {},
+        None => sampler.infinite_lights.push(index),
+      }
+    }
+    if !bounded.is_empty() {
+      sampler.build(&mut bounded);
+    }
+    sampler
+  }
+
+  /// Add nodes for the lights, returning their bounds
+  fn build(&mut self, lights: &mut [(usize, LightBounds)]) -> LightBounds {
+    let index = self.nodes.len();
+    if lights.len() == 1 {
+      let (light, bounds) = lights[0];
+      self.nodes.push(LightNode::Leaf { bounds, light });
+      return bounds;
+    }
+
+    let split = self.choose_split(lights);
+    // Fill in the interior node once the children are built, since its bounds depend on them
+    self.nodes.push(LightNode::Interior { bounds: lights[0].1, second_child: 0 });
+    let (first, second) = lights.split_at_mut(split);
+    let first = self.build(first);
+    let second_child = self.nodes.len();
+    let second = self.build(second);
+    let bounds = first.union(&second);
+    self.nodes[index] = LightNode::Interior { bounds, second_child };
+    bounds
+  }
+
+  /// Partition the lights into two groups, returning how many are in the first, by bucketing them along each axis
+  /// and picking the split that's cheapest by `LightBounds::cost`
+  fn choose_split(&self, lights: &mut [(usize, LightBounds)]) -> usize {
+    const BUCKETS: usize = 12;
+    let bounds = lights.iter().skip(1).fold(lights[0].1, |b, (_, l)| b.union(l)).bounds;
+    let first_centroid = lights[0].1.centroid();
+    let centroid_bounds = lights.iter().fold(Bounds3::new(first_centroid, first_centroid), |b, (_, l)| b.encompass(l.centroid()));
+
+    let bucket_of = |light: &LightBounds, axis: u8| {
+      let (min, max) = (centroid_bounds.min[axis], centroid_bounds.max[axis]);
+      (((light.centroid()[axis] - min) / (max - min) * BUCKETS as f64) as usize).min(BUCKETS - 1)
+    };
+
+    let mut best: Option<(f64, u8, usize)> = None;
+    for axis in 0..3 {
+      if centroid_bounds.max[axis] == centroid_bounds.min[axis] {
+        continue;
+      }
+      let mut buckets: [Option<LightBounds>; BUCKETS] = [None; BUCKETS];
+      for (_, light) in lights.iter() {
+        let bucket = &mut buckets[bucket_of(light, axis)];
+        *bucket = Some(bucket.map_or(*light, |b| b.union(light)));
+      }
+      let union = |buckets: &[Option<LightBounds>]| buckets.iter().flatten().fold(None, |total: Option<LightBounds>, b| {
+        Some(total.map_or(*b, |total| total.union(b)))
+      });
+      for split in 1..BUCKETS {
+        let cost = match (union(&buckets[..split]), union(&buckets[split..])) {
+          (Some(below), Some(above)) => below.cost(&bounds, axis) + above.cost(&bounds, axis),
+          _ => continue,
+        };
+        if best.map_or(true, |(best_cost, _, _)| cost < best_cost) {
+          best = Some((cost, axis, split));
+        }
+      }
+    }
+
+    match best {
+      Some((_, axis, split)) => {
+        let mut count = 0;
+        for i in 0..lights.len() {
+          if bucket_of(&lights[i].1, axis) < split {
+            lights.swap(i, count);
+            count += 1;
+          }
+        }
+        count
+      },
+      // The lights are all in the same place, so any split is as good as another
+      None => lights.len() / 2,
+    }
+  }
+
+  /// The probability of choosing a light from the tree, rather than one without bounds
+  fn tree_probability(&self) -> f64 {
+    let infinite = self.infinite_lights.len() as f64;
+    let tree = if self.nodes.is_empty() { 0. } else { 1. };
+    if infinite + tree == 0. { 0. } else { tree / (infinite + tree) }
+  }
+
+  fn importance(&self, node: usize, reference: &Intersection) -> f64 {
+    self.nodes[node].bounds().importance(reference.point, reference.normal)
+  }
+}
+
+impl LightSampler for BVHLightSampler {
+  fn sample(&self, reference: &Intersection, u: f64) -> Option<(usize, f64)> {
+    if self.nodes.is_empty() && self.infinite_lights.is_empty() {
+      return None;
+    }
+    let tree_probability = self.tree_probability();
+    let infinite_probability = 1. - tree_probability;
+    if u < infinite_probability {
+      let count = self.infinite_lights.len();
+      let index = ((u / infinite_probability * count as f64) as usize).min(count - 1);
+      return Some((self.infinite_lights[index], infinite_probability / count as f64));
+    }
+    if self.importance(0, reference) == 0. {
+      return None;
+    }
+
+    // Walk down the tree, choosing each child in proportion to how much it could light the point
+    let mut u = ((u - infinite_probability) / tree_probability).min(1. - f64::EPSILON);
+    let mut probability = tree_probability;
+    let mut node = 0;
+    loop {
+      match self.nodes[node] {
+        LightNode::Leaf { light, .. } => return Some((light, probability)),
+        LightNode::Interior { second_child, .. } => {
+          let (first, second) = (self.importance(node + 1, reference), self.importance(second_child, reference));
+          if first == 0. && second == 0. {
+            return None;
+          }
+          let first_probability = first / (first + second);
+          if u < first_probability {
+            node += 1;
+            u = (u / first_probability).min(1. - f64::EPSILON);
+            probability *= first_probability;
+          } else {
+            node = second_child;
+            u = ((u - first_probability) / (1. - first_probability)).min(1. - f64::EPSILON);
+            probability *= 1. - first_probability;
+          }
+        },
+      }
+    }
+  }
+
+  fn sample_any(&self, u: f64) -> Option<(usize, f64)> { self.power.sample_any(u) }
+  fn probability_any(&self, light: usize) -> f64 { self.power.probability_any(light) }
+}
@@ -0,0 +1,121 @@
+use std::{f64::consts::PI, sync::Arc};
+
+use crate::{geometry::{Intersection, Normal3, Point2, Ray, Vector3}, render::{HdrImage, Spectrum, WrapMode, cosine_sample_hemisphere}};
+use crate::scene::{EmissionSample, Light, LightBounds, LightFlags, LightInstance, RadianceSample, Scene, Shape, ShapeInstance};
+
+/// Light given off evenly in every direction by the surface of a shape, from the side its normal faces,
+/// or from both sides
+#[derive(Clone)]
+pub struct AreaLight {
+  pub emitted_color: Spectrum,
+  pub scale: f64,
+  pub two_sided: bool,
+  /// An image tinting the light across the surface by its texture coordinates, like the picture on a screen.
+  /// Shared between the lights on each triangle of a mesh.
+  pub image: Option<Arc<HdrImage>>,
+  /// The light given off on average over the surface, before scaling, which is just `emitted_color` without an
+  /// image.  Worked out once for the whole mesh, rather than summing the image for every triangle.
+  pub average_color: Spectrum,
+  /// The surface giving off the light, shared with the primitive it belongs to
+  pub shape: Arc<ShapeInstance>,
+}
+
+impl AreaLight {
+  pub fn new(shape: Arc<ShapeInstance>, emitted_color: Spectrum) -> Self {
+    Self { emitted_color, scale: 1., two_sided: false, image: None, average_color: emitted_color, shape }
+  }
+
+  // pbrt: L()
+  pub fn emitted_radiance(&self, intersection: &Intersection, direction: Vector3) -> Spectrum {
+    if !self.two_sided && direction.dot(intersection.normal.into()) <= 0. {
+      return Spectrum::default();
+    }
+    match &self.image {
+      // Images have v = 0 at the bottom, but are stored from the top
+      Some(image) => self.emitted_color * image.lookup(Point2::new(intersection.uv.x, 1. - intersection.uv.y), WrapMode::Repeat) * self.scale,
+      None => self.emitted_color * self.scale,
+    }
+  }
+
+  /// Whether this is the light given off by the same surface as `light`, so that light reaching a point from
+  /// this surface can be credited to it
+  pub fn is_light(&self, light: &LightInstance) -> bool {
+    match light {
+      LightInstance::AreaLight(other) => Arc::ptr_eq(&self.shape, &other.shape),
+      _ => false,
+    }
+  }
+}
+
+impl Light for AreaLight {
+  fn preprocess(&mut self, _scene: &Scene) {}
+
+  fn power(&self) -> Spectrum {
+    let sides = if self.two_sided { 2. } else { 1. };
+    self.average_color * self.scale * sides * self.shape.area() * PI
+  }
+  fn background_radiance(&self, _ray: &Ray) -> Spectrum { Spectrum::default() }
+  fn sample_radiance(&self, intersection: &Intersection, u: Point2) -> RadianceSample {
+    let sample = self.shape.sample_from(intersection, u);
+    let offset = sample.intersection.point - intersection.point;
+    if sample.probability_distribution == 0. || offset.length_squared() == 0. {
+      return RadianceSample::default();
+    }
+    let incident_direction = offset.normalized();
+    let light_interaction = Intersection { distance: offset.length(), ..sample.intersection };
+    RadianceSample {
+      color: self.emitted_radiance(&light_interaction, -incident_direction),
+      incident_direction,
+      probability_distribution: sample.probability_distribution,
+      intersections: (*intersection, light_interaction),
+    }
+  }
+  fn radiance_probability(&self, intersection: &Intersection, incident_direction: Vector3) -> f64 {
+    self.shape.pdf(intersection, incident_direction)
+  }
+  fn flags(&self) -> LightFlags { LightFlags::AREA }
+  fn sample_emission(&self, position_sample: Point2, direction_sample: Point2) -> EmissionSample {
+    let sample = self.shape.sample(position_sample);
+    let normal: Vector3 = sample.intersection.normal.into();
+
+    // Two sided lights spend half their samples on each side
+    let mut u = direction_sample;
+    let mut back = false;
+    if self.two_sided {
+      back = u.x >= 0.5;
+      u.x = if back { (u.x - 0.5) * 2. } else { u.x * 2. }.min(1. - f64::EPSILON);
+    }
+    let local = cosine_sample_hemisphere(u);
+    let (s, t) = normal.coordinate_system();
+    let direction = s * local.x + t * local.y + normal * local.z;
+    let direction = if back { -direction } else { direction };
+    let sides = if self.two_sided { 2. } else { 1. };
+
+    EmissionSample {
+      color: self.emitted_radiance(&sample.intersection, direction),
+      ray: sample.intersection.spawn_ray(direction),
+      normal: sample.intersection.normal,
+      position_probability: sample.probability_distribution,
+      direction_probability: local.z / (PI * sides),
+    }
+  }
+  fn emission_probability(&self, ray: &Ray, normal: Normal3) -> (f64, f64) {
+    let cos_theta = ray.direction.normalized().dot(normal.into());
+    let direction_probability = match self.two_sided {
+      true => cos_theta.abs() / (2. * PI),
+      false => cos_theta.max(0.) / PI,
+    };
+    (1. / self.shape.area(), direction_probability)
+  }
+  fn bounds(&self) -> Option<LightBounds> {
+    let normals = self.shape.normal_bounds();
+    Some(LightBounds {
+      bounds: self.shape.world_bounds(),
+      intensity: self.average_color.luminance() * self.scale * self.shape.area(),
+      direction: normals.direction,
+      cos_theta_o: normals.cos_theta,
+      cos_theta_e: 0.,
+      two_sided: self.two_sided,
+    })
+  }
+}
\ No newline at end of file
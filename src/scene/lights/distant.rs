@@ -0,0 +1,122 @@
+use std::f64::consts::PI;
+
+use crate::{geometry::{Intersection, Normal3, Point2, Point3, Ray, TO_RADIANS, Transform, Vector3}, render::{Spectrum, concentric_sample_disk, uniform_cone_pdf, uniform_sample_cone}};
+use crate::scene::{EmissionSample, Light, LightFlags, PbrtParameters, RadianceSample, Scene};
+
+/// Light arriving from so far away that it reaches everything in the scene from the same direction, like sunlight
+///
+/// Given an angular diameter, the light comes instead from a small disc in the sky, like the sun itself,
+/// which softens the edges of shadows
+pub struct DistantLight {
+  /// Points towards the light
+  pub direction: Vector3,
+  /// How much light lands on a surface facing the light
+  pub irradiance: Spectrum,
+  /// Cosine of the angle between the middle of the disc and its edge, or exactly 1 for light from a single direction
+  pub cos_max: f64,
+  world_center: Point3,
+  world_radius: f64,
+}
+
+impl DistantLight {
+  /// A light the size of the sun, as seen from earth, has an angular diameter of about 0.53 degrees
+  pub fn sun(direction: Vector3, irradiance: Spectrum, angular_diameter: f64) -> Self {
+    Self {
+      direction: direction.normalized(),
+      irradiance,
+      cos_max: (angular_diameter * TO_RADIANS / 2.).cos(),
+      world_center: Point3 { x: 0., y: 0., z: 0. },
+      world_radius: 0.,
+    }
+  }
+
+  /// Build the light from a statement like `LightSource "distant" "point from" [0 10 0] "point to" [0 0 0] "rgb L" [3 3 3]`,
+  /// with an optional `"float angulardiameter"` in degrees to make it a sun
+  pub fn from_pbrt(statement: &str, light_to_world: Transform) -> std::io::Result<Self> {
+    let parameters = PbrtParameters::parse(statement)?;
+    let irradiance = parameters.spectrum("L", Spectrum::white())? * parameters.float("scale", 1.)?;
+    let from = parameters.point("from", Point3 { x: 0., y: 0., z: 0. })?;
+    let to = parameters.point("to", Point3 { x: 0., y: 0., z: 1. })?;
+    let angular_diameter = parameters.float("angulardiameter", 0.)?;
+    Ok(Self::sun(light_to_world * (from - to), irradiance, angular_diameter))
+  }
+
+  fn is_sun(&self) -> bool {
+    self.cos_max < 1.
+  }
+
+  /// The radiance from every direction within the sun's disc, such that it adds up to the irradiance
+  fn disc_radiance(&self) -> Spectrum {
+    self.irradiance / (PI * (1. - self.cos_max * self.cos_max))
+  }
+
+  fn within_disc(&self, direction: Vector3) -> bool {
+    self.is_sun() && direction.normalized().dot(self.direction) >= self.cos_max
+  }
+
+  /// Choose a direction towards the light, and the density it was chosen with
+  fn sample_direction(&self, u: Point2) -> (Vector3, f64) {
+    if !self.is_sun() {
+      return (self.direction, 1.);
+    }
+    let local = uniform_sample_cone(u, self.cos_max);
+    let (du, dv) = self.direction.coordinate_system();
+    (du * local.x + dv * local.y + self.direction * local.z, uniform_cone_pdf(self.cos_max))
+  }
+}
+
+impl Light for DistantLight {
+  fn preprocess(&mut self, scene: &Scene) {
+    let (center, radius) = scene.world_bounds.bounding_sphere();
+    self.world_center = center;
+    self.world_radius = radius;
+  }
+  fn power(&self) -> Spectrum {
+    // Everything reaching the scene passes through a disc as wide as it
+    self.irradiance * PI * self.world_radius * self.world_radius
+  }
+  fn background_radiance(&self, ray: &Ray) -> Spectrum {
+    if self.within_disc(ray.direction) { self.disc_radiance() } else { Spectrum::default() }
+  }
+  fn sample_radiance(&self, intersection: &Intersection, u: Point2) -> RadianceSample {
+    let (incident_direction, probability_distribution) = self.sample_direction(u);
+    let color = if self.is_sun() { self.disc_radiance() } else { self.irradiance };
+    // Anywhere outside the scene will do for checking that nothing blocks the light
+    let light_interaction = Intersection {
+      point: intersection.point + incident_direction * (2. * self.world_radius),
+      distance: 2. * self.world_radius,
+      ..Default::default()
+    };
+    RadianceSample {
+      color,
+      incident_direction,
+      probability_distribution,
+      intersections: (*intersection, light_interaction),
+    }
+  }
+  fn radiance_probability(&self, _: &Intersection, incident_direction: Vector3) -> f64 {
+    if self.within_disc(incident_direction) { uniform_cone_pdf(self.cos_max) } else { 0. }
+  }
+  fn flags(&self) -> LightFlags {
+    if self.is_sun() { LightFlags::INFINITE } else { LightFlags::DELTA_DIRECTION }
+  }
+  fn sample_emission(&self, position_sample: Point2, direction_sample: Point2) -> EmissionSample {
+    // Start from a disc facing the light, just outside the scene, so that its rays cover the whole scene
+    let (towards_light, direction_probability) = self.sample_direction(direction_sample);
+    let (du, dv) = towards_light.coordinate_system();
+    let disc = concentric_sample_disk(position_sample);
+    let origin = self.world_center + (towards_light + du * disc.x + dv * disc.y) * self.world_radius;
+    let color = if self.is_sun() { self.disc_radiance() } else { self.irradiance };
+    EmissionSample {
+      color,
+      ray: Ray { origin, direction: -towards_light, time_max: f64::INFINITY },
+      normal: (-towards_light).into(),
+      position_probability: 1. / (PI * self.world_radius * self.world_radius),
+      direction_probability,
+    }
+  }
+  fn emission_probability(&self, ray: &Ray, _: Normal3) -> (f64, f64) {
+    let direction_probability = if self.within_disc(-ray.direction) { uniform_cone_pdf(self.cos_max) } else { 0. };
+    (1. / (PI * self.world_radius * self.world_radius), direction_probability)
+  }
+}
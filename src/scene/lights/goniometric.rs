@@ -0,0 +1,281 @@
+use std::{f64::consts::{PI, TAU}, fs, io, path::Path};
+
+use crate::{geometry::{DirectionCone, Intersection, Normal3, Point2, Point3, Ray, Transform, Vector3}, render::{Spectrum, uniform_sample_sphere, uniform_sphere_pdf}};
+use crate::scene::{EmissionSample, Light, LightBounds, LightFlags, PbrtParameters, RadianceSample, Scene};
+
+/// How brightly a real luminaire shines in each direction, read from an IES LM-63 photometric file
+///
+/// Only type C photometry is supported, where vertical angles are measured from straight down
+/// and horizontal angles go around that axis.
+pub struct IesProfile {
+  /// In degrees, increasing
+  pub vertical_angles: Vec<f64>,
+  /// In degrees, increasing
+  pub horizontal_angles: Vec<f64>,
+  /// In candela, for each horizontal angle in turn, one value for each vertical angle
+  pub candela: Vec<f64>,
+}
+
+impl IesProfile {
+  pub fn read(file: &Path) -> io::Result<Self> {
+    Self::parse(&fs::read_to_string(file)?)
+  }
+
+  pub fn parse(text: &str) -> io::Result<Self> {
+    // Everything up to the TILT line is free form text describing the luminaire
+    let mut lines = text.lines();
+    let tilt = lines.by_ref()
+      .find_map(|line| line.trim().strip_prefix("TILT="))
+      .ok_or_else(|| invalid("IES file has no TILT line"))?
+      .trim()
+      .to_string();
+    let rest: Vec<&str> = lines.collect();
+    let mut numbers = rest.iter().flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+      .filter(|word| !word.is_empty())
+      .map(|word| word.parse::<f64>().map_err(|_| invalid(&format!("Expected a number in IES file, found {:?}", word))));
+    let mut next = || numbers.next().unwrap_or_else(|| Err(invalid("IES file ends unexpectedly")));
+
+    match tilt.as_str() {
+      "NONE" => {},
+      // How the lamp's output changes as the luminaire is tilted, which doesn't matter for a fixed light
+      "INCLUDE" => {
+        let _geometry = next()?;
+        let pairs = next()? as usize;
+        for _ in 0..pairs.saturating_mul(2) {
+          next()?;
+        }
+      },
+      other => return Err(invalid(&format!("Unsupported IES tilt file {:?}", other))),
+    }
+
+    let _lamps = next()?;
+    let _lumens_per_lamp = next()?;
+    let multiplier = next()?;
+    let vertical_count = next()? as usize;
+    let horizontal_count = next()? as usize;
+    let photometric_type = next()?;
+    if photometric_type != 1. {
+      return Err(invalid("Only type C IES photometry is supported"));
+    }
+    // Units, dimensions of the luminous opening
+    for _ in 0..4 {
+      next()?;
+    }
+    let ballast_factor = next()?;
+    let ballast_lamp_factor = next()?;
+    let _input_watts = next()?;
+
+    if vertical_count == 0 || horizontal_count == 0 {
+      return Err(invalid("IES file has no candela values"));
+    }
+    let vertical_angles = (0..vertical_count).map(|_| next()).collect::<io::Result<Vec<_>>>()?;
+    let horizontal_angles = (0..horizontal_count).map(|_| next()).collect::<io::Result<Vec<_>>>()?;
+    let scale = multiplier * ballast_factor * ballast_lamp_factor;
+    let candela_count = vertical_count.checked_mul(horizontal_count).ok_or_else(|| invalid("IES file has too many candela values"))?;
+    let candela = (0..candela_count).map(|_| next().map(|c| c * scale)).collect::<io::Result<Vec<_>>>()?;
+    Ok(Self { vertical_angles, horizontal_angles, candela })
+  }
+
+  /// The intensity in candela at an angle from straight down and an angle around that axis, both in degrees
+  pub fn intensity(&self, vertical: f64, horizontal: f64) -> f64 {
+    // Files only give the part of the distribution that isn't repeated by symmetry
+    let last = *self.horizontal_angles.last().unwrap();
+    let horizontal = horizontal.rem_euclid(360.);
+    let horizontal = if last == 0. {
+      0.
+    } else if last == 90. {
+      let h = horizontal % 180.;
+      if h > 90. { 180. - h } else { h }
+    } else if last == 180. && horizontal > 180. {
+      360. - horizontal
+    } else {
+      horizontal
+    };
+
+    let (v, dv) = match find_angle(&self.vertical_angles, vertical) {
+      Some(found) => found,
+      None => return 0.,
+    };
+    let (h, dh) = find_angle(&self.horizontal_angles, horizontal).unwrap_or((0, 0.));
+    let vertical_count = self.vertical_angles.len();
+    let value = |h: usize, v: usize| {
+      let h = h.min(self.horizontal_angles.len() - 1);
+      self.candela[h * vertical_count + v.min(vertical_count - 1)]
+    };
+    (value(h, v) * (1. - dv) + value(h, v + 1) * dv) * (1. - dh) +
+      (value(h + 1, v) * (1. - dv) + value(h + 1, v + 1) * dv) * dh
+  }
+}
+
+/// Which interval of a list of increasing angles an angle falls in, and how far along it, if it's in range at all
+fn find_angle(angles: &[f64], angle: f64) -> Option<(usize, f64)> {
+  if angle < angles[0] || angle > *angles.last().unwrap() {
+    return None;
+  }
+  if angles.len() == 1 {
+    return Some((0, 0.));
+  }
+  let i = angles.partition_point(|&a| a <= angle).saturating_sub(1).min(angles.len() - 2);
+  let t = (angle - angles[i]) / (angles[i + 1] - angles[i]);
+  Some((i, t.clamp(0., 1.)))
+}
+
+/// A point light whose brightness varies with direction, following the measured output of a real luminaire
+///
+/// In light space the luminaire points down -z, and horizontal angles go from +x towards +y
+pub struct GoniometricLight {
+  pub light_to_world: Transform,
+  pub world_to_light: Transform,
+  pub position: Point3,
+  /// Multiplies the profile's candela values
+  pub intensity: Spectrum,
+  pub profile: IesProfile,
+}
+
+impl GoniometricLight {
+  pub fn new(light_to_world: Transform, intensity: Spectrum, profile: IesProfile) -> Self {
+    Self {
+      light_to_world,
+      world_to_light: light_to_world.inverse(),
+      position: light_to_world * Point3 { x: 0., y: 0., z: 0. },
+      intensity,
+      profile,
+    }
+  }
+
+  /// Build the light from a statement like `LightSource "goniometric" "string filename" "downlight.ies" "float scale" 0.01`,
+  /// reading the file relative to `directory`
+  pub fn from_pbrt(statement: &str, light_to_world: Transform, directory: &Path) -> io::Result<Self> {
+    let parameters = PbrtParameters::parse(statement)?;
+    let intensity = parameters.spectrum("I", Spectrum::white())? * parameters.float("scale", 1.)?;
+    let from = parameters.point("from", Point3 { x: 0., y: 0., z: 0. })?;
+    let file = parameters.string("filename").ok_or_else(|| invalid("Goniometric lights need a \"string filename\""))?;
+    let profile = IesProfile::read(&directory.join(file))?;
+    Ok(Self::new(light_to_world * Transform::translate(Vector3::from(from)), intensity, profile))
+  }
+
+  /// The intensity leaving the light in a direction in light space
+  fn scale(&self, direction: Vector3) -> Spectrum {
+    let w = direction.normalized();
+    let vertical = (-w.z).clamp(-1., 1.).acos().to_degrees();
+    let horizontal = w.y.atan2(w.x).to_degrees();
+    self.intensity * self.profile.intensity(vertical, horizontal)
+  }
+}
+
+impl Light for GoniometricLight {
+  fn preprocess(&mut self, _: &Scene) {}
+  fn power(&self) -> Spectrum {
+    // Add up the intensity over the sphere of directions
+    let (width, height) = (64, 32);
+    let mut total = Spectrum::default();
+    for row in 0..height {
+      let theta = PI * (row as f64 + 0.5) / height as f64;
+      let solid_angle = (TAU / width as f64) * (PI / height as f64) * theta.sin();
+      for column in 0..width {
+        let phi = TAU * (column as f64 + 0.5) / width as f64;
+        let direction = Vector3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+        total += self.scale(direction) * solid_angle;
+      }
+    }
+    total
+  }
+  fn background_radiance(&self, _: &Ray) -> Spectrum { Spectrum::default() }
+  fn sample_radiance(&self, intersection: &Intersection, _: Point2) -> RadianceSample {
+    let offset = self.position - intersection.point;
+    let incident_direction = offset.normalized();
+    let color = self.scale(self.world_to_light * -incident_direction) / offset.length_squared();
+    let light_interaction = Intersection {
+      point: self.position,
+      distance: offset.length(),
+      ..Default::default()
+    };
+    RadianceSample {
+      color,
+      incident_direction,
+      probability_distribution: 1.,
+      intersections: (*intersection, light_interaction),
+    }
+  }
+  fn radiance_probability(&self, _: &Intersection, _: Vector3) -> f64 { 0. }
+  fn flags(&self) -> LightFlags { LightFlags::DELTA_POSITION }
+  fn sample_emission(&self, direction_sample: Point2, _: Point2) -> EmissionSample {
+    let local = uniform_sample_sphere(direction_sample);
+    let direction = self.light_to_world * local;
+    EmissionSample {
+      color: self.scale(local),
+      ray: Ray { origin: self.position, direction, time_max: f64::INFINITY },
+      normal: direction.into(),
+      position_probability: 1.,
+      direction_probability: uniform_sphere_pdf(),
+    }
+  }
+  fn emission_probability(&self, _: &Ray, _: Normal3) -> (f64, f64) {
+    (0., uniform_sphere_pdf())
+  }
+  fn bounds(&self) -> Option<LightBounds> {
+    let brightest = self.profile.candela.iter().cloned().fold(0., f64::max);
+    Some(LightBounds::point(self.position, self.intensity.luminance() * brightest, DirectionCone::entire_sphere(), 0.))
+  }
+}
+
+fn invalid(message: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const PROFILE: &str = "IESNA:LM-63-2002
+[TEST] A made up downlight
+TILT=NONE
+1 1000 2 3 2 1 1 0.5 0.5 0
+1.0 1.0 100
+0 45 90
+0 180
+100 50, 0
+200 100 0
+";
+
+  #[test]
+  fn parses_profile() {
+    let profile = IesProfile::parse(PROFILE).unwrap();
+    assert_eq!(profile.vertical_angles, vec![0., 45., 90.]);
+    assert_eq!(profile.horizontal_angles, vec![0., 180.]);
+    // Scaled by the multiplier
+    assert_eq!(profile.candela, vec![200., 100., 0., 400., 200., 0.]);
+    assert_eq!(profile.intensity(0., 0.), 200.);
+    assert_eq!(profile.intensity(22.5, 0.), 150.);
+    assert_eq!(profile.intensity(0., 90.), 300.);
+    // Mirrored past 180 degrees, and nothing beyond the last vertical angle
+    assert_eq!(profile.intensity(0., 270.), 300.);
+    assert_eq!(profile.intensity(120., 0.), 0.);
+  }
+
+  #[test]
+  fn skips_included_tilt() {
+    let profile = PROFILE.replace("TILT=NONE", "TILT=INCLUDE\n1\n2\n0 90\n1 1");
+    assert_eq!(IesProfile::parse(&profile).unwrap().candela.len(), 6);
+  }
+
+  #[test]
+  fn rejects_truncated_profile() {
+    let end = PROFILE.rfind("200").unwrap();
+    for length in 0..end {
+      if PROFILE.is_char_boundary(length) {
+        assert!(IesProfile::parse(&PROFILE[..length]).is_err(), "accepted the first {} bytes", length);
+      }
+    }
+  }
+
+  #[test]
+  fn rejects_malformed_profile() {
+    assert!(IesProfile::parse(&PROFILE.replace("TILT=NONE", "TILT=lamp.tlt")).is_err());
+    assert!(IesProfile::parse(&PROFILE.replace("0 45 90", "0 forty-five 90")).is_err());
+    // Type A photometry
+    assert!(IesProfile::parse(&PROFILE.replace("3 2 1 1", "3 2 3 1")).is_err());
+    assert!(IesProfile::parse(&PROFILE.replace("3 2 1 1", "0 2 1 1")).is_err());
+    assert!(IesProfile::parse(&PROFILE.replace("3 2 1 1", "1e300 1e300 1 1")).is_err());
+  }
+}
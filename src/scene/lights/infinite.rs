@@ -0,0 +1,147 @@
+use std::{f64::consts::{PI, TAU}, path::Path};
+
+use crate::{geometry::{Intersection, Normal3, Point2, Point3, Ray, Transform, Vector3}, render::{Distribution2D, HdrImage, Spectrum, WrapMode, concentric_sample_disk}};
+use crate::scene::{EmissionSample, Light, LightFlags, PbrtParameters, RadianceSample, Scene};
+
+/// Light arriving from every direction, from an environment surrounding the whole scene
+///
+/// The environment is an equirectangular image, with +z in light space at the top of the image,
+/// and directions are chosen in proportion to how bright the image is in that direction
+pub struct InfiniteAreaLight {
+  pub light_to_world: Transform,
+  pub world_to_light: Transform,
+  pub map: HdrImage,
+  distribution: Distribution2D,
+  world_center: Point3,
+  world_radius: f64,
+}
+
+impl InfiniteAreaLight {
+  pub fn new(light_to_world: Transform, map: HdrImage) -> Self {
+    // Rows near the poles cover less of the sphere, so they're chosen less often
+    let mut function = Vec::with_capacity(map.width * map.height);
+    for y in 0..map.height {
+      let sin_theta = (PI * (y as f64 + 0.5) / map.height as f64).sin();
+      function.extend((0..map.width).map(|x| map.texel(x, y).luminance().max(0.) * sin_theta));
+    }
+    let distribution = Distribution2D::new(&function, map.width, map.height);
+    Self {
+      light_to_world,
+      world_to_light: light_to_world.inverse(),
+      map,
+      distribution,
+      world_center: Point3 { x: 0., y: 0., z: 0. },
+      world_radius: 0.,
+    }
+  }
+
+  /// Build the light from a statement like `LightSource "infinite" "string mapname" "sky.exr" "float scale" 2`,
+  /// reading the map relative to `directory`.  Without a map, the light is the same color in every direction.
+  pub fn from_pbrt(statement: &str, light_to_world: Transform, directory: &Path) -> std::io::Result<Self> {
+    let parameters = PbrtParameters::parse(statement)?;
+    let scale = parameters.spectrum("L", Spectrum::white())? * parameters.float("scale", 1.)?;
+    let mut map = match parameters.string("mapname") {
+      Some(file) => HdrImage::read(&directory.join(file))?,
+      None => HdrImage::constant(Spectrum::white()),
+    };
+    for pixel in &mut map.pixels {
+      *pixel = *pixel * scale;
+    }
+    Ok(Self::new(light_to_world, map))
+  }
+
+  /// Where a direction in world space falls on the map, and the sine of its angle from +z in light space
+  fn map_point(&self, direction: Vector3) -> (Point2, f64) {
+    let w = (self.world_to_light * direction).normalized();
+    let theta = w.z.clamp(-1., 1.).acos();
+    let phi = w.y.atan2(w.x);
+    let phi = if phi < 0. { phi + TAU } else { phi };
+    (Point2::new(phi / TAU, theta / PI), theta.sin())
+  }
+
+  /// The direction in world space for a point on the map, and the sine of its angle from +z in light space
+  fn map_direction(&self, p: Point2) -> (Vector3, f64) {
+    let (theta, phi) = (p.y * PI, p.x * TAU);
+    let sin_theta = theta.sin();
+    let w = Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), theta.cos());
+    ((self.light_to_world * w).normalized(), sin_theta)
+  }
+
+  /// Convert a density over the map into one over solid angle
+  fn solid_angle_density(map_pdf: f64, sin_theta: f64) -> f64 {
+    if sin_theta == 0. { 0. } else { map_pdf / (2. * PI * PI * sin_theta) }
+  }
+}
+
+impl Light for InfiniteAreaLight {
+  fn preprocess(&mut self, scene: &Scene) {
+    let (center, radius) = scene.world_bounds.bounding_sphere();
+    self.world_center = center;
+    self.world_radius = radius;
+  }
+  fn power(&self) -> Spectrum {
+    // The average radiance over the sphere, arriving at a disc as wide as the scene
+    let mut total = Spectrum::default();
+    let mut weight = 0.;
+    for y in 0..self.map.height {
+      let sin_theta = (PI * (y as f64 + 0.5) / self.map.height as f64).sin();
+      for x in 0..self.map.width {
+        total += self.map.texel(x, y) * sin_theta;
+      }
+      weight += sin_theta * self.map.width as f64;
+    }
+    total / weight * PI * self.world_radius * self.world_radius
+  }
+  fn background_radiance(&self, ray: &Ray) -> Spectrum {
+    self.map.lookup(self.map_point(ray.direction).0, WrapMode::Repeat)
+  }
+  fn sample_radiance(&self, intersection: &Intersection, u: Point2) -> RadianceSample {
+    let (p, map_pdf) = self.distribution.sample_continuous(u);
+    let (incident_direction, sin_theta) = self.map_direction(p);
+    let probability_distribution = Self::solid_angle_density(map_pdf, sin_theta);
+    if probability_distribution == 0. {
+      return RadianceSample::default();
+    }
+    // Anywhere outside the scene will do for checking that nothing blocks the light
+    let light_interaction = Intersection {
+      point: intersection.point + incident_direction * (2. * self.world_radius),
+      distance: 2. * self.world_radius,
+      ..Default::default()
+    };
+    RadianceSample {
+      color: self.map.lookup(p, WrapMode::Repeat),
+      incident_direction,
+      probability_distribution,
+      intersections: (*intersection, light_interaction),
+    }
+  }
+  fn radiance_probability(&self, _: &Intersection, incident_direction: Vector3) -> f64 {
+    let (p, sin_theta) = self.map_point(incident_direction);
+    Self::solid_angle_density(self.distribution.probability(p), sin_theta)
+  }
+  fn flags(&self) -> LightFlags { LightFlags::INFINITE }
+  fn sample_emission(&self, position_sample: Point2, direction_sample: Point2) -> EmissionSample {
+    let (p, map_pdf) = self.distribution.sample_continuous(direction_sample);
+    let (towards_light, sin_theta) = self.map_direction(p);
+    let direction_probability = Self::solid_angle_density(map_pdf, sin_theta);
+    if direction_probability == 0. {
+      return EmissionSample::default();
+    }
+    // Start from a disc facing the light, just outside the scene, so that its rays cover the whole scene
+    let (du, dv) = towards_light.coordinate_system();
+    let disc = concentric_sample_disk(position_sample);
+    let origin = self.world_center + (towards_light + du * disc.x + dv * disc.y) * self.world_radius;
+    EmissionSample {
+      color: self.map.lookup(p, WrapMode::Repeat),
+      ray: Ray { origin, direction: -towards_light, time_max: f64::INFINITY },
+      normal: (-towards_light).into(),
+      position_probability: 1. / (PI * self.world_radius * self.world_radius),
+      direction_probability,
+    }
+  }
+  fn emission_probability(&self, ray: &Ray, _: Normal3) -> (f64, f64) {
+    let (p, sin_theta) = self.map_point(-ray.direction);
+    let direction_probability = Self::solid_angle_density(self.distribution.probability(p), sin_theta);
+    (1. / (PI * self.world_radius * self.world_radius), direction_probability)
+  }
+}
@@ -0,0 +1,16 @@
+mod area;
+mod distant;
+mod goniometric;
+mod infinite;
+mod point;
+mod projection;
+mod sky;
+mod spot;
+pub use area::*;
+pub use distant::*;
+pub use goniometric::*;
+pub use infinite::*;
+pub use point::*;
+pub use projection::*;
+pub use sky::*;
+pub use spot::*;
\ No newline at end of file
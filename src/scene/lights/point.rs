@@ -0,0 +1,49 @@
+use crate::{geometry::{DirectionCone, Intersection, Normal3, Point2, Point3, Ray, Vector3}, render::{Spectrum, uniform_sample_sphere, uniform_sphere_pdf}};
+use crate::scene::{EmissionSample, Light, LightBounds, LightFlags, RadianceSample, Scene};
+
+pub struct PointLight {
+  pub position: Point3,
+  pub color: Spectrum,
+}
+
+impl Light for PointLight {
+  fn preprocess(&mut self, _: &Scene) {}
+  fn power(&self) -> Spectrum { self.color * 4. * 3.141592 }
+  fn background_radiance(&self, _: &Ray) -> Spectrum { Spectrum::default() }
+  fn sample_radiance(&self, intersection: &Intersection, _: Point2) -> RadianceSample {
+    let offset = self.position - intersection.point;
+    let incident_direction = offset.normalized();
+    let color = self.color / offset.length_squared();
+    let light_interaction = Intersection {
+      point: self.position,
+      distance: offset.length(),
+      ..Default::default()
+    };
+    return RadianceSample {
+      color,
+      incident_direction,
+      probability_distribution: 1.,
+      intersections: (*intersection, light_interaction),
+    }
+  }
+  // No direction could be chosen by chance, since there's only one that reaches the light
+  fn radiance_probability(&self, _: &Intersection, _: Vector3) -> f64 { 0. }
+  fn flags(&self) -> LightFlags { LightFlags::DELTA_POSITION }
+  fn sample_emission(&self, direction_sample: Point2, _: Point2) -> EmissionSample {
+    // Point lights shine equally in every direction
+    let direction = uniform_sample_sphere(direction_sample);
+    EmissionSample {
+      color: self.color,
+      ray: Ray { origin: self.position, direction, time_max: f64::INFINITY },
+      normal: direction.into(),
+      position_probability: 1.,
+      direction_probability: uniform_sphere_pdf(),
+    }
+  }
+  fn emission_probability(&self, _: &Ray, _: Normal3) -> (f64, f64) {
+    (0., uniform_sphere_pdf())
+  }
+  fn bounds(&self) -> Option<LightBounds> {
+    Some(LightBounds::point(self.position, self.color.luminance(), DirectionCone::entire_sphere(), 0.))
+  }
+}
@@ -0,0 +1,135 @@
+use std::{io, path::Path};
+
+use crate::{geometry::{Bounds2, DirectionCone, Intersection, Normal3, Point2, Point3, Ray, TO_RADIANS, Transform, Vector3}, render::{HdrImage, Spectrum, WrapMode, screen_to_raster, uniform_cone_pdf, uniform_sample_cone}};
+use crate::scene::{EmissionSample, Light, LightBounds, LightFlags, PbrtParameters, RadianceSample, Scene};
+
+/// A point light shining an image out through a frustum, like a slide projector or a gobo in front of a stage light
+///
+/// In light space the light sits at the origin and projects down +z, with the top of the image towards +y
+pub struct ProjectionLight {
+  pub light_to_world: Transform,
+  pub world_to_light: Transform,
+  pub position: Point3,
+  pub intensity: Spectrum,
+  pub image: HdrImage,
+  /// Projects light space onto the screen, as for a perspective camera
+  pub light_to_screen: Transform,
+  /// The part of the screen the image covers, with the image's aspect ratio
+  pub screen_bounds: Bounds2<f64>,
+  pub screen_to_raster: Transform,
+  /// Cosine of the angle between the axis and the corners of the image, beyond which there's no light
+  pub cos_total_width: f64,
+}
+
+impl ProjectionLight {
+  /// The field of view is in degrees, across the narrower side of the image
+  pub fn new(light_to_world: Transform, intensity: Spectrum, image: HdrImage, field_of_view: f64) -> Self {
+    let aspect = image.width as f64 / image.height as f64;
+    let (x, y) = if aspect > 1. { (aspect, 1.) } else { (1., 1. / aspect) };
+    let screen_bounds = Bounds2 { min: Point2 { x: -x, y: -y }, max: Point2 { x, y } };
+    let tan_half_angle = (field_of_view * TO_RADIANS / 2.).tan();
+    let corner = Vector3::new(x * tan_half_angle, y * tan_half_angle, 1.);
+    Self {
+      light_to_world,
+      world_to_light: light_to_world.inverse(),
+      position: light_to_world * Point3 { x: 0., y: 0., z: 0. },
+      intensity,
+      light_to_screen: Transform::perspective(field_of_view, 1e-3, 1e30),
+      screen_bounds,
+      screen_to_raster: screen_to_raster(screen_bounds, image.width as f64, image.height as f64),
+      cos_total_width: 1. / corner.length(),
+      image,
+    }
+  }
+
+  /// Build the light from a statement like `LightSource "projection" "string mapname" "gobo.png" "float fov" 30`,
+  /// reading the image relative to `directory`
+  pub fn from_pbrt(statement: &str, light_to_world: Transform, directory: &Path) -> io::Result<Self> {
+    let parameters = PbrtParameters::parse(statement)?;
+    let intensity = parameters.spectrum("I", Spectrum::white())? * parameters.float("scale", 1.)?;
+    let field_of_view = parameters.float("fov", 45.)?;
+    let image = match parameters.string("mapname") {
+      Some(file) => HdrImage::read(&directory.join(file))?,
+      None => HdrImage::constant(Spectrum::white()),
+    };
+    Ok(Self::new(light_to_world, intensity, image, field_of_view))
+  }
+
+  /// How much of the intensity leaves the light in a direction in light space, from the part of the image it passes through
+  fn projection(&self, direction: Vector3) -> Spectrum {
+    if direction.z <= 0. {
+      return Spectrum::default();
+    }
+    let screen = self.light_to_screen * Point3::from(direction);
+    let (min, max) = (self.screen_bounds.min, self.screen_bounds.max);
+    if screen.x < min.x || screen.x > max.x || screen.y < min.y || screen.y > max.y {
+      return Spectrum::default();
+    }
+    let raster = self.screen_to_raster * Point3::new(screen.x, screen.y, 0.);
+    self.image.lookup(Point2::new(raster.x / self.image.width as f64, raster.y / self.image.height as f64), WrapMode::Clamp)
+  }
+}
+
+impl Light for ProjectionLight {
+  fn preprocess(&mut self, _: &Scene) {}
+  fn power(&self) -> Spectrum {
+    // Each pixel covers a patch of the plane a unit in front of the light, which subtends less of the sphere towards the edges
+    let edge = Vector3::from(self.light_to_screen.inverse() * Point3::new(1., 1., 0.5));
+    let tan_half_angle = edge.x / edge.z;
+    let (width, height) = (self.image.width as f64, self.image.height as f64);
+    let (min, max) = (self.screen_bounds.min, self.screen_bounds.max);
+    let pixel_area = (max.x - min.x) * (max.y - min.y) * tan_half_angle * tan_half_angle / (width * height);
+    let mut total = Spectrum::default();
+    for y in 0..self.image.height {
+      for x in 0..self.image.width {
+        let u = (min.x + (max.x - min.x) * (x as f64 + 0.5) / width) * tan_half_angle;
+        let v = (max.y - (max.y - min.y) * (y as f64 + 0.5) / height) * tan_half_angle;
+        let solid_angle = pixel_area / (1. + u * u + v * v).powf(1.5);
+        total += self.image.texel(x, y) * solid_angle;
+      }
+    }
+    self.intensity * total
+  }
+  fn background_radiance(&self, _: &Ray) -> Spectrum { Spectrum::default() }
+  fn sample_radiance(&self, intersection: &Intersection, _: Point2) -> RadianceSample {
+    let offset = self.position - intersection.point;
+    let incident_direction = offset.normalized();
+    let color = self.intensity * self.projection(self.world_to_light * -incident_direction) / offset.length_squared();
+    let light_interaction = Intersection {
+      point: self.position,
+      distance: offset.length(),
+      ..Default::default()
+    };
+    RadianceSample {
+      color,
+      incident_direction,
+      probability_distribution: 1.,
+      intersections: (*intersection, light_interaction),
+    }
+  }
+  fn radiance_probability(&self, _: &Intersection, _: Vector3) -> f64 { 0. }
+  fn flags(&self) -> LightFlags { LightFlags::DELTA_POSITION }
+  fn sample_emission(&self, direction_sample: Point2, _: Point2) -> EmissionSample {
+    let local = uniform_sample_cone(direction_sample, self.cos_total_width);
+    let direction = self.light_to_world * local;
+    EmissionSample {
+      color: self.intensity * self.projection(local),
+      ray: Ray { origin: self.position, direction, time_max: f64::INFINITY },
+      normal: direction.into(),
+      position_probability: 1.,
+      direction_probability: uniform_cone_pdf(self.cos_total_width),
+    }
+  }
+  fn emission_probability(&self, ray: &Ray, _: Normal3) -> (f64, f64) {
+    let cos_theta = (self.world_to_light * ray.direction).normalized().z;
+    let direction_probability = if cos_theta >= self.cos_total_width { uniform_cone_pdf(self.cos_total_width) } else { 0. };
+    (0., direction_probability)
+  }
+  fn bounds(&self) -> Option<LightBounds> {
+    // The image can be bright anywhere up to the edge of the frustum, and is dark beyond it
+    let brightest = self.image.pixels.iter().map(|pixel| pixel.luminance()).fold(0., f64::max);
+    let cone = DirectionCone::from_direction(self.light_to_world * Vector3::new(0., 0., 1.));
+    Some(LightBounds::point(self.position, self.intensity.luminance() * brightest, cone, self.cos_total_width))
+  }
+}
+
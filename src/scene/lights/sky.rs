@@ -0,0 +1,160 @@
+use std::f64::consts::{FRAC_PI_2, PI, TAU};
+
+use crate::{geometry::{Transform, Vector3}, render::{HdrImage, Spectrum}};
+use crate::scene::{DistantLight, InfiniteAreaLight, LightInstance, PbrtParameters};
+
+/// A clear daytime sky, following Preetham, Shirley and Smits' "A Practical Analytic Model for Daylight"
+///
+/// In light space the zenith is +z, as at the top of an `InfiniteAreaLight`'s map.  The sky's radiance is in kilocandelas
+/// per square metre and the sun's irradiance in kilolux, so under a high sun a white floor has a radiance of around 35,
+/// and a scale of a few hundredths suits a camera that expects values around 1.  Below the horizon is flat ground,
+/// lit by the sky and sun.
+pub struct SkyModel {
+  /// Points towards the sun, in light space
+  pub sun_direction: Vector3,
+  /// How hazy the air is, from 2 for a very clear day to around 10 for a hazy one
+  pub turbidity: f64,
+  /// The sun's angle from the zenith
+  sun_theta: f64,
+  /// The coefficients of the Perez function for each of luminance and the x and y chromaticities
+  perez: [[f64; 5]; 3],
+  /// Luminance and x and y chromaticities at the zenith
+  zenith: [f64; 3],
+  /// The ground is diffuse, lit by the sky and sun, so it's equally bright in every direction
+  ground_radiance: Spectrum,
+}
+
+impl SkyModel {
+  pub fn new(sun_direction: Vector3, turbidity: f64, ground_albedo: Spectrum) -> Self {
+    let sun_direction = sun_direction.normalized();
+    // The model only covers a sun above the horizon
+    let sun_theta = sun_direction.z.clamp(-1., 1.).acos().min(FRAC_PI_2);
+    let t = turbidity;
+    let perez = [
+      [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+      [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+      [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+    ];
+
+    let chi = (4. / 9. - t / 120.) * (PI - 2. * sun_theta);
+    let luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+    let (s, s2, s3) = (sun_theta, sun_theta * sun_theta, sun_theta * sun_theta * sun_theta);
+    let x = t * t * (0.00166 * s3 - 0.00375 * s2 + 0.00209 * s) +
+      t * (-0.02903 * s3 + 0.06377 * s2 - 0.03202 * s + 0.00394) +
+      (0.11693 * s3 - 0.21196 * s2 + 0.06052 * s + 0.25886);
+    let y = t * t * (0.00275 * s3 - 0.00610 * s2 + 0.00317 * s) +
+      t * (-0.04214 * s3 + 0.08970 * s2 - 0.04153 * s + 0.00516) +
+      (0.15346 * s3 - 0.26756 * s2 + 0.06670 * s + 0.26688);
+
+    let mut sky = Self {
+      sun_direction,
+      turbidity,
+      sun_theta,
+      perez,
+      zenith: [luminance, x, y],
+      ground_radiance: Spectrum::default(),
+    };
+
+    // The ground is diffuse, so it's as bright in every direction as the light landing on it allows
+    let (width, height) = (64, 32);
+    let mut irradiance = sky.sun_irradiance() * sun_direction.z.max(0.);
+    for row in 0..height / 2 {
+      let theta = PI * (row as f64 + 0.5) / height as f64;
+      let solid_angle = (TAU / width as f64) * (PI / height as f64) * theta.sin();
+      for column in 0..width {
+        let phi = TAU * (column as f64 + 0.5) / width as f64;
+        let direction = Vector3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+        irradiance += sky.sky_radiance(direction) * theta.cos() * solid_angle;
+      }
+    }
+    sky.ground_radiance = ground_albedo * irradiance / PI;
+    sky
+  }
+
+  /// Build the sky from a statement like `LightSource "sky" "vector sundir" [0 1 1] "float turbidity" 3`,
+  /// along with a sun to match unless it has `"bool sun" "false"`
+  pub fn from_pbrt(statement: &str, light_to_world: Transform) -> std::io::Result<Vec<LightInstance>> {
+    let parameters = PbrtParameters::parse(statement)?;
+    let sun_direction = parameters.get("sundir", &[0., 0., 1.])?;
+    let sky = Self::new(
+      Vector3::new(sun_direction[0], sun_direction[1], sun_direction[2]),
+      parameters.float("turbidity", 3.)?,
+      parameters.spectrum("albedo", Spectrum::greyscale(0.1))?,
+    );
+    let scale = parameters.float("scale", 1.)?;
+    let mut lights = vec![sky.light(light_to_world, scale).into()];
+    if parameters.string("sun") != Some("false") {
+      lights.push(sky.sun(light_to_world, parameters.float("sunangulardiameter", 0.53)?, scale).into());
+    }
+    Ok(lights)
+  }
+
+  /// The Perez function for one of luminance and the chromaticities, relative to its value at the zenith
+  fn perez(&self, channel: usize, cos_theta: f64, gamma: f64) -> f64 {
+    let [a, b, c, d, e] = self.perez[channel];
+    let f = |cos_theta: f64, gamma: f64| (1. + a * (b / cos_theta).exp()) * (1. + c * (d * gamma).exp() + e * gamma.cos().powi(2));
+    self.zenith[channel] * f(cos_theta, gamma) / f(1., self.sun_theta)
+  }
+
+  /// The radiance of the sky in a direction above the horizon
+  fn sky_radiance(&self, direction: Vector3) -> Spectrum {
+    let direction = direction.normalized();
+    let cos_theta = direction.z.max(1e-4);
+    let gamma = direction.dot(self.sun_direction).clamp(-1., 1.).acos();
+    let luminance = self.perez(0, cos_theta, gamma);
+    let (x, y) = (self.perez(1, cos_theta, gamma), self.perez(2, cos_theta, gamma));
+    xyz_to_rgb(x / y * luminance, luminance, (1. - x - y) / y * luminance)
+  }
+
+  /// The radiance arriving from a direction in light space, whether from the sky or the ground
+  pub fn radiance(&self, direction: Vector3) -> Spectrum {
+    if direction.z > 0. { self.sky_radiance(direction) } else { self.ground_radiance }
+  }
+
+  /// How much light from the sun lands on a surface facing it, after scattering by air and haze on the way through
+  /// the atmosphere.  Red, green and blue are taken at 680, 550 and 440 nanometres.
+  pub fn sun_irradiance(&self) -> Spectrum {
+    if self.sun_direction.z <= 0. {
+      return Spectrum::default();
+    }
+    // How much air the light passes through, relative to coming straight down
+    let relative_mass = 1. / (self.sun_theta.cos() + 0.15 * (93.885 - self.sun_theta.to_degrees()).powf(-1.253));
+    let beta = 0.04608365 * self.turbidity - 0.04586025;
+    let transmittance = |micrometres: f64| {
+      let rayleigh = (-0.008735 * micrometres.powf(-4.08) * relative_mass).exp();
+      let aerosol = (-beta * micrometres.powf(-1.3) * relative_mass).exp();
+      rayleigh * aerosol
+    };
+    // Sunlight above the atmosphere gives about 128 kilolux
+    Spectrum { r: transmittance(0.68), g: transmittance(0.55), b: transmittance(0.44) } * 128.
+  }
+
+  /// Bake the sky into an environment map, to light the scene with
+  pub fn light(&self, light_to_world: Transform, scale: f64) -> InfiniteAreaLight {
+    let (width, height) = (512, 256);
+    let mut pixels = Vec::with_capacity(width * height);
+    for row in 0..height {
+      let theta = PI * (row as f64 + 0.5) / height as f64;
+      for column in 0..width {
+        let phi = TAU * (column as f64 + 0.5) / width as f64;
+        let direction = Vector3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+        pixels.push(self.radiance(direction) * scale);
+      }
+    }
+    InfiniteAreaLight::new(light_to_world, HdrImage { width, height, pixels })
+  }
+
+  /// The sun to go with the sky, with an angular diameter in degrees, or 0 for a sharp edged sun
+  pub fn sun(&self, light_to_world: Transform, angular_diameter: f64, scale: f64) -> DistantLight {
+    DistantLight::sun(light_to_world * self.sun_direction, self.sun_irradiance() * scale, angular_diameter)
+  }
+}
+
+/// Convert from CIE XYZ to linear sRGB, dropping any colors sRGB can't show
+fn xyz_to_rgb(x: f64, y: f64, z: f64) -> Spectrum {
+  Spectrum {
+    r: (3.2404542 * x - 1.5371385 * y - 0.4985314 * z).max(0.),
+    g: (-0.9692660 * x + 1.8760108 * y + 0.0415560 * z).max(0.),
+    b: (0.0556434 * x - 0.2040259 * y + 1.0572252 * z).max(0.),
+  }
+}
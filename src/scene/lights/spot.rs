@@ -0,0 +1,116 @@
+use std::f64::consts::TAU;
+
+use crate::{geometry::{DirectionCone, Intersection, Matrix4x4, Normal3, Point2, Point3, Ray, TO_RADIANS, Transform, Vector3}, render::{Spectrum, uniform_cone_pdf, uniform_sample_cone}};
+use crate::scene::{EmissionSample, Light, LightBounds, LightFlags, PbrtParameters, RadianceSample, Scene};
+
+/// A point light that only shines within a cone, fading out towards its edge
+///
+/// In light space the light sits at the origin and points down +z
+pub struct SpotLight {
+  pub light_to_world: Transform,
+  pub world_to_light: Transform,
+  pub position: Point3,
+  pub intensity: Spectrum,
+  /// Cosine of the angle from the axis beyond which there's no light
+  pub cos_total_width: f64,
+  /// Cosine of the angle from the axis at which the light starts to fade
+  pub cos_falloff_start: f64,
+}
+
+impl SpotLight {
+  /// The angles are in degrees, measured from the axis of the cone
+  pub fn new(light_to_world: Transform, intensity: Spectrum, total_width: f64, falloff_start: f64) -> Self {
+    Self {
+      light_to_world,
+      world_to_light: light_to_world.inverse(),
+      position: light_to_world * Point3 { x: 0., y: 0., z: 0. },
+      intensity,
+      cos_total_width: (total_width * TO_RADIANS).cos(),
+      cos_falloff_start: (falloff_start * TO_RADIANS).cos(),
+    }
+  }
+
+  /// Build the light from a statement like `LightSource "spot" "point from" [0 5 0] "point to" [0 0 0] "float coneangle" 20`
+  pub fn from_pbrt(statement: &str, light_to_world: Transform) -> std::io::Result<Self> {
+    let parameters = PbrtParameters::parse(statement)?;
+    let intensity = parameters.spectrum("I", Spectrum::white())? * parameters.float("scale", 1.)?;
+    let cone_angle = parameters.float("coneangle", 30.)?;
+    let cone_delta = parameters.float("conedeltaangle", 5.)?;
+    let from = parameters.point("from", Point3 { x: 0., y: 0., z: 0. })?;
+    let to = parameters.point("to", Point3 { x: 0., y: 0., z: 1. })?;
+
+    // Turn the light so that +z points from `from` to `to`
+    let direction = (to - from).normalized();
+    let (du, dv) = direction.coordinate_system();
+    let rows = [
+      [du.x, du.y, du.z, 0.],
+      [dv.x, dv.y, dv.z, 0.],
+      [direction.x, direction.y, direction.z, 0.],
+      [0., 0., 0., 1.],
+    ];
+    let direction_to_z = Transform::new(Matrix4x4::new(rows), Some(Matrix4x4::transpose(rows)));
+    let light_to_world = light_to_world * Transform::translate(Vector3::from(from)) * direction_to_z.inverse();
+    Ok(Self::new(light_to_world, intensity, cone_angle, cone_angle - cone_delta))
+  }
+
+  /// How much of the full intensity leaves the light in a direction in light space
+  fn falloff(&self, direction: Vector3) -> f64 {
+    let cos_theta = direction.normalized().z;
+    if cos_theta < self.cos_total_width {
+      return 0.;
+    }
+    if cos_theta >= self.cos_falloff_start {
+      return 1.;
+    }
+    let delta = (cos_theta - self.cos_total_width) / (self.cos_falloff_start - self.cos_total_width);
+    (delta * delta) * (delta * delta)
+  }
+}
+
+impl Light for SpotLight {
+  fn preprocess(&mut self, _: &Scene) {}
+  fn power(&self) -> Spectrum {
+    // Treat the falloff as though it were linear in the cosine, which is close enough to choose lights by
+    self.intensity * TAU * (1. - 0.5 * (self.cos_falloff_start + self.cos_total_width))
+  }
+  fn background_radiance(&self, _: &Ray) -> Spectrum { Spectrum::default() }
+  fn sample_radiance(&self, intersection: &Intersection, _: Point2) -> RadianceSample {
+    let offset = self.position - intersection.point;
+    let incident_direction = offset.normalized();
+    let color = self.intensity * self.falloff(self.world_to_light * -incident_direction) / offset.length_squared();
+    let light_interaction = Intersection {
+      point: self.position,
+      distance: offset.length(),
+      ..Default::default()
+    };
+    RadianceSample {
+      color,
+      incident_direction,
+      probability_distribution: 1.,
+      intersections: (*intersection, light_interaction),
+    }
+  }
+  fn radiance_probability(&self, _: &Intersection, _: Vector3) -> f64 { 0. }
+  fn flags(&self) -> LightFlags { LightFlags::DELTA_POSITION }
+  fn sample_emission(&self, direction_sample: Point2, _: Point2) -> EmissionSample {
+    let local = uniform_sample_cone(direction_sample, self.cos_total_width);
+    let direction = self.light_to_world * local;
+    EmissionSample {
+      color: self.intensity * self.falloff(local),
+      ray: Ray { origin: self.position, direction, time_max: f64::INFINITY },
+      normal: direction.into(),
+      position_probability: 1.,
+      direction_probability: uniform_cone_pdf(self.cos_total_width),
+    }
+  }
+  fn emission_probability(&self, ray: &Ray, _: Normal3) -> (f64, f64) {
+    let cos_theta = (self.world_to_light * ray.direction).normalized().z;
+    let direction_probability = if cos_theta >= self.cos_total_width { uniform_cone_pdf(self.cos_total_width) } else { 0. };
+    (0., direction_probability)
+  }
+  fn bounds(&self) -> Option<LightBounds> {
+    let cone = DirectionCone::new(self.light_to_world * Vector3::new(0., 0., 1.), self.cos_falloff_start);
+    let cos_theta_e = (self.cos_total_width.acos() - self.cos_falloff_start.acos()).cos();
+    Some(LightBounds::point(self.position, self.intensity.luminance(), cone, cos_theta_e))
+  }
+}
@@ -1,9 +1,11 @@
+use std::{collections::hash_map::DefaultHasher, hash::{Hash, Hasher}};
+
 use bumpalo::Bump;
 
 use crate::{geometry::{Intersection}, render::{BSDF, BxDFInstance, Fresnel, LambertianReflection, OrenNayar, Spectrum, SpecularTransmission}};
 use super::{Glass, Matte, Mirror, Plastic};
 
-#[derive(Clone)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum TransportMode {
   Radiance,
   Importance
@@ -21,6 +23,31 @@ pub enum MaterialInstance {
   Glass(Glass),
 }
 
+impl MaterialInstance {
+  /// A number identifying this material.  Materials are copied into every primitive and interaction that uses them,
+  /// so it's a hash of the kind of material and its parameters, the same for every copy
+  pub fn id(&self) -> u64 {
+    let spectrum = |s: Spectrum| [s.r, s.g, s.b];
+    let (kind, parameters): (u8, Vec<f64>) = match self {
+      MaterialInstance::Matte(m) => (0, [&spectrum(m.color)[..], &[m.roughness]].concat()),
+      MaterialInstance::Mirror(m) => (1, spectrum(m.color).to_vec()),
+      MaterialInstance::Plastic(m) => (2, [
+        &spectrum(m.diffuse_reflection)[..], &spectrum(m.glossy_reflection)[..], &[m.roughness, m.remap_roughness as u8 as f64],
+      ].concat()),
+      MaterialInstance::Glass(m) => (3, [
+        &spectrum(m.color_reflected)[..], &spectrum(m.color_transmitted)[..],
+        &[m.roughness.0, m.roughness.1, m.refraction, m.remap_roughness as u8 as f64],
+      ].concat()),
+    };
+    let mut hasher = DefaultHasher::new();
+    kind.hash(&mut hasher);
+    for parameter in parameters {
+      parameter.to_bits().hash(&mut hasher);
+    }
+    hasher.finish()
+  }
+}
+
 // Can't use enum_dispatch because of lifetime parameters
 impl Material for MaterialInstance {
   fn compute_scattering_functions<'a>(&'a self, intersection: &Intersection, arena: &'a Bump, mode: TransportMode, allow_multiple_lobes: bool) -> &'a mut BSDF {
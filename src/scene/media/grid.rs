@@ -0,0 +1,346 @@
+use std::{fs::File, io::{self, BufReader, Read}, path::Path};
+
+use crate::{geometry::{Bounds3, Point3, Ray, Transform, Vector3}, render::{Sampler, SamplerInstance, Spectrum}, scene::{HenyeyGreenstein, Medium, MediumInteraction, MediumSample, PbrtParameters}};
+
+const VOLUME_MAGIC: &[u8; 8] = b"OPTQGRID";
+const VOLUME_VERSION: u32 = 1;
+/// The bytes before the densities in a volume file: the magic, version and resolution
+const VOLUME_HEADER_SIZE: u64 = 24;
+
+/// How many cells the coarse grid of majorants has along each axis
+const MAJORANT_RESOLUTION: usize = 16;
+
+/// A medium whose density varies through space, like smoke or clouds, given by a grid of samples
+/// filling the unit cube in medium space
+///
+/// Distances are sampled using the average of the extinction's channels, so `sigma_a + sigma_s` should be grey
+pub struct GridDensityMedium {
+  /// How much light is absorbed per unit distance, at a density of 1
+  pub sigma_a: Spectrum,
+  /// How much light is scattered per unit distance, at a density of 1
+  pub sigma_s: Spectrum,
+  pub phase: HenyeyGreenstein,
+  world_to_medium: Transform,
+  sigma_e: f64,
+  /// The number of samples along x, y and z
+  resolution: [usize; 3],
+  /// The samples, with x varying fastest and then y
+  density: Vec<f64>,
+  /// The greatest density within each cell of a coarse grid over the medium, so that tracking
+  /// can take long steps through thin or empty regions
+  majorants: Vec<f64>,
+}
+
+impl GridDensityMedium {
+  pub fn new(sigma_a: Spectrum, sigma_s: Spectrum, g: f64, medium_to_world: Transform, resolution: [usize; 3], density: Vec<f64>) -> Self {
+    assert_eq!(density.len(), resolution.iter().product::<usize>(), "Density grid doesn't match its resolution");
+    let majorants = build_majorants(resolution, &density);
+    Self {
+      sigma_a,
+      sigma_s,
+      phase: HenyeyGreenstein { g },
+      world_to_medium: medium_to_world.inverse(),
+      sigma_e: (sigma_a + sigma_s).average(),
+      resolution,
+      density,
+      majorants,
+    }
+  }
+
+  /// Build a medium from a pbrt `MakeNamedMedium` statement for a `"heterogeneous"` medium, with its densities
+  /// given inline as `"float density" [ ... ]`, or read from the binary volume file in `"string filename"`,
+  /// and placed in the world by `object_to_world` and its `p0` and `p1`
+  pub fn from_pbrt(statement: &str, object_to_world: Transform, directory: &Path) -> io::Result<Self> {
+    let parameters = PbrtParameters::parse(statement)?;
+    let (resolution, density) = match parameters.string("filename") {
+      Some(file) => read_volume(&directory.join(file))?,
+      None => {
+        let resolution = [
+          parameters.float("nx", 1.)? as usize,
+          parameters.float("ny", 1.)? as usize,
+          parameters.float("nz", 1.)? as usize,
+        ];
+        let density = parameters.values("density").map(|d| d.to_vec()).unwrap_or_default();
+        check_resolution(resolution, density.len())?;
+        (resolution, density)
+      },
+    };
+    Self::from_parameters(&parameters, object_to_world, resolution, density)
+  }
+
+  /// Load a binary volume file on its own, filling the unit cube with pbrt's default scattering properties
+  pub fn from_volume(file: &Path, object_to_world: Transform) -> io::Result<Self> {
+    let (resolution, density) = read_volume(file)?;
+    Self::from_parameters(&PbrtParameters::parse("")?, object_to_world, resolution, density)
+  }
+
+  fn from_parameters(parameters: &PbrtParameters, object_to_world: Transform, resolution: [usize; 3], density: Vec<f64>) -> io::Result<Self> {
+    let scale = parameters.float("scale", 1.)?;
+    let sigma_a = parameters.spectrum("sigma_a", Spectrum { r: 0.0011, g: 0.0024, b: 0.014 })? * scale;
+    let sigma_s = parameters.spectrum("sigma_s", Spectrum { r: 2.55, g: 3.21, b: 3.77 })? * scale;
+    let g = parameters.float("g", 0.)?;
+
+    let p0 = parameters.point("p0", Point3 { x: 0., y: 0., z: 0. })?;
+    let p1 = parameters.point("p1", Point3 { x: 1., y: 1., z: 1. })?;
+    let medium_to_world = object_to_world *
+      Transform::translate(Vector3::from(p0)) *
+      Transform::scale(p1 - p0);
+    Ok(Self::new(sigma_a, sigma_s, g, medium_to_world, resolution, density))
+  }
+
+  /// The density at a point in medium space, interpolated between the eight nearest samples
+  pub fn density_at(&self, point: Point3) -> f64 {
+    // Each sample sits at the center of its cell
+    let x = point.x * self.resolution[0] as f64 - 0.5;
+    let y = point.y * self.resolution[1] as f64 - 0.5;
+    let z = point.z * self.resolution[2] as f64 - 0.5;
+    let (ix, iy, iz) = (x.floor(), y.floor(), z.floor());
+    let (dx, dy, dz) = (x - ix, y - iy, z - iz);
+    let (ix, iy, iz) = (ix as i64, iy as i64, iz as i64);
+
+    let d = |ox, oy, oz| self.sample(ix + ox, iy + oy, iz + oz);
+    let d00 = lerp(dx, d(0, 0, 0), d(1, 0, 0));
+    let d10 = lerp(dx, d(0, 1, 0), d(1, 1, 0));
+    let d01 = lerp(dx, d(0, 0, 1), d(1, 0, 1));
+    let d11 = lerp(dx, d(0, 1, 1), d(1, 1, 1));
+    lerp(dz, lerp(dy, d00, d10), lerp(dy, d01, d11))
+  }
+
+  /// A single sample of the grid, treating everything outside it as empty
+  fn sample(&self, x: i64, y: i64, z: i64) -> f64 {
+    let [nx, ny, nz] = self.resolution;
+    if x < 0 || y < 0 || z < 0 || x >= nx as i64 || y >= ny as i64 || z >= nz as i64 {
+      return 0.;
+    }
+    self.density[(z as usize * ny + y as usize) * nx + x as usize]
+  }
+
+  /// The ray in medium space, with a unit direction in world space so that its times are distances, and the
+  /// times at which it enters and leaves the grid, if it passes through it
+  fn medium_ray(&self, ray: &Ray) -> Option<(Ray, f64, f64)> {
+    let length = ray.direction.length();
+    let ray = self.world_to_medium * Ray {
+      origin: ray.origin,
+      direction: ray.direction / length,
+      time_max: ray.time_max * length,
+    };
+    let (t_min, t_max) = Bounds3::new(Point3::new(0., 0., 0.), Point3::new(1., 1., 1.)).intersect_times(&ray)?;
+    Some((ray, t_min, t_max))
+  }
+
+  /// Walk the ray through the coarse grid between the two times, calling `visit` with the start and end time of
+  /// each cell it crosses along with that cell's majorant extinction, until `visit` returns false
+  fn for_each_segment(&self, ray: &Ray, t_min: f64, t_max: f64, mut visit: impl FnMut(f64, f64, f64) -> bool) {
+    let resolution = MAJORANT_RESOLUTION as f64;
+    let start = ray.origin + ray.direction * t_min;
+    let origin = [start.x * resolution, start.y * resolution, start.z * resolution];
+    let direction = [ray.direction.x * resolution, ray.direction.y * resolution, ray.direction.z * resolution];
+
+    // Set up a 3D DDA, tracking when the ray next crosses into a new cell along each axis
+    let mut cell = [0i64; 3];
+    let mut step = [0i64; 3];
+    let mut next_time = [f64::INFINITY; 3];
+    let mut delta_time = [f64::INFINITY; 3];
+    for axis in 0..3 {
+      cell[axis] = (origin[axis].floor() as i64).clamp(0, MAJORANT_RESOLUTION as i64 - 1);
+      if direction[axis] > 0. {
+        step[axis] = 1;
+        next_time[axis] = t_min + (cell[axis] as f64 + 1. - origin[axis]) / direction[axis];
+        delta_time[axis] = 1. / direction[axis];
+      } else if direction[axis] < 0. {
+        step[axis] = -1;
+        next_time[axis] = t_min + (cell[axis] as f64 - origin[axis]) / direction[axis];
+        delta_time[axis] = -1. / direction[axis];
+      }
+    }
+
+    let mut time = t_min;
+    loop {
+      let axis = if next_time[0] < next_time[1] {
+        if next_time[0] < next_time[2] { 0 } else { 2 }
+      } else if next_time[1] < next_time[2] { 1 } else { 2 };
+      let end = next_time[axis].min(t_max);
+      let index = ((cell[2] as usize * MAJORANT_RESOLUTION) + cell[1] as usize) * MAJORANT_RESOLUTION + cell[0] as usize;
+      if !visit(time, end, self.majorants[index] * self.sigma_e) || end >= t_max {
+        return;
+      }
+
+      time = end;
+      cell[axis] += step[axis];
+      if cell[axis] < 0 || cell[axis] >= MAJORANT_RESOLUTION as i64 {
+        return;
+      }
+      next_time[axis] += delta_time[axis];
+    }
+  }
+}
+
+impl Medium for GridDensityMedium {
+  // Ratio tracking: step through the medium as though it had the majorant's density throughout,
+  // and at each step scale the transmittance by the chance that there was nothing really there
+  fn transmittance(&self, ray: &Ray, sampler: &mut SamplerInstance) -> Spectrum {
+    let (medium_ray, t_min, t_max) = match self.medium_ray(ray) {
+      Some(entry) => entry,
+      None => return Spectrum::greyscale(1.),
+    };
+
+    let mut transmittance = 1.;
+    self.for_each_segment(&medium_ray, t_min, t_max, |start, end, majorant| {
+      if majorant <= 0. {
+        return true;
+      }
+      let mut time = start;
+      loop {
+        time -= (1. - sampler.get_1d()).ln() / majorant;
+        if time >= end {
+          return true;
+        }
+        let density = self.density_at(medium_ray.origin + medium_ray.direction * time);
+        transmittance *= (1. - density * self.sigma_e / majorant).max(0.);
+
+        // Once there's little left, randomly give up rather than spend ever longer tracking it down to nothing
+        if transmittance < 0.1 {
+          let q = (1. - transmittance).max(0.05);
+          if sampler.get_1d() < q {
+            transmittance = 0.;
+            return false;
+          }
+          transmittance /= 1. - q;
+        }
+      }
+    });
+    Spectrum::greyscale(transmittance)
+  }
+
+  // Delta tracking: step through the medium as though it had the majorant's density throughout,
+  // and treat each step as a real collision with the probability of the real density over the majorant
+  fn sample(&self, ray: &Ray, sampler: &mut SamplerInstance) -> MediumSample {
+    let passed = MediumSample { weight: Spectrum::greyscale(1.), interaction: None };
+    let (medium_ray, t_min, t_max) = match self.medium_ray(ray) {
+      Some(entry) => entry,
+      None => return passed,
+    };
+
+    let mut collision = None;
+    self.for_each_segment(&medium_ray, t_min, t_max, |start, end, majorant| {
+      if majorant <= 0. {
+        return true;
+      }
+      let mut time = start;
+      loop {
+        time -= (1. - sampler.get_1d()).ln() / majorant;
+        if time >= end {
+          return true;
+        }
+        let density = self.density_at(medium_ray.origin + medium_ray.direction * time);
+        if density * self.sigma_e / majorant > sampler.get_1d() {
+          collision = Some(time);
+          return false;
+        }
+      }
+    });
+
+    match collision {
+      Some(distance) => MediumSample {
+        weight: self.sigma_s / self.sigma_e,
+        interaction: Some(MediumInteraction {
+          point: ray.origin + ray.direction * (distance / ray.direction.length()),
+          outgoing: -ray.direction,
+          phase: self.phase,
+        }),
+      },
+      None => passed,
+    }
+  }
+}
+
+/// The greatest density in reach of any point within each cell of the coarse grid, counting the neighbouring
+/// samples that trilinear interpolation blends in
+fn build_majorants(resolution: [usize; 3], density: &[f64]) -> Vec<f64> {
+  let coarse = MAJORANT_RESOLUTION as f64;
+  let samples_within = |axis: usize, cell: usize| {
+    let n = resolution[axis] as f64;
+    let low = (cell as f64 / coarse * n - 0.5).floor().max(0.) as usize;
+    let high = ((((cell + 1) as f64 / coarse * n - 0.5).floor() + 1.) as usize).min(resolution[axis] - 1);
+    low..=high
+  };
+
+  let [nx, ny, _] = resolution;
+  let mut majorants = vec![0.; MAJORANT_RESOLUTION * MAJORANT_RESOLUTION * MAJORANT_RESOLUTION];
+  for cz in 0..MAJORANT_RESOLUTION {
+    for cy in 0..MAJORANT_RESOLUTION {
+      for cx in 0..MAJORANT_RESOLUTION {
+        let mut max: f64 = 0.;
+        for z in samples_within(2, cz) {
+          for y in samples_within(1, cy) {
+            for x in samples_within(0, cx) {
+              max = max.max(density[(z * ny + y) * nx + x]);
+            }
+          }
+        }
+        majorants[(cz * MAJORANT_RESOLUTION + cy) * MAJORANT_RESOLUTION + cx] = max;
+      }
+    }
+  }
+  majorants
+}
+
+/// Load the resolution and densities from a binary volume file, as exported by simulation tools
+///
+/// The format is little-endian throughout:
+///  - the 8 byte magic `OPTQGRID`, then a u32 version, then the u32 resolution along x, y and z
+///  - then each density as an f32, with x varying fastest and then y
+fn read_volume(file: &Path) -> io::Result<([usize; 3], Vec<f64>)> {
+  let input = File::open(file)?;
+  let length = input.metadata()?.len();
+  let mut input = BufReader::new(input);
+
+  let mut magic = [0u8; 8];
+  input.read_exact(&mut magic)?;
+  if &magic != VOLUME_MAGIC {
+    return Err(invalid("Not a volume file"));
+  }
+  let version = read_u32(&mut input)?;
+  if version != VOLUME_VERSION {
+    return Err(invalid(&format!("Unsupported volume version {}", version)));
+  }
+  let resolution = [read_u32(&mut input)? as usize, read_u32(&mut input)? as usize, read_u32(&mut input)? as usize];
+  // Make sure the file really holds that many densities before setting aside room for them
+  let count = resolution.iter().try_fold(1usize, |count, &n| count.checked_mul(n));
+  let size = count.and_then(|count| count.checked_mul(4));
+  if size.map_or(true, |size| size as u64 != length.saturating_sub(VOLUME_HEADER_SIZE)) {
+    return Err(invalid(&format!("Volume file doesn't hold the {}x{}x{} densities it says", resolution[0], resolution[1], resolution[2])));
+  }
+  let count = count.unwrap_or_default();
+  check_resolution(resolution, count)?;
+
+  let mut bytes = vec![0u8; count * 4];
+  input.read_exact(&mut bytes)?;
+  let density = bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64).collect();
+  Ok((resolution, density))
+}
+
+fn check_resolution(resolution: [usize; 3], count: usize) -> io::Result<()> {
+  let [nx, ny, nz] = resolution;
+  if nx == 0 || ny == 0 || nz == 0 {
+    return Err(invalid("Density grid has an empty resolution"));
+  }
+  if count != nx * ny * nz {
+    return Err(invalid(&format!("Expected {} densities for a {}x{}x{} grid, found {}", nx * ny * nz, nx, ny, nz, count)));
+  }
+  Ok(())
+}
+
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+  a + (b - a) * t
+}
+
+fn invalid(message: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+  let mut bytes = [0u8; 4];
+  input.read_exact(&mut bytes)?;
+  Ok(u32::from_le_bytes(bytes))
+}
@@ -0,0 +1,64 @@
+use std::io;
+
+use crate::{geometry::Ray, render::{Sampler, SamplerInstance, Spectrum}, scene::{HenyeyGreenstein, Medium, MediumInteraction, MediumSample, PbrtParameters}};
+
+/// A medium with the same density everywhere, like fog or murky water
+pub struct HomogeneousMedium {
+  /// How much light is absorbed per unit distance
+  pub sigma_a: Spectrum,
+  /// How much light is scattered per unit distance
+  pub sigma_s: Spectrum,
+  /// How much light is lost altogether per unit distance: the sum of absorption and scattering
+  pub sigma_e: Spectrum,
+  pub phase: HenyeyGreenstein,
+}
+
+impl HomogeneousMedium {
+  pub fn new(sigma_a: Spectrum, sigma_s: Spectrum, g: f64) -> Self {
+    Self { sigma_a, sigma_s, sigma_e: sigma_a + sigma_s, phase: HenyeyGreenstein { g } }
+  }
+
+  /// Build a medium from a pbrt `MakeNamedMedium` statement for a `"homogeneous"` medium
+  pub fn from_pbrt(statement: &str) -> io::Result<Self> {
+    let parameters = PbrtParameters::parse(statement)?;
+
+    let scale = parameters.float("scale", 1.)?;
+    let sigma_a = parameters.spectrum("sigma_a", Spectrum { r: 0.0011, g: 0.0024, b: 0.014 })? * scale;
+    let sigma_s = parameters.spectrum("sigma_s", Spectrum { r: 2.55, g: 3.21, b: 3.77 })? * scale;
+    let g = parameters.float("g", 0.)?;
+    Ok(Self::new(sigma_a, sigma_s, g))
+  }
+}
+
+impl Medium for HomogeneousMedium {
+  fn transmittance(&self, ray: &Ray, _sampler: &mut SamplerInstance) -> Spectrum {
+    let distance = (ray.time_max * ray.direction.length()).min(f64::MAX);
+    (self.sigma_e * -distance).exp()
+  }
+
+  fn sample(&self, ray: &Ray, sampler: &mut SamplerInstance) -> MediumSample {
+    // Pick a color channel, and sample a distance according to its extinction
+    let channel = ((sampler.get_1d() * 3.) as usize).min(2);
+    let length = ray.direction.length();
+    let distance = -(1. - sampler.get_1d()).ln() / self.sigma_e.channel(channel);
+    let time = (distance / length).min(ray.time_max);
+    let scattered = time < ray.time_max;
+
+    let transmittance = (self.sigma_e * -(time * length).min(f64::MAX)).exp();
+    // Average the density of every channel, since any of them might have been the one that was sampled
+    let density = if scattered { self.sigma_e * transmittance } else { transmittance };
+    let pdf = density.average();
+    if pdf == 0. {
+      return MediumSample { weight: Spectrum::default(), interaction: None };
+    }
+
+    if scattered {
+      MediumSample {
+        weight: transmittance * self.sigma_s / pdf,
+        interaction: Some(MediumInteraction { point: ray.origin + ray.direction * time, outgoing: -ray.direction, phase: self.phase }),
+      }
+    } else {
+      MediumSample { weight: transmittance / pdf, interaction: None }
+    }
+  }
+}
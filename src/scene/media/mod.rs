@@ -0,0 +1,4 @@
+mod grid;
+mod homogeneous;
+pub use grid::*;
+pub use homogeneous::*;
\ No newline at end of file
@@ -0,0 +1,96 @@
+use std::{f64::consts::PI, sync::Arc};
+
+use enum_dispatch::enum_dispatch;
+
+use crate::{geometry::{Intersection, Point2, Point3, Ray, Vector3}, render::{SamplerInstance, Spectrum}};
+use super::{GridDensityMedium, HomogeneousMedium};
+
+/// Describes how light scatters off the particles in a medium
+#[derive(Clone, Copy)]
+pub struct HenyeyGreenstein {
+  /// The asymmetry: negative values scatter light back the way it came, positive values forward
+  pub g: f64,
+}
+
+impl HenyeyGreenstein {
+  /// The fraction of light arriving from `incoming` that scatters towards `outgoing`, which is also the density
+  /// with which `sample` chooses it
+  pub fn evaluate(&self, outgoing: Vector3, incoming: Vector3) -> f64 {
+    henyey_greenstein(outgoing.dot(incoming), self.g)
+  }
+
+  /// Choose an incoming direction in proportion to the phase function, returning it and its density
+  pub fn sample(&self, outgoing: Vector3, u: Point2) -> (f64, Vector3) {
+    let g = self.g;
+    let cos_theta = if g.abs() < 1e-3 {
+      1. - 2. * u.x
+    } else {
+      let square = (1. - g * g) / (1. + g - 2. * g * u.x);
+      -(1. + g * g - square * square) / (2. * g)
+    };
+
+    // Build the direction around the outgoing one
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    let phi = 2. * PI * u.y;
+    let (v1, v2) = outgoing.coordinate_system();
+    let incoming = v1 * (sin_theta * phi.cos()) + v2 * (sin_theta * phi.sin()) + outgoing * cos_theta;
+    (henyey_greenstein(cos_theta, g), incoming)
+  }
+}
+
+fn henyey_greenstein(cos_theta: f64, g: f64) -> f64 {
+  let denominator = 1. + g * g + 2. * g * cos_theta;
+  (1. - g * g) / (4. * PI * denominator * denominator.max(0.).sqrt())
+}
+
+/// A point inside a medium where light scatters
+#[derive(Clone, Copy)]
+pub struct MediumInteraction {
+  pub point: Point3,
+  pub outgoing: Vector3,
+  pub phase: HenyeyGreenstein,
+}
+
+impl MediumInteraction {
+  /// The interaction as a surface-less intersection, for sampling lights and spawning rays from it
+  pub fn intersection(&self) -> Intersection {
+    Intersection { point: self.point, outgoing: self.outgoing, ..Default::default() }
+  }
+}
+
+/// The outcome of sampling how far a ray travels through a medium
+pub struct MediumSample {
+  /// Scales the ray's throughput, accounting for the transmittance and the probability of the sample
+  pub weight: Spectrum,
+  /// Where the ray scatters, or None if it passed all the way through to the end of the ray
+  pub interaction: Option<MediumInteraction>,
+}
+
+#[enum_dispatch]
+pub trait Medium {
+  /// The fraction of light that makes it along the ray, from its origin to `time_max`
+  fn transmittance(&self, ray: &Ray, sampler: &mut SamplerInstance) -> Spectrum; // pbrt: Tr()
+  /// Choose how far along the ray it scatters, if it does before reaching `time_max`
+  fn sample(&self, ray: &Ray, sampler: &mut SamplerInstance) -> MediumSample;
+}
+
+#[enum_dispatch(Medium)]
+pub enum MediumInstance {
+  HomogeneousMedium,
+  GridDensityMedium,
+}
+
+/// Which medium is on either side of a surface, if it's the boundary between two
+/// None means empty space, without a medium
+#[derive(Clone, Default)]
+pub struct MediumInterface {
+  pub inside: Option<Arc<MediumInstance>>,
+  pub outside: Option<Arc<MediumInstance>>,
+}
+
+impl MediumInterface {
+  /// The medium a ray leaving the surface in the given direction travels through
+  pub fn towards(&self, intersection: &Intersection, direction: Vector3) -> Option<Arc<MediumInstance>> {
+    if direction.dot(intersection.normal.into()) > 0. { self.outside.clone() } else { self.inside.clone() }
+  }
+}
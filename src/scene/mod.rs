@@ -1,15 +1,25 @@
 mod scene;
 mod light;
+mod light_sampler;
+mod lights;
 mod material;
 mod materials;
+mod media;
+mod medium;
+mod pbrt;
 mod primitive;
 mod shape;
 mod shapes;
 
 pub use scene::*;
 pub use light::*;
+pub use light_sampler::*;
+pub use lights::*;
 pub use material::*;
 pub use materials::*;
+pub use media::*;
+pub use medium::*;
+pub use pbrt::*;
 pub use primitive::*;
 pub use shape::*;
 pub use shapes::*;
\ No newline at end of file
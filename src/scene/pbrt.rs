@@ -0,0 +1,238 @@
+use std::{collections::{HashMap, HashSet}, io};
+
+use crate::{geometry::Point3, render::Spectrum};
+
+/// The parameters of a single pbrt statement, such as
+/// `LightSource "spot" "point from" [0 5 0] "float coneangle" 20`
+///
+/// Only what's needed to set up lights, media and meshes is kept: the numeric and string parameters, and the bare string
+/// naming the kind of thing the statement describes
+pub struct PbrtParameters {
+  kind: Option<String>,
+  numbers: HashMap<String, Vec<f64>>,
+  strings: HashMap<String, String>,
+  /// The numeric parameters given as a blackbody temperature, rather than a color
+  blackbody: HashSet<String>,
+}
+
+impl PbrtParameters {
+  pub fn parse(statement: &str) -> io::Result<Self> {
+    let mut parameters = Self { kind: None, numbers: HashMap::new(), strings: HashMap::new(), blackbody: HashSet::new() };
+    // The parameter currently taking values, and whether its values are strings rather than numbers
+    let mut current: Option<(String, bool)> = None;
+
+    let mut chars = statement.chars().peekable();
+    while let Some(c) = chars.next() {
+      match c {
+        '#' => while chars.next_if(|&c| c != '\n').is_some() {},
+        '"' => {
+          let text: String = std::iter::from_fn(|| chars.next_if(|&c| c != '"')).collect();
+          if chars.next().is_none() {
+            return Err(invalid(&format!("Unterminated string {:?}", text)));
+          }
+          let words: Vec<&str> = text.split_whitespace().collect();
+          match (&current, words.as_slice()) {
+            // String parameters only take the one value
+            (Some((name, true)), _) => {
+              parameters.strings.insert(name.clone(), text);
+              current = None;
+            },
+            (_, &[kind, name]) => {
+              let numeric = matches!(kind, "float" | "integer" | "point" | "point2" | "point3" | "vector" | "vector3" | "normal" | "normal3" | "rgb" | "color" | "blackbody");
+              if numeric {
+                parameters.numbers.entry(name.to_string()).or_default();
+              }
+              if kind == "blackbody" {
+                parameters.blackbody.insert(name.to_string());
+              }
+              current = Some((name.to_string(), !numeric));
+            },
+            // Anything else names the light, medium, etc. itself
+            _ => if parameters.kind.is_none() {
+              parameters.kind = Some(text);
+            },
+          }
+        },
+        '[' | ']' => {},
+        c if c.is_whitespace() => {},
+        c => {
+          let mut word = c.to_string();
+          word.extend(std::iter::from_fn(|| chars.next_if(|c| !c.is_whitespace() && !"\"[]#".contains(*c))));
+          match (&current, word.parse::<f64>()) {
+            (Some((name, false)), Ok(value)) => parameters.numbers.get_mut(name).unwrap().push(value),
+            // pbrt-v4 writes bools bare, as in `"bool twosided" true`
+            (Some((name, true)), _) if word == "true" || word == "false" => {
+              parameters.strings.insert(name.clone(), word);
+              current = None;
+            },
+            (Some((name, true)), _) => return Err(invalid(&format!("Expected a quoted string for {}, found {:?}", name, word))),
+            (Some((name, false)), Err(_)) => return Err(invalid(&format!("Expected a number for {}, found {:?}", name, word))),
+            // Skip the statement's own keyword
+            (None, _) => {},
+          }
+        },
+      }
+    }
+    if let Some((name, true)) = current {
+      return Err(invalid(&format!("No value given for {}", name)));
+    }
+    Ok(parameters)
+  }
+
+  /// The first bare string in the statement, like the `"spot"` in `LightSource "spot"`
+  pub fn kind(&self) -> Option<&str> {
+    self.kind.as_deref()
+  }
+
+  /// All the values given for a parameter, however many there are
+  pub fn values(&self, name: &str) -> Option<&[f64]> {
+    self.numbers.get(name).map(|values| values.as_slice())
+  }
+
+  /// The values of a parameter, which must have as many values as its default
+  pub fn get(&self, name: &str, default: &[f64]) -> io::Result<Vec<f64>> {
+    match self.numbers.get(name) {
+      Some(values) if values.len() != default.len() => Err(invalid(&format!(
+        "Expected {} values for {}, found {}", default.len(), name, values.len(),
+      ))),
+      Some(values) => Ok(values.clone()),
+      None => Ok(default.to_vec()),
+    }
+  }
+
+  pub fn float(&self, name: &str, default: f64) -> io::Result<f64> {
+    Ok(self.get(name, &[default])?[0])
+  }
+
+  pub fn point(&self, name: &str, default: Point3) -> io::Result<Point3> {
+    let p = self.get(name, &[default.x, default.y, default.z])?;
+    Ok(Point3 { x: p[0], y: p[1], z: p[2] })
+  }
+
+  /// A color given as `"rgb L" [r g b]`, or as a temperature in kelvin with `"blackbody L" [T]`,
+  /// optionally followed by a scale as in pbrt's older `"blackbody L" [T scale]`
+  pub fn spectrum(&self, name: &str, default: Spectrum) -> io::Result<Spectrum> {
+    if self.blackbody.contains(name) {
+      let (temperature, scale) = match self.values(name).unwrap_or(&[]) {
+        &[temperature] => (temperature, 1.),
+        &[temperature, scale] => (temperature, scale),
+        values => return Err(invalid(&format!("Expected a temperature and optional scale for {}, found {} values", name, values.len()))),
+      };
+      if !temperature.is_finite() || temperature <= 0. {
+        return Err(invalid(&format!("Expected a positive temperature in kelvin for {}, found {}", name, temperature)));
+      }
+      return Ok(Spectrum::blackbody(temperature) * scale);
+    }
+    let c = self.get(name, &[default.r, default.g, default.b])?;
+    Ok(Spectrum { r: c[0], g: c[1], b: c[2] })
+  }
+
+  pub fn string(&self, name: &str) -> Option<&str> {
+    self.strings.get(name).map(|s| s.as_str())
+  }
+
+  /// A parameter like `"bool twosided" "true"`, or `"bool twosided" true`
+  pub fn bool(&self, name: &str, default: bool) -> io::Result<bool> {
+    match self.string(name) {
+      None => Ok(default),
+      Some("true") => Ok(true),
+      Some("false") => Ok(false),
+      Some(other) => Err(invalid(&format!("Expected true or false for {}, found {:?}", name, other))),
+    }
+  }
+}
+
+/// Split the text of a pbrt file into its statements, each starting with its keyword, like `LightSource` or `Rotate`
+pub fn pbrt_statements(text: &str) -> Vec<&str> {
+  let mut starts = vec![];
+  let (mut in_string, mut in_comment, mut previous) = (false, false, ' ');
+  for (i, c) in text.char_indices() {
+    match c {
+      '\n' if in_comment => in_comment = false,
+      _ if in_comment => {},
+      '"' => in_string = !in_string,
+      '#' if !in_string => in_comment = true,
+      c if !in_string && c.is_ascii_uppercase() && (previous.is_whitespace() || previous == ']') => starts.push(i),
+      _ => {},
+    }
+    previous = c;
+  }
+  starts.iter().enumerate().map(|(n, &start)| {
+    let end = starts.get(n + 1).copied().unwrap_or(text.len());
+    text[start..end].trim_end()
+  }).collect()
+}
+
+fn invalid(message: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_parameters() {
+    let parameters = PbrtParameters::parse(r#"LightSource "spot" # A comment with "quotes"
+      "point from" [0 5 -1.5] "float coneangle" 20 "rgb I" [1 0.5 0.25]
+      "string filename" "lamp.ies" "bool twosided" "false""#).unwrap();
+    assert_eq!(parameters.kind(), Some("spot"));
+    let from = parameters.point("from", Point3::default()).unwrap();
+    assert_eq!((from.x, from.y, from.z), (0., 5., -1.5));
+    assert_eq!(parameters.float("coneangle", 30.).unwrap(), 20.);
+    assert_eq!(parameters.float("conedelta", 5.).unwrap(), 5.);
+    let i = parameters.spectrum("I", Spectrum::white()).unwrap();
+    assert_eq!((i.r, i.g, i.b), (1., 0.5, 0.25));
+    assert_eq!(parameters.string("filename"), Some("lamp.ies"));
+    assert!(!parameters.bool("twosided", true).unwrap());
+  }
+
+  #[test]
+  fn parses_bare_bools() {
+    let parameters = PbrtParameters::parse(r#"Shape "bilinearmesh" "bool twosided" true "bool emissive" false"#).unwrap();
+    assert!(parameters.bool("twosided", false).unwrap());
+    assert!(!parameters.bool("emissive", true).unwrap());
+    assert!(parameters.bool("flipped", true).unwrap());
+  }
+
+  #[test]
+  fn parses_blackbody() {
+    let parameters = PbrtParameters::parse(r#"LightSource "point" "blackbody I" [5500 2]"#).unwrap();
+    let i = parameters.spectrum("I", Spectrum::white()).unwrap();
+    assert_eq!(i.r, Spectrum::blackbody(5500.).r * 2.);
+
+    for temperature in ["0", "-300", "inf", "NaN"] {
+      let parameters = PbrtParameters::parse(&format!(r#"LightSource "point" "blackbody I" [{}]"#, temperature)).unwrap();
+      assert!(parameters.spectrum("I", Spectrum::white()).is_err(), "accepted {} kelvin", temperature);
+    }
+  }
+
+  #[test]
+  fn rejects_malformed_parameters() {
+    let invalid = [
+      r#"LightSource "goniometric" "string filename" lamp.ies"#,
+      r#"LightSource "point" "float scale" [two]"#,
+      r#"LightSource "point" "float scale"#,
+      r#"LightSource "goniometric" "string filename""#,
+      r#"LightSource "goniometric" "string filename" "lamp.ies"#,
+    ];
+    for statement in invalid {
+      assert!(PbrtParameters::parse(statement).is_err(), "accepted {:?}", statement);
+    }
+
+    let parameters = PbrtParameters::parse(r#"LightSource "spot" "point from" [0 5] "bool twosided" "maybe" "blackbody I" []"#).unwrap();
+    assert!(parameters.point("from", Point3::default()).is_err());
+    assert!(parameters.bool("twosided", false).is_err());
+    assert!(parameters.spectrum("I", Spectrum::white()).is_err());
+  }
+
+  #[test]
+  fn splits_statements() {
+    let statements = pbrt_statements("LightSource \"point\" # Not A statement\n  \"rgb I\" [1 1 1]\nAttributeBegin\nShape \"sphere\" \"string name\" \"Not Either\"");
+    assert_eq!(statements, vec![
+      "LightSource \"point\" # Not A statement\n  \"rgb I\" [1 1 1]",
+      "AttributeBegin",
+      "Shape \"sphere\" \"string name\" \"Not Either\"",
+    ]);
+  }
+}
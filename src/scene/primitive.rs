@@ -5,11 +5,15 @@ use bumpalo::Bump;
 use enum_dispatch::enum_dispatch;
 use crate::{geometry::{Bounds3, Interaction, Point3, Ray, Vector3}};
 
-use super::{AreaLight, MaterialInstance, Shape, ShapeInstance};
+use super::{AreaLight, LightInstance, MaterialInstance, MediumInterface, Shape, ShapeInstance, TriangleMesh};
 #[enum_dispatch]
 pub trait Primitive {
   fn world_bounds(&self) -> Bounds3<f64>;
   fn intersect(&self, ray: &Ray) -> Option<Interaction>;
+  /// How many acceleration structure nodes a ray visits while looking for its intersection, for debugging
+  fn nodes_visited(&self, _ray: &Ray) -> u32 { 0 }
+  /// Add the lights given off by any emissive surfaces, so they can be sampled like any other light
+  fn area_lights(&self, _lights: &mut Vec<LightInstance>) {}
 }
 
 #[enum_dispatch(Primitive)]
@@ -32,9 +36,32 @@ impl Primitive for NullPrimitive {
 }
 
 pub struct GeometricPrimitive {
-  pub shape: ShapeInstance,
+  /// Shared with the primitive's area light, if it gives off light
+  pub shape: Arc<ShapeInstance>,
   pub material: Option<MaterialInstance>,
   pub emission: Option<AreaLight>,
+  /// The media inside and outside the shape, if it separates two
+  pub medium_interface: Option<MediumInterface>,
+}
+
+impl GeometricPrimitive {
+  /// A primitive for each triangle of a mesh.  If the mesh gives off light, `emission` makes an area light for each
+  /// triangle, so that light samplers can choose between the triangles and each can be sampled on its own.
+  pub fn from_mesh(
+    mesh: Arc<TriangleMesh>,
+    material: Option<MaterialInstance>,
+    emission: Option<&dyn Fn(Arc<ShapeInstance>) -> AreaLight>,
+  ) -> Vec<PrimitiveInstance> {
+    mesh.to_triangles().into_iter().map(|triangle| {
+      let shape = Arc::new(triangle);
+      GeometricPrimitive {
+        emission: emission.map(|emission| emission(shape.clone())),
+        shape,
+        material,
+        medium_interface: None,
+      }.into()
+    }).collect()
+  }
 }
 
 impl Primitive for GeometricPrimitive {
@@ -48,9 +75,16 @@ impl Primitive for GeometricPrimitive {
         intersection,
         emission: self.emission.clone(),
         material: self.material.clone(),
+        medium_interface: self.medium_interface.clone(),
       }
     }) 
   }
+
+  fn area_lights(&self, lights: &mut Vec<LightInstance>) {
+    if let Some(emission) = &self.emission {
+      lights.push(emission.clone().into());
+    }
+  }
 }
 
 pub struct PrimitiveList {
@@ -75,6 +109,12 @@ impl Primitive for PrimitiveList {
         }
         min_interaction
     }
+
+    fn area_lights(&self, lights: &mut Vec<LightInstance>) {
+        for p in &self.primitives {
+          p.area_lights(lights);
+        }
+    }
 }
 
 pub enum SplitMethod { SurfaceArea, Linear, Middle, EqualCounts }
@@ -282,6 +322,25 @@ impl Primitive for BVHAggregate {
   }
 
   fn intersect(&self, ray: &Ray) -> Option<Interaction> {
+    self.traverse(ray, &mut 0)
+  }
+
+  fn nodes_visited(&self, ray: &Ray) -> u32 {
+    let mut visited = 0;
+    self.traverse(ray, &mut visited);
+    visited
+  }
+
+  fn area_lights(&self, lights: &mut Vec<LightInstance>) {
+    for primitive in &self.primitives {
+      primitive.area_lights(lights);
+    }
+  }
+}
+
+impl BVHAggregate {
+  /// Find the closest intersection, counting the nodes visited along the way
+  fn traverse(&self, ray: &Ray, visited: &mut u32) -> Option<Interaction> {
     let inv_dir = Vector3::new(1. / ray.direction.x, 1. / ray.direction.y, 1. / ray.direction.z);
     let is_neg = [
       if inv_dir.x < 0. { 1 } else { 0 },
@@ -297,6 +356,7 @@ impl Primitive for BVHAggregate {
     let mut ray = ray.clone();
     loop {
       let node = &self.nodes[curr_node];
+      *visited += 1;
       if node.bounds().any_intersect_precomputed(&ray, inv_dir, is_neg) {
         match node {
           BVHNode::Interior { axis, second_child, .. } => {
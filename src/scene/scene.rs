@@ -1,47 +1,84 @@
+use std::{collections::HashMap, sync::Arc};
+
 use crate::{geometry::{Bounds3, Interaction, Ray, Transform, Vector3}, render::Spectrum};
 
-use super::{AreaLight, GeometricPrimitive, Light, LightInstance, Primitive, PrimitiveInstance, ShapeInstance, SphereShape};
+use super::{AreaLight, BVHLightSampler, GeometricPrimitive, Light, LightInstance, LightSamplerInstance, Primitive, PrimitiveInstance, ShapeInstance, SphereShape};
 
 #[allow(dead_code)]
 pub struct Scene {
   pub lights: Vec<LightInstance>,
+  /// Chooses which light to sample, for integrators that take one light sample at a time
+  pub light_sampler: LightSamplerInstance,
+  /// Where each area light is in `lights`, by the address of its shape, since the copies of them held by
+  /// primitives aren't the ones in `lights`
+  pub area_light_indices: HashMap<usize, usize>,
   pub root: PrimitiveInstance,
   pub world_bounds: Bounds3<f64>,
 }
 
 #[allow(dead_code)]
 impl Scene {
+  /// Any emissive primitives under `root` are added to the lights, which are sampled with a `BVHLightSampler`
   pub fn new(root: PrimitiveInstance, lights: Vec<LightInstance>) -> Scene {
     let world_bounds = root.world_bounds();
+    let mut lights = lights;
+    root.area_lights(&mut lights);
     let mut scene = Scene {
       lights: vec![],
+      light_sampler: BVHLightSampler::new(&[]).into(),
+      area_light_indices: HashMap::new(),
       root,
       world_bounds,
     };
 
     for mut light in lights {
       light.preprocess(&scene);
+      if let LightInstance::AreaLight(area) = &light {
+        scene.area_light_indices.insert(Arc::as_ptr(&area.shape) as usize, scene.lights.len());
+      }
       scene.lights.push(light);
     }
+    scene.light_sampler = BVHLightSampler::new(&scene.lights).into();
 
     scene
   }
 
   pub fn from(scene: &pbrt_rs::Scene) -> Scene {
     Scene::new(
-      PrimitiveInstance::from(GeometricPrimitive {
-        shape: ShapeInstance::from(SphereShape { object_to_world: Transform::translate( Vector3 { x: 0., y: 0., z: -10. } ), radius: 2. }),
-        material: None,
-        emission: Some(AreaLight { emitted_color: Spectrum { r: 0.3, g: 0., b: 0. } }),
-      }),
+      {
+        let shape = Arc::new(ShapeInstance::from(SphereShape { object_to_world: Transform::translate( Vector3 { x: 0., y: 0., z: -10. } ), radius: 2. }));
+        PrimitiveInstance::from(GeometricPrimitive {
+          shape: shape.clone(),
+          material: None,
+          emission: Some(AreaLight::new(shape, Spectrum { r: 0.3, g: 0., b: 0. })),
+          medium_interface: None,
+        })
+      },
       scene.lights.iter().map(LightInstance::from).collect(),
     )
   }
 
+  /// Where a light is in `lights`
+  pub fn light_index(&self, light: &LightInstance) -> Option<usize> {
+    match light {
+      LightInstance::AreaLight(area) => self.area_light_index(area),
+      _ => self.lights.iter().position(|other| std::ptr::eq(other, light)),
+    }
+  }
+
+  /// Where the light given off by an emissive primitive is in `lights`
+  pub fn area_light_index(&self, light: &AreaLight) -> Option<usize> {
+    self.area_light_indices.get(&(Arc::as_ptr(&light.shape) as usize)).copied()
+  }
+
   pub fn intersect(&self, ray: &Ray) -> Option<Interaction> {
     self.root.intersect(&ray)
   }
 
+  pub fn nodes_visited(&self, ray: &Ray) -> u32 {
+    self.root.nodes_visited(ray)
+  }
+
   pub fn any_intersect(&self, ray: &Ray) -> bool {
     // TODO: optimize
     self.root.intersect(&ray).is_some()
@@ -1,9 +1,17 @@
 use enum_dispatch::enum_dispatch;
 
-use crate::geometry::{Bounds3, Intersection, Ray, Transform};
+use crate::geometry::{Bounds3, DirectionCone, Intersection, Point2, Ray, Transform, Vector3};
 
 use super::{SphereShape, DiskShape, TriangleShape};
 
+/// A point chosen on the surface of a shape
+#[derive(Default)]
+pub struct ShapeSample {
+  pub intersection: Intersection,
+  /// Over area for `Shape::sample`, or over solid angle at the reference point for `Shape::sample_from`
+  pub probability_distribution: f64,
+}
+
 #[enum_dispatch]
 pub trait Shape {
   fn object_to_world(&self) -> Transform;
@@ -13,7 +21,43 @@ pub trait Shape {
   }
   fn intersect(&self, ray: &Ray) -> Option<Intersection>;
   fn any_intersect(&self, ray: &Ray) -> bool { self.intersect(ray).is_some() }
+
+  /// The surface area, in world space
+  fn area(&self) -> f64;
+  /// Choose a point uniformly over the surface
+  fn sample(&self, u: Point2) -> ShapeSample;
+  /// Choose a point on the surface as seen from a reference point, such as one being lit by the shape.
+  /// Shapes that can do better than choosing uniformly by area, like spheres, favour the part that's visible.
+  fn sample_from(&self, reference: &Intersection, u: Point2) -> ShapeSample {
+    solid_angle_sample(self.sample(u), reference)
+  }
+  /// The density over solid angle with which `sample_from` would choose a direction from the reference point
+  fn pdf(&self, reference: &Intersection, incident_direction: Vector3) -> f64 {
+    area_pdf(self, reference, incident_direction)
+  }
+  /// The directions the surface's normals face in, in world space
+  fn normal_bounds(&self) -> DirectionCone { DirectionCone::entire_sphere() }
 }
+/// Convert a point chosen uniformly by area into one chosen over solid angle from the reference point
+pub fn solid_angle_sample(mut sample: ShapeSample, reference: &Intersection) -> ShapeSample {
+  let offset = sample.intersection.point - reference.point;
+  let distance_sq = offset.length_squared();
+  let cos_theta = if distance_sq == 0. { 0. } else { offset.dot(sample.intersection.normal.into()).abs() / distance_sq.sqrt() };
+  sample.probability_distribution = if cos_theta == 0. { 0. } else { sample.probability_distribution * distance_sq / cos_theta };
+  sample
+}
+
+/// The density over solid angle of choosing a direction from the reference point, for points chosen uniformly by area
+pub fn area_pdf<S: Shape + ?Sized>(shape: &S, reference: &Intersection, incident_direction: Vector3) -> f64 {
+  let hit = match shape.intersect(&reference.spawn_ray(incident_direction)) {
+    Some(hit) => hit,
+    None => return 0.,
+  };
+  let distance_sq = (hit.point - reference.point).length_squared();
+  let cos_theta = incident_direction.normalized().dot(hit.normal.into()).abs();
+  if cos_theta == 0. { 0. } else { distance_sq / (cos_theta * shape.area()) }
+}
+
 #[enum_dispatch(Shape)]
 pub enum ShapeInstance {
   NullShape,
@@ -28,4 +72,6 @@ impl Shape for NullShape {
   fn object_to_world(&self) -> Transform { Transform::default() }
   fn bounds(&self) -> Bounds3<f64> { Bounds3::default() }
   fn intersect(&self, _ray: &Ray) -> Option<Intersection> { None }
+  fn area(&self) -> f64 { 0. }
+  fn sample(&self, _u: Point2) -> ShapeSample { ShapeSample::default() }
 }
\ No newline at end of file
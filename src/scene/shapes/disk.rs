@@ -1,6 +1,6 @@
 use std::f64::consts;
 
-use crate::{geometry::{Bounds3, Intersection, MulWithError, Normal3, Point3, Transform, Vector3}, scene::Shape};
+use crate::{geometry::{Bounds3, DirectionCone, Intersection, MulWithError, Normal3, Point2, Point3, Transform, Vector3}, scene::{Shape, ShapeSample}};
 
 pub struct DiskShape {
   pub object_to_world: Transform,
@@ -60,6 +60,7 @@ impl Shape for DiskShape {
 
     Some(self.object_to_world * Intersection {
       point: hit_point,
+      uv: Point2::new(u, v),
       point_derivative: (dpdu, dpdv),
       error: Vector3::default(),
       distance: hit_time,
@@ -70,4 +71,24 @@ impl Shape for DiskShape {
       shading_normal_derivative: (dndu, dndv),
     })
   }
+
+  fn area(&self) -> f64 {
+    consts::PI * (self.radius * self.radius - self.inner_radius * self.inner_radius)
+  }
+
+  fn sample(&self, u: Point2) -> ShapeSample {
+    // Choose the radius so that points are spread evenly over the ring's area
+    let radius = (self.inner_radius * self.inner_radius + u.x * (self.radius * self.radius - self.inner_radius * self.inner_radius)).sqrt();
+    let phi = u.y * consts::TAU;
+    let point = self.object_to_world * Point3::new(radius * phi.cos(), radius * phi.sin(), self.height);
+    let normal = (self.object_to_world * Normal3::new(0., 0., 1.)).normalized();
+    ShapeSample {
+      intersection: Intersection { point, normal, shading_normal: normal, ..Default::default() },
+      probability_distribution: 1. / self.area(),
+    }
+  }
+
+  fn normal_bounds(&self) -> DirectionCone {
+    DirectionCone::from_direction((self.object_to_world * Normal3::new(0., 0., 1.)).into())
+  }
 }
\ No newline at end of file
@@ -1,6 +1,7 @@
 use std::f64::consts;
 
-use crate::{geometry::{Bounds3, ErrorFloat, Intersection, MulWithError, Normal3, Point3, Ray, Transform, Vector3, gamma}, scene::Shape};
+use crate::{geometry::{Bounds3, ErrorFloat, Intersection, MulWithError, Normal3, Point2, Point3, Ray, Transform, Vector3, gamma}, render::{uniform_cone_pdf, uniform_sample_sphere}};
+use crate::scene::{Shape, ShapeSample, area_pdf, solid_angle_sample};
 
 
 pub struct SphereShape {
@@ -123,6 +124,7 @@ impl Shape for SphereShape {
 
     Some(self.object_to_world * Intersection {
       point: point_hit,
+      uv: Point2::new(u, v),
       point_derivative: (dpdu, dpdv),
       outgoing: -ray.direction,
       normal,
@@ -133,4 +135,74 @@ impl Shape for SphereShape {
       error,
     })
   }
+
+  fn area(&self) -> f64 {
+    2. * consts::TAU * self.radius * self.radius
+  }
+
+  fn sample(&self, u: Point2) -> ShapeSample {
+    let direction = uniform_sample_sphere(u);
+    let point = Point3::from(direction * self.radius);
+    let (point, error) = self.object_to_world.mul_with_error_in(point, Vector3::from(point).abs() * gamma(5));
+    let normal = (self.object_to_world * Normal3::from(direction)).normalized();
+    ShapeSample {
+      intersection: Intersection { point, error, normal, shading_normal: normal, ..Default::default() },
+      probability_distribution: 1. / self.area(),
+    }
+  }
+
+  fn sample_from(&self, reference: &Intersection, u: Point2) -> ShapeSample {
+    let center = self.object_to_world * Point3::new(0., 0., 0.);
+    let origin = reference.point.offset_for_error(reference.error, reference.normal, center - reference.point);
+    let to_center = center - origin;
+    let distance_sq = to_center.length_squared();
+    // From inside, all of the sphere can be seen
+    if distance_sq <= self.radius * self.radius {
+      return solid_angle_sample(self.sample(u), reference);
+    }
+
+    // Otherwise only choose from the cone of directions the sphere covers
+    let distance = distance_sq.sqrt();
+    let sin_theta_max_sq = self.radius * self.radius / distance_sq;
+    let cos_theta_max = (1. - sin_theta_max_sq).max(0.).sqrt();
+    let (sin_theta_sq, cos_theta) = if sin_theta_max_sq < 0.00068523 {
+      // Avoid losing precision for spheres that are tiny or far away
+      let sin_theta_sq = sin_theta_max_sq * u.x;
+      (sin_theta_sq, (1. - sin_theta_sq).sqrt())
+    } else {
+      let cos_theta = (1. - u.x) + u.x * cos_theta_max;
+      (1. - cos_theta * cos_theta, cos_theta)
+    };
+    let phi = u.y * consts::TAU;
+
+    // Find the point on the sphere in that direction, as an angle from the axis towards the reference point
+    let surface_distance = distance * cos_theta - (self.radius * self.radius - distance_sq * sin_theta_sq).max(0.).sqrt();
+    let cos_alpha = (distance_sq + self.radius * self.radius - surface_distance * surface_distance) / (2. * distance * self.radius);
+    let sin_alpha = (1. - cos_alpha * cos_alpha).max(0.).sqrt();
+    let axis = to_center / distance;
+    let (axis_x, axis_y) = axis.coordinate_system();
+    let normal = -(axis_x * (sin_alpha * phi.cos()) + axis_y * (sin_alpha * phi.sin()) + axis * cos_alpha);
+    let point = center + normal * self.radius;
+    ShapeSample {
+      intersection: Intersection {
+        point,
+        error: Vector3::from(point).abs() * gamma(5),
+        normal: normal.into(),
+        shading_normal: normal.into(),
+        ..Default::default()
+      },
+      probability_distribution: uniform_cone_pdf(cos_theta_max),
+    }
+  }
+
+  fn pdf(&self, reference: &Intersection, incident_direction: Vector3) -> f64 {
+    let center = self.object_to_world * Point3::new(0., 0., 0.);
+    let origin = reference.point.offset_for_error(reference.error, reference.normal, center - reference.point);
+    let distance_sq = (center - origin).length_squared();
+    if distance_sq <= self.radius * self.radius {
+      return area_pdf(self, reference, incident_direction);
+    }
+    let cos_theta_max = (1. - self.radius * self.radius / distance_sq).max(0.).sqrt();
+    uniform_cone_pdf(cos_theta_max)
+  }
 }
\ No newline at end of file
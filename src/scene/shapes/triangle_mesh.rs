@@ -1,12 +1,22 @@
 use std::sync::Arc;
 
-use crate::{geometry::{Bounds3, Intersection, Normal3, Point3, Ray, Transform, Vector3, gamma}, scene::{Shape, ShapeInstance}};
+use crate::{geometry::{Bounds3, DirectionCone, Intersection, Normal3, Point2, Point3, Ray, Transform, Vector3, gamma}, render::{spherical_triangle_area, uniform_sample_spherical_triangle}};
+use crate::scene::{Shape, ShapeInstance, ShapeSample, area_pdf, solid_angle_sample};
+
+/// Triangles covering less solid angle than this are sampled by area instead, since the spherical triangle
+/// sampling loses precision for them
+const MIN_SPHERICAL_SAMPLE_AREA: f64 = 3e-4;
+/// Triangles covering more solid angle than this are sampled by area instead, since the reference point is so
+/// close to them that the sampling becomes unstable
+const MAX_SPHERICAL_SAMPLE_AREA: f64 = 6.22;
 
 pub struct TriangleMesh {
   pub indices: Vec<usize>,
   pub vertices: Vec<Point3>,
   pub normals: Vec<Normal3>,
   pub tangents: Vec<Vector3>,
+  /// Texture coordinates for each vertex, or empty if the mesh has none
+  pub uvs: Vec<Point2>,
 }
 
 impl TriangleMesh {
@@ -16,6 +26,7 @@ impl TriangleMesh {
     vs: &[Point3],
     ns: &[Normal3],
     ts: &[Vector3],
+    uvs: &[Point2],
   ) -> Self {
 
     let indices = idx.to_vec();
@@ -30,7 +41,7 @@ impl TriangleMesh {
       tangents.push(object_to_world * ts[i]);
     }
 
-    Self { indices, vertices, normals, tangents }
+    Self { indices, vertices, normals, tangents, uvs: uvs.to_vec() }
   }
 
   pub fn to_triangles(self: Arc<Self>) -> Vec<ShapeInstance> {
@@ -58,6 +69,41 @@ impl TriangleShape {
       self.mesh.vertices[self.mesh.indices[self.index + 2]]
     )
   }
+
+  /// The texture coordinates at the corners, or pbrt's default parameterization if the mesh has none
+  fn uvs(&self) -> (Point2, Point2, Point2) {
+    if self.mesh.uvs.is_empty() {
+      return (Point2::new(0., 0.), Point2::new(1., 0.), Point2::new(1., 1.));
+    }
+    (
+      self.mesh.uvs[self.mesh.indices[self.index    ]],
+      self.mesh.uvs[self.mesh.indices[self.index + 1]],
+      self.mesh.uvs[self.mesh.indices[self.index + 2]]
+    )
+  }
+
+  fn uv(&self, b0: f64, b1: f64, b2: f64) -> Point2 {
+    let (uv0, uv1, uv2) = self.uvs();
+    Point2::new(uv0.x * b0 + uv1.x * b1 + uv2.x * b2, uv0.y * b0 + uv1.y * b1 + uv2.y * b2)
+  }
+
+  /// The solid angle the triangle covers as seen from a point
+  fn solid_angle(&self, point: Point3) -> f64 {
+    let (a, b, c) = self.directions_from(point);
+    spherical_triangle_area(a, b, c)
+  }
+
+  /// The directions from a point to each corner
+  fn directions_from(&self, point: Point3) -> (Vector3, Vector3, Vector3) {
+    let (p0, p1, p2) = self.vertices();
+    ((p0 - point).normalized(), (p1 - point).normalized(), (p2 - point).normalized())
+  }
+
+  /// Whether a reference point is far enough away from the triangle, and the triangle big enough,
+  /// for it to be sampled by solid angle
+  fn samples_spherically(&self, solid_angle: f64) -> bool {
+    (MIN_SPHERICAL_SAMPLE_AREA..=MAX_SPHERICAL_SAMPLE_AREA).contains(&solid_angle)
+  }
 }
                       
 impl Shape for TriangleShape {
@@ -176,8 +222,11 @@ impl Shape for TriangleShape {
 
     let normal = Normal3::from(dp02.cross(dp12).normalized());
 
+    let uv = self.uv(b0, b1, b2);
+
     return Some(Intersection {
       point: point_hit,
+      uv,
       error,
       distance: time_hit,
       normal: normal,
@@ -188,4 +237,59 @@ impl Shape for TriangleShape {
       ..Default::default()
     });
   }
+
+  fn area(&self) -> f64 {
+    let (p0, p1, p2) = self.vertices();
+    0.5 * (p1 - p0).cross(p2 - p0).length()
+  }
+
+  fn sample(&self, u: Point2) -> ShapeSample {
+    let (p0, p1, p2) = self.vertices();
+    // Barycentric coordinates spread evenly over the triangle
+    let su0 = u.x.sqrt();
+    let (b0, b1) = (1. - su0, u.y * su0);
+    let b2 = 1. - b0 - b1;
+    let point: Point3 = p0 * b0 + p1 * b1 + p2 * b2;
+    let error = (Vector3::from(p0 * b0).abs() + Vector3::from(p1 * b1).abs() + Vector3::from(p2 * b2).abs()) * gamma(6);
+    let normal = Normal3::from((p0 - p2).cross(p1 - p2).normalized());
+    ShapeSample {
+      intersection: Intersection { point, error, normal, shading_normal: normal, uv: self.uv(b0, b1, b2), ..Default::default() },
+      probability_distribution: 1. / self.area(),
+    }
+  }
+
+  /// Choose uniformly over the solid angle the triangle covers, unless it covers too little or too much of the sky
+  /// for that to work well, and then choose by area
+  fn sample_from(&self, reference: &Intersection, u: Point2) -> ShapeSample {
+    let solid_angle = self.solid_angle(reference.point);
+    if !self.samples_spherically(solid_angle) {
+      return solid_angle_sample(self.sample(u), reference);
+    }
+    let (a, b, c) = self.directions_from(reference.point);
+    let (direction, probability_distribution) = match uniform_sample_spherical_triangle(a, b, c, u) {
+      Some(sample) => sample,
+      None => return ShapeSample::default(),
+    };
+    // Find where that direction meets the triangle, for its normal and texture coordinates
+    match self.intersect(&reference.spawn_ray(direction)) {
+      Some(intersection) => ShapeSample { intersection, probability_distribution },
+      None => ShapeSample::default(),
+    }
+  }
+
+  fn pdf(&self, reference: &Intersection, incident_direction: Vector3) -> f64 {
+    let solid_angle = self.solid_angle(reference.point);
+    if !self.samples_spherically(solid_angle) {
+      return area_pdf(self, reference, incident_direction);
+    }
+    match self.intersect(&reference.spawn_ray(incident_direction)) {
+      Some(_) => 1. / solid_angle,
+      None => 0.,
+    }
+  }
+
+  fn normal_bounds(&self) -> DirectionCone {
+    let (p0, p1, p2) = self.vertices();
+    DirectionCone::from_direction((p0 - p2).cross(p1 - p2))
+  }
 }
\ No newline at end of file